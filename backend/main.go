@@ -9,8 +9,14 @@ import (
   "math"
   "math/rand"
   "sync"
+  "time"
 )
 
+// How long a disconnected player keeps the seat for resuming
+const resumeGrace = 30 * time.Second
+// Period of session state pushed to subscribed clients
+const streamInterval = time.Second / 60
+
 type GameContext struct {
   // ID if game session in case more sessions are supported
   game_id uint32
@@ -41,6 +47,29 @@ type GameContext struct {
   // it is master because only that player can
   // impact on ball behavior
   ball_master uint32
+  // Seed of gameplay random generator shared by both players
+  seed uint64
+  // Last point after which left player is ready to serve
+  player_left_serve uint32
+  // Last point after which right player is ready to serve
+  player_right_serve uint32
+  // Player lost connection, seat is kept until resumeGrace passes
+  player_left_away bool
+  player_right_away bool
+  // Newest simulation tick reported by players
+  tick uint64
+  // Sequence number of the last message accepted from each player
+  player_left_seq uint64
+  player_right_seq uint64
+  // How players keep in sync, picked by the player who opened the session
+  netcode pong.Netcode
+  // Score agreed on, only the player who conceded a point reports it
+  points_played uint32
+  score_left int32
+  score_right int32
+  sets_left uint32
+  sets_right uint32
+  point_winner uint32
 }
 
 type GameContexts struct {
@@ -51,6 +80,7 @@ type GameContexts struct {
 type ConnectionContext struct {
   player_id uint32 
   session_id uint32
+  resume_token uint64
 }
 
 var upgrader = websocket.Upgrader{
@@ -74,7 +104,20 @@ var game_contexts = GameContexts {
 }
 
 var game_sessions = make(map[uint32]*GameContext)
+// Guarded by game_contexts.mtx, like the maps below
 var connected_players = make(map[*websocket.Conn]ConnectionContext)
+var resume_tokens = make(map[uint64]ConnectionContext)
+// Connection currently used by player, old connection of resumed player must not free the seat
+var player_conns = make(map[uint32]*websocket.Conn)
+// Connection to *sync.Mutex, websocket allows only one writer at a time and streams write from own goroutine
+var conn_write_mtx sync.Map
+
+func writeMessage(conn *websocket.Conn, out []byte) error {
+  mtx, _ := conn_write_mtx.LoadOrStore(conn, &sync.Mutex{})
+  mtx.(*sync.Mutex).Lock()
+  defer mtx.(*sync.Mutex).Unlock()
+  return conn.WriteMessage(1, out)
+}
 
 func getGameCtx() *GameContext {
   for i:=0; i < len(game_contexts.ctx); i++ {
@@ -95,9 +138,15 @@ func getGameCtxConcurent() *GameContext {
   return retval
 }
 
-func getSessionIdAndPlayerId() (uint32, uint32) {
+func getSessionIdAndPlayerId(netcode pong.Netcode) (uint32, uint32, uint64, pong.Netcode) {
   var sessionId uint32 = math.MaxUint32
   var playerId uint32 = math.MaxUint32
+  var seed uint64 = 0
+  if netcode == pong.Netcode_Server {
+    // Only the Rust server simulates matches, players here keep sending positions
+    log.Println("Server netcode not supported, using ball master")
+    netcode = pong.Netcode_BallMaster
+  }
   game_contexts.mtx.Lock()
   var gameCtx = getGameCtx()
   if gameCtx != nil {
@@ -120,6 +169,14 @@ func getSessionIdAndPlayerId() (uint32, uint32) {
       gameCtx.ball_posx = math.MaxInt32
       gameCtx.ball_posy = math.MaxInt32
       gameCtx.ball_master = math.MaxUint32
+      gameCtx.seed = rand.Uint64()
+      gameCtx.netcode = netcode
+      gameCtx.points_played = 0
+      gameCtx.score_left = 0
+      gameCtx.score_right = 0
+      gameCtx.sets_left = 0
+      gameCtx.sets_right = 0
+      gameCtx.point_winner = 0
       sessionId = gameId
       game_sessions[sessionId] = gameCtx
     }
@@ -128,27 +185,39 @@ func getSessionIdAndPlayerId() (uint32, uint32) {
       sessionId = gameCtx.game_id
       playerId = (gameCtx.game_id << 2) | 0x1
       gameCtx.player_left = playerId
+      gameCtx.player_left_seq = 0
     } else {
       log.Println("Context is initialized, generating next player id")
       sessionId = gameCtx.game_id
       playerId = (gameCtx.game_id << 2) | 0x2
       gameCtx.player_right = playerId
+      gameCtx.player_right_seq = 0
     }
+    seed = gameCtx.seed
+    netcode = gameCtx.netcode
   } else {
     log.Println("Could not find an empty session!")
   }
   game_contexts.mtx.Unlock()
-  return sessionId, playerId
+  return sessionId, playerId, seed, netcode
 }
 
 func removePlayerFromSession(player uint32, session uint32) {
   game_contexts.mtx.Lock()
+  for token, conn_ctx := range resume_tokens {
+    if conn_ctx.player_id == player {
+      delete(resume_tokens, token)
+    }
+  }
+  delete(player_conns, player)
   ctx, ok := game_sessions[session]
   if ok {
     if ctx.player_left == player {
       ctx.player_left = math.MaxUint32
+      ctx.player_left_away = false
     } else if ctx.player_right == player {
       ctx.player_right = math.MaxUint32
+      ctx.player_right_away = false
     } else {
       log.Println("Invalid player id")
     }
@@ -156,59 +225,195 @@ func removePlayerFromSession(player uint32, session uint32) {
   game_contexts.mtx.Unlock()
 }
 
-func defaultPage(w http.ResponseWriter, r *http.Request) {
-  w.Header().Set("Content-Type", "text/plain")
-  w.Write([]byte("Mighty backend welcomes you, player!\n"))
+// Mark player away and free the seat unless the player resumes in time.
+func playerDisconnected(conn *websocket.Conn, player_ctx ConnectionContext) {
+  game_contexts.mtx.Lock()
+  if player_conns[player_ctx.player_id] != conn {
+    // Player already resumed on another connection
+    game_contexts.mtx.Unlock()
+    return
+  }
+  ctx, ok := game_sessions[player_ctx.session_id]
+  if ok {
+    if ctx.player_left == player_ctx.player_id {
+      ctx.player_left_away = true
+    } else if ctx.player_right == player_ctx.player_id {
+      ctx.player_right_away = true
+    }
+  }
+  game_contexts.mtx.Unlock()
+
+  time.AfterFunc(resumeGrace, func() {
+    game_contexts.mtx.Lock()
+    still_away := player_conns[player_ctx.player_id] == conn
+    game_contexts.mtx.Unlock()
+    if still_away {
+      log.Println("Player did not come back, session: ", player_ctx.session_id, " player: ", player_ctx.player_id)
+      removePlayerFromSession(player_ctx.player_id, player_ctx.session_id)
+    }
+  })
 }
 
-func handleHello(_ *websocket.Conn, msg *pong.PongData) {
-  log.Println("Hello msg:", msg.GetHello().Msg)
+func newResumeToken() uint64 {
+  var token = rand.Uint64()
+  for token == 0 {
+    token = rand.Uint64()
+  }
+  return token
 }
 
-func handleIdReq(conn *websocket.Conn, _ *pong.PongData) {
-  log.Println("Get ID message received")
-  var sessionId, playerId = getSessionIdAndPlayerId()
+func sendIdRsp(conn *websocket.Conn, rsp *pong.CmdIdSet) {
   set_id_msg := pong.PongData {
     Type: pong.DataType_SetId,
     Data: &pong.PongData_IdRsp{
-      IdRsp : &pong.CmdIdSet{
-        Id: uint32(playerId),
-        Session: uint32(sessionId),
-      },
+      IdRsp : rsp,
     },
   }
   log.Println("Sending ID response: {}", &set_id_msg)
   out, err := proto.Marshal(&set_id_msg)
   if err != nil {
-    log.Println("Failed to serialize hello message ", err)
+    log.Println("Failed to serialize id message ", err)
   }
-  println("Sending: {:?}", out)
-  err = conn.WriteMessage(1, out)
+  err = writeMessage(conn, out)
   if err != nil {
     log.Println("WriteMessage err:", err)
   }
   log.Println("ID response sent")
-  connected_players[conn] = ConnectionContext{
-    player_id: playerId,
-    session_id: sessionId,
+}
+
+// Rooms are only served by rengine-server, here the lobby is always empty and players use quick match.
+func handleListRooms(conn *websocket.Conn, _ *pong.PongData) {
+  rooms_msg := pong.PongData {
+    Type: pong.DataType_Rooms,
+    Data: &pong.PongData_Rooms{
+      Rooms: &pong.CmdRooms{},
+    },
+  }
+  out, err := proto.Marshal(&rooms_msg)
+  if err != nil {
+    log.Println("Failed to serialize rooms message ", err)
+    return
+  }
+  err = writeMessage(conn, out)
+  if err != nil {
+    log.Println("WriteMessage err:", err)
   }
 }
 
-func handleCtxReq(conn *websocket.Conn, msg *pong.PongData) {
-  var sessionId = msg.GetCtxReq().GetSession()
-  log.Println("Get CTX message received for session:", sessionId)
-  ctx, ok := game_sessions[sessionId]
+// Rooms and ranked matches are only served by rengine-server, the client shows the error in the lobby.
+func handleRoomReq(conn *websocket.Conn, error string) {
+  log.Println("Refusing request:", error)
+  sendIdRsp(conn, &pong.CmdIdSet{
+    Id: math.MaxUint32,
+    Session: math.MaxUint32,
+    Error: error,
+  })
+}
+
+// No live matches are listed here, watching by join code is refused the same way.
+func handleSpectate(conn *websocket.Conn, _ *pong.PongData) {
+  log.Println("Spectators not supported, refusing")
+  spectating_msg := pong.PongData {
+    Type: pong.DataType_Spectating,
+    Data: &pong.PongData_Spectating{
+      Spectating: &pong.CmdSpectating{
+        Session: math.MaxUint32,
+        Error: "Watching matches is not supported by this server",
+      },
+    },
+  }
+  out, err := proto.Marshal(&spectating_msg)
+  if err != nil {
+    log.Println("Failed to serialize spectating message ", err)
+    return
+  }
+  err = writeMessage(conn, out)
+  if err != nil {
+    log.Println("WriteMessage err:", err)
+  }
+}
+
+// Give the player the seat back, session max uint32 tells the client it is gone.
+func handleResume(conn *websocket.Conn, token uint64) {
+  game_contexts.mtx.Lock()
+  player_ctx, ok := resume_tokens[token]
+  var seed uint64 = 0
+  var netcode = pong.Netcode_BallMaster
+  if ok {
+    ctx := game_sessions[player_ctx.session_id]
+    seed = ctx.seed
+    netcode = ctx.netcode
+    if ctx.player_left == player_ctx.player_id {
+      ctx.player_left_away = false
+    } else {
+      ctx.player_right_away = false
+    }
+    player_conns[player_ctx.player_id] = conn
+    connected_players[conn] = player_ctx
+  }
+  game_contexts.mtx.Unlock()
+
   if !ok {
-    log.Println("Invalid session id requested")
+    log.Println("Unknown resume token")
+    sendIdRsp(conn, &pong.CmdIdSet{
+      Id: math.MaxUint32,
+      Session: math.MaxUint32,
+    })
     return
   }
-  
-  log.Println("Left_id: ", ctx.player_left, " Right_id: ", ctx.player_right, " Ball vx: ", ctx.ball_vx, " vy: ", ctx.ball_vy)
-  log.Println("Left_rdy: ", ctx.player_left_ready, " Right_rdy: ", ctx.player_right_ready)
+  log.Println("Player resumed, session: ", player_ctx.session_id, " player: ", player_ctx.player_id)
+  sendIdRsp(conn, &pong.CmdIdSet{
+    Id: player_ctx.player_id,
+    Session: player_ctx.session_id,
+    Seed: seed,
+    ResumeToken: token,
+    Netcode: netcode,
+  })
+}
+
+func defaultPage(w http.ResponseWriter, r *http.Request) {
+  w.Header().Set("Content-Type", "text/plain")
+  w.Write([]byte("Mighty backend welcomes you, player!\n"))
+}
+
+func handleHello(_ *websocket.Conn, msg *pong.PongData) {
+  log.Println("Hello msg:", msg.GetHello().Msg)
+}
+
+func handleIdReq(conn *websocket.Conn, msg *pong.PongData) {
+  log.Println("Get ID message received")
+  if msg.GetIdReq().GetResumeToken() != 0 {
+    handleResume(conn, msg.GetIdReq().GetResumeToken())
+    return
+  }
+  var sessionId, playerId, seed, netcode = getSessionIdAndPlayerId(msg.GetIdReq().GetNetcode())
+  var token = newResumeToken()
+  player_ctx := ConnectionContext{
+    player_id: playerId,
+    session_id: sessionId,
+    resume_token: token,
+  }
+  game_contexts.mtx.Lock()
+  resume_tokens[token] = player_ctx
+  player_conns[playerId] = conn
+  connected_players[conn] = player_ctx
+  game_contexts.mtx.Unlock()
+  sendIdRsp(conn, &pong.CmdIdSet{
+    Id: uint32(playerId),
+    Session: uint32(sessionId),
+    Seed: seed,
+    ResumeToken: token,
+    Netcode: netcode,
+  })
+}
+
+// Serialized CmdCtxSet with current state of the session, seq 0 for replies outside of a stream
+func ctxMessage(ctx *GameContext, seq uint64) ([]byte, error) {
   set_ctx_msg := pong.PongData {
     Type: pong.DataType_SetCtx,
     Data: &pong.PongData_CtxRsp{
       CtxRsp: &pong.CmdCtxSet {
+        Session: ctx.game_id,
         LeftId: ctx.player_left,
         RightId: ctx.player_right,
         LeftPos: ctx.player_left_pos,
@@ -218,21 +423,127 @@ func handleCtxReq(conn *websocket.Conn, msg *pong.PongData) {
         BallPosx: ctx.ball_posx,
         BallPosy: ctx.ball_posy,
         BallMaster: ctx.ball_master,
+        LeftServe: ctx.player_left_serve,
+        RightServe: ctx.player_right_serve,
+        LeftAway: ctx.player_left_away,
+        RightAway: ctx.player_right_away,
+        Seq: seq,
+        Tick: ctx.tick,
+        SentMs: uint64(time.Now().UnixMilli()),
+        PointsPlayed: ctx.points_played,
+        ScoreLeft: ctx.score_left,
+        ScoreRight: ctx.score_right,
+        SetsLeft: ctx.sets_left,
+        SetsRight: ctx.sets_right,
+        PointWinner: ctx.point_winner,
       },
     },
   }
-  out, err := proto.Marshal(&set_ctx_msg)
+  return proto.Marshal(&set_ctx_msg)
+}
+
+func handleCtxReq(conn *websocket.Conn, msg *pong.PongData) {
+  var sessionId = msg.GetCtxReq().GetSession()
+  log.Println("Get CTX message received for session:", sessionId)
+  game_contexts.mtx.Lock()
+  ctx, ok := game_sessions[sessionId]
+  if !ok {
+    game_contexts.mtx.Unlock()
+    log.Println("Invalid session id requested")
+    return
+  }
+  
+  log.Println("Left_id: ", ctx.player_left, " Right_id: ", ctx.player_right, " Ball vx: ", ctx.ball_vx, " vy: ", ctx.ball_vy)
+  log.Println("Left_rdy: ", ctx.player_left_ready, " Right_rdy: ", ctx.player_right_ready)
+  out, err := ctxMessage(ctx, 0)
+  // Written without the lock, a slow client must not hold up the other players
+  game_contexts.mtx.Unlock()
   if err != nil {
     log.Println("Failed to serialize set_ctx message", err)
   }
-  err = conn.WriteMessage(1, out)
+  err = writeMessage(conn, out)
   if err != nil {
     log.Println("WritMessage err:", err)
   }
   log.Println("CTX response sent")
 }
 
-func handleCtxRsp(_ *websocket.Conn, msg *pong.PongData) {
+// Push session state to the client every streamInterval until its connection closes.
+func streamSession(conn *websocket.Conn, sessionId uint32, done chan struct{}) {
+  ticker := time.NewTicker(streamInterval)
+  defer ticker.Stop()
+  var seq uint64 = 0
+  for {
+    select {
+    case <-done:
+      return
+    case <-ticker.C:
+      game_contexts.mtx.Lock()
+      ctx, ok := game_sessions[sessionId]
+      var out []byte
+      var err error
+      if ok {
+        seq++
+        out, err = ctxMessage(ctx, seq)
+      }
+      game_contexts.mtx.Unlock()
+      if !ok || err != nil {
+        log.Println("Stream of session ", sessionId, " stopped: ", err)
+        return
+      }
+      if err := writeMessage(conn, out); err != nil {
+        log.Println("Stream write err:", err)
+        return
+      }
+    }
+  }
+}
+
+func handleSubscribe(conn *websocket.Conn, msg *pong.PongData, done chan struct{}) {
+  var sessionId = msg.GetSubscribe().GetSession()
+  game_contexts.mtx.Lock()
+  _, ok := game_sessions[sessionId]
+  game_contexts.mtx.Unlock()
+  if !ok {
+    log.Println("Invalid session id for subscribe")
+    return
+  }
+  log.Println("Streaming session ", sessionId)
+  go streamSession(conn, sessionId, done)
+}
+
+// Player seated on the connection, messages for any other session are dropped. The player in the
+// message itself is never trusted, session ids are known to both players. The caller holds game_contexts.mtx.
+func seatedPlayer(conn *websocket.Conn, sessionId uint32) (uint32, bool) {
+  player_ctx, ok := connected_players[conn]
+  if !ok || player_ctx.session_id != sessionId || player_ctx.player_id == math.MaxUint32 {
+    log.Println("Dropping message for session ", sessionId, " from a player not seated in it")
+    return math.MaxUint32, false
+  }
+  return player_ctx.player_id, true
+}
+
+// Whether message `seq` from player is newer than the ones already applied, 0 is from clients that do not count.
+// The caller holds game_contexts.mtx.
+func acceptSeq(ctx *GameContext, playerId uint32, seq uint64) bool {
+  if seq == 0 {
+    return true
+  }
+  if playerId == ctx.player_left {
+    if seq <= ctx.player_left_seq {
+      return false
+    }
+    ctx.player_left_seq = seq
+  } else if playerId == ctx.player_right {
+    if seq <= ctx.player_right_seq {
+      return false
+    }
+    ctx.player_right_seq = seq
+  }
+  return true
+}
+
+func handleCtxRsp(conn *websocket.Conn, msg *pong.PongData) {
   var sessionId = msg.GetCtxRsp().GetSession()
   var player_left = msg.GetCtxRsp().GetLeftPos()
   var player_right = msg.GetCtxRsp().GetRightPos()
@@ -241,11 +552,24 @@ func handleCtxRsp(_ *websocket.Conn, msg *pong.PongData) {
   var ball_posx = msg.GetCtxRsp().GetBallPosx()
   var ball_posy = msg.GetCtxRsp().GetBallPosy()
   log.Println("Received context for session:", sessionId)
+  game_contexts.mtx.Lock()
+  defer game_contexts.mtx.Unlock()
   ctx, ok := game_sessions[sessionId]
   if !ok {
     log.Println("Invalid session id for context set")
     return
   }
+  playerId, seated := seatedPlayer(conn, sessionId)
+  if !seated {
+    return
+  }
+  if !acceptSeq(ctx, playerId, msg.GetCtxRsp().GetSeq()) {
+    log.Println("Dropping out of order context ", msg.GetCtxRsp().GetSeq())
+    return
+  }
+  if msg.GetCtxRsp().GetTick() > ctx.tick {
+    ctx.tick = msg.GetCtxRsp().GetTick()
+  }
 
   if player_left != -1 {
     ctx.player_left_pos = player_left
@@ -273,12 +597,35 @@ func handleCtxRsp(_ *websocket.Conn, msg *pong.PongData) {
 
 }
 
-func handleReady(_ *websocket.Conn, msg *pong.PongData) {
+func handleReady(conn *websocket.Conn, msg *pong.PongData) {
   var sessionId = msg.GetReady().GetSession()
-  var playerId = msg.GetReady().GetPlayer()
+  game_contexts.mtx.Lock()
+  defer game_contexts.mtx.Unlock()
   ctx, ok := game_sessions[sessionId]
   if !ok {
     log.Println("Invalid session id for ready cmd")
+    return
+  }
+  playerId, seated := seatedPlayer(conn, sessionId)
+  if !seated {
+    return
+  }
+  if !acceptSeq(ctx, playerId, msg.GetReady().GetSeq()) {
+    log.Println("Dropping out of order ready ", msg.GetReady().GetSeq())
+    return
+  }
+
+  // Ready to serve after a point, players launch the ball themselves once both agreed
+  var serve = msg.GetReady().GetServe()
+  if serve > 0 {
+    if playerId == ctx.player_left {
+      ctx.player_left_serve = serve
+    } else if playerId == ctx.player_right {
+      ctx.player_right_serve = serve
+    } else {
+      log.Println("Invalid player ID for ready cmd")
+    }
+    return
   }
 
   if playerId == ctx.player_left {
@@ -290,24 +637,117 @@ func handleReady(_ *websocket.Conn, msg *pong.PongData) {
   }
 
   if ctx.player_left_ready && ctx.player_right_ready {
-    // generate ball x and y speed
-    ctx.ball_vx = 5;
-    ctx.ball_vy = 5;
+    // generate ball x and y speed, in pixels per second
+    ctx.ball_vx = 300;
+    ctx.ball_vy = 300;
     ctx.ball_posx = math.MaxInt32;
     ctx.ball_posy = math.MaxInt32;
     ctx.ball_master = ctx.player_right
   }
 }
 
-func reader(conn *websocket.Conn) {
+// Point reported by the player who conceded it. Each point number is counted once, a resent
+// report of a point already counted or a report skipping one is ignored.
+func handleLostPoint(conn *websocket.Conn, msg *pong.PongData) {
+  var lost = msg.GetLostPoint()
+  var sessionId = lost.GetSession()
+  game_contexts.mtx.Lock()
+  defer game_contexts.mtx.Unlock()
+  ctx, ok := game_sessions[sessionId]
+  if !ok {
+    log.Println("Invalid session id for lost point")
+    return
+  }
+  playerId, seated := seatedPlayer(conn, sessionId)
+  if !seated {
+    return
+  }
+  var winner uint32
+  if playerId == ctx.player_left {
+    winner = ctx.player_right
+  } else if playerId == ctx.player_right {
+    winner = ctx.player_left
+  } else {
+    log.Println("Invalid player ID for lost point")
+    return
+  }
+  if lost.GetPoint() != ctx.points_played + 1 {
+    log.Println("Ignoring lost point ", lost.GetPoint(), " with ", ctx.points_played, " points played")
+    return
+  }
+  if !acceptSeq(ctx, playerId, lost.GetSeq()) {
+    log.Println("Dropping out of order lost point ", lost.GetSeq())
+    return
+  }
+  ctx.points_played = lost.GetPoint()
+  ctx.score_left = lost.GetScoreLeft()
+  ctx.score_right = lost.GetScoreRight()
+  ctx.sets_left = lost.GetSetsLeft()
+  ctx.sets_right = lost.GetSetsRight()
+  ctx.point_winner = winner
+  log.Println("Point ", ctx.points_played, " to ", winner, " score ", ctx.score_left, ":", ctx.score_right)
+}
+
+// Rollback sessions only exchange inputs, pass them to the opponent as they are.
+func handleInput(conn *websocket.Conn, msg *pong.PongData) {
+  var sessionId = msg.GetInput().GetSession()
+  game_contexts.mtx.Lock()
+  var opponent_conn *websocket.Conn = nil
+  ctx, ok := game_sessions[sessionId]
+  playerId, seated := seatedPlayer(conn, sessionId)
+  if ok && seated {
+    if playerId == ctx.player_left {
+      opponent_conn = player_conns[ctx.player_right]
+    } else if playerId == ctx.player_right {
+      opponent_conn = player_conns[ctx.player_left]
+    }
+    if msg.GetInput().GetTick() > ctx.tick {
+      ctx.tick = msg.GetInput().GetTick()
+    }
+  }
+  game_contexts.mtx.Unlock()
+  if !ok {
+    log.Println("Invalid session id for input")
+    return
+  }
+  if !seated {
+    return
+  }
+  if opponent_conn == nil {
+    // Opponent not there yet or reconnecting, inputs are sent again until acknowledged
+    return
+  }
+  out, err := proto.Marshal(msg)
+  if err != nil {
+    log.Println("Failed to serialize input message ", err)
+    return
+  }
+  if err := writeMessage(opponent_conn, out); err != nil {
+    log.Println("Input forward err:", err)
+  }
+}
+
+// Forget the player of the connection, false when it never got a seat.
+func takeConnection(conn *websocket.Conn) (ConnectionContext, bool) {
+  game_contexts.mtx.Lock()
+  defer game_contexts.mtx.Unlock()
+  player_ctx, ok := connected_players[conn]
+  delete(connected_players, conn)
+  return player_ctx, ok
+}
+
+// Closes `done` when the connection goes away.
+func reader(conn *websocket.Conn, done chan struct{}) {
+  defer close(done)
   for {
     // read in a message
     _, p, err := conn.ReadMessage()
     if err != nil {
-      player_ctx := connected_players[conn]
+      player_ctx, seated := takeConnection(conn)
       log.Println("ReadMessage error: ", err, " Session: ", player_ctx.session_id, " Player: ", player_ctx.player_id)
-      removePlayerFromSession(player_ctx.player_id, player_ctx.session_id)
-      delete(connected_players, conn)
+      if seated {
+        playerDisconnected(conn, player_ctx)
+      }
       return
     }
 
@@ -332,6 +772,31 @@ func reader(conn *websocket.Conn) {
       break
     case pong.DataType_Ready:
       handleReady(conn, &pong_msg)
+    case pong.DataType_Subscribe:
+      handleSubscribe(conn, &pong_msg, done)
+    case pong.DataType_Input:
+      handleInput(conn, &pong_msg)
+    case pong.DataType_LostPoint:
+      handleLostPoint(conn, &pong_msg)
+    case pong.DataType_ListRooms:
+      handleListRooms(conn, &pong_msg)
+    case pong.DataType_CreateRoom, pong.DataType_JoinRoom:
+      handleRoomReq(conn, "Rooms are not supported by this server, use quick match")
+    case pong.DataType_Queue:
+      handleRoomReq(conn, "Ranked matches are not supported by this server")
+    case pong.DataType_LeaveQueue:
+      // Nobody is ever queued here
+    case pong.DataType_Spectate:
+      handleSpectate(conn, &pong_msg)
+    case pong.DataType_StopSpectating:
+      // Nobody is ever watching here
+    case pong.DataType_AddCpu:
+      // Matches are never simulated here, there is no computer player to seat
+    case pong.DataType_LeaveRoom:
+      // Seat is kept like after a dropped connection
+      if player_ctx, seated := takeConnection(conn); seated {
+        playerDisconnected(conn, player_ctx)
+      }
     default:
       log.Println("Unsupported message received")
     }
@@ -358,13 +823,14 @@ func wsPage(w http.ResponseWriter, r *http.Request) {
   if err != nil {
     log.Println("Failed to serialize hello message ", err)
   }
-  err = ws.WriteMessage(1, out)
+  err = writeMessage(ws, out)
   if err != nil {
     log.Println(err)
   }
   // listen indefinitely for new messages coming
   // through on our WebSocket connection
-  reader(ws)
+  reader(ws, make(chan struct{}))
+  conn_write_mtx.Delete(ws)
 }
 
 func main() {
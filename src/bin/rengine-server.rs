@@ -0,0 +1,32 @@
+use std::path::PathBuf;
+
+use rengine::server::{self, DEFAULT_ADDRESS};
+
+fn main() {
+    let args: Vec<String> = std::env::args().collect();
+    let mut address = DEFAULT_ADDRESS.to_string();
+    if let Some(index) = args.iter().position(|arg| arg == "--listen") {
+        match args.get(index + 1) {
+            Some(listen) => address = listen.clone(),
+            None => println!("--listen needs an address, e.g. --listen 0.0.0.0:8080"),
+        }
+    }
+    let mut ratings = PathBuf::from("ratings.toml");
+    if let Some(index) = args.iter().position(|arg| arg == "--ratings") {
+        match args.get(index + 1) {
+            Some(path) => ratings = PathBuf::from(path),
+            None => println!("--ratings needs a file, e.g. --ratings ratings.toml"),
+        }
+    }
+
+    match server::start_with_ratings(&address, &ratings) {
+        Ok(handle) => {
+            println!("Clients connect to ws://<host>:{}/ws", handle.local_addr().port());
+            handle.wait();
+        },
+        Err(err) => {
+            println!("Failed to start server on {}: {}", address, err);
+            std::process::exit(1);
+        },
+    }
+}
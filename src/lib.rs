@@ -0,0 +1,6 @@
+// Counters are bumped with `x = x + y`, protos are filled in field by field after `default()`
+// and values are limited with `max(..).min(..)`, which unlike `clamp` never panics
+#![allow(clippy::assign_op_pattern, clippy::field_reassign_with_default, clippy::manual_clamp)]
+
+pub mod pong;
+pub mod server;
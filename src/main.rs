@@ -1,5 +1,3 @@
-mod pong;
-
 fn main() {
-    pong::pong()
+    rengine::pong::pong()
 }
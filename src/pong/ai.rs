@@ -0,0 +1,261 @@
+// Computer controlled paddle. It produces the same PaddleInput a keyboard would, so the
+// simulation cannot tell it apart from a human and replays record it like any other input.
+
+use std::collections::VecDeque;
+
+use serde::{Deserialize, Serialize};
+
+use super::rng::GameRng;
+use super::world::{PaddleInput, PongWorld, ScreenSide, BALL_HEIGHT, BALL_WIDTH, PADDLE_HEIGHT, RES_HEIGHT};
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+pub enum Difficulty {
+    Easy,
+    #[default]
+    Normal,
+    Hard,
+    Expert,
+}
+
+impl Difficulty {
+    pub fn next(&self) -> Difficulty {
+        match self {
+            Difficulty::Easy => Difficulty::Normal,
+            Difficulty::Normal => Difficulty::Hard,
+            Difficulty::Hard => Difficulty::Expert,
+            Difficulty::Expert => Difficulty::Easy,
+        }
+    }
+
+    pub fn prev(&self) -> Difficulty {
+        match self {
+            Difficulty::Easy => Difficulty::Expert,
+            Difficulty::Normal => Difficulty::Easy,
+            Difficulty::Hard => Difficulty::Normal,
+            Difficulty::Expert => Difficulty::Hard,
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Difficulty::Easy => "Easy",
+            Difficulty::Normal => "Normal",
+            Difficulty::Hard => "Hard",
+            Difficulty::Expert => "Expert",
+        }
+    }
+
+    pub fn params(&self) -> AiParams {
+        match self {
+            Difficulty::Easy => AiParams { reaction_ticks: 30, wall_bounces: 0, speed: 0.6, error: 0.9 },
+            Difficulty::Normal => AiParams { reaction_ticks: 18, wall_bounces: 1, speed: 0.8, error: 0.6 },
+            Difficulty::Hard => AiParams { reaction_ticks: 10, wall_bounces: 2, speed: 0.95, error: 0.35 },
+            Difficulty::Expert => AiParams { reaction_ticks: 4, wall_bounces: 8, speed: 1.0, error: 0.15 },
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct AiParams {
+    // Ticks between the ball moving and the AI noticing it
+    pub reaction_ticks: u32,
+    // Number of wall bounces the AI follows when predicting where the ball arrives
+    pub wall_bounces: u32,
+    // Portion (0.0 - 1.0) of the full paddle speed the AI uses
+    pub speed: f32,
+    // Largest aiming mistake as a portion of half paddle height, 1.0 barely touches the ball
+    pub error: f32,
+}
+
+// Ball as the AI remembers seeing it
+#[derive(Debug, Clone, Copy)]
+struct BallSight {
+    pos_x: f32,
+    pos_y: f32,
+    velocity_x: f32,
+    velocity_y: f32,
+}
+
+// Paddle does not chase targets closer than this, avoids shaking around the target.
+const AI_DEAD_ZONE: f32 = 8.0;
+
+#[derive(Debug, Clone)]
+pub struct AiController {
+    pub side: ScreenSide,
+    pub difficulty: Difficulty,
+    params: AiParams,
+    // Own random generator, the world one must stay untouched to keep matches reproducible
+    rng: GameRng,
+    sights: VecDeque<BallSight>,
+    // Movement budget, paddle moves only on ticks where it reaches a whole step
+    speed_budget: f32,
+    // Offset from the predicted position, rolled whenever the ball starts coming toward us
+    aim_error: f32,
+    incoming: bool,
+}
+
+impl AiController {
+    pub fn new(side: ScreenSide, difficulty: Difficulty, seed: u64) -> AiController {
+        AiController {
+            side,
+            difficulty,
+            params: difficulty.params(),
+            rng: GameRng::new(seed),
+            sights: VecDeque::new(),
+            speed_budget: 0.0,
+            aim_error: 0.0,
+            incoming: false,
+        }
+    }
+
+    // Input for the next tick of `world`.
+    pub fn input(&mut self, world: &PongWorld) -> PaddleInput {
+        let ball = &world.ball;
+        self.sights.push_back(BallSight { pos_x: ball.pos_x, pos_y: ball.pos_y, velocity_x: ball.velocity_x, velocity_y: ball.velocity_y });
+        while self.sights.len() > self.params.reaction_ticks as usize + 1 {
+            self.sights.pop_front();
+        }
+        let sight = self.sights[0];
+
+        let incoming = if self.side == ScreenSide::Left { sight.velocity_x < 0.0 } else { sight.velocity_x > 0.0 };
+        if incoming && !self.incoming {
+            let max_error = self.params.error * PADDLE_HEIGHT / 2.0;
+            self.aim_error = self.rng.range(-(max_error as i32), max_error as i32) as f32;
+        }
+        self.incoming = incoming;
+
+        let paddle = if self.side == ScreenSide::Left { &world.paddle_left } else { &world.paddle_right };
+        let target = if incoming {
+            // Ball center touches the paddle half a ball before its inner face
+            let rect = paddle.rect();
+            let face_x = if self.side == ScreenSide::Left { rect.x + rect.width + BALL_WIDTH/2.0 } else { rect.x - BALL_WIDTH/2.0 };
+            self.predict_arrival(&sight, face_x) + self.aim_error
+        } else {
+            RES_HEIGHT as f32 / 2.0
+        };
+
+        self.speed_budget = self.speed_budget + self.params.speed;
+        if self.speed_budget < 1.0 {
+            return PaddleInput::default();
+        }
+        self.speed_budget = self.speed_budget - 1.0;
+
+        let distance = target - paddle.pos_y;
+        PaddleInput {
+            up: distance < -AI_DEAD_ZONE,
+            down: distance > AI_DEAD_ZONE,
+            axis: 0.0,
+        }
+    }
+
+    // Height at which the ball reaches `paddle_x`, following at most `wall_bounces` reflections.
+    // Bounces the AI cannot foresee leave it aiming at the last point it could work out.
+    fn predict_arrival(&self, sight: &BallSight, paddle_x: f32) -> f32 {
+        if sight.velocity_x == 0.0 {
+            return sight.pos_y;
+        }
+        let top = BALL_HEIGHT/2.0;
+        let bottom = RES_HEIGHT as f32 - BALL_HEIGHT/2.0;
+        let mut pos_y = sight.pos_y.max(top).min(bottom);
+        let mut velocity_y = sight.velocity_y;
+        let mut time = ((paddle_x - sight.pos_x) / sight.velocity_x).max(0.0);
+        let mut bounces: u32 = 0;
+        while time > 0.0 {
+            let end_y = pos_y + velocity_y * time;
+            if end_y >= top && end_y <= bottom {
+                return end_y;
+            }
+            if bounces >= self.params.wall_bounces {
+                return end_y.max(top).min(bottom);
+            }
+            let wall = if end_y < top { top } else { bottom };
+            let hit_time = (wall - pos_y) / velocity_y;
+            time = time - hit_time;
+            pos_y = wall;
+            velocity_y = -velocity_y;
+            bounces = bounces + 1;
+        }
+        pos_y
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sight(pos: (f32, f32), velocity: (f32, f32)) -> BallSight {
+        BallSight { pos_x: pos.0, pos_y: pos.1, velocity_x: velocity.0, velocity_y: velocity.1 }
+    }
+
+    fn assert_near(value: f32, expected: f32) {
+        assert!((value - expected).abs() < 0.01, "{} is not {}", value, expected);
+    }
+
+    // Ticks until the right paddle first moves toward a ball coming low, and how many of `ticks` it moves on.
+    fn reaction(difficulty: Difficulty, ticks: u32) -> (u32, u32) {
+        let mut ai = AiController::new(ScreenSide::Right, difficulty, 7);
+        let mut world = PongWorld::new(1);
+        world.ball.pos_y = world.paddle_right.pos_y;
+        world.ball.velocity_x = -300.0;
+        world.ball.velocity_y = 0.0;
+        // Going away, the paddle stays in the middle
+        for _ in 0..40 {
+            assert_eq!(ai.input(&world), PaddleInput::default());
+        }
+        world.ball.pos_y = RES_HEIGHT as f32 - BALL_HEIGHT;
+        world.ball.velocity_x = 300.0;
+        let mut first = None;
+        let mut moves = 0;
+        for tick in 0..ticks {
+            let input = ai.input(&world);
+            assert!(!input.up);
+            if input.down {
+                first.get_or_insert(tick);
+                moves = moves + 1;
+            }
+        }
+        (first.unwrap(), moves)
+    }
+
+    #[test]
+    fn harder_difficulties_react_sooner_and_move_more() {
+        let difficulties = [Difficulty::Easy, Difficulty::Normal, Difficulty::Hard, Difficulty::Expert];
+        let results: Vec<(u32, u32)> = difficulties.iter().map(|difficulty| reaction(*difficulty, 120)).collect();
+        for pair in results.windows(2) {
+            assert!(pair[0].0 > pair[1].0, "{:?}", results);
+            assert!(pair[0].1 < pair[1].1, "{:?}", results);
+        }
+        // Nothing is noticed before the reaction time is over
+        for (difficulty, (first, _)) in difficulties.iter().zip(results.iter()) {
+            assert!(*first >= difficulty.params().reaction_ticks, "{:?}", difficulty);
+        }
+        for pair in difficulties.windows(2) {
+            let (easier, harder) = (pair[0].params(), pair[1].params());
+            assert!(easier.wall_bounces < harder.wall_bounces);
+            assert!(easier.error > harder.error);
+        }
+    }
+
+    #[test]
+    fn prediction_follows_wall_bounces() {
+        let top = BALL_HEIGHT/2.0;
+        let bottom = RES_HEIGHT as f32 - BALL_HEIGHT/2.0;
+        // Ball needs 3 seconds to the paddle and hits the top wall after 80 pixels
+        let up = sight((400.0, top + 80.0), (-100.0, -100.0));
+        let expert = AiController::new(ScreenSide::Left, Difficulty::Expert, 1);
+        assert_near(expert.predict_arrival(&up, 100.0), top + 220.0);
+        // Easy does not see the bounce coming and waits at the wall
+        let easy = AiController::new(ScreenSide::Left, Difficulty::Easy, 1);
+        assert_eq!(easy.predict_arrival(&up, 100.0), top);
+
+        // Two bounces, bottom wall and then the top one
+        let down = sight((100.0, bottom - 100.0), (100.0, 400.0));
+        let height = bottom - top;
+        let expected = top + (1200.0 - 100.0 - height);
+        assert_near(expert.predict_arrival(&down, 400.0), expected);
+        let normal = AiController::new(ScreenSide::Right, Difficulty::Normal, 1);
+        assert_eq!(normal.predict_arrival(&down, 400.0), top);
+        // Straight path needs no bounce at all
+        assert_near(easy.predict_arrival(&sight((400.0, 300.0), (-100.0, 20.0)), 100.0), 360.0);
+    }
+}
@@ -0,0 +1,2668 @@
+use std::fmt::Debug;
+use std::{collections::BTreeMap, ffi::CString};
+use std::path::Path;
+
+use raylib::{ffi::{rlScalef, LoadSound, PlaySound, SetMasterVolume, Sound}, prelude::*};
+use websocket::url::Url;
+
+use std::thread::{self, JoinHandle};
+use std::sync::mpsc::{channel, Sender, Receiver, TryRecvError};
+
+
+use super::protos::pong::PongData;
+
+use crate::pong::protos::pong::DataType;
+
+use super::protos::pong::{CmdCpuAdd, CmdCtxSet, CmdIdGet, CmdInput, CmdLostPoint, CmdQueueJoin, CmdQueueLeave, CmdQueueStatus, CmdRated, CmdReady, CmdRoomCreate, CmdRoomJoin, CmdRoomLeave, CmdRoomsGet, CmdSpectate, CmdSpectating, CmdWorld, Difficulty as ProtoDifficulty, Netcode as ProtoNetcode, Room};
+use super::ai::{AiController, Difficulty};
+use super::clock::{FixedClock, MAX_STEPS_PER_FRAME};
+use super::connection::{netcode_from_proto, proto_netcode, srv_thread, SrvMessage};
+use super::net::{now_ms, MessageStats, NetError, MAX_RECONNECT_ATTEMPTS};
+use super::interp::{NetView, SnapshotBuffer, MAX_INTERP_DELAY_MS};
+use super::input::{binding_name, Action, GamepadMonitor, GamepadSettings, Key, Owner, BINDINGS, MAX_GAMEPADS};
+use super::loopback::Loopback;
+use super::replay::Replay;
+use super::prediction::{apply_world_state, decode_rules, encode_rules, PredictionSession};
+use super::rollback::{InputMessage, Netcode, PlayerInput, RollbackSession, MAX_INPUT_DELAY};
+use super::rng::new_seed;
+use super::settings::{settings_path, Settings, RESOLUTIONS};
+use super::tls::TlsSettings;
+use super::world::{Ball, BallPhysics, MatchPhase, MatchRules, MATCH_PRESETS, ServeDirection, Paddle, PongWorld, Rect, ScreenSide, WorldEvent, WorldInputs, PADDLE_WIDTH, RES_HEIGHT, RES_WIDTH, TICK_RATE};
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum GameState {
+    Menu, // Main menu
+    Server, // Pick game server
+    Connect, // Connect to game server
+    Lobby, // Pick a room on the game server
+    RoomCreate, // Name and join code of a new room
+    RoomCode, // Join a private room by its code
+    Queue, // Wait for a ranked opponent
+    Spectate, // Watch a match of other players
+    ConnectionError, // Connection to game server failed
+    Reconnecting, // Multiplayer paused, connection of one of the players dropped
+    Waiting, // Wait for other player
+    Init, // Initialize state
+    Loop, // Game main loop
+    Scored, // Player scored
+    Finished, // Game finished
+    Options, // Options menu
+    Controls, // Key bindings
+    Paused, // Local match paused, gamepad disconnected
+    Quit, // Quit game
+}
+
+#[derive(Debug, Default, Eq, PartialOrd, Ord, PartialEq)]
+enum MenuState {
+    #[default]
+    NewGame,
+    VersusCpu,
+    Multiplayer,
+    Rules,
+    Options,
+    Quit,
+}
+
+impl MenuState {
+    fn next(&self) -> MenuState {
+        match self {
+            MenuState::NewGame => MenuState::VersusCpu,
+            MenuState::VersusCpu => MenuState::Multiplayer,
+            MenuState::Multiplayer => MenuState::Rules,
+            MenuState::Rules => MenuState::Options,
+            MenuState::Options => MenuState::Quit,
+            MenuState::Quit => MenuState::NewGame,
+        }
+    }
+
+    fn prev(&self) -> MenuState {
+        match self {
+            MenuState::NewGame => MenuState::Quit,
+            MenuState::VersusCpu => MenuState::NewGame,
+            MenuState::Multiplayer => MenuState::VersusCpu,
+            MenuState::Rules => MenuState::Multiplayer,
+            MenuState::Options => MenuState::Rules,
+            MenuState::Quit => MenuState::Options,
+        }
+    }
+}
+
+#[derive(Debug, Default, Clone, Copy, Eq, PartialOrd, Ord, PartialEq)]
+enum OptionsState {
+    #[default]
+    Controls,
+    Volume,
+    Fullscreen,
+    Resolution,
+    PointsToWin,
+    PlayerName,
+    ServerAddress,
+    NetDelay,
+    Netcode,
+    InputDelay,
+    LeftPad,
+    RightPad,
+    DeadZone,
+    Back,
+}
+
+const OPTIONS_ORDER: [OptionsState; 14] = [
+    OptionsState::Controls,
+    OptionsState::Volume,
+    OptionsState::Fullscreen,
+    OptionsState::Resolution,
+    OptionsState::PointsToWin,
+    OptionsState::PlayerName,
+    OptionsState::ServerAddress,
+    OptionsState::NetDelay,
+    OptionsState::Netcode,
+    OptionsState::InputDelay,
+    OptionsState::LeftPad,
+    OptionsState::RightPad,
+    OptionsState::DeadZone,
+    OptionsState::Back,
+];
+
+impl OptionsState {
+    fn next(&self) -> OptionsState {
+        let index = OPTIONS_ORDER.iter().position(|item| item == self).unwrap();
+        OPTIONS_ORDER[(index + 1) % OPTIONS_ORDER.len()]
+    }
+
+    fn prev(&self) -> OptionsState {
+        let index = OPTIONS_ORDER.iter().position(|item| item == self).unwrap();
+        OPTIONS_ORDER[(index + OPTIONS_ORDER.len() - 1) % OPTIONS_ORDER.len()]
+    }
+}
+
+struct GameContext {
+    world: PongWorld,
+    // World as it was one tick ago, rendering interpolates between it and the current one
+    world_prev: PongWorld,
+    clock: FixedClock,
+    // Inputs of the local match in progress, saved when the match finishes
+    replay: Option<Replay>,
+    // Computer player of a single player match
+    ai: Option<AiController>,
+    settings: Settings,
+    gamepads: GamepadMonitor,
+    // State to return to when the pause ends
+    paused_from: Option<GameState>,
+    state: GameState,
+    state_menu: StateMenuContext,
+    state_options: StateOptionsContext,
+    state_controls: StateControlsContext,
+    state_server: StateServerContext,
+    state_lobby: StateLobbyContext,
+    state_connection_error: StateConnectionErrorContext,
+    // Server given with --server or RENGINE_SERVER, used instead of the one from settings
+    server_override: Option<String>,
+    // Certificate checks disabled with --insecure, not saved to settings
+    insecure_override: bool,
+    // Debug view of remote entities in multiplayer, switched with F3
+    net_view: NetView,
+    // Online match or loopback test in rollback mode
+    rollback: Option<RollbackSession>,
+    // Online match simulated by the server, own paddle is predicted
+    prediction: Option<PredictionSession>,
+    // Computer opponent behind simulated latency, Versus CPU matches use it when started with --loopback
+    loopback: Option<Loopback>,
+    loopback_latency: Option<u32>,
+    multiplayer: MultiplayerContext,
+    assets: GameAssets,
+}
+
+struct GameAssets {
+    menu_next: Sound,
+    ball_bounce: Sound,
+    player_scored: Sound,
+}
+
+#[derive(Default)]
+struct StateMenuContext {
+    current: MenuState,
+}
+
+#[derive(Default)]
+struct StateOptionsContext {
+    current: OptionsState,
+    // Text of the field being edited
+    editing: Option<String>,
+}
+
+#[derive(Default)]
+struct StateControlsContext {
+    // Index into BINDINGS, two more items follow: reset to defaults and back
+    current: usize,
+    // Waiting for a key to bind to the current item
+    rebinding: bool,
+    message: String,
+}
+
+#[derive(Default)]
+struct StateLobbyContext {
+    // Lobby: quick match, ranked match, create room, join with code and back, then open rooms and live matches.
+    // Room screens: the text fields, then confirm and back.
+    current: usize,
+    room_name: String,
+    join_code: String,
+    // Why the last create or join failed
+    error: String,
+    // Waiting for the server to seat us
+    joining: bool,
+}
+
+#[derive(Default)]
+struct StateServerContext {
+    // 0 is the address field, recent servers follow, last item is back
+    current: usize,
+    address: String,
+    error: String,
+}
+
+#[derive(Default)]
+struct StateConnectionErrorContext {
+    error: Option<NetError>,
+    // Server to retry with
+    server: Option<Url>,
+    back_selected: bool,
+}
+
+#[derive(Default)]
+struct MultiplayerContext {
+    thread: Option<JoinHandle<()>>,
+    server: Option<Url>,
+    id: u32,
+    session: u32,
+    // Room we have a seat in, with its join code when it is private
+    room: Option<Room>,
+    // Open rooms the server listed last, None until it answered the first time
+    rooms: Option<Vec<Room>>,
+    // Matches with both players in, listed together with the rooms
+    live: Vec<Room>,
+    // Watching session without a seat, id stays u32::MAX meanwhile
+    spectating: bool,
+    // When the list was last asked for, it is refreshed while in the lobby
+    rooms_sent_ms: u64,
+    // Last word of the server on our ranked search, None while not queued
+    queue: Option<CmdQueueStatus>,
+    queued_at_ms: u64,
+    // Our new rating once the ranked match was decided
+    rated: Option<CmdRated>,
+    netcode: Netcode,
+    // Random seed shared by both players of the session
+    seed: u64,
+    // Last point after which we told the server we are ready to serve
+    serve_sent: u32,
+    // When we last reported a point we conceded, sent again until the server counts it
+    lost_point_sent_ms: u64,
+    side: Option<ScreenSide>,
+    ctx: Option<CmdCtxSet>,
+    // Sequence number of the last gameplay message we sent
+    out_seq: u64,
+    // Snapshots received from the server
+    ctx_stats: MessageStats,
+    // Positions of the entities the other side moves, drawn smoothed. Spectators get both paddles.
+    remote_left: SnapshotBuffer,
+    remote_right: SnapshotBuffer,
+    remote_ball: SnapshotBuffer,
+    // Attempt of the reconnect in progress, 0 while connected
+    reconnecting: u32,
+    opponent_away: bool,
+    // Match with the opponent started, from then on the opponent leaving ends it
+    playing: bool,
+    game_rx: Option<Receiver<SrvMessage>>,
+    game_tx: Option<Sender<PongData>>,
+}
+
+// Start drawing a frame. Everything is drawn in RES_WIDTH x RES_HEIGHT coordinates and scaled to the window.
+fn begin_frame<'a>(rl: &'a mut RaylibHandle, thread: &RaylibThread) -> RaylibDrawHandle<'a> {
+    let scale_x = rl.get_screen_width() as f32 / RES_WIDTH as f32;
+    let scale_y = rl.get_screen_height() as f32 / RES_HEIGHT as f32;
+    let d = rl.begin_drawing(thread);
+    unsafe {
+        rlScalef(scale_x.min(scale_y), scale_x.min(scale_y), 1.0);
+    }
+    d
+}
+
+fn lerp(from: f32, to: f32, alpha: f32) -> f32 {
+    from + (to - from) * alpha
+}
+
+fn draw_rect(prev: &Rect, rect: &Rect, alpha: f32, d: &mut RaylibDrawHandle) {
+    let x = lerp(prev.x, rect.x, alpha).round() as i32;
+    let y = lerp(prev.y, rect.y, alpha).round() as i32;
+    d.draw_rectangle(x, y, rect.width as i32, rect.height as i32, Color::WHITE);
+}
+
+fn draw_paddle(prev: &Paddle, paddle: &Paddle, alpha: f32, d: &mut RaylibDrawHandle) {
+    draw_rect(&prev.rect(), &paddle.rect(), alpha, d);
+}
+
+fn draw_ball(prev: &Ball, ball: &Ball, alpha: f32, d: &mut RaylibDrawHandle) {
+    draw_rect(&prev.rect(), &ball.rect(), alpha, d);
+}
+
+// Entities were moved outside of the simulation step, nothing to interpolate from.
+fn sync_world_prev(game: &mut GameContext) {
+    game.world_prev = game.world.clone();
+}
+
+// Rollback and Server matches send inputs, the other ones positions of what the player moves.
+fn sends_inputs(game: &GameContext) -> bool {
+    game.rollback.is_some() || game.prediction.is_some()
+}
+
+fn is_local_player(side: ScreenSide, game: &GameContext) -> bool {
+    if let Some(rollback) = game.rollback.as_ref() {
+        return rollback.local_side == side;
+    }
+    if let Some(prediction) = game.prediction.as_ref() {
+        return prediction.local_side == side;
+    }
+    if game.multiplayer.thread.is_none() {
+        // for offline game both players are local
+        return true;
+    }
+
+    game.multiplayer.side == Some(side)
+}
+
+fn play_world_events(events: &[WorldEvent], game: &GameContext) {
+    for event in events {
+        match event {
+            WorldEvent::PaddleBounce(_) | WorldEvent::WallBounce => unsafe {
+                PlaySound(game.assets.ball_bounce);
+            },
+            WorldEvent::Scored(_) => unsafe {
+                PlaySound(game.assets.player_scored);
+            },
+            WorldEvent::Served(_) | WorldEvent::SetWon(_) | WorldEvent::Won(_) => (),
+        }
+    }
+}
+
+fn get_winner(game: &GameContext) -> &str {
+    if game.world.winner() == Some(ScreenSide::Left) {
+        "One"
+    } else {
+        "Two"
+    }
+}
+
+fn init_state(game: &mut GameContext, _rl: &mut RaylibHandle, _thread: &RaylibThread) {
+    if sends_inputs(game) && game.multiplayer.thread.is_some() {
+        // Both players have to start over on the same tick, they meet again through the waiting screen
+        multiplayer_leave(game);
+        game.state = GameState::Menu;
+        return;
+    }
+    let mut seed = game.multiplayer.seed;
+    if game.multiplayer.thread.is_none() {
+        seed = new_seed();
+        game.world.reseed(seed);
+        game.replay = Some(Replay::new(seed, &game.world));
+        if let Some(ai) = game.ai.as_ref() {
+            game.ai = Some(AiController::new(ai.side, ai.difficulty, seed.rotate_left(32)));
+        }
+    } else {
+        game.world.reseed(game.multiplayer.seed);
+        game.replay = None;
+    }
+    game.world.reset();
+    game.world.remote_goal = match game.multiplayer.thread {
+        Some(_) => game.multiplayer.side.map(|side| side.opposite()),
+        None => None,
+    };
+    game.rollback = None;
+    game.prediction = None;
+    if let Some(loopback) = game.loopback.as_ref() {
+        // Opponent must start from the very same world
+        let delay = game.settings.input_delay;
+        game.loopback = Some(Loopback::new(&game.world, delay, loopback.latency_ms, loopback.jitter_ms, seed.rotate_left(32)));
+        game.rollback = Some(RollbackSession::new(ScreenSide::Left, delay, &game.world));
+        game.replay = None;
+    }
+    game.multiplayer.serve_sent = 0;
+    sync_world_prev(game);
+    game.clock.reset();
+    game.state = GameState::Loop;
+}
+
+fn save_replay(game: &mut GameContext) {
+    if let Some(replay) = game.replay.take() {
+        let path = format!("replays/replay_{}.rpl", replay.seed);
+        let ret = replay.save(Path::new(&path));
+        if ret.is_err() {
+            println!("Failed to save replay {}: {}", path, ret.err().unwrap());
+        } else {
+            println!("Replay saved to {}", path);
+        }
+    }
+}
+
+// Points of every set, or just the final score for single set matches.
+fn final_score_message(game: &GameContext) -> String {
+    if game.world.rules.best_of <= 1 {
+        let (left, right) = game.world.set_scores.last().copied().unwrap_or((game.world.score_left, game.world.score_right));
+        return format!("{} - {}", left, right);
+    }
+    let sets: Vec<String> = game.world.set_scores.iter().map(|(left, right)| format!("{}-{}", left, right)).collect();
+    format!("Sets {} - {}:  {}", game.world.sets_left, game.world.sets_right, sets.join("  "))
+}
+
+fn finished_state(game: &mut GameContext, rl: &mut RaylibHandle, thread: &RaylibThread) {
+    if game.multiplayer.thread.is_some() {
+        // Server may still correct the score, even take the win back
+        multiplayer_update(game);
+        if multiplayer_interrupted(game) {
+            return;
+        }
+    }
+    if game.world.winner().is_none() {
+        game.state = GameState::Loop;
+    }
+    save_replay(game);
+    if game.loopback.is_some() {
+        if let Some(rollback) = game.rollback.take() {
+            println!("Loopback match finished, rollback stats: {:?}", rollback.stats);
+        }
+    }
+
+    if game.settings.controls.pressed(rl, Action::Back) {
+        game.state = GameState::Quit;
+        return;
+    }
+
+    if game.settings.controls.pressed(rl, Action::Confirm) {
+        game.state = GameState::Init;
+        return;
+    }
+
+    let mut d = begin_frame(rl, thread);
+    let y_offset = 80;
+    let finished_message = format!("Game finished, Player {} won.", get_winner(game));
+    let score_message = final_score_message(game);
+    let continue_message = "Do you want to play again?";
+    let yes_no_message = format!("{} / {}", game.settings.controls.label(Owner::Shared, Action::Confirm),
+        game.settings.controls.label(Owner::Shared, Action::Back));
+    d.clear_background(Color::BLACK);
+    d.draw_text(&finished_message, RES_WIDTH/2 - d.measure_text(&finished_message, 40)/2, y_offset, 40, Color::RED);
+    d.draw_text(&score_message, RES_WIDTH/2 - d.measure_text(&score_message, 40)/2, y_offset + 80, 40, Color::WHITE);
+    d.draw_text(continue_message, RES_WIDTH/2 - d.measure_text(continue_message, 40)/2, y_offset + 160, 40, Color::RED);
+    d.draw_text(&yes_no_message, RES_WIDTH/2 - d.measure_text(&yes_no_message, 60)/2, y_offset + 240, 60, Color::RED);
+    if !score_confirmed(game) {
+        let confirm_message = "Waiting for server to confirm the score ...";
+        d.draw_text(confirm_message, RES_WIDTH/2 - d.measure_text(confirm_message, 30)/2, y_offset + 340, 30, Color::GRAY);
+    } else if let Some(rated) = game.multiplayer.rated.as_ref() {
+        let rating_message = format!("Rating {} ({:+}), {} wins in {} ranked matches", rated.rating, rated.change, rated.wins, rated.matches);
+        d.draw_text(&rating_message, RES_WIDTH/2 - d.measure_text(&rating_message, 30)/2, y_offset + 340, 30, Color::YELLOW);
+    }
+}
+
+// Move to next or previous rules preset and remember the choice, custom rules go to the first preset.
+fn select_rules_preset(game: &mut GameContext, direction: i32) {
+    let count = MATCH_PRESETS.len() as i32;
+    let current = MATCH_PRESETS.iter().position(|(_, rules)| *rules == game.settings.rules);
+    let next = match current {
+        Some(index) => (index as i32 + direction).rem_euclid(count),
+        None => 0,
+    };
+    game.settings.rules = MATCH_PRESETS[next as usize].1;
+    settings_changed(game);
+}
+
+fn settings_changed(game: &mut GameContext) {
+    let ret = game.settings.save(&settings_path());
+    if ret.is_err() {
+        println!("Failed to save settings {}: {}", settings_path().display(), ret.err().unwrap());
+    }
+    unsafe {
+        PlaySound(game.assets.menu_next);
+    }
+}
+
+// Offline match, with CPU on the right side when `versus_cpu` is set.
+fn start_local_game(game: &mut GameContext, versus_cpu: bool) {
+    game.world.ball.physics = BallPhysics::default();
+    game.world.rules = game.settings.rules;
+    game.ai = None;
+    game.loopback = None;
+    game.world.remote_goal = None;
+    if versus_cpu {
+        match game.loopback_latency {
+            // Computer plays through a simulated network, tests rollback without a server
+            Some(latency) => game.loopback = Some(Loopback::new(&game.world, game.settings.input_delay, latency, latency / 4, 0)),
+            None => game.ai = Some(AiController::new(ScreenSide::Right, game.settings.ai_difficulty, 0)),
+        }
+    }
+    game.state = GameState::Init;
+}
+
+fn menu_state(game: &mut GameContext, rl: &mut RaylibHandle, thread: &RaylibThread) {
+    let versus_message = format!("< Versus CPU: {} >", game.settings.ai_difficulty.name());
+    let rules_message = format!("< Rules: {} >", game.settings.rules.name());
+    let menu_messages: BTreeMap<MenuState, &str> = BTreeMap::from([
+        (MenuState::NewGame, "New Game"), 
+        (MenuState::VersusCpu, versus_message.as_str()),
+        (MenuState::Multiplayer, "Multiplayer"), 
+        (MenuState::Rules, rules_message.as_str()),
+        (MenuState::Options, "Options"), 
+        (MenuState::Quit, "Quit"),
+    ]);
+    let keys = game.settings.controls.clone();
+
+    if keys.pressed(rl, Action::Down) {
+        game.state_menu.current = game.state_menu.current.next();
+        unsafe {
+            PlaySound(game.assets.menu_next);
+        }
+    } else if keys.pressed(rl, Action::Up) {
+        game.state_menu.current = game.state_menu.current.prev();
+        unsafe {
+            PlaySound(game.assets.menu_next);
+        }
+    } else if game.state_menu.current == MenuState::Rules && keys.pressed(rl, Action::Left) {
+        select_rules_preset(game, -1);
+        return;
+    } else if game.state_menu.current == MenuState::Rules &&
+        (keys.pressed(rl, Action::Right) || keys.pressed(rl, Action::Confirm)) {
+        select_rules_preset(game, 1);
+        return;
+    } else if game.state_menu.current == MenuState::VersusCpu && keys.pressed(rl, Action::Left) {
+        game.settings.ai_difficulty = game.settings.ai_difficulty.prev();
+        settings_changed(game);
+        return;
+    } else if game.state_menu.current == MenuState::VersusCpu && keys.pressed(rl, Action::Right) {
+        game.settings.ai_difficulty = game.settings.ai_difficulty.next();
+        settings_changed(game);
+        return;
+    } else if keys.pressed(rl, Action::Confirm) {
+        if game.state_menu.current == MenuState::Quit {
+            game.state = GameState::Quit;
+            return;
+        } else if game.state_menu.current == MenuState::Options {
+            game.state = GameState::Options;
+            return;
+        } else if game.state_menu.current == MenuState::NewGame {
+            start_local_game(game, false);
+            return;
+        } else if game.state_menu.current == MenuState::VersusCpu {
+            start_local_game(game, true);
+            return;
+        } else if game.state_menu.current == MenuState::Multiplayer {
+            // Remote paddle speed is not known locally, spin would differ between players
+            game.world.ball.physics = BallPhysics::classic();
+            game.ai = None;
+            server_select_start(game);
+            return;
+        }
+    }
+
+    let mut d = begin_frame(rl, thread);
+    let mut y_offset = 80;
+    d.clear_background(Color::BLACK);
+    for menu_message in menu_messages {
+        let menu_message_width = d.measure_text(menu_message.1, 40);
+        if menu_message.0 == game.state_menu.current {
+            d.draw_text(menu_message.1, RES_WIDTH/2 - menu_message_width/2, y_offset, 40, Color::RED);
+        } else {
+            d.draw_text(menu_message.1, RES_WIDTH/2 - menu_message_width/2, y_offset, 40, Color::WHITE);
+        }
+        y_offset = y_offset + 80;
+    }
+}
+
+fn gamepad_label(game: &GameContext, pad: i32) -> String {
+    if pad < 0 {
+        return "Keyboard only".to_string();
+    }
+    if game.gamepads.is_connected(pad) {
+        format!("{} (connected)", pad + 1)
+    } else {
+        format!("{}", pad + 1)
+    }
+}
+
+// Longest player name or server address accepted from the keyboard
+const MAX_TEXT_LEN: usize = 64;
+
+fn options_label(game: &GameContext, item: OptionsState) -> String {
+    let settings = &game.settings;
+    match item {
+        OptionsState::Controls => "Controls".to_string(),
+        OptionsState::Volume => format!("< Volume: {}% >", (settings.volume * 100.0).round()),
+        OptionsState::Fullscreen => format!("< Fullscreen: {} >", if settings.fullscreen { "On" } else { "Off" }),
+        OptionsState::Resolution => format!("< Window: {} x {} >", settings.window_width, settings.window_height),
+        OptionsState::PointsToWin => format!("< Points to win: {} >", settings.rules.points_to_win),
+        OptionsState::PlayerName => format!("Name: {}", settings.player_name),
+        OptionsState::ServerAddress => format!("Server: {}", settings.server_address),
+        OptionsState::NetDelay => format!("< Network smoothing: {} ms >", settings.interp_delay_ms),
+        OptionsState::Netcode => format!("< Online sync: {} >", settings.netcode.name()),
+        OptionsState::InputDelay => format!("< Input delay: {} ticks ({} ms) >", settings.input_delay, settings.input_delay * 1000 / TICK_RATE),
+        OptionsState::LeftPad => format!("< Left player pad: {} >", gamepad_label(game, settings.gamepads.left)),
+        OptionsState::RightPad => format!("< Right player pad: {} >", gamepad_label(game, settings.gamepads.right)),
+        OptionsState::DeadZone => format!("< Stick dead zone: {}% >", (settings.gamepads.dead_zone * 100.0).round()),
+        OptionsState::Back => "Back".to_string(),
+    }
+}
+
+// Make window and audio match the settings.
+fn apply_settings(game: &GameContext, rl: &mut RaylibHandle) {
+    unsafe {
+        SetMasterVolume(game.settings.volume);
+    }
+    if !rl.is_window_fullscreen() {
+        rl.set_window_size(game.settings.window_width, game.settings.window_height);
+    }
+    if rl.is_window_fullscreen() != game.settings.fullscreen {
+        rl.toggle_fullscreen();
+    }
+}
+
+// Change the selected option by one step, returns false for items which are not adjustable.
+fn options_adjust(game: &mut GameContext, direction: i32) -> bool {
+    let settings = &mut game.settings;
+    match game.state_options.current {
+        OptionsState::Volume => settings.volume = (settings.volume + direction as f32 * 0.1).max(0.0).min(1.0),
+        OptionsState::Fullscreen => settings.fullscreen = !settings.fullscreen,
+        OptionsState::Resolution => {
+            let current = RESOLUTIONS.iter().position(|size| *size == (settings.window_width, settings.window_height));
+            let next = match current {
+                Some(index) => (index as i32 + direction).rem_euclid(RESOLUTIONS.len() as i32) as usize,
+                None => 0,
+            };
+            settings.window_width = RESOLUTIONS[next].0;
+            settings.window_height = RESOLUTIONS[next].1;
+        },
+        OptionsState::PointsToWin => settings.rules.points_to_win = (settings.rules.points_to_win + direction).max(1).min(99),
+        OptionsState::NetDelay => settings.interp_delay_ms = (settings.interp_delay_ms as i32 + direction * 20).max(0).min(MAX_INTERP_DELAY_MS as i32) as u32,
+        OptionsState::Netcode => settings.netcode = settings.netcode.next(),
+        OptionsState::InputDelay => settings.input_delay = (settings.input_delay as i32 + direction).max(0).min(MAX_INPUT_DELAY as i32) as u32,
+        OptionsState::LeftPad => settings.gamepads.left = GamepadSettings::cycle(settings.gamepads.left, direction),
+        OptionsState::RightPad => settings.gamepads.right = GamepadSettings::cycle(settings.gamepads.right, direction),
+        OptionsState::DeadZone => settings.gamepads.dead_zone = (settings.gamepads.dead_zone + direction as f32 * 0.05).max(0.0).min(0.9),
+        _ => return false,
+    }
+    true
+}
+
+// Append typed characters to `text`, backspace removes the last one.
+fn text_input(text: &mut String, rl: &mut RaylibHandle) {
+    while let Some(c) = rl.get_char_pressed() {
+        if text.len() < MAX_TEXT_LEN && !c.is_control() {
+            text.push(c);
+        }
+    }
+    if rl.is_key_pressed(KeyboardKey::KEY_BACKSPACE) {
+        text.pop();
+    }
+}
+
+fn options_edit_text(game: &mut GameContext, rl: &mut RaylibHandle) {
+    text_input(game.state_options.editing.as_mut().unwrap(), rl);
+    if !game.settings.controls.pressed(rl, Action::Confirm) {
+        return;
+    }
+
+    let text = game.state_options.editing.take().unwrap();
+    let text = text.trim();
+    if text.is_empty() {
+        return;
+    }
+    if game.state_options.current == OptionsState::PlayerName {
+        game.settings.player_name = text.to_string();
+    } else {
+        game.settings.server_address = text.to_string();
+    }
+    settings_changed(game);
+}
+
+fn options_state(game: &mut GameContext, rl: &mut RaylibHandle, thread: &RaylibThread) {
+    let keys = game.settings.controls.clone();
+
+    if game.state_options.editing.is_some() {
+        options_edit_text(game, rl);
+    } else if keys.pressed(rl, Action::Down) {
+        game.state_options.current = game.state_options.current.next();
+        unsafe {
+            PlaySound(game.assets.menu_next);
+        }
+    } else if keys.pressed(rl, Action::Up) {
+        game.state_options.current = game.state_options.current.prev();
+        unsafe {
+            PlaySound(game.assets.menu_next);
+        }
+    } else if keys.pressed(rl, Action::Back) {
+        game.state = GameState::Menu;
+        return;
+    } else if keys.pressed(rl, Action::Left) || keys.pressed(rl, Action::Right) {
+        let direction = if keys.pressed(rl, Action::Left) { -1 } else { 1 };
+        if options_adjust(game, direction) {
+            apply_settings(game, rl);
+            settings_changed(game);
+        }
+    } else if keys.pressed(rl, Action::Confirm) {
+        match game.state_options.current {
+            OptionsState::Controls => {
+                game.state_controls = Default::default();
+                game.state = GameState::Controls;
+                return;
+            },
+            OptionsState::Back => {
+                game.state = GameState::Menu;
+                return;
+            },
+            OptionsState::PlayerName => game.state_options.editing = Some(game.settings.player_name.clone()),
+            OptionsState::ServerAddress => game.state_options.editing = Some(game.settings.server_address.clone()),
+            _ => {
+                if options_adjust(game, 1) {
+                    apply_settings(game, rl);
+                    settings_changed(game);
+                }
+            },
+        }
+    }
+
+    let labels: Vec<(OptionsState, String)> = OPTIONS_ORDER.iter().map(|item| (*item, options_label(game, *item))).collect();
+    let mut d = begin_frame(rl, thread);
+    let mut y_offset = 30;
+    d.clear_background(Color::BLACK);
+    for (item, label) in labels {
+        let label = match game.state_options.editing.as_ref() {
+            Some(text) if item == game.state_options.current => format!("{}_", text),
+            _ => label,
+        };
+        let label_width = d.measure_text(&label, 34);
+        let color = if item == game.state_options.current { Color::RED } else { Color::WHITE };
+        d.draw_text(&label, RES_WIDTH/2 - label_width/2, y_offset, 34, color);
+        y_offset = y_offset + 48;
+    }
+}
+
+// Bind pressed key to the selected item unless another binding active at the same time uses it.
+fn controls_rebind(game: &mut GameContext, key: Key) {
+    let (owner, action) = BINDINGS[game.state_controls.current];
+    let conflicts = game.settings.controls.conflicts(owner, action, key);
+    if !conflicts.is_empty() {
+        let (other_owner, other_action) = conflicts[0];
+        game.state_controls.message = format!("{} is already used by {}", key.label(), binding_name(other_owner, other_action));
+        return;
+    }
+    game.settings.controls.bind(owner, action, key);
+    game.state_controls.rebinding = false;
+    game.state_controls.message = String::new();
+    settings_changed(game);
+}
+
+fn controls_state(game: &mut GameContext, rl: &mut RaylibHandle, thread: &RaylibThread) {
+    let items = BINDINGS.len() + 2;
+    let keys = game.settings.controls.clone();
+
+    if game.state_controls.rebinding {
+        if keys.pressed(rl, Action::Back) {
+            game.state_controls.rebinding = false;
+            game.state_controls.message = String::new();
+        } else if let Some(key) = rl.get_key_pressed() {
+            controls_rebind(game, Key(key));
+        }
+    } else if keys.pressed(rl, Action::Down) {
+        game.state_controls.current = (game.state_controls.current + 1) % items;
+        game.state_controls.message = String::new();
+    } else if keys.pressed(rl, Action::Up) {
+        game.state_controls.current = (game.state_controls.current + items - 1) % items;
+        game.state_controls.message = String::new();
+    } else if keys.pressed(rl, Action::Back) {
+        game.state = GameState::Options;
+        return;
+    } else if keys.pressed(rl, Action::Confirm) {
+        if game.state_controls.current < BINDINGS.len() {
+            game.state_controls.rebinding = true;
+            game.state_controls.message = format!("Press new key, {} to cancel", keys.label(Owner::Shared, Action::Back));
+        } else if game.state_controls.current == BINDINGS.len() {
+            game.settings.controls = Default::default();
+            game.state_controls.message = "Controls reset to defaults".to_string();
+            settings_changed(game);
+        } else {
+            game.state = GameState::Options;
+            return;
+        }
+    }
+
+    let mut d = begin_frame(rl, thread);
+    let mut y_offset = 20;
+    d.clear_background(Color::BLACK);
+    for (index, (owner, action)) in BINDINGS.iter().enumerate() {
+        let color = if index == game.state_controls.current { Color::RED } else { Color::WHITE };
+        d.draw_text(&binding_name(*owner, *action), RES_WIDTH/2 - 300, y_offset, 30, color);
+        let label = if index == game.state_controls.current && game.state_controls.rebinding { "...".to_string() } else { game.settings.controls.label(*owner, *action) };
+        d.draw_text(&label, RES_WIDTH/2 + 150, y_offset, 30, color);
+        y_offset = y_offset + 45;
+    }
+    for (index, item) in ["Reset to defaults", "Back"].iter().enumerate() {
+        let color = if BINDINGS.len() + index == game.state_controls.current { Color::RED } else { Color::WHITE };
+        d.draw_text(item, RES_WIDTH/2 - 300, y_offset, 30, color);
+        y_offset = y_offset + 45;
+    }
+    d.draw_text(&game.state_controls.message, RES_WIDTH/2 - d.measure_text(&game.state_controls.message, 30)/2, y_offset + 10, 30, Color::YELLOW);
+}
+
+// Pad of a human player taking part in the running local match.
+fn is_match_gamepad(game: &GameContext, pad: i32) -> bool {
+    let ai_side = game.ai.as_ref().map(|ai| ai.side);
+    [ScreenSide::Left, ScreenSide::Right].iter()
+        .any(|side| Some(*side) != ai_side && game.settings.gamepads.pad(*side) == Some(pad))
+}
+
+// Report plugged and unplugged pads, pause local match when a player loses the controller.
+fn gamepad_hotplug(game: &mut GameContext, rl: &RaylibHandle) {
+    for (pad, connected) in game.gamepads.update(rl) {
+        if connected {
+            println!("Gamepad {} connected: {}", pad + 1, rl.get_gamepad_name(pad).unwrap_or_default());
+            continue;
+        }
+        println!("Gamepad {} disconnected", pad + 1);
+        let in_match = game.state == GameState::Loop || game.state == GameState::Scored;
+        // Online match keeps running on the other side, it cannot be paused
+        if in_match && game.multiplayer.thread.is_none() && is_match_gamepad(game, pad) {
+            game.paused_from = Some(game.state);
+            game.state = GameState::Paused;
+        }
+    }
+}
+
+fn paused_state(game: &mut GameContext, rl: &mut RaylibHandle, thread: &RaylibThread) {
+    let missing: Vec<i32> = (0..MAX_GAMEPADS).filter(|pad| is_match_gamepad(game, *pad) && !game.gamepads.is_connected(*pad)).collect();
+    if missing.is_empty() || game.settings.controls.pressed(rl, Action::Confirm) {
+        game.state = game.paused_from.take().unwrap_or(GameState::Loop);
+        game.clock.reset();
+        return;
+    }
+
+    let pads: Vec<String> = missing.iter().map(|pad| format!("{}", pad + 1)).collect();
+    let pause_message = format!("Gamepad {} disconnected", pads.join(", "));
+    let resume_message = format!("Reconnect it or press {} to continue", game.settings.controls.label(Owner::Shared, Action::Confirm));
+    let mut d = begin_frame(rl, thread);
+    draw_match(game, &mut d);
+    d.draw_text(&pause_message, RES_WIDTH/2 - d.measure_text(&pause_message, 40)/2, RES_HEIGHT/4 - 20, 40, Color::RED);
+    d.draw_text(&resume_message, RES_WIDTH/2 - d.measure_text(&resume_message, 30)/2, RES_HEIGHT/4 + 30, 30, Color::WHITE);
+}
+
+// Number of the point that was just played, used to agree on serve with the other player.
+fn current_point(game: &GameContext) -> u32 {
+    game.world.points_played
+}
+
+fn can_game_continue(game: &mut GameContext, rl: &mut RaylibHandle, _thread: &RaylibThread) -> bool {
+    if sends_inputs(game) {
+        // Serve is part of the inputs, once pressed it is kept until the ball goes
+        let point = current_point(game);
+        if game.settings.controls.pressed(rl, Action::Serve) {
+            game.multiplayer.serve_sent = point;
+        }
+        return game.multiplayer.serve_sent >= point;
+    }
+    if game.multiplayer.thread.is_none() {
+        return game.settings.controls.is_down(rl, Owner::Shared, Action::Serve);
+    }
+
+    // Online both players must confirm, server collects confirmations and shares them in context.
+    let point = current_point(game);
+    if game.settings.controls.pressed(rl, Action::Serve) && game.multiplayer.serve_sent < point {
+        srv_multiplayer_update_ready(game, point);
+        game.multiplayer.serve_sent = point;
+    }
+    if game.multiplayer.ctx.is_none() {
+        return false;
+    }
+    let ctx = game.multiplayer.ctx.as_ref().unwrap();
+    ctx.left_serve >= point && ctx.right_serve >= point
+}
+
+fn scored_message(game: &GameContext) -> String {
+    match game.world.phase {
+        MatchPhase::Celebrating { .. } if game.world.score_left == 0 && game.world.score_right == 0 && !game.world.set_scores.is_empty() => {
+            let (left, right) = game.world.set_scores[game.world.set_scores.len() - 1];
+            let winner = if left > right { "One" } else { "Two" };
+            format!("Player {} won set {}!", winner, game.world.set_scores.len())
+        },
+        MatchPhase::Celebrating { .. } => {
+            let scorer = if game.world.next_serve_side() == ScreenSide::Left { "Two" } else { "One" };
+            if game.world.serve_rules.direction == ServeDirection::TowardConceder {
+                format!("Player {} scored!", scorer)
+            } else {
+                "Point!".to_string()
+            }
+        },
+        MatchPhase::WaitingServe => {
+            let online = game.multiplayer.thread.is_some() || sends_inputs(game);
+            if online && game.multiplayer.serve_sent >= current_point(game) {
+                "Waiting for other player ...".to_string()
+            } else {
+                format!("Press {} to continue.", game.settings.controls.label(Owner::Shared, Action::Serve))
+            }
+        },
+        MatchPhase::Countdown { ticks_left } => format!("{}", ticks_left.div_ceil(TICK_RATE)),
+        _ => String::new(),
+    }
+}
+
+fn scored_state(game: &mut GameContext, rl: &mut RaylibHandle, thread: &RaylibThread) {
+    let mut serve = false;
+    if game.world.phase == MatchPhase::WaitingServe {
+        serve = can_game_continue(game, rl, thread);
+    }
+    simulate_frame(game, rl, serve);
+    multiplayer_update(game);
+    if multiplayer_interrupted(game) {
+        return;
+    }
+    let message = scored_message(game);
+    let message_width = rl.measure_text(&message, 40);
+
+    let mut d = begin_frame(rl, thread);
+    draw_match(game, &mut d);
+    d.draw_text(&message, RES_WIDTH/2 - message_width/2, RES_HEIGHT/4 - 20, 40, Color::WHITE);
+    game.state = state_for_phase(game);
+}
+
+fn srv_multiplayer_update_out(game: &mut GameContext) {
+    if game.multiplayer.thread.is_none() {
+        return;
+    }
+
+    if game.multiplayer.side.is_none() {
+        return;
+    }
+    let mut player_left_pos: i32 = -1;
+    let mut player_right_pos: i32 = -1;
+    if *game.multiplayer.side.as_mut().unwrap() == ScreenSide::Left {
+        player_left_pos = game.world.paddle_left.pos_y as i32;
+    } else {
+        player_right_pos = game.world.paddle_right.pos_y as i32;
+    }
+    if game.multiplayer.ctx.is_none() {
+        return;
+    }
+    game.multiplayer.out_seq = game.multiplayer.out_seq + 1;
+    let mut cmd_set_ctx: CmdCtxSet = CmdCtxSet::default();
+    cmd_set_ctx.session = game.multiplayer.session;
+    cmd_set_ctx.seq = game.multiplayer.out_seq;
+    cmd_set_ctx.tick = game.world.tick;
+    cmd_set_ctx.sent_ms = now_ms();
+    cmd_set_ctx.left_pos = player_left_pos;
+    cmd_set_ctx.right_pos = player_right_pos;
+    let ctx = game.multiplayer.ctx.as_mut().unwrap();
+    let ball = &game.world.ball;
+    if game.multiplayer.id == ctx.ball_master {
+        println!("Current master. Updating Ball: vx:{} vy:{} px:{} py:{}", ball.velocity_x, ball.velocity_y, ball.pos_x, ball.pos_y);
+        cmd_set_ctx.ball_vx = ball.velocity_x as i32;
+        cmd_set_ctx.ball_vy = ball.velocity_y as i32;
+        cmd_set_ctx.ball_posx = ball.pos_x as i32;
+        cmd_set_ctx.ball_posy = ball.pos_y as i32;
+    } else {
+        cmd_set_ctx.ball_vx = i32::MAX;
+        cmd_set_ctx.ball_vy = i32::MAX;
+    }
+    let pong_msg = proto_ctx_resp_msg(cmd_set_ctx);
+    // Failed send means the thread is gone, multiplayer_update picks up the reason
+    let _ = game.multiplayer.game_tx.as_mut().unwrap().send(pong_msg);
+}
+
+// Serve 0 means ready to start the match, otherwise ready to serve after given point.
+fn srv_multiplayer_update_ready(game: &mut GameContext, serve: u32) {
+    if game.multiplayer.thread.is_none() {
+        return;
+    }
+
+    println!("Sending Ready signal to SRV_THREAD");
+    let mut cmd_ready: CmdReady = CmdReady::default();
+    cmd_ready.session = game.multiplayer.session;
+    cmd_ready.player = game.multiplayer.id;
+    cmd_ready.serve = serve;
+    game.multiplayer.out_seq = game.multiplayer.out_seq + 1;
+    cmd_ready.seq = game.multiplayer.out_seq;
+    cmd_ready.tick = game.world.tick;
+    cmd_ready.sent_ms = now_ms();
+    let pong_msg = proto_ready_msg(cmd_ready);
+    let _ = game.multiplayer.game_tx.as_mut().unwrap().send(pong_msg);
+}
+
+// Report of a conceded point the server has not counted yet is sent again after this long
+const LOST_POINT_RESEND_MS: u64 = 1000;
+
+// Only the player who conceded tells the server about the point, together with the score after it.
+fn srv_multiplayer_lost_point(game: &mut GameContext) {
+    if game.multiplayer.thread.is_none() || sends_inputs(game) {
+        return;
+    }
+
+    println!("Sending lost point {} to SRV_THREAD", game.world.points_played);
+    let mut cmd_lost: CmdLostPoint = CmdLostPoint::default();
+    cmd_lost.session = game.multiplayer.session;
+    cmd_lost.player = game.multiplayer.id;
+    cmd_lost.point = game.world.points_played;
+    cmd_lost.score_left = game.world.score_left;
+    cmd_lost.score_right = game.world.score_right;
+    cmd_lost.sets_left = game.world.sets_left;
+    cmd_lost.sets_right = game.world.sets_right;
+    game.multiplayer.out_seq = game.multiplayer.out_seq + 1;
+    cmd_lost.seq = game.multiplayer.out_seq;
+    cmd_lost.tick = game.world.tick;
+    cmd_lost.sent_ms = now_ms();
+    game.multiplayer.lost_point_sent_ms = cmd_lost.sent_ms;
+    let pong_msg = proto_lost_point_msg(cmd_lost);
+    let _ = game.multiplayer.game_tx.as_mut().unwrap().send(pong_msg);
+}
+
+// Score the server counted wins over ours. A point the opponent conceded is applied once, when the
+// server count goes one past ours, and any other difference is taken over as it is.
+fn multiplayer_score(game: &mut GameContext, ctx: &CmdCtxSet) {
+    if sends_inputs(game) || game.multiplayer.side.is_none() {
+        return;
+    }
+    if ctx.points_played < game.world.points_played {
+        // Our report is still on the way or got lost with a dropped connection
+        if now_ms().saturating_sub(game.multiplayer.lost_point_sent_ms) > LOST_POINT_RESEND_MS {
+            srv_multiplayer_lost_point(game);
+        }
+        return;
+    }
+    if ctx.points_played == game.world.points_played + 1 && ctx.point_winner != 0 {
+        let scorer = if ctx.point_winner == ctx.left_id { ScreenSide::Left } else { ScreenSide::Right };
+        let events = game.world.award_point(scorer);
+        play_world_events(&events, game);
+    }
+    if game.world.reconcile_score(ctx.points_played, (ctx.score_left, ctx.score_right), (ctx.sets_left, ctx.sets_right)) {
+        println!("Score corrected by server to {} - {} sets {} - {}", ctx.score_left, ctx.score_right, ctx.sets_left, ctx.sets_right);
+    }
+}
+
+// Whether the server counted every point of the match the way we did.
+fn score_confirmed(game: &GameContext) -> bool {
+    if game.multiplayer.thread.is_none() || sends_inputs(game) {
+        return true;
+    }
+    match game.multiplayer.ctx.as_ref() {
+        Some(ctx) => ctx.points_played == game.world.points_played
+            && (ctx.score_left, ctx.score_right) == (game.world.score_left, game.world.score_right)
+            && (ctx.sets_left, ctx.sets_right) == (game.world.sets_left, game.world.sets_right),
+        None => false,
+    }
+}
+
+fn multiplayer_remote_paddles(game: &mut GameContext) {
+    if game.multiplayer.ctx.is_none() {
+        return;
+    }
+    let ctx = game.multiplayer.ctx.as_ref().unwrap();
+    if !is_local_player(ScreenSide::Left, game) && ctx.left_pos > 0 {
+        game.world.paddle_left.pos_y = ctx.left_pos as f32;
+    }
+    if !is_local_player(ScreenSide::Right, game) && ctx.right_pos > 0 {
+        game.world.paddle_right.pos_y = ctx.right_pos as f32;
+    }
+}
+
+// Game state which shows given phase of the match.
+fn state_for_phase(game: &GameContext) -> GameState {
+    match game.world.phase {
+        MatchPhase::Playing => GameState::Loop,
+        MatchPhase::Finished => GameState::Finished,
+        _ => GameState::Scored,
+    }
+}
+
+fn rollback_send(game: &mut GameContext, message: InputMessage) {
+    if let Some(loopback) = game.loopback.as_mut() {
+        loopback.send(message);
+        return;
+    }
+    if game.multiplayer.thread.is_none() || game.multiplayer.reconnecting != 0 {
+        return;
+    }
+    game.multiplayer.out_seq = game.multiplayer.out_seq + 1;
+    let mut cmd_input: CmdInput = CmdInput::default();
+    cmd_input.session = game.multiplayer.session;
+    cmd_input.player = game.multiplayer.id;
+    cmd_input.start_tick = message.start_tick;
+    cmd_input.inputs = message.inputs;
+    cmd_input.ack_tick = message.ack_tick;
+    cmd_input.tick = message.tick;
+    cmd_input.advantage = message.advantage;
+    cmd_input.check_tick = message.check_tick;
+    cmd_input.checksum = message.checksum;
+    cmd_input.seq = game.multiplayer.out_seq;
+    cmd_input.sent_ms = now_ms();
+    let _ = game.multiplayer.game_tx.as_mut().unwrap().send(proto_input_msg(cmd_input));
+}
+
+// Rollback match: only own inputs are sampled, the session guesses the opponent's and
+// repairs the world when a guess turns out wrong.
+fn simulate_rollback_frame(game: &mut GameContext, rl: &RaylibHandle, serve: bool) {
+    let mut rollback = game.rollback.take().unwrap();
+    let frame_time = rl.get_frame_time() as f64;
+    if let Some(loopback) = game.loopback.as_mut() {
+        for message in loopback.update(frame_time * 1000.0) {
+            rollback.receive(&message);
+        }
+    }
+    rollback.update(&mut game.world);
+
+    let paddle = game.settings.controls.paddle(rl, rollback.local_side, &game.settings.gamepads);
+    let input = PlayerInput { paddle, serve };
+    let steps = game.clock.advance(frame_time);
+    let mut waited = false;
+    for _ in 0..steps {
+        // Skip at most one tick a frame, so catching up stays smooth
+        if !waited && rollback.should_wait(&game.world) {
+            waited = true;
+            rollback.stats.waits = rollback.stats.waits + 1;
+            continue;
+        }
+        rollback.add_local_input(&game.world, input);
+        let prev = game.world.clone();
+        let events = match rollback.advance(&mut game.world) {
+            Some(events) => events,
+            None => {
+                rollback.stats.stalls = rollback.stats.stalls + 1;
+                break;
+            },
+        };
+        game.world_prev = prev;
+        play_world_events(&events, game);
+        if matches!(game.world_prev.phase, MatchPhase::Celebrating { .. }) && !matches!(game.world.phase, MatchPhase::Celebrating { .. }) {
+            sync_world_prev(game);
+        }
+        if state_for_phase(game) != game.state {
+            break;
+        }
+    }
+    let message = rollback.message(&game.world);
+    game.rollback = Some(rollback);
+    rollback_send(game, message);
+}
+
+// Server match: own inputs run the match ahead until the server state for them arrives.
+fn simulate_prediction_frame(game: &mut GameContext, rl: &RaylibHandle, serve: bool) {
+    let mut prediction = game.prediction.take().unwrap();
+    let paddle = game.settings.controls.paddle(rl, prediction.local_side, &game.settings.gamepads);
+    let input = PlayerInput { paddle, serve };
+    let steps = game.clock.advance(rl.get_frame_time() as f64);
+    for _ in 0..steps {
+        if !prediction.can_advance() {
+            prediction.stats.stalls = prediction.stats.stalls + 1;
+            break;
+        }
+        game.world_prev = game.world.clone();
+        let events = prediction.advance(&mut game.world, input);
+        play_world_events(&events, game);
+        if matches!(game.world_prev.phase, MatchPhase::Celebrating { .. }) && !matches!(game.world.phase, MatchPhase::Celebrating { .. }) {
+            sync_world_prev(game);
+        }
+        if state_for_phase(game) != game.state {
+            break;
+        }
+    }
+    let message = prediction.message(&game.world);
+    game.prediction = Some(prediction);
+    rollback_send(game, message);
+}
+
+// Run as many simulation ticks as the time elapsed since last frame requires.
+fn simulate_frame(game: &mut GameContext, rl: &RaylibHandle, serve: bool) {
+    if game.rollback.is_some() {
+        simulate_rollback_frame(game, rl, serve);
+        return;
+    }
+    if game.prediction.is_some() {
+        simulate_prediction_frame(game, rl, serve);
+        return;
+    }
+    let mut inputs: WorldInputs = WorldInputs::default();
+    let ai_side = game.ai.as_ref().map(|ai| ai.side);
+    if is_local_player(ScreenSide::Left, game) && ai_side != Some(ScreenSide::Left) {
+        inputs.left = game.settings.controls.paddle(rl, ScreenSide::Left, &game.settings.gamepads);
+    }
+    if is_local_player(ScreenSide::Right, game) && ai_side != Some(ScreenSide::Right) {
+        inputs.right = game.settings.controls.paddle(rl, ScreenSide::Right, &game.settings.gamepads);
+    }
+    if game.multiplayer.thread.is_some() && rl.is_key_pressed(KeyboardKey::KEY_F3) {
+        game.net_view = game.net_view.next();
+    }
+    inputs.serve = serve;
+    let steps = game.clock.advance(rl.get_frame_time() as f64);
+    for _ in 0..steps {
+        multiplayer_remote_paddles(game);
+        if let Some(ai) = game.ai.as_mut() {
+            let input = ai.input(&game.world);
+            if ai.side == ScreenSide::Left {
+                inputs.left = input;
+            } else {
+                inputs.right = input;
+            }
+        }
+        game.world_prev = game.world.clone();
+        if let Some(replay) = game.replay.as_mut() {
+            replay.record(&inputs);
+        }
+        let events = game.world.step(&inputs);
+        play_world_events(&events, game);
+        if events.iter().any(|event| matches!(event, WorldEvent::Scored(scorer) if game.multiplayer.side == Some(scorer.opposite()))) {
+            srv_multiplayer_lost_point(game);
+        }
+        if matches!(game.world_prev.phase, MatchPhase::Celebrating { .. }) && !matches!(game.world.phase, MatchPhase::Celebrating { .. }) {
+            // Ball and paddles jumped to the center
+            sync_world_prev(game);
+        }
+        if inputs.serve && game.world.phase != MatchPhase::WaitingServe {
+            // Serve input is consumed by the tick which launched the ball
+            inputs.serve = false;
+        }
+        if state_for_phase(game) != game.state {
+            break;
+        }
+    }
+}
+
+fn draw_match(game: &GameContext, d: &mut RaylibDrawHandle) {
+    let alpha = game.clock.alpha();
+    let score_left = format!("{}", game.world.score_left);
+    let score_right = format!("{}", game.world.score_right);
+    let score_right_len = d.measure_text(&score_right, 40);
+
+    d.clear_background(Color::BLACK);
+    d.draw_text(&score_left, PADDLE_WIDTH as i32 + 10, 10, 40, Color::WHITE);
+    d.draw_text(&score_right, RES_WIDTH - 10 - PADDLE_WIDTH as i32 - score_right_len, 10, 40, Color::WHITE);
+    d.draw_fps(RES_WIDTH-25, 0);
+
+    if game.world.rules.best_of > 1 {
+        let sets_left = format!("Sets {}", game.world.sets_left);
+        let sets_right = format!("Sets {}", game.world.sets_right);
+        let sets_right_len = d.measure_text(&sets_right, 20);
+        d.draw_text(&sets_left, PADDLE_WIDTH as i32 + 10, 55, 20, Color::GRAY);
+        d.draw_text(&sets_right, RES_WIDTH - 10 - PADDLE_WIDTH as i32 - sets_right_len, 55, 20, Color::GRAY);
+    }
+    if game.world.is_overtime() {
+        let message = "Sudden death";
+        d.draw_text(message, RES_WIDTH/2 - d.measure_text(message, 30)/2, 10, 30, Color::RED);
+    } else if let Some(secs) = game.world.set_time_left() {
+        let message = format!("{}:{:02}", secs / 60, secs % 60);
+        d.draw_text(&message, RES_WIDTH/2 - d.measure_text(&message, 30)/2, 10, 30, Color::WHITE);
+    }
+
+    if game.multiplayer.thread.is_none() || sends_inputs(game) || game.net_view == NetView::Raw {
+        draw_paddle(&game.world_prev.paddle_left, &game.world.paddle_left, alpha, d);
+        draw_paddle(&game.world_prev.paddle_right, &game.world.paddle_right, alpha, d);
+        draw_ball(&game.world_prev.ball, &game.world.ball, alpha, d);
+    } else {
+        draw_smoothed(game, alpha, d);
+    }
+    if game.multiplayer.thread.is_some() {
+        if let Some(ctx) = game.multiplayer.ctx.as_ref().filter(|ctx| ctx.spectators > 0) {
+            let watching_msg = format!("{} watching", ctx.spectators);
+            d.draw_text(&watching_msg, RES_WIDTH/2 - d.measure_text(&watching_msg, 20)/2, 50, 20, Color::GRAY);
+        }
+        if game.net_view != NetView::Smoothed {
+            let view_msg = format!("Net view: {} (F3)", game.net_view.label());
+            d.draw_text(&view_msg, 10, RES_HEIGHT - 60, 20, Color::GRAY);
+        }
+        draw_insecure_banner(game, d);
+    }
+}
+
+// Remote paddle and the ball while the other side moves it are drawn interp_delay_ms in the past
+// from the snapshot buffers, everything else like in a local match.
+fn draw_smoothed(game: &GameContext, alpha: f32, d: &mut RaylibDrawHandle) {
+    let now = now_ms();
+    let delay = game.settings.interp_delay_ms;
+    let multiplayer = &game.multiplayer;
+    let paddles = [
+        (ScreenSide::Left, &game.world_prev.paddle_left, &game.world.paddle_left),
+        (ScreenSide::Right, &game.world_prev.paddle_right, &game.world.paddle_right),
+    ];
+    for (side, prev, paddle) in paddles {
+        let remote = if side == ScreenSide::Left { &multiplayer.remote_left } else { &multiplayer.remote_right };
+        let sample = if is_local_player(side, game) { None } else { remote.sample(now, delay) };
+        match sample {
+            Some((_, y)) => {
+                let mut rect = paddle.rect();
+                rect.y = y - rect.height/2.0;
+                draw_rect(&rect, &rect, 1.0, d);
+                if game.net_view == NetView::Compare {
+                    let raw = paddle.rect();
+                    d.draw_rectangle_lines(raw.x as i32, raw.y as i32, raw.width as i32, raw.height as i32, Color::GREEN);
+                }
+            },
+            None => draw_paddle(prev, paddle, alpha, d),
+        }
+    }
+    match multiplayer.remote_ball.sample(now, delay) {
+        Some((x, y)) => {
+            let mut rect = game.world.ball.rect();
+            rect.x = x - rect.width/2.0;
+            rect.y = y - rect.height/2.0;
+            draw_rect(&rect, &rect, 1.0, d);
+            if game.net_view == NetView::Compare {
+                if let Some(raw) = multiplayer.remote_ball.newest() {
+                    let raw = Rect { x: raw.x - rect.width/2.0, y: raw.y - rect.height/2.0, width: rect.width, height: rect.height };
+                    d.draw_rectangle_lines(raw.x as i32, raw.y as i32, raw.width as i32, raw.height as i32, Color::GREEN);
+                }
+            }
+        },
+        None => draw_ball(&game.world_prev.ball, &game.world.ball, alpha, d),
+    }
+}
+
+fn loop_state(game: &mut GameContext, rl: &mut RaylibHandle, thread: &RaylibThread) {
+    simulate_frame(game, rl, false);
+    multiplayer_update(game);
+    if multiplayer_interrupted(game) {
+        return;
+    }
+
+    let mut d = begin_frame(rl, thread);
+    draw_match(game, &mut d);
+    game.state = state_for_phase(game);
+}
+
+// Seat in any room with a free seat, or a new one.
+fn proto_quick_match_msg(netcode: Netcode, player_name: &str, rules: &MatchRules) -> PongData {
+    let mut msg_get_id: PongData = PongData::new();
+    let mut cmd_get_id: CmdIdGet = CmdIdGet::default();
+    cmd_get_id.netcode = proto_netcode(netcode).into();
+    cmd_get_id.player_name = player_name.to_string();
+    cmd_get_id.rules = Some(encode_rules(rules)).into();
+    msg_get_id.type_ = DataType::GetId.into();
+    msg_get_id.set_id_req(cmd_get_id);
+    msg_get_id
+}
+
+fn proto_rooms_req_msg() -> PongData {
+    let mut msg_rooms: PongData = PongData::new();
+    msg_rooms.type_ = DataType::ListRooms.into();
+    msg_rooms.set_rooms_req(CmdRoomsGet::default());
+    msg_rooms
+}
+
+fn proto_room_create_msg(create: CmdRoomCreate) -> PongData {
+    let mut msg_create: PongData = PongData::new();
+    msg_create.type_ = DataType::CreateRoom.into();
+    msg_create.set_room_create(create);
+    msg_create
+}
+
+fn proto_room_join_msg(join: CmdRoomJoin) -> PongData {
+    let mut msg_join: PongData = PongData::new();
+    msg_join.type_ = DataType::JoinRoom.into();
+    msg_join.set_room_join(join);
+    msg_join
+}
+
+fn proto_room_leave_msg(leave: CmdRoomLeave) -> PongData {
+    let mut msg_leave: PongData = PongData::new();
+    msg_leave.type_ = DataType::LeaveRoom.into();
+    msg_leave.set_room_leave(leave);
+    msg_leave
+}
+
+fn proto_cpu_add_msg(add: CmdCpuAdd) -> PongData {
+    let mut msg_cpu: PongData = PongData::new();
+    msg_cpu.type_ = DataType::AddCpu.into();
+    msg_cpu.set_cpu_add(add);
+    msg_cpu
+}
+
+fn proto_difficulty(difficulty: Difficulty) -> ProtoDifficulty {
+    match difficulty {
+        Difficulty::Easy => ProtoDifficulty::Easy,
+        Difficulty::Normal => ProtoDifficulty::Normal,
+        Difficulty::Hard => ProtoDifficulty::Hard,
+        Difficulty::Expert => ProtoDifficulty::Expert,
+    }
+}
+
+fn proto_queue_join_msg(join: CmdQueueJoin) -> PongData {
+    let mut msg_queue: PongData = PongData::new();
+    msg_queue.type_ = DataType::Queue.into();
+    msg_queue.set_queue_join(join);
+    msg_queue
+}
+
+fn proto_queue_leave_msg() -> PongData {
+    let mut msg_leave: PongData = PongData::new();
+    msg_leave.type_ = DataType::LeaveQueue.into();
+    msg_leave.set_queue_leave(CmdQueueLeave::default());
+    msg_leave
+}
+
+fn proto_spectate_msg(spectate: CmdSpectate) -> PongData {
+    let mut msg_spectate: PongData = PongData::new();
+    msg_spectate.type_ = DataType::Spectate.into();
+    msg_spectate.set_spectate(spectate);
+    msg_spectate
+}
+
+fn proto_stop_spectating_msg() -> PongData {
+    let mut msg_stop: PongData = PongData::new();
+    msg_stop.type_ = DataType::StopSpectating.into();
+    msg_stop.set_spectate(CmdSpectate::default());
+    msg_stop
+}
+
+fn proto_ctx_resp_msg(ctx: CmdCtxSet) -> PongData {
+    let mut msg_set_ctx: PongData = PongData::new();
+    msg_set_ctx.type_ = DataType::SetCtx.into();
+    msg_set_ctx.set_ctx_rsp(ctx);
+    msg_set_ctx
+}
+
+fn proto_input_msg(input: CmdInput) -> PongData {
+    let mut msg_input: PongData = PongData::new();
+    msg_input.type_ = DataType::Input.into();
+    msg_input.set_input(input);
+    msg_input
+}
+
+fn proto_lost_point_msg(lost: CmdLostPoint) -> PongData {
+    let mut msg_lost: PongData = PongData::new();
+    msg_lost.type_ = DataType::LostPoint.into();
+    msg_lost.set_lost_point(lost);
+    msg_lost
+}
+
+fn proto_ready_msg(ctx: CmdReady) -> PongData {
+    let mut msg_ready: PongData = PongData::new();
+    msg_ready.type_ = DataType::Ready.into();
+    msg_ready.set_ready(ctx);
+    msg_ready
+}
+
+// Check server address before connecting, error explains what is wrong with it.
+fn parse_server_url(address: &str) -> Result<Url, String> {
+    let url = Url::parse(address.trim()).map_err(|err| format!("Invalid server address \"{}\": {}", address, err))?;
+    if url.scheme() != "ws" && url.scheme() != "wss" {
+        return Err(format!("Server address must start with ws:// or wss://, got \"{}\"", url.scheme()));
+    }
+    if url.host_str().is_none() {
+        return Err(format!("Server address \"{}\" has no host", address));
+    }
+    Ok(url)
+}
+
+fn tls_settings(game: &GameContext) -> TlsSettings {
+    let mut tls = game.settings.tls.clone();
+    tls.insecure = tls.insecure || game.insecure_override;
+    tls
+}
+
+// Shown on every multiplayer screen while certificate checks are off.
+fn draw_insecure_banner(game: &GameContext, d: &mut RaylibDrawHandle) {
+    if !tls_settings(game).insecure {
+        return;
+    }
+    let banner_msg = "INSECURE: server certificate is not verified";
+    d.draw_rectangle(0, RES_HEIGHT - 28, RES_WIDTH, 28, Color::MAROON);
+    d.draw_text(banner_msg, (RES_WIDTH - d.measure_text(banner_msg, 20))/2, RES_HEIGHT - 24, 20, Color::YELLOW);
+}
+
+fn srv_thread_start(game: &mut GameContext) {
+    let (thread_tx, game_rx) = channel::<SrvMessage>();
+    let (game_tx, thread_rx) = channel::<PongData>();
+    let url = game.multiplayer.server.clone().unwrap();
+    let tls = tls_settings(game);
+    game.multiplayer.game_tx = Some(game_tx);
+    game.multiplayer.game_rx = Some(game_rx);
+    game.multiplayer.thread = Some(thread::spawn(move || srv_thread(url, tls, thread_tx, thread_rx)));
+}
+
+// Tell the server to free our seat now instead of keeping it for a reconnect.
+fn srv_multiplayer_leave_room(game: &mut GameContext) {
+    if game.multiplayer.thread.is_none() || !multiplayer_is_connected(game) {
+        return;
+    }
+    println!("Leaving room of session {}", game.multiplayer.session);
+    let mut cmd_leave: CmdRoomLeave = CmdRoomLeave::default();
+    cmd_leave.session = game.multiplayer.session;
+    cmd_leave.player = game.multiplayer.id;
+    let _ = game.multiplayer.game_tx.as_mut().unwrap().send(proto_room_leave_msg(cmd_leave));
+}
+
+// Server's computer takes the free seat of our session.
+fn srv_multiplayer_add_cpu(game: &mut GameContext) {
+    if game.multiplayer.thread.is_none() || !multiplayer_is_connected(game) {
+        return;
+    }
+    println!("Asking for a computer player in session {}", game.multiplayer.session);
+    let mut cmd_cpu: CmdCpuAdd = CmdCpuAdd::default();
+    cmd_cpu.session = game.multiplayer.session;
+    cmd_cpu.difficulty = proto_difficulty(game.settings.ai_difficulty).into();
+    let _ = game.multiplayer.game_tx.as_mut().unwrap().send(proto_cpu_add_msg(cmd_cpu));
+}
+
+// Drop the connection, server thread notices closed channel and finishes.
+fn multiplayer_leave(game: &mut GameContext) {
+    srv_multiplayer_leave_room(game);
+    let stats = &game.multiplayer.ctx_stats;
+    println!("Leaving multiplayer session {}, snapshots received: {} dropped: {} last age: {} ms", game.multiplayer.session, stats.received, stats.dropped, stats.age_ms);
+    if let Some(rollback) = game.rollback.take() {
+        println!("Rollback stats: {:?}", rollback.stats);
+    }
+    if let Some(prediction) = game.prediction.take() {
+        println!("Prediction stats: {:?}", prediction.stats);
+    }
+    game.multiplayer = Default::default();
+}
+
+fn multiplayer_failed(game: &mut GameContext, err: NetError) {
+    println!("Multiplayer connection failed: {}", err);
+    game.state_connection_error = StateConnectionErrorContext {
+        error: Some(err),
+        server: game.multiplayer.server.clone(),
+        back_selected: false,
+    };
+    multiplayer_leave(game);
+    game.paused_from = None;
+    game.state = GameState::ConnectionError;
+}
+
+// Stop the match until both players are connected again.
+fn multiplayer_pause(game: &mut GameContext) {
+    if game.state != GameState::Reconnecting {
+        game.paused_from = Some(game.state);
+        game.state = GameState::Reconnecting;
+    }
+}
+
+// Multiplayer update took the game out of the calling state.
+fn multiplayer_interrupted(game: &GameContext) -> bool {
+    game.state == GameState::ConnectionError || game.state == GameState::Reconnecting
+}
+
+fn reconnecting_state(game: &mut GameContext, rl: &mut RaylibHandle, thread: &RaylibThread) {
+    multiplayer_update(game);
+    if game.state != GameState::Reconnecting {
+        return;
+    }
+    if game.multiplayer.reconnecting == 0 && !game.multiplayer.opponent_away {
+        game.state = game.paused_from.take().unwrap_or(GameState::Loop);
+        game.clock.reset();
+        return;
+    }
+    if game.settings.controls.pressed(rl, Action::Back) {
+        multiplayer_leave(game);
+        game.paused_from = None;
+        game.state = GameState::Menu;
+        return;
+    }
+
+    let message = if game.multiplayer.reconnecting > 0 {
+        format!("Reconnecting... (attempt {} of {})", game.multiplayer.reconnecting, MAX_RECONNECT_ATTEMPTS)
+    } else {
+        "Opponent reconnecting...".to_string()
+    };
+    let leave_message = format!("Press {} to leave the match", game.settings.controls.label(Owner::Shared, Action::Back));
+    let in_match = game.paused_from == Some(GameState::Loop) || game.paused_from == Some(GameState::Scored);
+    let mut d = begin_frame(rl, thread);
+    if in_match {
+        draw_match(game, &mut d);
+    } else {
+        d.clear_background(Color::BLACK);
+    }
+    d.draw_text(&message, RES_WIDTH/2 - d.measure_text(&message, 40)/2, RES_HEIGHT/4 - 20, 40, Color::RED);
+    d.draw_text(&leave_message, RES_WIDTH/2 - d.measure_text(&leave_message, 30)/2, RES_HEIGHT/4 + 30, 30, Color::WHITE);
+    if !in_match {
+        draw_insecure_banner(game, &mut d);
+    }
+}
+
+fn multiplayer_is_connected(game: &GameContext) -> bool {
+    if game.multiplayer.id != u32::MAX && game.multiplayer.session != u32::MAX {
+        return true;
+    }
+    false
+}
+
+// Remember where the server put the entities the other side moves.
+fn multiplayer_snapshot(game: &mut GameContext, ctx: &CmdCtxSet) {
+    let received_ms = now_ms();
+    let (left, right) = match game.multiplayer.side {
+        Some(ScreenSide::Left) => (false, true),
+        Some(ScreenSide::Right) => (true, false),
+        None => (game.multiplayer.spectating, game.multiplayer.spectating),
+    };
+    if left && ctx.left_pos > 0 {
+        game.multiplayer.remote_left.push(ctx.sent_ms, received_ms, 0.0, ctx.left_pos as f32);
+    }
+    if right && ctx.right_pos > 0 {
+        game.multiplayer.remote_right.push(ctx.sent_ms, received_ms, 0.0, ctx.right_pos as f32);
+    }
+    if ctx.ball_master == game.multiplayer.id || ctx.ball_master == u32::MAX {
+        // Ball is simulated here, snapshots from before would be stale when the other side takes it again
+        game.multiplayer.remote_ball.clear();
+    } else if ctx.ball_posx != i32::MAX && ctx.ball_posy != i32::MAX {
+        game.multiplayer.remote_ball.push(ctx.sent_ms, received_ms, ctx.ball_posx as f32, ctx.ball_posy as f32);
+    }
+}
+
+fn multiplayer_receive(game: &mut GameContext, mut rx_data: PongData) {
+    match rx_data.type_.enum_value_or(DataType::Hello) {
+        DataType::SetId => {
+            game.state_lobby.joining = false;
+            if !rx_data.id_rsp().error.is_empty() {
+                game.state_lobby.error = rx_data.id_rsp().error.clone();
+                return;
+            }
+            game.multiplayer.room = rx_data.id_rsp().room.clone().into_option();
+            game.multiplayer.id = rx_data.id_rsp().id;
+            game.multiplayer.session = rx_data.id_rsp().session;
+            game.multiplayer.seed = rx_data.id_rsp().seed;
+            game.multiplayer.netcode = netcode_from_proto(rx_data.id_rsp().netcode.enum_value_or(ProtoNetcode::BallMaster));
+            game.world.reseed(game.multiplayer.seed);
+            println!("Loop session id: {} player id: {}", game.multiplayer.session, game.multiplayer.id);
+        },
+        DataType::Rooms => {
+            let rooms = rx_data.take_rooms();
+            game.multiplayer.rooms = Some(rooms.rooms);
+            game.multiplayer.live = rooms.live;
+        },
+        DataType::Spectating => {
+            let switching = game.state_lobby.joining;
+            game.state_lobby.joining = false;
+            let spectating = rx_data.take_spectating();
+            if !spectating.error.is_empty() {
+                // Failed switch keeps the match we watch, otherwise it is the one which closed
+                if !switching {
+                    game.multiplayer.spectating = false;
+                }
+                game.state_lobby.error = spectating.error;
+                return;
+            }
+            spectate_start(game, spectating);
+        },
+        DataType::QueueStatus => game.multiplayer.queue = Some(rx_data.take_queue_status()),
+        DataType::Rated => {
+            let rated = rx_data.take_rated();
+            println!("Ranked match rated, rating {} ({:+})", rated.rating, rated.change);
+            game.multiplayer.rated = Some(rated);
+        },
+        DataType::SetCtx => {
+            //println!("Loop ctx: {}", rx_data.ctx_rsp());
+            if rx_data.ctx_rsp().session != game.multiplayer.session {
+                // Pushed before the server got our leave
+                return;
+            }
+            if !game.multiplayer.ctx_stats.accept(rx_data.ctx_rsp().seq, rx_data.ctx_rsp().sent_ms) {
+                println!("Dropping old snapshot {} last applied {}", rx_data.ctx_rsp().seq, game.multiplayer.ctx_stats.last_seq);
+                return;
+            }
+            let sends_inputs = sends_inputs(game);
+            let ball = &mut game.world.ball;
+            // Rollback players simulate the ball themselves, Server players take it from world states
+            if !sends_inputs && rx_data.ctx_rsp().ball_master != game.multiplayer.id {
+                if rx_data.ctx_rsp().ball_vy != i32::MAX && rx_data.ctx_rsp().ball_vx != i32::MAX {
+                    ball.velocity_x = rx_data.ctx_rsp().ball_vx as f32;
+                    ball.velocity_y = rx_data.ctx_rsp().ball_vy as f32;
+                }
+                if rx_data.ctx_rsp().ball_master != u32::MAX &&  rx_data.ctx_rsp().ball_posx != i32::MAX && rx_data.ctx_rsp().ball_posx != i32::MAX {
+                        ball.pos_x = rx_data.ctx_rsp().ball_posx as f32;
+                        ball.pos_y = rx_data.ctx_rsp().ball_posy as f32;
+                }
+            }
+            let ctx = rx_data.take_ctx_rsp();
+            multiplayer_snapshot(game, &ctx);
+            multiplayer_score(game, &ctx);
+            if game.multiplayer.spectating && game.multiplayer.netcode == Netcode::BallMaster {
+                // Nothing else tells spectators the score of these matches
+                game.world.score_left = ctx.score_left;
+                game.world.score_right = ctx.score_right;
+                game.world.sets_left = ctx.sets_left;
+                game.world.sets_right = ctx.sets_right;
+            }
+            let (opponent_id, opponent_away) = match game.multiplayer.side {
+                Some(ScreenSide::Left) => (ctx.right_id, ctx.right_away),
+                Some(ScreenSide::Right) => (ctx.left_id, ctx.left_away),
+                None => (u32::MAX, false),
+            };
+            // Opponent left the room for good, either right away or by not coming back in time
+            if (game.multiplayer.opponent_away || game.multiplayer.playing) && opponent_id == u32::MAX {
+                multiplayer_failed(game, NetError::OpponentLeft);
+                return;
+            }
+            game.multiplayer.opponent_away = opponent_away;
+            if opponent_away {
+                multiplayer_pause(game);
+            }
+            game.multiplayer.ctx = Some(ctx);
+            //println!("Ball vx: {} vy: {}", ball.velocity_x, ball.velocity_y);
+        },
+        DataType::Input => {
+            let input = rx_data.take_input();
+            if let Some(rollback) = game.rollback.as_mut() {
+                rollback.receive(&InputMessage {
+                    start_tick: input.start_tick,
+                    inputs: input.inputs,
+                    ack_tick: input.ack_tick,
+                    tick: input.tick,
+                    advantage: input.advantage,
+                    check_tick: input.check_tick,
+                    checksum: input.checksum,
+                });
+            }
+        }
+        DataType::World => {
+            let state = rx_data.take_world();
+            if let Some(prediction) = game.prediction.as_mut() {
+                let points_played = game.world.points_played;
+                if prediction.apply(&mut game.world, &state) && game.world.points_played != points_played {
+                    // Point the prediction missed or scored differently, ball jumps to where the server has it
+                    sync_world_prev(game);
+                }
+            } else if game.multiplayer.spectating {
+                spectate_world(game, &state);
+            }
+        }
+        _ => println!("Received invalid data type from thread: {:?}", rx_data.type_),
+    }
+}
+
+// Apply everything the server thread sent since last frame, then send our state once.
+fn multiplayer_update(game: &mut GameContext) {
+    if game.multiplayer.thread.is_none() {
+        println!("Multiplaer thread not started");
+        return;
+    }
+
+    loop {
+        match game.multiplayer.game_rx.as_mut().unwrap().try_recv() {
+            Ok(SrvMessage::Data(data)) => multiplayer_receive(game, data),
+            Ok(SrvMessage::Error(err)) => multiplayer_failed(game, err),
+            Ok(SrvMessage::Reconnecting(attempt)) => {
+                game.multiplayer.reconnecting = attempt;
+                multiplayer_pause(game);
+            },
+            Ok(SrvMessage::Resumed) => {
+                println!("Multiplayer session {} resumed", game.multiplayer.session);
+                game.multiplayer.reconnecting = 0;
+                // New connection, server counts its snapshots from the start again
+                game.multiplayer.ctx_stats.last_seq = 0;
+                game.multiplayer.remote_left.clear();
+                game.multiplayer.remote_right.clear();
+                game.multiplayer.remote_ball.clear();
+            },
+            Err(TryRecvError::Empty) => break,
+            // Thread finished without telling why
+            Err(TryRecvError::Disconnected) => multiplayer_failed(game, NetError::Closed),
+        }
+        if game.multiplayer.thread.is_none() {
+            return;
+        }
+    }
+    // Inputs were sent by simulate_rollback_frame or simulate_prediction_frame
+    if game.multiplayer.reconnecting == 0 && !sends_inputs(game) {
+        srv_multiplayer_update_out(game);
+    }
+}
+
+// Server used when the multiplayer screen opens, command line and environment win over settings.
+fn default_server(game: &GameContext) -> String {
+    match game.server_override.as_ref() {
+        Some(address) => address.clone(),
+        None => game.settings.server_address.clone(),
+    }
+}
+
+fn server_select_start(game: &mut GameContext) {
+    game.state_server = Default::default();
+    game.state_server.address = default_server(game);
+    // Bad address from command line or environment is reported right away
+    if let Err(err) = parse_server_url(&game.state_server.address) {
+        game.state_server.error = err;
+    }
+    game.state = GameState::Server;
+}
+
+fn server_select_connect(game: &mut GameContext, address: String) {
+    match parse_server_url(&address) {
+        Ok(url) => {
+            game.settings.remember_server(address.trim());
+            settings_changed(game);
+            game.server_override = None;
+            game.multiplayer.server = Some(url);
+            game.state = GameState::Connect;
+        },
+        Err(err) => {
+            println!("{}", err);
+            game.state_server.address = address;
+            game.state_server.error = err;
+        },
+    }
+}
+
+fn server_state(game: &mut GameContext, rl: &mut RaylibHandle, thread: &RaylibThread) {
+    let recent = game.settings.recent_servers.clone();
+    let items = recent.len() + 2;
+    let keys = game.settings.controls.clone();
+
+    if game.state_server.current == 0 {
+        let before = game.state_server.address.clone();
+        text_input(&mut game.state_server.address, rl);
+        if game.state_server.address != before {
+            game.state_server.error = String::new();
+        }
+    }
+    if keys.pressed(rl, Action::Down) {
+        game.state_server.current = (game.state_server.current + 1) % items;
+        unsafe {
+            PlaySound(game.assets.menu_next);
+        }
+    } else if keys.pressed(rl, Action::Up) {
+        game.state_server.current = (game.state_server.current + items - 1) % items;
+        unsafe {
+            PlaySound(game.assets.menu_next);
+        }
+    } else if game.state_server.current != 0 && keys.pressed(rl, Action::Back) {
+        game.state = GameState::Menu;
+        return;
+    } else if keys.pressed(rl, Action::Confirm) {
+        let current = game.state_server.current;
+        if current == items - 1 {
+            game.state = GameState::Menu;
+            return;
+        }
+        let address = if current == 0 { game.state_server.address.clone() } else { recent[current - 1].clone() };
+        game.state_server.current = 0;
+        server_select_connect(game, address);
+        return;
+    }
+
+    let address_msg = if game.state_server.current == 0 { format!("Server: {}_", game.state_server.address) } else { format!("Server: {}", game.state_server.address) };
+    let mut d = begin_frame(rl, thread);
+    d.clear_background(Color::BLACK);
+    d.draw_text("Multiplayer", (RES_WIDTH - d.measure_text("Multiplayer", 40))/2, 30, 40, Color::WHITE);
+    let color = if game.state_server.current == 0 { Color::RED } else { Color::WHITE };
+    d.draw_text(&address_msg, 100, 120, 34, color);
+    d.draw_text(&game.state_server.error, 100, 170, 26, Color::YELLOW);
+    let mut y_offset = 240;
+    if !recent.is_empty() {
+        d.draw_text("Recent servers", 100, y_offset, 30, Color::GRAY);
+        y_offset = y_offset + 50;
+    }
+    for (index, address) in recent.iter().enumerate() {
+        let color = if game.state_server.current == index + 1 { Color::RED } else { Color::WHITE };
+        d.draw_text(address, 140, y_offset, 30, color);
+        y_offset = y_offset + 45;
+    }
+    let color = if game.state_server.current == items - 1 { Color::RED } else { Color::WHITE };
+    d.draw_text("Back", 100, y_offset + 20, 34, color);
+    draw_insecure_banner(game, &mut d);
+}
+
+fn connect_state(game: &mut GameContext, rl: &mut RaylibHandle, thread: &RaylibThread) {
+    multiplayer_update(game);
+    if multiplayer_interrupted(game) {
+        return;
+    }
+    if game.multiplayer.thread.is_none() {
+        game.multiplayer.id = u32::MAX;
+        game.multiplayer.session = u32::MAX;
+        println!("SRV thread starting");
+        srv_thread_start(game);
+        println!("SRV thread started");
+        srv_multiplayer_list_rooms(game);
+    } else if game.multiplayer.rooms.is_some() {
+        // Server answered, connection works
+        game.state_lobby = Default::default();
+        game.state = GameState::Lobby;
+    }
+
+    let connecting_msg = "Connecting ...";
+    let connecting_msg_len = rl.measure_text(connecting_msg, 40);
+    let mut d = begin_frame(rl, thread);
+
+    d.clear_background(Color::BLACK);
+    d.draw_text(connecting_msg, (RES_WIDTH - connecting_msg_len)/2 , 10, 40, Color::WHITE);
+    draw_insecure_banner(game, &mut d);
+}
+
+// Open rooms are asked for this often while in the lobby
+const ROOMS_REFRESH_MS: u64 = 1000;
+const MAX_LISTED_ROOMS: usize = 6;
+// Same limit as the server has
+const MAX_JOIN_CODE: usize = 8;
+
+fn srv_multiplayer_list_rooms(game: &mut GameContext) {
+    if game.multiplayer.thread.is_none() {
+        return;
+    }
+    game.multiplayer.rooms_sent_ms = now_ms();
+    let _ = game.multiplayer.game_tx.as_mut().unwrap().send(proto_rooms_req_msg());
+}
+
+// Messages of the lobby screens, false when they have to give way to another state.
+fn lobby_update(game: &mut GameContext) -> bool {
+    multiplayer_update(game);
+    if multiplayer_interrupted(game) {
+        return false;
+    }
+    if multiplayer_is_connected(game) {
+        game.state = GameState::Waiting;
+        return false;
+    }
+    if game.multiplayer.spectating {
+        game.state = GameState::Spectate;
+        return false;
+    }
+    // Asking again also keeps the connection from timing out while nothing else is sent
+    if now_ms().saturating_sub(game.multiplayer.rooms_sent_ms) > ROOMS_REFRESH_MS {
+        srv_multiplayer_list_rooms(game);
+    }
+    true
+}
+
+// Give up our seat and pick another room on the same connection.
+fn lobby_return(game: &mut GameContext) {
+    srv_multiplayer_leave_room(game);
+    if game.multiplayer.spectating {
+        let _ = game.multiplayer.game_tx.as_mut().unwrap().send(proto_stop_spectating_msg());
+    }
+    game.rollback = None;
+    game.prediction = None;
+    let multiplayer = &mut game.multiplayer;
+    multiplayer.id = u32::MAX;
+    multiplayer.session = u32::MAX;
+    multiplayer.room = None;
+    multiplayer.side = None;
+    multiplayer.ctx = None;
+    multiplayer.ctx_stats = Default::default();
+    multiplayer.remote_left.clear();
+    multiplayer.remote_right.clear();
+    multiplayer.remote_ball.clear();
+    multiplayer.serve_sent = 0;
+    multiplayer.opponent_away = false;
+    multiplayer.playing = false;
+    multiplayer.queue = None;
+    multiplayer.rated = None;
+    multiplayer.spectating = false;
+    game.state_lobby.current = 0;
+    game.state_lobby.error = String::new();
+    game.state_lobby.joining = false;
+    srv_multiplayer_list_rooms(game);
+    game.state = GameState::Lobby;
+}
+
+fn room_label(room: &Room) -> String {
+    let name = if room.name.is_empty() { format!("Room {}", room.session) } else { room.name.clone() };
+    let netcode = netcode_from_proto(room.netcode.enum_value_or(ProtoNetcode::BallMaster));
+    let rules = decode_rules(room.rules.as_ref());
+    format!("{} - {} ({}, {})", name, room.players.join(", "), netcode.name(), rules.name())
+}
+
+fn live_label(room: &Room) -> String {
+    let players = room.players.join(" vs ");
+    let watching = if room.spectators > 0 { format!(", {} watching", room.spectators) } else { String::new() };
+    format!("{}  {} - {}  ({}{})", players, room.score_left, room.score_right, room.name, watching)
+}
+
+fn lobby_join(game: &mut GameContext, message: PongData) {
+    game.state_lobby.error = String::new();
+    game.state_lobby.joining = true;
+    let _ = game.multiplayer.game_tx.as_mut().unwrap().send(message);
+}
+
+fn lobby_state(game: &mut GameContext, rl: &mut RaylibHandle, thread: &RaylibThread) {
+    if !lobby_update(game) {
+        return;
+    }
+    const LOBBY_ITEMS: [&str; 5] = ["Quick match", "Ranked match", "Create room", "Join with code", "Back"];
+    let rooms = game.multiplayer.rooms.clone().unwrap_or_default();
+    let live = game.multiplayer.live.clone();
+    let items = LOBBY_ITEMS.len() + rooms.len() + live.len();
+    // Rooms come and go, selection stays within the list
+    game.state_lobby.current = game.state_lobby.current.min(items - 1);
+    let keys = game.settings.controls.clone();
+
+    if keys.pressed(rl, Action::Down) {
+        game.state_lobby.current = (game.state_lobby.current + 1) % items;
+        unsafe {
+            PlaySound(game.assets.menu_next);
+        }
+    } else if keys.pressed(rl, Action::Up) {
+        game.state_lobby.current = (game.state_lobby.current + items - 1) % items;
+        unsafe {
+            PlaySound(game.assets.menu_next);
+        }
+    } else if keys.pressed(rl, Action::Back) {
+        multiplayer_leave(game);
+        server_select_start(game);
+        return;
+    } else if keys.pressed(rl, Action::Confirm) && !game.state_lobby.joining {
+        let player_name = game.settings.player_name.clone();
+        match game.state_lobby.current {
+            0 => lobby_join(game, proto_quick_match_msg(game.settings.netcode, &player_name, &game.settings.rules)),
+            1 => {
+                queue_join(game);
+                return;
+            },
+            2 | 3 => {
+                game.state = if game.state_lobby.current == 2 { GameState::RoomCreate } else { GameState::RoomCode };
+                game.state_lobby.current = 0;
+                game.state_lobby.error = String::new();
+                return;
+            },
+            4 => {
+                multiplayer_leave(game);
+                server_select_start(game);
+                return;
+            },
+            current if current < LOBBY_ITEMS.len() + rooms.len() => {
+                let mut cmd_join: CmdRoomJoin = CmdRoomJoin::default();
+                cmd_join.session = rooms[current - LOBBY_ITEMS.len()].session;
+                cmd_join.player_name = player_name;
+                lobby_join(game, proto_room_join_msg(cmd_join));
+            },
+            current => {
+                let mut cmd_spectate: CmdSpectate = CmdSpectate::default();
+                cmd_spectate.session = live[current - LOBBY_ITEMS.len() - rooms.len()].session;
+                lobby_join(game, proto_spectate_msg(cmd_spectate));
+            },
+        }
+    }
+
+    let server_msg = game.multiplayer.server.as_ref().map_or(String::new(), |url| url.to_string());
+    let mut d = begin_frame(rl, thread);
+    d.clear_background(Color::BLACK);
+    d.draw_text("Lobby", (RES_WIDTH - d.measure_text("Lobby", 40))/2, 30, 40, Color::WHITE);
+    d.draw_text(&server_msg, (RES_WIDTH - d.measure_text(&server_msg, 20))/2, 80, 20, Color::GRAY);
+    let mut y_offset = 120;
+    for (index, item) in LOBBY_ITEMS.iter().enumerate() {
+        let color = if game.state_lobby.current == index { Color::RED } else { Color::WHITE };
+        d.draw_text(item, 100, y_offset, 34, color);
+        y_offset = y_offset + 45;
+    }
+    y_offset = y_offset + 10;
+    d.draw_text("Open rooms and live matches", 100, y_offset, 30, Color::GRAY);
+    y_offset = y_offset + 45;
+    if rooms.is_empty() && live.is_empty() {
+        d.draw_text("No open rooms, create one or use quick match", 140, y_offset, 26, Color::GRAY);
+    }
+    // Scroll so the selected entry stays on screen
+    let labels: Vec<String> = rooms.iter().map(|room| format!("Join: {}", room_label(room)))
+        .chain(live.iter().map(|room| format!("Watch: {}", live_label(room))))
+        .collect();
+    let selected_room = game.state_lobby.current.saturating_sub(LOBBY_ITEMS.len());
+    let first = selected_room.saturating_sub(MAX_LISTED_ROOMS - 1);
+    for (index, label) in labels.iter().enumerate().skip(first).take(MAX_LISTED_ROOMS) {
+        let color = if game.state_lobby.current == index + LOBBY_ITEMS.len() { Color::RED } else { Color::WHITE };
+        d.draw_text(label, 140, y_offset, 26, color);
+        y_offset = y_offset + 34;
+    }
+    if game.state_lobby.joining {
+        d.draw_text("Joining ...", 100, RES_HEIGHT - 80, 26, Color::WHITE);
+    } else {
+        d.draw_text(&game.state_lobby.error, 100, RES_HEIGHT - 80, 26, Color::YELLOW);
+    }
+    draw_insecure_banner(game, &mut d);
+}
+
+// Join codes are letters and digits, the server compares them upper case.
+fn join_code_input(code: &mut String, rl: &mut RaylibHandle) {
+    text_input(code, rl);
+    *code = code.chars().filter(|c| c.is_ascii_alphanumeric()).map(|c| c.to_ascii_uppercase()).take(MAX_JOIN_CODE).collect();
+}
+
+fn room_create_state(game: &mut GameContext, rl: &mut RaylibHandle, thread: &RaylibThread) {
+    if !lobby_update(game) {
+        return;
+    }
+    // Name, join code, create, back
+    let items = 4;
+    let keys = game.settings.controls.clone();
+    match game.state_lobby.current {
+        0 => text_input(&mut game.state_lobby.room_name, rl),
+        1 => join_code_input(&mut game.state_lobby.join_code, rl),
+        _ => (),
+    }
+    if keys.pressed(rl, Action::Down) {
+        game.state_lobby.current = (game.state_lobby.current + 1) % items;
+        unsafe {
+            PlaySound(game.assets.menu_next);
+        }
+    } else if keys.pressed(rl, Action::Up) {
+        game.state_lobby.current = (game.state_lobby.current + items - 1) % items;
+        unsafe {
+            PlaySound(game.assets.menu_next);
+        }
+    } else if (game.state_lobby.current == 2 && keys.pressed(rl, Action::Back)) || (game.state_lobby.current == 3 && keys.pressed(rl, Action::Confirm)) {
+        game.state_lobby.current = 2;
+        game.state_lobby.error = String::new();
+        game.state = GameState::Lobby;
+        return;
+    } else if keys.pressed(rl, Action::Confirm) && !game.state_lobby.joining {
+        let mut cmd_create: CmdRoomCreate = CmdRoomCreate::default();
+        cmd_create.name = game.state_lobby.room_name.trim().to_string();
+        cmd_create.join_code = game.state_lobby.join_code.clone();
+        cmd_create.netcode = proto_netcode(game.settings.netcode).into();
+        cmd_create.player_name = game.settings.player_name.clone();
+        cmd_create.rules = Some(encode_rules(&game.settings.rules)).into();
+        lobby_join(game, proto_room_create_msg(cmd_create));
+    }
+
+    let current = game.state_lobby.current;
+    let cursor = |index: usize| if current == index { "_" } else { "" };
+    let name_msg = format!("Name: {}{}", game.state_lobby.room_name, cursor(0));
+    let code_msg = format!("Join code: {}{}", game.state_lobby.join_code, cursor(1));
+    let rules_msg = format!("Rules: {}, picked in the main menu", game.settings.rules.name());
+    let color = |index: usize| if current == index { Color::RED } else { Color::WHITE };
+    let mut d = begin_frame(rl, thread);
+    d.clear_background(Color::BLACK);
+    d.draw_text("Create room", (RES_WIDTH - d.measure_text("Create room", 40))/2, 30, 40, Color::WHITE);
+    d.draw_text(&rules_msg, (RES_WIDTH - d.measure_text(&rules_msg, 24))/2, 80, 24, Color::GRAY);
+    d.draw_text(&name_msg, 100, 130, 34, color(0));
+    d.draw_text(&code_msg, 100, 190, 34, color(1));
+    d.draw_text("Empty join code makes a room anyone can join from the lobby list,", 140, 240, 22, Color::GRAY);
+    d.draw_text("with a code only players who know it can join.", 140, 268, 22, Color::GRAY);
+    d.draw_text("Create", 100, 330, 34, color(2));
+    d.draw_text("Back", 100, 390, 34, color(3));
+    if game.state_lobby.joining {
+        d.draw_text("Creating ...", 100, RES_HEIGHT - 80, 26, Color::WHITE);
+    } else {
+        d.draw_text(&game.state_lobby.error, 100, RES_HEIGHT - 80, 26, Color::YELLOW);
+    }
+    draw_insecure_banner(game, &mut d);
+}
+
+fn room_code_state(game: &mut GameContext, rl: &mut RaylibHandle, thread: &RaylibThread) {
+    if !lobby_update(game) {
+        return;
+    }
+    // Join code, join, watch, back
+    let items = 4;
+    let keys = game.settings.controls.clone();
+    if game.state_lobby.current == 0 {
+        join_code_input(&mut game.state_lobby.join_code, rl);
+    }
+    if keys.pressed(rl, Action::Down) {
+        game.state_lobby.current = (game.state_lobby.current + 1) % items;
+        unsafe {
+            PlaySound(game.assets.menu_next);
+        }
+    } else if keys.pressed(rl, Action::Up) {
+        game.state_lobby.current = (game.state_lobby.current + items - 1) % items;
+        unsafe {
+            PlaySound(game.assets.menu_next);
+        }
+    } else if (game.state_lobby.current > 0 && keys.pressed(rl, Action::Back)) || (game.state_lobby.current == 3 && keys.pressed(rl, Action::Confirm)) {
+        game.state_lobby.current = 3;
+        game.state_lobby.error = String::new();
+        game.state = GameState::Lobby;
+        return;
+    } else if keys.pressed(rl, Action::Confirm) && !game.state_lobby.joining {
+        if game.state_lobby.join_code.is_empty() {
+            game.state_lobby.error = "Enter the join code of the room".to_string();
+        } else if game.state_lobby.current == 2 {
+            let mut cmd_spectate: CmdSpectate = CmdSpectate::default();
+            cmd_spectate.join_code = game.state_lobby.join_code.clone();
+            lobby_join(game, proto_spectate_msg(cmd_spectate));
+        } else {
+            let mut cmd_join: CmdRoomJoin = CmdRoomJoin::default();
+            cmd_join.join_code = game.state_lobby.join_code.clone();
+            cmd_join.player_name = game.settings.player_name.clone();
+            lobby_join(game, proto_room_join_msg(cmd_join));
+        }
+    }
+
+    let current = game.state_lobby.current;
+    let code_msg = format!("Join code: {}{}", game.state_lobby.join_code, if current == 0 { "_" } else { "" });
+    let color = |index: usize| if current == index { Color::RED } else { Color::WHITE };
+    let mut d = begin_frame(rl, thread);
+    d.clear_background(Color::BLACK);
+    d.draw_text("Join with code", (RES_WIDTH - d.measure_text("Join with code", 40))/2, 30, 40, Color::WHITE);
+    d.draw_text(&code_msg, 100, 130, 34, color(0));
+    d.draw_text("Join", 100, 210, 34, color(1));
+    d.draw_text("Watch", 100, 270, 34, color(2));
+    d.draw_text("Back", 100, 330, 34, color(3));
+    if game.state_lobby.joining {
+        d.draw_text("Joining ...", 100, RES_HEIGHT - 80, 26, Color::WHITE);
+    } else {
+        d.draw_text(&game.state_lobby.error, 100, RES_HEIGHT - 80, 26, Color::YELLOW);
+    }
+    draw_insecure_banner(game, &mut d);
+}
+
+// Ranked matches are paired by the server, our rating is kept under the key from settings.
+fn queue_join(game: &mut GameContext) {
+    let mut cmd_queue: CmdQueueJoin = CmdQueueJoin::default();
+    cmd_queue.player_key = game.settings.player_key.clone();
+    cmd_queue.player_name = game.settings.player_name.clone();
+    game.state_lobby.error = String::new();
+    game.multiplayer.queue = None;
+    game.multiplayer.queued_at_ms = now_ms();
+    let _ = game.multiplayer.game_tx.as_mut().unwrap().send(proto_queue_join_msg(cmd_queue));
+    game.state = GameState::Queue;
+}
+
+fn queue_state(game: &mut GameContext, rl: &mut RaylibHandle, thread: &RaylibThread) {
+    if !lobby_update(game) {
+        return;
+    }
+    if !game.state_lobby.error.is_empty() {
+        // Server refused to queue us, the lobby shows why
+        game.multiplayer.queue = None;
+        game.state_lobby.current = 1;
+        game.state = GameState::Lobby;
+        return;
+    }
+    if game.settings.controls.pressed(rl, Action::Back) || game.settings.controls.pressed(rl, Action::Confirm) {
+        let _ = game.multiplayer.game_tx.as_mut().unwrap().send(proto_queue_leave_msg());
+        game.multiplayer.queue = None;
+        game.state_lobby.current = 1;
+        game.state = GameState::Lobby;
+        return;
+    }
+
+    let queued_secs = now_ms().saturating_sub(game.multiplayer.queued_at_ms) / 1000;
+    let time_msg = format!("Searching for {}:{:02}", queued_secs / 60, queued_secs % 60);
+    let mut lines = Vec::new();
+    match game.multiplayer.queue.as_ref() {
+        Some(status) => {
+            lines.push(format!("Your rating: {} after {} ranked matches", status.rating, status.matches));
+            let wait_msg = if status.estimated_wait_ms == 0 {
+                "Estimated wait: unknown".to_string()
+            } else {
+                format!("Estimated wait: {} s", status.estimated_wait_ms.div_ceil(1000))
+            };
+            lines.push(wait_msg);
+            lines.push(format!("Players searching: {}", status.players_queued));
+            lines.push(format!("Opponents rated {} to {}", status.rating - status.window, status.rating + status.window));
+        },
+        None => lines.push("Joining the queue ...".to_string()),
+    }
+    let leave_msg = format!("Press {} to stop searching", game.settings.controls.label(Owner::Shared, Action::Back));
+    let mut d = begin_frame(rl, thread);
+    d.clear_background(Color::BLACK);
+    d.draw_text("Ranked match", (RES_WIDTH - d.measure_text("Ranked match", 40))/2, 30, 40, Color::WHITE);
+    d.draw_text(&time_msg, (RES_WIDTH - d.measure_text(&time_msg, 34))/2, 130, 34, Color::RED);
+    let mut y_offset = 210;
+    for line in lines.iter() {
+        d.draw_text(line, 100, y_offset, 30, Color::WHITE);
+        y_offset = y_offset + 45;
+    }
+    d.draw_text("The search widens the longer it takes.", 100, y_offset + 20, 22, Color::GRAY);
+    d.draw_text(&leave_msg, 100, RES_HEIGHT - 80, 26, Color::WHITE);
+    draw_insecure_banner(game, &mut d);
+}
+
+// Server accepted us as spectator of a session, drawing starts from an empty field.
+fn spectate_start(game: &mut GameContext, spectating: CmdSpectating) {
+    println!("Watching session {}", spectating.session);
+    game.world = PongWorld::new(0);
+    game.world.rules = decode_rules(spectating.room.as_ref().and_then(|room| room.rules.as_ref()));
+    game.world.reset();
+    sync_world_prev(game);
+    game.clock.reset();
+    let multiplayer = &mut game.multiplayer;
+    multiplayer.spectating = true;
+    multiplayer.session = spectating.session;
+    multiplayer.netcode = netcode_from_proto(spectating.netcode.enum_value_or(ProtoNetcode::BallMaster));
+    multiplayer.room = spectating.room.into_option();
+    multiplayer.side = None;
+    multiplayer.ctx = None;
+    // Server counts snapshots of every watched session from the start
+    multiplayer.ctx_stats = Default::default();
+    multiplayer.remote_left.clear();
+    multiplayer.remote_right.clear();
+    multiplayer.remote_ball.clear();
+}
+
+// Server and Rollback matches are simulated by the server, spectators take its states as they are.
+fn spectate_world(game: &mut GameContext, state: &CmdWorld) {
+    if state.session != game.multiplayer.session {
+        return;
+    }
+    let received_ms = now_ms();
+    let multiplayer = &mut game.multiplayer;
+    if state.points_played != game.world.points_played {
+        // Ball is back in the middle, nothing to smooth between
+        multiplayer.remote_ball.clear();
+    }
+    multiplayer.remote_left.push(state.sent_ms, received_ms, 0.0, state.left_y);
+    multiplayer.remote_right.push(state.sent_ms, received_ms, 0.0, state.right_y);
+    multiplayer.remote_ball.push(state.sent_ms, received_ms, state.ball_x, state.ball_y);
+    game.world_prev = game.world.clone();
+    apply_world_state(&mut game.world, state);
+}
+
+// Live match after or before `session` in the list, wrapping around.
+fn spectate_next(live: &[Room], session: u32, direction: i32) -> Option<u32> {
+    if live.is_empty() {
+        return None;
+    }
+    let len = live.len() as i32;
+    let next = match live.iter().position(|room| room.session == session) {
+        Some(current) => (current as i32 + direction + len) % len,
+        None => 0,
+    };
+    Some(live[next as usize].session).filter(|next| *next != session)
+}
+
+fn spectate_state(game: &mut GameContext, rl: &mut RaylibHandle, thread: &RaylibThread) {
+    multiplayer_update(game);
+    if multiplayer_interrupted(game) {
+        return;
+    }
+    if !game.multiplayer.spectating {
+        // Match closed, the lobby shows why
+        game.state_lobby.current = 0;
+        game.state = GameState::Lobby;
+        return;
+    }
+    // The list of live matches is kept fresh for switching
+    if now_ms().saturating_sub(game.multiplayer.rooms_sent_ms) > ROOMS_REFRESH_MS {
+        srv_multiplayer_list_rooms(game);
+    }
+    let keys = game.settings.controls.clone();
+    if keys.pressed(rl, Action::Back) {
+        lobby_return(game);
+        return;
+    }
+    let direction = if keys.pressed(rl, Action::Right) { 1 } else if keys.pressed(rl, Action::Left) { -1 } else { 0 };
+    if direction != 0 && !game.state_lobby.joining {
+        if let Some(session) = spectate_next(&game.multiplayer.live, game.multiplayer.session, direction) {
+            let mut cmd_spectate: CmdSpectate = CmdSpectate::default();
+            cmd_spectate.session = session;
+            lobby_join(game, proto_spectate_msg(cmd_spectate));
+        }
+    }
+
+    let (left_name, right_name, waiting) = match game.multiplayer.ctx.as_ref() {
+        Some(ctx) => (ctx.left_name.clone(), ctx.right_name.clone(), ctx.left_id == u32::MAX || ctx.right_id == u32::MAX),
+        None => (String::new(), String::new(), true),
+    };
+    let room_msg = match game.multiplayer.room.as_ref() {
+        Some(room) if !room.name.is_empty() => format!("Watching {}", room.name),
+        _ => "Watching".to_string(),
+    };
+    let status_msg = match game.world.winner() {
+        _ if waiting => "Waiting for players ...".to_string(),
+        Some(ScreenSide::Left) => format!("{} won", left_name),
+        Some(ScreenSide::Right) => format!("{} won", right_name),
+        None => String::new(),
+    };
+    let position = game.multiplayer.live.iter().position(|room| room.session == game.multiplayer.session);
+    let switch_msg = match position {
+        Some(index) => format!("Match {} of {}, {} / {} to switch, {} for the lobby", index + 1, game.multiplayer.live.len(),
+            keys.label(Owner::Shared, Action::Left), keys.label(Owner::Shared, Action::Right), keys.label(Owner::Shared, Action::Back)),
+        None => format!("{} for the lobby", keys.label(Owner::Shared, Action::Back)),
+    };
+    let mut d = begin_frame(rl, thread);
+    draw_match(game, &mut d);
+    let name_y = if game.world.rules.best_of > 1 { 80 } else { 55 };
+    d.draw_text(&left_name, PADDLE_WIDTH as i32 + 10, name_y, 24, Color::WHITE);
+    d.draw_text(&right_name, RES_WIDTH - 10 - PADDLE_WIDTH as i32 - d.measure_text(&right_name, 24), name_y, 24, Color::WHITE);
+    d.draw_text(&room_msg, RES_WIDTH/2 - d.measure_text(&room_msg, 24)/2, RES_HEIGHT - 90, 24, Color::GRAY);
+    d.draw_text(&switch_msg, RES_WIDTH/2 - d.measure_text(&switch_msg, 20)/2, RES_HEIGHT - 60, 20, Color::GRAY);
+    d.draw_text(&status_msg, RES_WIDTH/2 - d.measure_text(&status_msg, 40)/2, RES_HEIGHT/4 - 20, 40, Color::WHITE);
+    if !game.state_lobby.error.is_empty() {
+        d.draw_text(&game.state_lobby.error, 100, RES_HEIGHT - 120, 24, Color::YELLOW);
+    }
+}
+
+fn connection_error_state(game: &mut GameContext, rl: &mut RaylibHandle, thread: &RaylibThread) {
+    let keys = game.settings.controls.clone();
+    if keys.pressed(rl, Action::Up) || keys.pressed(rl, Action::Down) {
+        game.state_connection_error.back_selected = !game.state_connection_error.back_selected;
+        unsafe {
+            PlaySound(game.assets.menu_next);
+        }
+    } else if keys.pressed(rl, Action::Back) {
+        server_select_start(game);
+        return;
+    } else if keys.pressed(rl, Action::Confirm) {
+        if game.state_connection_error.back_selected || game.state_connection_error.server.is_none() {
+            server_select_start(game);
+        } else {
+            game.multiplayer.server = game.state_connection_error.server.clone();
+            game.state = GameState::Connect;
+        }
+        return;
+    }
+
+    let error_msg = match game.state_connection_error.error.as_ref() {
+        Some(err) => err.to_string(),
+        None => "Connection failed".to_string(),
+    };
+    let mut d = begin_frame(rl, thread);
+    d.clear_background(Color::BLACK);
+    d.draw_text("Connection error", (RES_WIDTH - d.measure_text("Connection error", 40))/2, 30, 40, Color::RED);
+    // Errors from TLS libraries get long, break them into lines that fit the screen
+    let mut y_offset = 120;
+    let mut line = String::new();
+    for word in error_msg.split(' ') {
+        let candidate = if line.is_empty() { word.to_string() } else { format!("{} {}", line, word) };
+        if !line.is_empty() && d.measure_text(&candidate, 26) > RES_WIDTH - 100 {
+            d.draw_text(&line, 50, y_offset, 26, Color::WHITE);
+            y_offset = y_offset + 36;
+            line = word.to_string();
+        } else {
+            line = candidate;
+        }
+    }
+    d.draw_text(&line, 50, y_offset, 26, Color::WHITE);
+
+    let retry_color = if game.state_connection_error.back_selected { Color::WHITE } else { Color::RED };
+    let back_color = if game.state_connection_error.back_selected { Color::RED } else { Color::WHITE };
+    d.draw_text("Retry", (RES_WIDTH - d.measure_text("Retry", 40))/2, RES_HEIGHT/2 + 60, 40, retry_color);
+    d.draw_text("Back", (RES_WIDTH - d.measure_text("Back", 40))/2, RES_HEIGHT/2 + 140, 40, back_color);
+    draw_insecure_banner(game, &mut d);
+}
+
+// Rules of the session we are seated in, picked by the player who opened it.
+fn session_rules(game: &GameContext) -> MatchRules {
+    decode_rules(game.multiplayer.room.as_ref().and_then(|room| room.rules.as_ref()))
+}
+
+// Both players build the same world from the session seed and rules and start from tick 0.
+fn rollback_start(game: &mut GameContext) {
+    game.world = PongWorld::new(game.multiplayer.seed);
+    game.world.rules = session_rules(game);
+    game.world.reset();
+    sync_world_prev(game);
+    game.clock.reset();
+    game.replay = None;
+    game.ai = None;
+    game.multiplayer.serve_sent = 0;
+    let side = game.multiplayer.side.unwrap();
+    game.rollback = Some(RollbackSession::new(side, game.settings.input_delay, &game.world));
+    println!("Rollback match started, input delay {} ticks", game.settings.input_delay);
+}
+
+// Both players and the server build the same world from the session seed and rules, the server one counts.
+fn prediction_start(game: &mut GameContext) {
+    game.world = PongWorld::new(game.multiplayer.seed);
+    game.world.rules = session_rules(game);
+    game.world.reset();
+    sync_world_prev(game);
+    game.clock.reset();
+    game.replay = None;
+    game.ai = None;
+    game.multiplayer.serve_sent = 0;
+    let side = game.multiplayer.side.unwrap();
+    game.prediction = Some(PredictionSession::new(side));
+    println!("Server match started");
+}
+
+fn waiting_state(game: &mut GameContext, rl: &mut RaylibHandle, thread: &RaylibThread) {
+    multiplayer_update(game);
+    if multiplayer_interrupted(game) {
+        return;
+    }
+    const WAITING_MESSAGES: &[&str] = &["Waiting .  ", "Waiting  . ", "Waiting   ."];
+    static mut WAITING_COUNTER: usize = 0;
+    let waiting_msg: &str;
+    let mut send_request: bool = false;
+    // Shame on me for wanting to have an animated waiting screen and using unsafe. Like an animal.
+    unsafe  {
+        waiting_msg = WAITING_MESSAGES[WAITING_COUNTER/60];
+        WAITING_COUNTER = (WAITING_COUNTER + 1) % (WAITING_MESSAGES.len() * 60);
+        if WAITING_COUNTER.is_multiple_of(60) {
+            send_request = true;
+        }
+    }
+    let waiting_msg_len = rl.measure_text(waiting_msg, 40);
+    let confirm = game.settings.controls.label(Owner::Shared, Action::Confirm);
+    let cpu_msg = format!("Press {} to let the server CPU ({}) take the free seat", confirm, game.settings.ai_difficulty.name());
+    let cpu_msg_len = rl.measure_text(&cpu_msg, 30);
+    let leave_msg = format!("Press {} to leave the room", game.settings.controls.label(Owner::Shared, Action::Back));
+    let leave_msg_len = rl.measure_text(&leave_msg, 30);
+    let name_msg = format!("Playing as {}", game.settings.player_name);
+    let name_msg_len = rl.measure_text(&name_msg, 30);
+    let (room_msg, code_msg) = match game.multiplayer.room.as_ref() {
+        Some(room) if room.name.is_empty() => (format!("Room {}", room.session), room.join_code.clone()),
+        Some(room) => (format!("Room {}", room.name), room.join_code.clone()),
+        None => (String::new(), String::new()),
+    };
+    let code_msg = if code_msg.is_empty() { code_msg } else { format!("Join code: {}", code_msg) };
+    // Names in the room, fresh from the server once it pushes the session
+    let players: Vec<String> = match game.multiplayer.ctx.as_ref() {
+        Some(ctx) => [&ctx.left_name, &ctx.right_name].iter().filter(|name| !name.is_empty()).map(|name| name.to_string()).collect(),
+        None => game.multiplayer.room.as_ref().map_or(Vec::new(), |room| room.players.clone()),
+    };
+    let players_msg = format!("In the room: {}", players.join(", "));
+    let rules_msg = format!("Rules: {}", session_rules(game).name());
+
+    if game.settings.controls.pressed(rl, Action::Confirm) {
+        // Match starts once the computer shows up in the session like a player who joined, the room
+        // turns into a Server netcode one for it
+        srv_multiplayer_add_cpu(game);
+    }
+    if game.settings.controls.pressed(rl, Action::Back) {
+        lobby_return(game);
+        return;
+    }
+
+    if send_request && game.multiplayer.ctx.is_some() {
+        // If both players have IDs assigned not equal 0xFFFFFFFF it means that we can proceed to
+        // the next state. Also check which side we are assigned.
+        let ctx = game.multiplayer.ctx.as_ref().unwrap();
+        println!("Left_id: {} Right_id: {}", ctx.left_id, ctx.right_id);
+        if ctx.left_id == game.multiplayer.id {
+            game.multiplayer.side = Some(ScreenSide::Left);
+        } else if ctx.right_id == game.multiplayer.id {
+            game.multiplayer.side = Some(ScreenSide::Right);
+        } else {
+            // Neither player has assigned our Id - must be communicaiton error.
+            println!("Invalid id assigned, could not determine side.")
+        }
+        if ctx.left_id != u32::MAX && ctx.right_id != u32::MAX && game.multiplayer.side.is_some() {
+            println!("Second player connected, can start the game.");
+            game.multiplayer.playing = true;
+            if game.multiplayer.netcode == Netcode::Rollback {
+                rollback_start(game);
+            } else if game.multiplayer.netcode == Netcode::Server {
+                // Server starts simulating once both players are ready
+                prediction_start(game);
+                srv_multiplayer_update_ready(game, 0);
+            } else {
+                game.world.rules = session_rules(game);
+                // Points we score count once the opponent reports them
+                game.world.remote_goal = game.multiplayer.side.map(|side| side.opposite());
+                srv_multiplayer_update_ready(game, 0);
+            }
+            game.state = GameState::Loop;
+        }
+    }
+
+    let mut d = begin_frame(rl, thread);
+
+    d.clear_background(Color::BLACK);
+    d.draw_text(waiting_msg, (RES_WIDTH - waiting_msg_len)/2 , 10, 40, Color::WHITE);
+    d.draw_text(&cpu_msg, (RES_WIDTH - cpu_msg_len)/2 , RES_HEIGHT/2, 30, Color::GRAY);
+    d.draw_text(&name_msg, (RES_WIDTH - name_msg_len)/2 , 70, 30, Color::WHITE);
+    d.draw_text(&room_msg, (RES_WIDTH - d.measure_text(&room_msg, 30))/2, 140, 30, Color::WHITE);
+    d.draw_text(&code_msg, (RES_WIDTH - d.measure_text(&code_msg, 30))/2, 190, 30, Color::YELLOW);
+    d.draw_text(&players_msg, (RES_WIDTH - d.measure_text(&players_msg, 26))/2, 240, 26, Color::GRAY);
+    d.draw_text(&rules_msg, (RES_WIDTH - d.measure_text(&rules_msg, 26))/2, 275, 26, Color::GRAY);
+    d.draw_text(&leave_msg, (RES_WIDTH - leave_msg_len)/2 , RES_HEIGHT/2 + 50, 30, Color::GRAY);
+    draw_insecure_banner(game, &mut d);
+}
+
+// Re-run recorded match without opening a window and report how it ended.
+fn replay_check(path: &str) {
+    let replay = Replay::load(Path::new(path));
+    if replay.is_err() {
+        println!("Failed to load replay {}: {}", path, replay.err().unwrap());
+        return;
+    }
+    let replay = replay.unwrap();
+    let world = replay.simulate();
+    println!("Replay {} seed: {} ticks: {} sets: {} - {} score: {} - {} ball: ({}, {})", path, replay.seed, world.tick,
+        world.sets_left, world.sets_right, world.score_left, world.score_right, world.ball.pos_x, world.ball.pos_y);
+}
+
+pub fn pong() {
+    let args: Vec<String> = std::env::args().collect();
+    if args.len() == 3 && args[1] == "--replay" {
+        replay_check(&args[2]);
+        return;
+    }
+    let mut server_override = std::env::var("RENGINE_SERVER").ok().filter(|address| !address.trim().is_empty());
+    if let Some(index) = args.iter().position(|arg| arg == "--server") {
+        match args.get(index + 1) {
+            Some(address) => server_override = Some(address.clone()),
+            None => println!("--server needs an address, e.g. --server wss://example.com:8443/ws"),
+        }
+    }
+    let insecure_override = args.iter().any(|arg| arg == "--insecure");
+    let mut loopback_latency: Option<u32> = None;
+    if let Some(index) = args.iter().position(|arg| arg == "--loopback") {
+        match args.get(index + 1).and_then(|latency| latency.parse::<u32>().ok()) {
+            Some(latency) => loopback_latency = Some(latency),
+            None => println!("--loopback needs latency in milliseconds, e.g. --loopback 80"),
+        }
+    }
+
+    let mut settings = Settings::load(&settings_path());
+    if settings.player_key.is_empty() {
+        settings.player_key = format!("{:016x}", new_seed());
+        if let Err(err) = settings.save(&settings_path()) {
+            println!("Failed to save settings {}: {}", settings_path().display(), err);
+        }
+    }
+    let (mut rl, thread) = raylib::init()
+        .size(settings.window_width, settings.window_height)
+        .title("Safe Pong in RUST")
+        .build();
+
+    let rl_audio = raylib::audio::RaylibAudio::init_audio_device();
+    if rl_audio.is_err() {
+        println!("Failed to initialize audio device!");
+    }
+
+    println!("Assets path exists: {}", Path::new("assets/ball_bounce.wav").exists());
+    let mut game: GameContext;
+    unsafe {
+        let menu_next_path = CString::new("assets/menu_next.wav").unwrap();
+        let ball_bounce_path = CString::new("assets/ball_bounce.wav").unwrap();
+        let player_scored_path = CString::new("assets/player_scored.wav").unwrap();
+        game = GameContext {
+            world: PongWorld::new(new_seed()),
+            world_prev: PongWorld::new(0),
+            clock: FixedClock::new(TICK_RATE, MAX_STEPS_PER_FRAME),
+            replay: None,
+            ai: None,
+            settings,
+            gamepads: Default::default(),
+            paused_from: None,
+            state: GameState::Menu,
+            state_menu: Default::default(),
+            state_options: Default::default(),
+            state_controls: Default::default(),
+            state_server: Default::default(),
+            state_lobby: Default::default(),
+            state_connection_error: Default::default(),
+            server_override,
+            insecure_override,
+            net_view: NetView::default(),
+            rollback: None,
+            prediction: None,
+            loopback: None,
+            loopback_latency,
+            multiplayer: Default::default(),
+            assets: GameAssets {
+                menu_next: LoadSound(menu_next_path.as_ptr()),
+                ball_bounce: LoadSound(ball_bounce_path.as_ptr()),
+                player_scored: LoadSound(player_scored_path.as_ptr()),
+            }
+        };
+    }
+
+    rl.set_target_fps(60);
+    apply_settings(&game, &mut rl);
+
+    while !rl.window_should_close() && game.state != GameState::Quit {
+        gamepad_hotplug(&mut game, &rl);
+        match game.state {
+            GameState::Server => server_state(&mut game, &mut rl, &thread),
+            GameState::Connect => connect_state(&mut game, &mut rl, &thread),
+            GameState::Lobby => lobby_state(&mut game, &mut rl, &thread),
+            GameState::RoomCreate => room_create_state(&mut game, &mut rl, &thread),
+            GameState::RoomCode => room_code_state(&mut game, &mut rl, &thread),
+            GameState::Queue => queue_state(&mut game, &mut rl, &thread),
+            GameState::Spectate => spectate_state(&mut game, &mut rl, &thread),
+            GameState::ConnectionError => connection_error_state(&mut game, &mut rl, &thread),
+            GameState::Reconnecting => reconnecting_state(&mut game, &mut rl, &thread),
+            GameState::Waiting => waiting_state(&mut game, &mut rl, &thread),
+            GameState::Init => init_state(&mut game, &mut rl, &thread),
+            GameState::Loop => loop_state(&mut game, &mut rl, &thread),
+            GameState::Scored => scored_state(&mut game, &mut rl, &thread),
+            GameState::Menu => menu_state(&mut game, &mut rl, &thread),
+            GameState::Finished => finished_state(&mut game, &mut rl, &thread),
+            GameState::Options => options_state(&mut game, &mut rl, &thread),
+            GameState::Controls => controls_state(&mut game, &mut rl, &thread),
+            GameState::Paused => paused_state(&mut game, &mut rl, &thread),
+            _ => game.state = GameState::Quit,
+        }
+    }
+}
@@ -0,0 +1,94 @@
+// Fixed timestep clock. Rendering runs at whatever rate the machine manages,
+// real frame time is accumulated and spent in whole simulation ticks.
+
+// Upper bound of ticks simulated during one rendered frame. When the game cannot
+// keep up (debugger, window drag, slow machine) the remaining backlog is dropped
+// instead of spiraling into ever longer frames.
+pub const MAX_STEPS_PER_FRAME: u32 = 8;
+
+#[derive(Debug, Clone)]
+pub struct FixedClock {
+    tick_dt: f64,
+    max_steps: u32,
+    accumulator: f64,
+}
+
+impl FixedClock {
+    pub fn new(tick_rate: u32, max_steps: u32) -> FixedClock {
+        FixedClock {
+            tick_dt: 1.0 / tick_rate as f64,
+            max_steps,
+            accumulator: 0.0,
+        }
+    }
+
+    pub fn reset(&mut self) {
+        self.accumulator = 0.0;
+    }
+
+    // Feed real time elapsed since last frame, returns number of ticks to simulate now.
+    pub fn advance(&mut self, frame_time: f64) -> u32 {
+        self.accumulator = self.accumulator + frame_time.max(0.0);
+        let mut steps: u32 = 0;
+        while self.accumulator >= self.tick_dt && steps < self.max_steps {
+            self.accumulator = self.accumulator - self.tick_dt;
+            steps = steps + 1;
+        }
+        if self.accumulator >= self.tick_dt {
+            self.accumulator = self.accumulator % self.tick_dt;
+        }
+        steps
+    }
+
+    // How far (0.0 - 1.0) rendering is between the last simulated tick and the next one.
+    pub fn alpha(&self) -> f32 {
+        (self.accumulator / self.tick_dt) as f32
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn advance_catches_up_with_short_and_long_frames() {
+        let mut clock = FixedClock::new(100, MAX_STEPS_PER_FRAME);
+        // Frames shorter than a tick add up
+        assert_eq!(clock.advance(0.004), 0);
+        assert_eq!(clock.advance(0.004), 0);
+        assert_eq!(clock.advance(0.004), 1);
+        assert_eq!(clock.advance(0.035), 3);
+        // Time going backwards counts as nothing
+        assert_eq!(clock.advance(-1.0), 0);
+        let total: u32 = (0..100).map(|_| clock.advance(0.0125)).sum();
+        assert_eq!(total, 125);
+    }
+
+    #[test]
+    fn max_steps_drop_the_backlog() {
+        let mut clock = FixedClock::new(100, MAX_STEPS_PER_FRAME);
+        assert_eq!(clock.advance(10.0), MAX_STEPS_PER_FRAME);
+        // Nothing is left to catch up on, the next frame runs its own ticks only
+        assert!(clock.alpha() < 1.0);
+        assert_eq!(clock.advance(0.02), 2);
+
+        clock.advance(0.005);
+        clock.reset();
+        assert_eq!(clock.alpha(), 0.0);
+        assert_eq!(clock.advance(0.009), 0);
+    }
+
+    #[test]
+    fn alpha_stays_between_ticks() {
+        let mut clock = FixedClock::new(120, MAX_STEPS_PER_FRAME);
+        assert_eq!(clock.alpha(), 0.0);
+        for frame_time in [0.001, 0.004, 0.0167, 0.033, 0.1, 0.5, 3.0] {
+            clock.advance(frame_time);
+            let alpha = clock.alpha();
+            assert!((0.0..1.0).contains(&alpha), "{} after {}", alpha, frame_time);
+        }
+        let mut clock = FixedClock::new(100, MAX_STEPS_PER_FRAME);
+        clock.advance(0.015);
+        assert!((clock.alpha() - 0.5).abs() < 0.001);
+    }
+}
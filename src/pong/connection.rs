@@ -0,0 +1,255 @@
+// Server thread of the game. Connects, passes messages between the game loop and the server and
+// gets the seat back after a dropped connection. Nothing in here needs raylib, tests drive the
+// server through it too.
+
+use std::sync::mpsc::{Receiver, Sender, TryRecvError};
+use std::thread::sleep;
+
+use protobuf::Message;
+use websocket::dataframe::Opcode;
+use websocket::stream::sync::NetworkStream;
+use websocket::sync::Client;
+use websocket::url::Url;
+use websocket::ws::dataframe::DataFrame;
+use websocket::{OwnedMessage, WebSocketError};
+
+use super::net::{reconnect_delay, FrameStream, NetError, MAX_RECONNECT_ATTEMPTS};
+use super::protos::pong::{CmdHello, CmdIdGet, CmdSubscribe, DataType, Netcode as ProtoNetcode, PongData};
+use super::rollback::Netcode;
+use super::tls::{self, TlsSettings};
+
+// Server thread to game loop
+pub enum SrvMessage {
+    Data(PongData),
+    // Connection dropped, trying to get it back, attempt counts from 1
+    Reconnecting(u32),
+    Resumed,
+    // Connection is gone, thread finishes after sending it
+    Error(NetError),
+}
+
+#[allow(dead_code)]
+fn proto_hello_msg(msg: &str) -> OwnedMessage {
+    // hello protobuf message
+    let mut msg_hello: PongData = PongData::new();
+    let cmd_hello: CmdHello = CmdHello{
+        msg: msg.to_string(),
+        special_fields: ::protobuf::SpecialFields::default(),
+    };
+    msg_hello.set_hello(cmd_hello);
+
+    OwnedMessage::Binary(msg_hello.write_to_bytes().unwrap())
+}
+
+// Non zero `resume_token` asks for the id and session given out with it before.
+fn proto_id_req_msg(resume_token: u64, netcode: Netcode) -> OwnedMessage {
+    let mut msg_get_id: PongData = PongData::new();
+    let mut cmd_get_id: CmdIdGet = CmdIdGet::default();
+    cmd_get_id.resume_token = resume_token;
+    cmd_get_id.netcode = proto_netcode(netcode).into();
+    msg_get_id.type_ = DataType::GetId.into();
+    msg_get_id.set_id_req(cmd_get_id);
+
+    OwnedMessage::Binary(msg_get_id.write_to_bytes().unwrap())
+}
+
+pub fn proto_netcode(netcode: Netcode) -> ProtoNetcode {
+    match netcode {
+        Netcode::Rollback => ProtoNetcode::Rollback,
+        Netcode::BallMaster => ProtoNetcode::BallMaster,
+        Netcode::Server => ProtoNetcode::Server,
+    }
+}
+
+pub fn netcode_from_proto(netcode: ProtoNetcode) -> Netcode {
+    match netcode {
+        ProtoNetcode::Rollback => Netcode::Rollback,
+        ProtoNetcode::BallMaster => Netcode::BallMaster,
+        ProtoNetcode::Server => Netcode::Server,
+    }
+}
+
+fn proto_subscribe_msg(session: u32) -> Vec<u8> {
+    let mut msg_subscribe: PongData = PongData::new();
+    let mut cmd_subscribe: CmdSubscribe = CmdSubscribe::default();
+    cmd_subscribe.session = session;
+    msg_subscribe.type_ = DataType::Subscribe.into();
+    msg_subscribe.set_subscribe(cmd_subscribe);
+    msg_subscribe.write_to_bytes().unwrap()
+}
+
+// Next message from the server.
+fn srv_recv(ws: &mut Client<Box<dyn NetworkStream + Send>>) -> Result<PongData, NetError> {
+    let frame = ws.recv_dataframe()?;
+    if frame.opcode == Opcode::Close {
+        return Err(NetError::Closed);
+    }
+    PongData::parse_from_bytes(&frame.take_payload()).map_err(|err| NetError::Protocol(err.to_string()))
+}
+
+// Unknown message types are an error instead of a panic, newer server may send them.
+fn data_type(data: &PongData) -> Result<DataType, NetError> {
+    data.type_.enum_value().map_err(|value| NetError::Protocol(format!("unknown message type {}", value)))
+}
+
+fn srv_connect(url: &Url, tls: &TlsSettings) -> Result<websocket::sync::Client<Box<dyn NetworkStream + std::marker::Send>>, NetError> {
+    println!("Creating new socket to {}", url);
+    let stream = tls::connect(url, tls)?;
+    let mut ws = websocket::ClientBuilder::from_url(url).connect_on(stream).map_err(|err| match err {
+        WebSocketError::IoError(err) => NetError::from(err),
+        err => NetError::Handshake(err.to_string()),
+    })?;
+    let srv_resp = srv_recv(&mut ws)?;
+    println!("Srv_resp: {}", srv_resp);
+    if data_type(&srv_resp)? == DataType::Hello {
+        println!("Server hello msg: {:?} - {}", DataType::Hello, srv_resp.hello().msg);
+    } else {
+        println!("Did not receive hello! {:?}", srv_resp.type_);
+    }
+    Ok(ws)
+}
+
+fn srv_get_id(ws: &mut Client<Box<dyn NetworkStream + Send>>, resume_token: u64, netcode: Netcode) -> Result<PongData, NetError> {
+    let msg = proto_id_req_msg(resume_token, netcode);
+    ws.send_message(&msg)?;
+
+    let srv_resp = srv_recv(ws)?;
+    if data_type(&srv_resp)? != DataType::SetId {
+        return Err(NetError::Protocol("did not receive id response".to_string()));
+    }
+    if resume_token != 0 && srv_resp.id_rsp().session == u32::MAX {
+        return Err(NetError::SessionExpired);
+    }
+    println!("Received player id: {} session id: {} from server", srv_resp.id_rsp().id, srv_resp.id_rsp().session);
+    Ok(srv_resp)
+}
+
+fn srv_send_data(stream: &mut FrameStream, ctx: PongData) -> Result<(), NetError> {
+    stream.send(ctx.write_to_bytes().map_err(|err| NetError::Protocol(err.to_string()))?)
+}
+
+// Subscribe to pushed session state, from here on nothing waits for a reply.
+fn srv_subscribe(ws: Client<Box<dyn NetworkStream + Send>>, session: u32) -> Result<FrameStream, NetError> {
+    let mut stream = FrameStream::new(ws)?;
+    stream.send(proto_subscribe_msg(session))?;
+    Ok(stream)
+}
+
+// Pass messages between game and server until the game drops its end of the channel (Ok) or connection fails.
+// Once the server gives us a seat the session is subscribed to and `resume_token` kept for reconnecting.
+fn srv_exchange(stream: &mut FrameStream, resume_token: &mut u64, tx: &Sender<SrvMessage>, rx: &Receiver<PongData>) -> Result<(), NetError> {
+    loop {
+        loop {
+            match rx.try_recv() {
+                Ok(pong_msg) => {
+                    if pong_msg.type_.enum_value() == Ok(DataType::LeaveRoom) {
+                        // Back in the lobby, nothing to resume
+                        *resume_token = 0;
+                    }
+                    srv_send_data(stream, pong_msg)?
+                },
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return Ok(()),
+            }
+        }
+
+        // Older snapshots are of no use once a newer one arrived
+        let mut newest_ctx: Option<PongData> = None;
+        for payload in stream.poll()? {
+            let srv_msg = PongData::parse_from_bytes(&payload).map_err(|err| NetError::Protocol(err.to_string()))?;
+            let srv_type = data_type(&srv_msg)?;
+            if srv_type == DataType::SetCtx {
+                if newest_ctx.as_ref().is_none_or(|newest| srv_msg.ctx_rsp().seq >= newest.ctx_rsp().seq) {
+                    newest_ctx = Some(srv_msg);
+                }
+                continue;
+            }
+            if srv_type == DataType::SetId && srv_msg.id_rsp().error.is_empty() && srv_msg.id_rsp().session != u32::MAX {
+                println!("Received player id: {} session id: {} from server", srv_msg.id_rsp().id, srv_msg.id_rsp().session);
+                *resume_token = srv_msg.id_rsp().resume_token;
+                stream.send(proto_subscribe_msg(srv_msg.id_rsp().session))?;
+            }
+            if tx.send(SrvMessage::Data(srv_msg)).is_err() {
+                return Ok(());
+            }
+        }
+        if let Some(srv_ctx) = newest_ctx {
+            if tx.send(SrvMessage::Data(srv_ctx)).is_err() {
+                return Ok(());
+            }
+        }
+    }
+}
+
+// Reconnect with growing delays and take the old seat back. None when the game left meanwhile.
+fn srv_resume(url: &Url, tls: &TlsSettings, resume_token: u64, tx: &Sender<SrvMessage>, rx: &Receiver<PongData>, mut last_err: NetError) -> Result<Option<FrameStream>, NetError> {
+    for attempt in 0..MAX_RECONNECT_ATTEMPTS {
+        if tx.send(SrvMessage::Reconnecting(attempt + 1)).is_err() {
+            return Ok(None);
+        }
+        sleep(reconnect_delay(attempt));
+        // Anything the game sent while paused is out of date
+        loop {
+            match rx.try_recv() {
+                Ok(_) => continue,
+                Err(TryRecvError::Empty) => break,
+                Err(TryRecvError::Disconnected) => return Ok(None),
+            }
+        }
+
+        println!("Reconnecting to {}, attempt {}", url, attempt + 1);
+        let resumed = srv_connect(url, tls).and_then(|mut ws| {
+            // Session keeps its netcode, requested one does not matter
+            let multiplayer_data = srv_get_id(&mut ws, resume_token, Netcode::default())?;
+            srv_subscribe(ws, multiplayer_data.id_rsp().session)
+        });
+        match resumed {
+            Ok(stream) => {
+                if tx.send(SrvMessage::Resumed).is_err() {
+                    return Ok(None);
+                }
+                return Ok(Some(stream));
+            },
+            Err(NetError::SessionExpired) => return Err(NetError::SessionExpired),
+            Err(err) => {
+                println!("Reconnect failed: {}", err);
+                last_err = err;
+            },
+        }
+    }
+    Err(last_err)
+}
+
+// Talk to the server until the game leaves. Ok means the game left.
+// The game picks a room itself, the thread only relays the lobby messages.
+fn srv_session(url: &Url, tls: &TlsSettings, tx: &Sender<SrvMessage>, rx: &Receiver<PongData>) -> Result<(), NetError> {
+    let ws = srv_connect(url, tls)?;
+    let mut stream = FrameStream::new(ws)?;
+    let mut resume_token = 0;
+    loop {
+        let err = match srv_exchange(&mut stream, &mut resume_token, tx, rx) {
+            Ok(()) => return Ok(()),
+            Err(err) => err,
+        };
+        // Servers without resume support give no token, in the lobby there is no seat to resume
+        if resume_token == 0 {
+            return Err(err);
+        }
+        println!("Connection lost: {}", err);
+        stream = match srv_resume(url, tls, resume_token, tx, rx, err)? {
+            Some(stream) => stream,
+            None => return Ok(()),
+        };
+    }
+}
+
+pub fn srv_thread(url: Url, tls: TlsSettings, tx: Sender<SrvMessage>, rx: Receiver<PongData>) {
+    match srv_session(&url, &tls, &tx, &rx) {
+        Ok(()) => println!("Game left multiplayer, closing connection"),
+        Err(err) => {
+            println!("Connection to {} failed: {}", url, err);
+            // Nobody listens when the game already left, nothing else to do with the error then
+            let _ = tx.send(SrvMessage::Error(err));
+        },
+    }
+}
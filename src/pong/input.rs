@@ -0,0 +1,394 @@
+// Action based input. Game screens ask for actions, the keys behind them come from
+// the user's keymap so every control can be rebound from the Options menu. Gamepads
+// drive menus from any pad and paddles from the pad assigned to the player.
+
+use std::collections::BTreeMap;
+use std::fmt;
+
+use raylib::prelude::*;
+use serde::de::{self, Deserializer, Visitor};
+use serde::{Deserialize, Serialize, Serializer};
+
+use super::world::{quantize_axis, PaddleInput, ScreenSide};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize)]
+pub enum Action {
+    Up,
+    Down,
+    Left,
+    Right,
+    Confirm,
+    Back,
+    Serve,
+}
+
+// Who reads a binding, each player has own paddle keys, everything else is shared
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Owner {
+    LeftPlayer,
+    RightPlayer,
+    Shared,
+}
+
+// Every binding shown on the controls page, in display order
+pub const BINDINGS: [(Owner, Action); 11] = [
+    (Owner::LeftPlayer, Action::Up),
+    (Owner::LeftPlayer, Action::Down),
+    (Owner::RightPlayer, Action::Up),
+    (Owner::RightPlayer, Action::Down),
+    (Owner::Shared, Action::Serve),
+    (Owner::Shared, Action::Up),
+    (Owner::Shared, Action::Down),
+    (Owner::Shared, Action::Left),
+    (Owner::Shared, Action::Right),
+    (Owner::Shared, Action::Confirm),
+    (Owner::Shared, Action::Back),
+];
+
+// Keyboard key stored in the settings file by its raylib name, e.g. "KEY_Q"
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Key(pub KeyboardKey);
+
+// Highest raylib key code, see KeyboardKey
+const MAX_KEY_CODE: i32 = 348;
+
+impl Key {
+    pub fn from_name(name: &str) -> Option<Key> {
+        (0..=MAX_KEY_CODE).filter_map(key_from_i32).find(|key| format!("{:?}", key) == name).map(Key)
+    }
+
+    pub fn name(&self) -> String {
+        format!("{:?}", self.0)
+    }
+
+    // Name for on screen texts, without the KEY_ prefix
+    pub fn label(&self) -> String {
+        self.name().trim_start_matches("KEY_").to_string()
+    }
+}
+
+impl Serialize for Key {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&self.name())
+    }
+}
+
+struct KeyVisitor;
+
+impl<'de> Visitor<'de> for KeyVisitor {
+    type Value = Key;
+
+    fn expecting(&self, formatter: &mut fmt::Formatter) -> fmt::Result {
+        formatter.write_str("raylib key name like \"KEY_Q\"")
+    }
+
+    fn visit_str<E: de::Error>(self, value: &str) -> Result<Key, E> {
+        Key::from_name(value).ok_or_else(|| E::custom(format!("unknown key {}", value)))
+    }
+}
+
+impl<'de> Deserialize<'de> for Key {
+    fn deserialize<D: Deserializer<'de>>(deserializer: D) -> Result<Key, D::Error> {
+        deserializer.deserialize_str(KeyVisitor)
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Keymap {
+    pub left: BTreeMap<Action, Key>,
+    pub right: BTreeMap<Action, Key>,
+    pub shared: BTreeMap<Action, Key>,
+}
+
+impl Default for Keymap {
+    fn default() -> Keymap {
+        Keymap {
+            left: BTreeMap::from([
+                (Action::Up, Key(KeyboardKey::KEY_Q)),
+                (Action::Down, Key(KeyboardKey::KEY_A)),
+            ]),
+            right: BTreeMap::from([
+                (Action::Up, Key(KeyboardKey::KEY_P)),
+                (Action::Down, Key(KeyboardKey::KEY_L)),
+            ]),
+            shared: BTreeMap::from([
+                (Action::Up, Key(KeyboardKey::KEY_UP)),
+                (Action::Down, Key(KeyboardKey::KEY_DOWN)),
+                (Action::Left, Key(KeyboardKey::KEY_LEFT)),
+                (Action::Right, Key(KeyboardKey::KEY_RIGHT)),
+                (Action::Confirm, Key(KeyboardKey::KEY_ENTER)),
+                (Action::Back, Key(KeyboardKey::KEY_BACKSPACE)),
+                (Action::Serve, Key(KeyboardKey::KEY_SPACE)),
+            ]),
+        }
+    }
+}
+
+// Raylib supports up to 4 gamepads
+pub const MAX_GAMEPADS: i32 = 4;
+
+// Fixed gamepad layout, D-pad navigates, bottom face button confirms and serves, right one goes back.
+fn gamepad_button(action: Action) -> GamepadButton {
+    match action {
+        Action::Up => GamepadButton::GAMEPAD_BUTTON_LEFT_FACE_UP,
+        Action::Down => GamepadButton::GAMEPAD_BUTTON_LEFT_FACE_DOWN,
+        Action::Left => GamepadButton::GAMEPAD_BUTTON_LEFT_FACE_LEFT,
+        Action::Right => GamepadButton::GAMEPAD_BUTTON_LEFT_FACE_RIGHT,
+        Action::Confirm => GamepadButton::GAMEPAD_BUTTON_RIGHT_FACE_DOWN,
+        Action::Back => GamepadButton::GAMEPAD_BUTTON_RIGHT_FACE_RIGHT,
+        Action::Serve => GamepadButton::GAMEPAD_BUTTON_RIGHT_FACE_DOWN,
+    }
+}
+
+fn any_gamepad_pressed(rl: &RaylibHandle, action: Action) -> bool {
+    (0..MAX_GAMEPADS).any(|pad| rl.is_gamepad_available(pad) && rl.is_gamepad_button_pressed(pad, gamepad_button(action)))
+}
+
+fn any_gamepad_down(rl: &RaylibHandle, action: Action) -> bool {
+    (0..MAX_GAMEPADS).any(|pad| rl.is_gamepad_available(pad) && rl.is_gamepad_button_down(pad, gamepad_button(action)))
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct GamepadSettings {
+    // Gamepad index of each player, -1 when the player uses only keyboard
+    pub left: i32,
+    pub right: i32,
+    // Stick movement (0.0 - 1.0) ignored around the center
+    pub dead_zone: f32,
+}
+
+impl Default for GamepadSettings {
+    fn default() -> GamepadSettings {
+        GamepadSettings {
+            left: 0,
+            right: 1,
+            dead_zone: 0.2,
+        }
+    }
+}
+
+impl GamepadSettings {
+    pub fn sanitized(&self) -> GamepadSettings {
+        GamepadSettings {
+            left: self.left.max(-1).min(MAX_GAMEPADS - 1),
+            right: self.right.max(-1).min(MAX_GAMEPADS - 1),
+            dead_zone: self.dead_zone.max(0.0).min(0.9),
+        }
+    }
+
+    pub fn pad(&self, side: ScreenSide) -> Option<i32> {
+        let pad = if side == ScreenSide::Left { self.left } else { self.right };
+        if pad < 0 {
+            None
+        } else {
+            Some(pad)
+        }
+    }
+
+    // Next choice for the options menu: no pad, then each pad index.
+    pub fn cycle(pad: i32, direction: i32) -> i32 {
+        (pad + 1 + direction).rem_euclid(MAX_GAMEPADS + 1) - 1
+    }
+
+    // Stick position of the player's pad with dead zone removed, rescaled so movement
+    // starts from zero at the dead zone edge.
+    fn axis(&self, rl: &RaylibHandle, pad: i32) -> f32 {
+        let value = rl.get_gamepad_axis_movement(pad, GamepadAxis::GAMEPAD_AXIS_LEFT_Y);
+        if value.abs() <= self.dead_zone {
+            return 0.0;
+        }
+        let scaled = (value.abs() - self.dead_zone) / (1.0 - self.dead_zone);
+        quantize_axis(scaled * value.signum())
+    }
+}
+
+// Remembers which pads were connected, so plugging and unplugging can be reported.
+#[derive(Debug, Default)]
+pub struct GamepadMonitor {
+    connected: [bool; MAX_GAMEPADS as usize],
+}
+
+impl GamepadMonitor {
+    // Pads which changed since last call, with their new state.
+    pub fn update(&mut self, rl: &RaylibHandle) -> Vec<(i32, bool)> {
+        let mut changes: Vec<(i32, bool)> = Vec::new();
+        for pad in 0..MAX_GAMEPADS {
+            let available = rl.is_gamepad_available(pad);
+            if available != self.connected[pad as usize] {
+                self.connected[pad as usize] = available;
+                changes.push((pad, available));
+            }
+        }
+        changes
+    }
+
+    pub fn is_connected(&self, pad: i32) -> bool {
+        (0..MAX_GAMEPADS).contains(&pad) && self.connected[pad as usize]
+    }
+}
+
+// Bindings used while the ball is in play, as opposed to menu navigation.
+fn is_game_binding(owner: Owner, action: Action) -> bool {
+    owner != Owner::Shared || action == Action::Serve
+}
+
+pub fn binding_name(owner: Owner, action: Action) -> String {
+    match owner {
+        Owner::LeftPlayer => format!("Left player {:?}", action),
+        Owner::RightPlayer => format!("Right player {:?}", action),
+        Owner::Shared if action == Action::Serve => "Serve".to_string(),
+        Owner::Shared => format!("Menu {:?}", action),
+    }
+}
+
+impl Keymap {
+    fn map(&self, owner: Owner) -> &BTreeMap<Action, Key> {
+        match owner {
+            Owner::LeftPlayer => &self.left,
+            Owner::RightPlayer => &self.right,
+            Owner::Shared => &self.shared,
+        }
+    }
+
+    fn map_mut(&mut self, owner: Owner) -> &mut BTreeMap<Action, Key> {
+        match owner {
+            Owner::LeftPlayer => &mut self.left,
+            Owner::RightPlayer => &mut self.right,
+            Owner::Shared => &mut self.shared,
+        }
+    }
+
+    // Bindings missing in a hand edited file fall back to defaults.
+    pub fn sanitized(&self) -> Keymap {
+        let defaults = Keymap::default();
+        let mut keymap = Keymap { left: BTreeMap::new(), right: BTreeMap::new(), shared: BTreeMap::new() };
+        for (owner, action) in BINDINGS {
+            let key = self.key(owner, action).or(defaults.key(owner, action)).unwrap();
+            keymap.map_mut(owner).insert(action, key);
+        }
+        keymap
+    }
+
+    pub fn key(&self, owner: Owner, action: Action) -> Option<Key> {
+        self.map(owner).get(&action).copied()
+    }
+
+    pub fn bind(&mut self, owner: Owner, action: Action, key: Key) {
+        self.map_mut(owner).insert(action, key);
+    }
+
+    // Bindings which already use `key` and are active at the same time as the given one.
+    pub fn conflicts(&self, owner: Owner, action: Action, key: Key) -> Vec<(Owner, Action)> {
+        BINDINGS.iter()
+            .filter(|(other_owner, other_action)| (*other_owner, *other_action) != (owner, action))
+            .filter(|(other_owner, other_action)| is_game_binding(*other_owner, *other_action) == is_game_binding(owner, action))
+            .filter(|(other_owner, other_action)| self.key(*other_owner, *other_action) == Some(key))
+            .copied()
+            .collect()
+    }
+
+    pub fn label(&self, owner: Owner, action: Action) -> String {
+        match self.key(owner, action) {
+            Some(key) => key.label(),
+            None => "-".to_string(),
+        }
+    }
+
+    // Shared bindings also react to buttons of any gamepad.
+    pub fn is_pressed(&self, rl: &RaylibHandle, owner: Owner, action: Action) -> bool {
+        let key = match self.key(owner, action) {
+            Some(key) => rl.is_key_pressed(key.0),
+            None => false,
+        };
+        key || (owner == Owner::Shared && any_gamepad_pressed(rl, action))
+    }
+
+    pub fn is_down(&self, rl: &RaylibHandle, owner: Owner, action: Action) -> bool {
+        let key = match self.key(owner, action) {
+            Some(key) => rl.is_key_down(key.0),
+            None => false,
+        };
+        key || (owner == Owner::Shared && any_gamepad_down(rl, action))
+    }
+
+    // Shortcut for screens, which are driven by the shared bindings.
+    pub fn pressed(&self, rl: &RaylibHandle, action: Action) -> bool {
+        self.is_pressed(rl, Owner::Shared, action)
+    }
+
+    // Keyboard and the player's gamepad combined, D-pad acts like keys, stick gives proportional speed.
+    pub fn paddle(&self, rl: &RaylibHandle, side: ScreenSide, gamepads: &GamepadSettings) -> PaddleInput {
+        let owner = if side == ScreenSide::Left { Owner::LeftPlayer } else { Owner::RightPlayer };
+        let mut input = PaddleInput {
+            up: self.is_down(rl, owner, Action::Up),
+            down: self.is_down(rl, owner, Action::Down),
+            axis: 0.0,
+        };
+        if let Some(pad) = gamepads.pad(side) {
+            if rl.is_gamepad_available(pad) {
+                input.up = input.up || rl.is_gamepad_button_down(pad, gamepad_button(Action::Up));
+                input.down = input.down || rl.is_gamepad_button_down(pad, gamepad_button(Action::Down));
+                input.axis = gamepads.axis(rl, pad);
+            }
+        }
+        input
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn conflicts_only_between_bindings_active_together() {
+        let mut keymap = Keymap::default();
+        for (owner, action) in BINDINGS {
+            let key = keymap.key(owner, action).unwrap();
+            assert!(keymap.conflicts(owner, action, key).is_empty(), "{}", binding_name(owner, action));
+        }
+        let right_up = keymap.key(Owner::RightPlayer, Action::Up).unwrap();
+        assert_eq!(keymap.conflicts(Owner::LeftPlayer, Action::Up, right_up), vec![(Owner::RightPlayer, Action::Up)]);
+        assert_eq!(keymap.conflicts(Owner::Shared, Action::Serve, right_up), vec![(Owner::RightPlayer, Action::Up)]);
+        // Menus are not used during a match, sharing keys with the paddles is fine
+        assert!(keymap.conflicts(Owner::Shared, Action::Up, right_up).is_empty());
+
+        keymap.bind(Owner::LeftPlayer, Action::Down, right_up);
+        let mut conflicts = keymap.conflicts(Owner::Shared, Action::Serve, right_up);
+        conflicts.sort();
+        assert_eq!(conflicts, vec![(Owner::LeftPlayer, Action::Down), (Owner::RightPlayer, Action::Up)]);
+    }
+
+    #[test]
+    fn sanitized_fills_in_missing_bindings() {
+        let mut keymap = Keymap { left: BTreeMap::new(), right: BTreeMap::new(), shared: BTreeMap::new() };
+        keymap.bind(Owner::RightPlayer, Action::Down, Key(KeyboardKey::KEY_SPACE));
+        let sanitized = keymap.sanitized();
+        let defaults = Keymap::default();
+        assert_eq!(sanitized.key(Owner::RightPlayer, Action::Down), Some(Key(KeyboardKey::KEY_SPACE)));
+        assert_eq!(sanitized.key(Owner::RightPlayer, Action::Up), defaults.key(Owner::RightPlayer, Action::Up));
+        assert_eq!(sanitized.shared, defaults.shared);
+        assert_eq!(defaults.sanitized(), defaults);
+    }
+
+    #[test]
+    fn keys_are_stored_by_name() {
+        let key = Key(KeyboardKey::KEY_SPACE);
+        assert_eq!(key.name(), "KEY_SPACE");
+        assert_eq!(key.label(), "SPACE");
+        assert_eq!(Key::from_name("KEY_SPACE"), Some(key));
+        assert_eq!(Key::from_name("SPACE"), None);
+
+        let mut keymap = Keymap::default();
+        keymap.bind(Owner::LeftPlayer, Action::Up, Key(KeyboardKey::KEY_W));
+        let content = toml::to_string(&keymap).unwrap();
+        assert!(content.contains("\"KEY_W\""), "{}", content);
+        assert_eq!(toml::from_str::<Keymap>(&content).unwrap(), keymap);
+
+        let unknown = content.replace("KEY_W", "KEY_NOPE");
+        assert!(toml::from_str::<Keymap>(&unknown).is_err());
+        let number = content.replace("\"KEY_W\"", "87");
+        assert!(toml::from_str::<Keymap>(&number).is_err());
+    }
+}
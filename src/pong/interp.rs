@@ -0,0 +1,198 @@
+// Smoothing of entities controlled by the other player. Snapshots arrive at uneven intervals,
+// so remote entities are drawn a little in the past, between two snapshots that already arrived.
+// When snapshots are late, movement continues along the last known direction for a short while.
+
+use std::collections::VecDeque;
+
+pub const DEFAULT_INTERP_DELAY_MS: u32 = 100;
+pub const MAX_INTERP_DELAY_MS: u32 = 500;
+// Longest time positions are extrapolated past the newest snapshot before they stop
+pub const MAX_EXTRAPOLATION_MS: f64 = 150.0;
+// Movement larger than this between two snapshots is a reset (serve, new point), not something to smooth
+pub const TELEPORT_DISTANCE: f32 = 200.0;
+// About a second of snapshots at the server push rate, more than the longest delay needs
+const MAX_SNAPSHOTS: usize = 64;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Snapshot {
+    // Sender clock, milliseconds
+    pub time_ms: f64,
+    pub x: f32,
+    pub y: f32,
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct SnapshotBuffer {
+    snapshots: VecDeque<Snapshot>,
+    // Smallest receive minus send time seen. Clocks are not synchronized, the fastest
+    // message is the best guess of their difference plus the network delay.
+    clock_offset: Option<f64>,
+}
+
+fn lerp(from: f32, to: f32, alpha: f32) -> f32 {
+    from + (to - from) * alpha
+}
+
+impl SnapshotBuffer {
+    pub fn clear(&mut self) {
+        self.snapshots.clear();
+        self.clock_offset = None;
+    }
+
+    // Add position sent at `sent_ms` by sender clock and received at `received_ms` by ours.
+    // Senders without timestamps send 0, receive time is used for them.
+    pub fn push(&mut self, sent_ms: u64, received_ms: u64, x: f32, y: f32) {
+        let sent_ms = if sent_ms == 0 { received_ms } else { sent_ms };
+        let offset = received_ms as f64 - sent_ms as f64;
+        let offset = match self.clock_offset {
+            Some(current) => current.min(offset),
+            None => offset,
+        };
+        self.clock_offset = Some(offset);
+
+        let snapshot = Snapshot { time_ms: sent_ms as f64, x, y };
+        if let Some(newest) = self.snapshots.back() {
+            if snapshot.time_ms < newest.time_ms {
+                return;
+            }
+            if (snapshot.x - newest.x).abs() > TELEPORT_DISTANCE || (snapshot.y - newest.y).abs() > TELEPORT_DISTANCE {
+                self.snapshots.clear();
+            }
+        }
+        self.snapshots.push_back(snapshot);
+        while self.snapshots.len() > MAX_SNAPSHOTS {
+            self.snapshots.pop_front();
+        }
+    }
+
+    // Position as it arrived last, without smoothing.
+    pub fn newest(&self) -> Option<Snapshot> {
+        self.snapshots.back().copied()
+    }
+
+    // Position `delay_ms` before `now_ms`, None until the first snapshot arrives.
+    pub fn sample(&self, now_ms: u64, delay_ms: u32) -> Option<(f32, f32)> {
+        let newest = *self.snapshots.back()?;
+        // Render time on the sender clock
+        let time = now_ms as f64 - self.clock_offset.unwrap_or(0.0) - delay_ms as f64;
+        let oldest = self.snapshots[0];
+        if time <= oldest.time_ms {
+            return Some((oldest.x, oldest.y));
+        }
+        if time >= newest.time_ms {
+            if self.snapshots.len() < 2 {
+                return Some((newest.x, newest.y));
+            }
+            let before = self.snapshots[self.snapshots.len() - 2];
+            let span = newest.time_ms - before.time_ms;
+            if span <= 0.0 {
+                return Some((newest.x, newest.y));
+            }
+            let ahead = (time - newest.time_ms).min(MAX_EXTRAPOLATION_MS);
+            let alpha = (ahead / span) as f32;
+            return Some((newest.x + (newest.x - before.x) * alpha, newest.y + (newest.y - before.y) * alpha));
+        }
+        for index in 1..self.snapshots.len() {
+            let to = self.snapshots[index];
+            if to.time_ms >= time {
+                let from = self.snapshots[index - 1];
+                let span = to.time_ms - from.time_ms;
+                let alpha = if span > 0.0 { ((time - from.time_ms) / span) as f32 } else { 1.0 };
+                return Some((lerp(from.x, to.x, alpha), lerp(from.y, to.y, alpha)));
+            }
+        }
+        Some((newest.x, newest.y))
+    }
+}
+
+// What the debug toggle shows for remote entities.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum NetView {
+    #[default]
+    Smoothed,
+    // Smoothed positions with outlines where the raw snapshots put them
+    Compare,
+    // Snapshots as they arrive, like without interpolation
+    Raw,
+}
+
+impl NetView {
+    pub fn next(&self) -> NetView {
+        match self {
+            NetView::Smoothed => NetView::Compare,
+            NetView::Compare => NetView::Raw,
+            NetView::Raw => NetView::Smoothed,
+        }
+    }
+
+    pub fn label(&self) -> &'static str {
+        match self {
+            NetView::Smoothed => "Smoothed",
+            NetView::Compare => "Smoothed + raw",
+            NetView::Raw => "Raw",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Two snapshots 100 ms apart, each took 50 ms to arrive.
+    fn moving_buffer() -> SnapshotBuffer {
+        let mut buffer = SnapshotBuffer::default();
+        buffer.push(1000, 1050, 0.0, 0.0);
+        buffer.push(1100, 1150, 100.0, 50.0);
+        buffer
+    }
+
+    #[test]
+    fn sample_interpolates_between_snapshots() {
+        let mut buffer = SnapshotBuffer::default();
+        assert_eq!(buffer.sample(1000, 0), None);
+        buffer = moving_buffer();
+        assert_eq!(buffer.sample(1200, 100), Some((50.0, 25.0)));
+        assert_eq!(buffer.sample(1175, 100), Some((25.0, 12.5)));
+        // Before the oldest snapshot the entity waits there
+        assert_eq!(buffer.sample(1000, 100), Some((0.0, 0.0)));
+
+        // Older snapshot arriving late is dropped, a slower one does not move the clock offset
+        buffer.push(1050, 1300, 90.0, 90.0);
+        buffer.push(1200, 1400, 200.0, 100.0);
+        assert_eq!(buffer.sample(1350, 150), Some((150.0, 75.0)));
+        assert_eq!(buffer.newest(), Some(Snapshot { time_ms: 1200.0, x: 200.0, y: 100.0 }));
+
+        buffer.clear();
+        assert_eq!(buffer.sample(1300, 150), None);
+    }
+
+    #[test]
+    fn extrapolation_stops_after_the_cap() {
+        let buffer = moving_buffer();
+        // 50 ms past the newest snapshot keeps moving the same way
+        assert_eq!(buffer.sample(1200, 0), Some((150.0, 75.0)));
+        // Long gone snapshots do not fly it off, it stops MAX_EXTRAPOLATION_MS ahead
+        let capped = Some((100.0 + MAX_EXTRAPOLATION_MS as f32, 50.0 + MAX_EXTRAPOLATION_MS as f32 / 2.0));
+        assert_eq!(buffer.sample(1150 + MAX_EXTRAPOLATION_MS as u64, 0), capped);
+        assert_eq!(buffer.sample(5000, 0), capped);
+
+        // Single snapshot has no direction to follow
+        let mut buffer = SnapshotBuffer::default();
+        buffer.push(1000, 1050, 10.0, 20.0);
+        assert_eq!(buffer.sample(2000, 0), Some((10.0, 20.0)));
+    }
+
+    #[test]
+    fn teleport_starts_over() {
+        let mut buffer = moving_buffer();
+        buffer.push(1200, 1250, 100.0 + TELEPORT_DISTANCE + 1.0, 50.0);
+        // Nothing to smooth from, the jump is shown as it is
+        let jumped = Some((100.0 + TELEPORT_DISTANCE + 1.0, 50.0));
+        assert_eq!(buffer.sample(1200, 100), jumped);
+        assert_eq!(buffer.sample(1400, 0), jumped);
+
+        // Smaller moves are smoothed again
+        buffer.push(1300, 1350, 300.0, 50.0);
+        assert_eq!(buffer.sample(1400, 100), Some((300.5, 50.0)));
+    }
+}
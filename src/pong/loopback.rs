@@ -0,0 +1,103 @@
+// Rollback match without a network. The opponent runs in the same process with its own world and
+// session, messages between the two are held back to simulate latency and jitter.
+
+use super::ai::{AiController, Difficulty};
+use super::clock::MAX_STEPS_PER_FRAME;
+use super::rng::GameRng;
+use super::rollback::{InputMessage, PlayerInput, RollbackSession};
+use super::world::{PongWorld, ScreenSide, TICK_RATE};
+
+struct InFlight {
+    deliver_ms: f64,
+    to_local: bool,
+    message: InputMessage,
+}
+
+pub struct Loopback {
+    // Latency one way, jitter is added on top of it at random
+    pub latency_ms: u32,
+    pub jitter_ms: u32,
+    // Opponent side of the match, played by the computer
+    pub world: PongWorld,
+    pub session: RollbackSession,
+    ai: AiController,
+    rng: GameRng,
+    now_ms: f64,
+    // Time not yet simulated by the opponent
+    pending_ms: f64,
+    // Time of the last delivery in each direction, messages do not overtake each other like on TCP
+    last_to_local_ms: f64,
+    last_to_remote_ms: f64,
+    in_flight: Vec<InFlight>,
+}
+
+impl Loopback {
+    // Opponent on the right starting from `world`, the state the local session starts from too.
+    pub fn new(world: &PongWorld, input_delay: u32, latency_ms: u32, jitter_ms: u32, seed: u64) -> Loopback {
+        Loopback {
+            latency_ms,
+            jitter_ms,
+            world: world.clone(),
+            session: RollbackSession::new(ScreenSide::Right, input_delay, world),
+            ai: AiController::new(ScreenSide::Right, Difficulty::default(), seed),
+            rng: GameRng::new(seed.rotate_left(17)),
+            now_ms: 0.0,
+            pending_ms: 0.0,
+            last_to_local_ms: 0.0,
+            last_to_remote_ms: 0.0,
+            in_flight: Vec::new(),
+        }
+    }
+
+    fn delay(&mut self) -> f64 {
+        self.latency_ms as f64 + self.rng.range(0, self.jitter_ms as i32) as f64
+    }
+
+    // Message from the local session toward the opponent.
+    pub fn send(&mut self, message: InputMessage) {
+        let deliver_ms = (self.now_ms + self.delay()).max(self.last_to_remote_ms);
+        self.last_to_remote_ms = deliver_ms;
+        self.in_flight.push(InFlight { deliver_ms, to_local: false, message });
+    }
+
+    // Let `elapsed_ms` pass: the opponent plays its ticks and messages which arrived are returned.
+    pub fn update(&mut self, elapsed_ms: f64) -> Vec<InputMessage> {
+        let tick_ms = 1000.0 / TICK_RATE as f64;
+        self.now_ms = self.now_ms + elapsed_ms;
+        self.pending_ms = (self.pending_ms + elapsed_ms).min(tick_ms * MAX_STEPS_PER_FRAME as f64);
+        let mut arrived = Vec::new();
+        let mut index = 0;
+        while index < self.in_flight.len() {
+            if self.in_flight[index].deliver_ms > self.now_ms {
+                index = index + 1;
+                continue;
+            }
+            let in_flight = self.in_flight.remove(index);
+            if in_flight.to_local {
+                arrived.push(in_flight.message);
+            } else {
+                self.session.receive(&in_flight.message);
+            }
+        }
+
+        self.session.update(&mut self.world);
+        while self.pending_ms >= tick_ms {
+            self.pending_ms = self.pending_ms - tick_ms;
+            if self.session.should_wait(&self.world) {
+                self.session.stats.waits = self.session.stats.waits + 1;
+                continue;
+            }
+            let input = PlayerInput { paddle: self.ai.input(&self.world), serve: true };
+            self.session.add_local_input(&self.world, input);
+            if self.session.advance(&mut self.world).is_none() {
+                self.session.stats.stalls = self.session.stats.stalls + 1;
+                break;
+            }
+        }
+        let message = self.session.message(&self.world);
+        let deliver_ms = (self.now_ms + self.delay()).max(self.last_to_local_ms);
+        self.last_to_local_ms = deliver_ms;
+        self.in_flight.push(InFlight { deliver_ms, to_local: true, message });
+        arrived
+    }
+}
@@ -0,0 +1,435 @@
+// Errors of the connection to the game server. Server thread sends them to the game loop,
+// which shows them on the connection error screen.
+
+use std::fmt;
+use std::io::{self, Read};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use websocket::dataframe::{DataFrame, Opcode};
+use websocket::sender::{Sender, Writer};
+use websocket::stream::sync::NetworkStream;
+use websocket::sync::Client;
+use websocket::{OwnedMessage, WebSocketError};
+
+// Longest wait for the server to accept the connection
+pub const CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+// Longest wait for the server to answer a request
+pub const IO_TIMEOUT: Duration = Duration::from_secs(5);
+
+// Longest wait for incoming data in one FrameStream::poll, also paces the server thread
+pub const POLL_INTERVAL: Duration = Duration::from_millis(5);
+// Server pushes many snapshots per second, this long without any means the connection is gone
+pub const STREAM_TIMEOUT: Duration = Duration::from_secs(2);
+// Nothing the server sends comes close, larger frame is garbage
+const MAX_FRAME_LEN: usize = 1 << 20;
+
+// Reconnect attempts after a dropped connection, about 20 s of waiting, server keeps the seat for 30 s
+pub const MAX_RECONNECT_ATTEMPTS: u32 = 8;
+
+// Wait before reconnect `attempt` (from 0): 250 ms doubled each time, at most 4 s.
+pub fn reconnect_delay(attempt: u32) -> Duration {
+    Duration::from_millis(250 * (1u64 << attempt.min(4)))
+}
+
+// Wall clock for message timestamps, milliseconds since Unix epoch.
+pub fn now_ms() -> u64 {
+    match SystemTime::now().duration_since(UNIX_EPOCH) {
+        Ok(elapsed) => elapsed.as_millis() as u64,
+        Err(_) => 0,
+    }
+}
+
+// Ordering and freshness of the messages from one sender.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MessageStats {
+    // Highest sequence number accepted
+    pub last_seq: u64,
+    pub received: u64,
+    // Out of order or repeated messages that were thrown away
+    pub dropped: u64,
+    // Time from sending to receiving the last accepted message. Clocks of the two machines
+    // are not synchronized, so only changes of it are meaningful, not the value itself.
+    pub age_ms: i64,
+}
+
+impl MessageStats {
+    // Whether message `seq` is newer than everything accepted before. Senders which do not count send 0, always accepted.
+    pub fn accept(&mut self, seq: u64, sent_ms: u64) -> bool {
+        self.received = self.received + 1;
+        if seq != 0 && seq <= self.last_seq {
+            self.dropped = self.dropped + 1;
+            return false;
+        }
+        if seq != 0 {
+            self.last_seq = seq;
+        }
+        if sent_ms != 0 {
+            self.age_ms = now_ms() as i64 - sent_ms as i64;
+        }
+        true
+    }
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum NetError {
+    // Address could not be resolved or used
+    InvalidAddress(String),
+    // Nobody accepted the connection
+    Connect(String),
+    // Server did not answer in time
+    Timeout,
+    // Certificate or TLS handshake problem
+    Tls(String),
+    // Websocket upgrade refused
+    Handshake(String),
+    // Server closed the connection
+    Closed,
+    // Server no longer keeps our seat after a reconnect
+    SessionExpired,
+    // Opponent dropped and did not come back
+    OpponentLeft,
+    // Malformed or unexpected message
+    Protocol(String),
+    // Any other I/O failure
+    Io(String),
+}
+
+impl fmt::Display for NetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NetError::InvalidAddress(msg) => write!(f, "Invalid server address: {}", msg),
+            NetError::Connect(msg) => write!(f, "Could not connect to server: {}", msg),
+            NetError::Timeout => write!(f, "Server did not answer in time"),
+            NetError::Tls(msg) => write!(f, "Secure connection failed: {}", msg),
+            NetError::Handshake(msg) => write!(f, "Server refused the connection: {}", msg),
+            NetError::Closed => write!(f, "Server closed the connection"),
+            NetError::SessionExpired => write!(f, "Reconnected too late, the match is gone"),
+            NetError::OpponentLeft => write!(f, "Opponent lost connection and did not come back"),
+            NetError::Protocol(msg) => write!(f, "Unexpected data from server: {}", msg),
+            NetError::Io(msg) => write!(f, "Connection failed: {}", msg),
+        }
+    }
+}
+
+impl From<io::Error> for NetError {
+    fn from(err: io::Error) -> NetError {
+        match err.kind() {
+            io::ErrorKind::WouldBlock | io::ErrorKind::TimedOut => NetError::Timeout,
+            io::ErrorKind::ConnectionReset | io::ErrorKind::ConnectionAborted |
+            io::ErrorKind::BrokenPipe | io::ErrorKind::UnexpectedEof => NetError::Closed,
+            _ => NetError::Io(err.to_string()),
+        }
+    }
+}
+
+impl From<WebSocketError> for NetError {
+    fn from(err: WebSocketError) -> NetError {
+        match err {
+            WebSocketError::IoError(err) => NetError::from(err),
+            WebSocketError::NoDataAvailable => NetError::Closed,
+            WebSocketError::ProtocolError(msg) | WebSocketError::DataFrameError(msg) => NetError::Protocol(msg.to_string()),
+            err => NetError::Protocol(err.to_string()),
+        }
+    }
+}
+
+// Size of the complete frame at the start of `data`, None until all of it arrived.
+fn frame_len(data: &[u8]) -> Result<Option<usize>, NetError> {
+    if data.len() < 2 {
+        return Ok(None);
+    }
+    let (header_len, payload_len) = match data[1] & 0x7F {
+        126 if data.len() < 4 => return Ok(None),
+        126 => (4, u16::from_be_bytes([data[2], data[3]]) as usize),
+        127 if data.len() < 10 => return Ok(None),
+        127 => {
+            let mut len = [0u8; 8];
+            len.copy_from_slice(&data[2..10]);
+            (10, u64::from_be_bytes(len).min(MAX_FRAME_LEN as u64 + 1) as usize)
+        },
+        len => (2, len as usize),
+    };
+    if payload_len > MAX_FRAME_LEN {
+        return Err(NetError::Protocol(format!("frame of {} bytes", payload_len)));
+    }
+    let mask_len = if data[1] & 0x80 != 0 { 4 } else { 0 };
+    let frame_len = header_len + mask_len + payload_len;
+    if data.len() < frame_len {
+        return Ok(None);
+    }
+    Ok(Some(frame_len))
+}
+
+// Websocket after the handshake. Reads never block for longer than POLL_INTERVAL and keep
+// partial frames buffered, so sending does not have to wait for the server to say something.
+pub struct FrameStream {
+    writer: Writer<Box<dyn NetworkStream + Send>>,
+    input: Vec<u8>,
+    last_received: Instant,
+}
+
+impl FrameStream {
+    pub fn new(client: Client<Box<dyn NetworkStream + Send>>) -> Result<FrameStream, NetError> {
+        let (stream, buffered) = client.into_stream();
+        stream.as_tcp().set_read_timeout(Some(POLL_INTERVAL))?;
+        let mut input = Vec::new();
+        // Bytes the handshake reader already took from the socket
+        if let Some((buf, pos, cap)) = buffered {
+            input.extend_from_slice(&buf[pos..cap]);
+        }
+        Ok(FrameStream {
+            writer: Writer { stream, sender: Sender::new(true) },
+            input,
+            last_received: Instant::now(),
+        })
+    }
+
+    pub fn send(&mut self, payload: Vec<u8>) -> Result<(), NetError> {
+        self.writer.send_message(&OwnedMessage::Binary(payload))?;
+        Ok(())
+    }
+
+    // Payloads of the messages that arrived since last poll, oldest first.
+    pub fn poll(&mut self) -> Result<Vec<Vec<u8>>, NetError> {
+        let mut chunk = [0u8; 4096];
+        loop {
+            match self.writer.stream.read(&mut chunk) {
+                Ok(0) => return Err(NetError::Closed),
+                Ok(read) => {
+                    self.input.extend_from_slice(&chunk[..read]);
+                    // Buffer was not filled, nothing more is waiting
+                    if read < chunk.len() {
+                        break;
+                    }
+                },
+                Err(err) if err.kind() == io::ErrorKind::WouldBlock || err.kind() == io::ErrorKind::TimedOut => break,
+                Err(err) => return Err(NetError::from(err)),
+            }
+        }
+
+        let mut payloads = Vec::new();
+        while let Some(len) = frame_len(&self.input)? {
+            let frame = DataFrame::read_dataframe(&mut &self.input[..len], false)?;
+            self.input.drain(..len);
+            self.last_received = Instant::now();
+            if !frame.finished {
+                return Err(NetError::Protocol("fragmented message".to_string()));
+            }
+            match frame.opcode {
+                Opcode::Text | Opcode::Binary => payloads.push(frame.data),
+                Opcode::Ping => self.writer.send_message(&OwnedMessage::Pong(frame.data))?,
+                Opcode::Close => return Err(NetError::Closed),
+                _ => {},
+            }
+        }
+        if self.last_received.elapsed() > STREAM_TIMEOUT {
+            return Err(NetError::Timeout);
+        }
+        Ok(payloads)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write;
+    use std::net::TcpStream;
+    use std::thread;
+
+    // Stream of a connected client and the raw socket of the server end.
+    fn stream_pair() -> (FrameStream, TcpStream) {
+        let mut server = websocket::sync::Server::bind("127.0.0.1:0").unwrap();
+        let port = server.local_addr().unwrap().port();
+        let accepting = thread::spawn(move || server.accept().ok().unwrap().accept().unwrap().into_stream().0);
+        let client = websocket::ClientBuilder::new(&format!("ws://127.0.0.1:{}", port)).unwrap().connect(None).unwrap();
+        (FrameStream::new(client).unwrap(), accepting.join().unwrap())
+    }
+
+    // Unmasked frame like the server sends it.
+    fn frame(opcode: u8, payload: &[u8]) -> Vec<u8> {
+        let mut data = vec![0x80 | opcode, payload.len() as u8];
+        data.extend_from_slice(payload);
+        data
+    }
+
+    // Keep polling until `count` payloads arrived, fails after a while.
+    fn poll_for(stream: &mut FrameStream, count: usize) -> Vec<Vec<u8>> {
+        let start = Instant::now();
+        let mut payloads = Vec::new();
+        while payloads.len() < count {
+            assert!(start.elapsed() < Duration::from_secs(2), "Got {} of {} payloads", payloads.len(), count);
+            payloads.extend(stream.poll().unwrap());
+        }
+        payloads
+    }
+
+    // Keep polling until it fails, fails itself after a while.
+    fn poll_error(stream: &mut FrameStream) -> NetError {
+        let start = Instant::now();
+        loop {
+            assert!(start.elapsed() < Duration::from_secs(2), "Poll did not fail");
+            if let Err(err) = stream.poll() {
+                return err;
+            }
+        }
+    }
+
+    #[test]
+    fn frame_len_waits_for_whole_frames() {
+        assert_eq!(frame_len(&[]), Ok(None));
+        assert_eq!(frame_len(&[0x82]), Ok(None));
+        assert_eq!(frame_len(&[0x82, 3, 1, 2]), Ok(None));
+        assert_eq!(frame_len(&[0x82, 3, 1, 2, 3, 0x82]), Ok(Some(5)));
+        // Mask key comes before the payload
+        assert_eq!(frame_len(&[0x82, 0x83, 9, 9, 9, 9, 1, 2]), Ok(None));
+        assert_eq!(frame_len(&[0x82, 0x83, 9, 9, 9, 9, 1, 2, 3]), Ok(Some(9)));
+    }
+
+    #[test]
+    fn frame_len_reads_extended_lengths() {
+        let mut data = vec![0x82, 126, 0x01];
+        assert_eq!(frame_len(&data), Ok(None));
+        data.push(0x00);
+        data.resize(4 + 255, 0);
+        assert_eq!(frame_len(&data), Ok(None));
+        data.push(0);
+        assert_eq!(frame_len(&data), Ok(Some(4 + 256)));
+
+        let mut data = vec![0x82, 127, 0, 0, 0, 0, 0, 1, 0];
+        assert_eq!(frame_len(&data), Ok(None));
+        data.push(0);
+        data.resize(10 + 65536, 0);
+        assert_eq!(frame_len(&data), Ok(Some(10 + 65536)));
+
+        // Lengths no server message comes close to are garbage, even before the payload arrives
+        let too_long = (MAX_FRAME_LEN as u64 + 1).to_be_bytes();
+        let mut data = vec![0x82, 127];
+        data.extend_from_slice(&too_long);
+        assert!(matches!(frame_len(&data), Err(NetError::Protocol(_))));
+        let mut data = vec![0x82, 127];
+        data.extend_from_slice(&u64::MAX.to_be_bytes());
+        assert!(matches!(frame_len(&data), Err(NetError::Protocol(_))));
+    }
+
+    #[test]
+    fn poll_puts_split_frames_together() {
+        let (mut stream, mut server) = stream_pair();
+        let first = frame(0x2, b"first");
+        server.write_all(&first[..3]).unwrap();
+        assert!(stream.poll().unwrap().is_empty());
+        server.write_all(&first[3..]).unwrap();
+        let mut rest = frame(0x2, b"second");
+        rest.extend(frame(0x1, b"third"));
+        server.write_all(&rest).unwrap();
+        assert_eq!(poll_for(&mut stream, 3), vec![b"first".to_vec(), b"second".to_vec(), b"third".to_vec()]);
+    }
+
+    #[test]
+    fn poll_answers_pings() {
+        let (mut stream, mut server) = stream_pair();
+        server.write_all(&frame(0x9, b"ping")).unwrap();
+        server.write_all(&frame(0x2, b"data")).unwrap();
+        assert_eq!(poll_for(&mut stream, 1), vec![b"data".to_vec()]);
+        // Client frames are masked
+        server.set_read_timeout(Some(Duration::from_secs(2))).unwrap();
+        let pong = DataFrame::read_dataframe(&mut server, true).unwrap();
+        assert_eq!((pong.opcode, pong.data), (Opcode::Pong, b"ping".to_vec()));
+    }
+
+    #[test]
+    fn malformed_frames_are_errors() {
+        // Server frames are never masked
+        let (mut stream, mut server) = stream_pair();
+        server.write_all(&[0x82, 0x81, 1, 2, 3, 4, 5]).unwrap();
+        assert!(matches!(poll_error(&mut stream), NetError::Protocol(_)));
+
+        // Extended length used for a short frame
+        let (mut stream, mut server) = stream_pair();
+        server.write_all(&[0x82, 126, 0, 1, 7]).unwrap();
+        assert!(matches!(poll_error(&mut stream), NetError::Protocol(_)));
+
+        let (mut stream, mut server) = stream_pair();
+        let mut fragment = frame(0x2, b"part");
+        // Without the FIN bit
+        fragment[0] = 0x2;
+        server.write_all(&fragment).unwrap();
+        assert_eq!(poll_error(&mut stream), NetError::Protocol("fragmented message".to_string()));
+
+        let (mut stream, mut server) = stream_pair();
+        server.write_all(&[0x82, 127, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF, 0xFF]).unwrap();
+        assert!(matches!(poll_error(&mut stream), NetError::Protocol(_)));
+    }
+
+    #[test]
+    fn poll_notices_closed_and_silent_connections() {
+        let (mut stream, mut server) = stream_pair();
+        server.write_all(&frame(0x8, &[])).unwrap();
+        assert_eq!(poll_error(&mut stream), NetError::Closed);
+
+        let (mut stream, server) = stream_pair();
+        drop(server);
+        assert_eq!(poll_error(&mut stream), NetError::Closed);
+
+        let (mut stream, mut server) = stream_pair();
+        stream.last_received = Instant::now() - STREAM_TIMEOUT;
+        // Anything from the server keeps the connection alive
+        server.write_all(&frame(0xA, b"")).unwrap();
+        let start = Instant::now();
+        while stream.last_received < start {
+            stream.poll().unwrap();
+        }
+        stream.last_received = Instant::now() - STREAM_TIMEOUT - Duration::from_millis(1);
+        assert_eq!(stream.poll(), Err(NetError::Timeout));
+    }
+
+    #[test]
+    fn errors_are_sorted_by_what_went_wrong() {
+        assert_eq!(NetError::from(io::Error::from(io::ErrorKind::TimedOut)), NetError::Timeout);
+        assert_eq!(NetError::from(io::Error::from(io::ErrorKind::WouldBlock)), NetError::Timeout);
+        assert_eq!(NetError::from(io::Error::from(io::ErrorKind::ConnectionReset)), NetError::Closed);
+        assert!(matches!(NetError::from(io::Error::from(io::ErrorKind::PermissionDenied)), NetError::Io(_)));
+        assert_eq!(NetError::from(WebSocketError::NoDataAvailable), NetError::Closed);
+        assert_eq!(NetError::from(WebSocketError::ProtocolError("bad frame")), NetError::Protocol("bad frame".to_string()));
+    }
+
+    #[test]
+    fn reconnect_delay_backs_off_up_to_the_cap() {
+        let delays: Vec<u64> = (0..MAX_RECONNECT_ATTEMPTS).map(|attempt| reconnect_delay(attempt).as_millis() as u64).collect();
+        assert_eq!(delays, vec![250, 500, 1000, 2000, 4000, 4000, 4000, 4000]);
+        assert_eq!(reconnect_delay(u32::MAX), Duration::from_secs(4));
+        // Attempts are over well before the server gives the seat away
+        let total: u64 = delays.iter().sum();
+        assert!(total < 30_000, "{}", total);
+    }
+
+    #[test]
+    fn accept_drops_repeated_and_reordered_messages() {
+        let mut stats = MessageStats::default();
+        // Senders which do not count are never dropped
+        assert!(stats.accept(0, 0));
+        assert!(stats.accept(0, 0));
+        assert_eq!((stats.last_seq, stats.received, stats.dropped), (0, 2, 0));
+
+        assert!(stats.accept(5, 0));
+        assert!(!stats.accept(5, 0));
+        assert!(!stats.accept(3, 0));
+        assert!(stats.accept(0, 0));
+        assert!(stats.accept(6, 0));
+        assert_eq!((stats.last_seq, stats.received, stats.dropped), (6, 7, 2));
+    }
+
+    #[test]
+    fn accept_tracks_age_of_accepted_messages() {
+        let mut stats = MessageStats::default();
+        assert!(stats.accept(1, now_ms() - 100));
+        assert!((100..1000).contains(&stats.age_ms), "{}", stats.age_ms);
+        let age = stats.age_ms;
+        // Dropped and untimed messages leave it alone
+        assert!(!stats.accept(1, now_ms() - 5000));
+        assert!(stats.accept(2, 0));
+        assert_eq!(stats.age_ms, age);
+        // Sender clock ahead of ours
+        assert!(stats.accept(3, now_ms() + 5000));
+        assert!(stats.age_ms < 0);
+    }
+}
@@ -0,0 +1,138 @@
+// Continuous collision helpers. Everything works on axis aligned boxes, the moving
+// box is swept along its movement for the tick so fast objects cannot skip over thin ones.
+
+use super::world::Rect;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Hit {
+    // Fraction (0.0 - 1.0) of the movement travelled before contact
+    pub time: f32,
+    // Surface normal of the face that was hit
+    pub normal_x: f32,
+    pub normal_y: f32,
+}
+
+fn axis_times(moving_min: f32, moving_max: f32, target_min: f32, target_max: f32, delta: f32) -> Option<(f32, f32)> {
+    if delta == 0.0 {
+        // Not moving along this axis, boxes must already overlap on it
+        if moving_min < target_max && moving_max > target_min {
+            return Some((f32::NEG_INFINITY, f32::INFINITY));
+        }
+        return None;
+    }
+    if delta > 0.0 {
+        Some(((target_min - moving_max) / delta, (target_max - moving_min) / delta))
+    } else {
+        Some(((target_max - moving_min) / delta, (target_min - moving_max) / delta))
+    }
+}
+
+// Sweep `moving` by (dx, dy) against static `target`. Returns the first contact within the movement,
+// boxes which already overlap or move apart report no hit.
+pub fn sweep_aabb(moving: &Rect, dx: f32, dy: f32, target: &Rect) -> Option<Hit> {
+    let (entry_x, exit_x) = axis_times(moving.x, moving.x + moving.width, target.x, target.x + target.width, dx)?;
+    let (entry_y, exit_y) = axis_times(moving.y, moving.y + moving.height, target.y, target.y + target.height, dy)?;
+
+    let entry = entry_x.max(entry_y);
+    let exit = exit_x.min(exit_y);
+    // Equal times mean the boxes only touch, e.g. sliding off a corner they have just hit
+    if entry >= exit || !(0.0..=1.0).contains(&entry) {
+        return None;
+    }
+
+    if entry_x > entry_y {
+        Some(Hit { time: entry, normal_x: -dx.signum(), normal_y: 0.0 })
+    } else {
+        Some(Hit { time: entry, normal_x: 0.0, normal_y: -dy.signum() })
+    }
+}
+
+// Time of impact of a box moving by `delta` along one axis with a wall at `wall`.
+pub fn sweep_wall(moving_min: f32, moving_max: f32, delta: f32, wall: f32) -> Option<f32> {
+    let time = if delta > 0.0 && moving_max <= wall {
+        (wall - moving_max) / delta
+    } else if delta < 0.0 && moving_min >= wall {
+        (wall - moving_min) / delta
+    } else {
+        return None;
+    };
+    if time <= 1.0 {
+        Some(time)
+    } else {
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rect(x: f32, y: f32, width: f32, height: f32) -> Rect {
+        Rect { x, y, width, height }
+    }
+
+    #[test]
+    fn sweep_aabb_hits_face_in_the_way() {
+        let target = rect(100.0, 0.0, 40.0, 200.0);
+        let hit = sweep_aabb(&rect(40.0, 80.0, 40.0, 40.0), 40.0, 0.0, &target).unwrap();
+        assert_eq!(hit, Hit { time: 0.5, normal_x: -1.0, normal_y: 0.0 });
+
+        let hit = sweep_aabb(&rect(160.0, 80.0, 40.0, 40.0), -40.0, 0.0, &target).unwrap();
+        assert_eq!(hit, Hit { time: 0.5, normal_x: 1.0, normal_y: 0.0 });
+
+        let hit = sweep_aabb(&rect(100.0, 240.0, 40.0, 40.0), 0.0, -80.0, &target).unwrap();
+        assert_eq!(hit, Hit { time: 0.5, normal_x: 0.0, normal_y: 1.0 });
+    }
+
+    #[test]
+    fn sweep_aabb_does_not_tunnel() {
+        // Movement many times longer than the target is wide
+        let target = rect(100.0, 0.0, 10.0, 200.0);
+        let hit = sweep_aabb(&rect(0.0, 80.0, 40.0, 40.0), 5000.0, 0.0, &target).unwrap();
+        assert_eq!(hit.normal_x, -1.0);
+        assert!((hit.time - 60.0 / 5000.0).abs() < 1e-6);
+    }
+
+    #[test]
+    fn sweep_aabb_misses() {
+        let target = rect(100.0, 0.0, 40.0, 200.0);
+        // Too short
+        assert_eq!(sweep_aabb(&rect(0.0, 80.0, 40.0, 40.0), 50.0, 0.0, &target), None);
+        // Passes below
+        assert_eq!(sweep_aabb(&rect(0.0, 200.0, 40.0, 40.0), 200.0, 0.0, &target), None);
+        // Moving away
+        assert_eq!(sweep_aabb(&rect(160.0, 80.0, 40.0, 40.0), 100.0, 0.0, &target), None);
+        // Touching after a corner hit, moving apart along y
+        assert_eq!(sweep_aabb(&rect(140.0, 160.0, 40.0, 40.0), -20.0, 20.0, &rect(100.0, 0.0, 40.0, 160.0)), None);
+        // Already overlapping
+        assert_eq!(sweep_aabb(&rect(110.0, 80.0, 40.0, 40.0), -100.0, 0.0, &target), None);
+    }
+
+    #[test]
+    fn sweep_aabb_corner_and_edge() {
+        let target = rect(100.0, 100.0, 40.0, 200.0);
+        // Both faces reached at the same time, the corner counts as the top face
+        let hit = sweep_aabb(&rect(40.0, 40.0, 40.0, 40.0), 40.0, 40.0, &target).unwrap();
+        assert_eq!(hit, Hit { time: 0.5, normal_x: 0.0, normal_y: -1.0 });
+
+        // Clipping the very edge of the front face is still a front hit
+        let hit = sweep_aabb(&rect(40.0, 61.0, 40.0, 40.0), 40.0, 0.0, &target).unwrap();
+        assert_eq!(hit, Hit { time: 0.5, normal_x: -1.0, normal_y: 0.0 });
+
+        // Sliding along the edge without overlap touches nothing
+        assert_eq!(sweep_aabb(&rect(40.0, 60.0, 40.0, 40.0), 40.0, 0.0, &target), None);
+    }
+
+    #[test]
+    fn sweep_wall_times() {
+        assert_eq!(sweep_wall(10.0, 50.0, -20.0, 0.0), Some(0.5));
+        assert_eq!(sweep_wall(600.0, 640.0, 160.0, 720.0), Some(0.5));
+        // Touching already
+        assert_eq!(sweep_wall(0.0, 40.0, -20.0, 0.0), Some(0.0));
+        // Far movement is caught, not skipped
+        assert_eq!(sweep_wall(10.0, 50.0, -1000.0, 0.0), Some(0.01));
+        assert_eq!(sweep_wall(10.0, 50.0, -5.0, 0.0), None);
+        assert_eq!(sweep_wall(10.0, 50.0, 20.0, 0.0), None);
+        assert_eq!(sweep_wall(10.0, 50.0, 0.0, 0.0), None);
+    }
+}
@@ -0,0 +1,347 @@
+// Matches simulated by the server. Players send their inputs and run the match ahead on their own,
+// guessing the opponent keeps doing what it did last. Every state from the server replaces the
+// guess, inputs the server has not simulated yet are then applied on top of it again.
+
+use std::collections::VecDeque;
+
+use super::protos::pong::{CmdWorld, Rules};
+use super::rng::GameRng;
+use super::rollback::{decode_input, encode_input, InputMessage, PlayerInput};
+use super::world::{MatchPhase, MatchRules, PongWorld, ScreenSide, WorldEvent, WorldInputs};
+
+// About a second ahead of the server, further means the connection is stuck
+pub const MAX_PENDING_INPUTS: usize = 120;
+// Own paddle or ball moved by more than this when the server state arrived, in pixels
+const CORRECTION_DISTANCE: f32 = 2.0;
+
+fn encode_phase(phase: MatchPhase) -> (u32, u32) {
+    match phase {
+        MatchPhase::Playing => (0, 0),
+        MatchPhase::Celebrating { ticks_left } => (1, ticks_left),
+        MatchPhase::WaitingServe => (2, 0),
+        MatchPhase::Countdown { ticks_left } => (3, ticks_left),
+        MatchPhase::Finished => (4, 0),
+    }
+}
+
+fn decode_phase(phase: u32, ticks_left: u32) -> MatchPhase {
+    match phase {
+        1 => MatchPhase::Celebrating { ticks_left },
+        2 => MatchPhase::WaitingServe,
+        3 => MatchPhase::Countdown { ticks_left },
+        4 => MatchPhase::Finished,
+        _ => MatchPhase::Playing,
+    }
+}
+
+fn encode_side(side: Option<ScreenSide>) -> u32 {
+    match side {
+        None => 0,
+        Some(ScreenSide::Left) => 1,
+        Some(ScreenSide::Right) => 2,
+    }
+}
+
+fn decode_side(side: u32) -> Option<ScreenSide> {
+    match side {
+        1 => Some(ScreenSide::Left),
+        2 => Some(ScreenSide::Right),
+        _ => None,
+    }
+}
+
+pub fn encode_rules(rules: &MatchRules) -> Rules {
+    let mut proto = Rules::default();
+    proto.points_to_win = rules.points_to_win;
+    proto.win_by_two = rules.win_by_two;
+    proto.best_of = rules.best_of;
+    proto.time_limit_secs = rules.time_limit_secs;
+    proto
+}
+
+// Rules of a session, the defaults when the server sent none.
+pub fn decode_rules(rules: Option<&Rules>) -> MatchRules {
+    let rules = match rules {
+        Some(rules) => rules,
+        None => return MatchRules::default(),
+    };
+    MatchRules {
+        points_to_win: rules.points_to_win,
+        win_by_two: rules.win_by_two,
+        best_of: rules.best_of,
+        time_limit_secs: rules.time_limit_secs,
+    }.sanitized()
+}
+
+// Everything of `world` that changes during a match. Rules are not included, they come with the session.
+pub fn world_state(world: &PongWorld) -> CmdWorld {
+    let mut state = CmdWorld::default();
+    let (phase, phase_ticks) = encode_phase(world.phase);
+    state.tick = world.tick;
+    state.ball_x = world.ball.pos_x;
+    state.ball_y = world.ball.pos_y;
+    state.ball_vx = world.ball.velocity_x;
+    state.ball_vy = world.ball.velocity_y;
+    state.ball_spin = world.ball.spin;
+    state.left_y = world.paddle_left.pos_y;
+    state.left_vy = world.paddle_left.velocity_y;
+    state.right_y = world.paddle_right.pos_y;
+    state.right_vy = world.paddle_right.velocity_y;
+    state.phase = phase;
+    state.phase_ticks = phase_ticks;
+    state.score_left = world.score_left;
+    state.score_right = world.score_right;
+    state.sets_left = world.sets_left;
+    state.sets_right = world.sets_right;
+    state.points_played = world.points_played;
+    state.set_ticks = world.set_ticks;
+    state.set_scores = world.set_scores.iter().flat_map(|(left, right)| [*left, *right]).collect();
+    state.rng_state = world.rng.state();
+    state.last_serve_left = world.last_serve == ScreenSide::Left;
+    state.pending_serve = encode_side(world.pending_serve);
+    state
+}
+
+pub fn apply_world_state(world: &mut PongWorld, state: &CmdWorld) {
+    world.tick = state.tick;
+    world.ball.pos_x = state.ball_x;
+    world.ball.pos_y = state.ball_y;
+    world.ball.velocity_x = state.ball_vx;
+    world.ball.velocity_y = state.ball_vy;
+    world.ball.spin = state.ball_spin;
+    world.paddle_left.pos_y = state.left_y;
+    world.paddle_left.velocity_y = state.left_vy;
+    world.paddle_right.pos_y = state.right_y;
+    world.paddle_right.velocity_y = state.right_vy;
+    world.phase = decode_phase(state.phase, state.phase_ticks);
+    world.score_left = state.score_left;
+    world.score_right = state.score_right;
+    world.sets_left = state.sets_left;
+    world.sets_right = state.sets_right;
+    world.points_played = state.points_played;
+    world.set_ticks = state.set_ticks;
+    world.set_scores = state.set_scores.chunks_exact(2).map(|pair| (pair[0], pair[1])).collect();
+    world.rng = GameRng::new(state.rng_state);
+    world.last_serve = if state.last_serve_left { ScreenSide::Left } else { ScreenSide::Right };
+    world.pending_serve = decode_side(state.pending_serve);
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct PredictionStats {
+    pub states: u64,
+    // States which moved own paddle or the ball noticeably away from the prediction
+    pub corrections: u64,
+    // Largest of those moves, pixels
+    pub max_correction: f32,
+    // Ticks simulated again on top of server states
+    pub replayed: u64,
+    // Frames the match waited because the server did not take inputs
+    pub stalls: u64,
+}
+
+pub struct PredictionSession {
+    pub local_side: ScreenSide,
+    // Own inputs the server has not simulated yet, by number
+    pending: VecDeque<(u64, PlayerInput)>,
+    next_input: u64,
+    // Opponent input the server used last, assumed to stay the same
+    remote_input: PlayerInput,
+    // Tick of the newest server state applied
+    server_tick: u64,
+    pub stats: PredictionStats,
+}
+
+impl PredictionSession {
+    pub fn new(local_side: ScreenSide) -> PredictionSession {
+        PredictionSession {
+            local_side,
+            pending: VecDeque::new(),
+            next_input: 1,
+            remote_input: PlayerInput::default(),
+            server_tick: 0,
+            stats: PredictionStats::default(),
+        }
+    }
+
+    pub fn can_advance(&self) -> bool {
+        self.pending.len() < MAX_PENDING_INPUTS
+    }
+
+    fn inputs(&self, local: PlayerInput) -> WorldInputs {
+        let (left, right) = match self.local_side {
+            ScreenSide::Left => (local, self.remote_input),
+            ScreenSide::Right => (self.remote_input, local),
+        };
+        WorldInputs { left: left.paddle, right: right.paddle, serve: left.serve && right.serve }
+    }
+
+    // Run one tick with own `input`, it is sent to the server until a state includes it.
+    pub fn advance(&mut self, world: &mut PongWorld, input: PlayerInput) -> Vec<WorldEvent> {
+        self.pending.push_back((self.next_input, input));
+        self.next_input = self.next_input + 1;
+        world.step(&self.inputs(input))
+    }
+
+    // Every input the server has not simulated yet, start_tick is the number of the first.
+    pub fn message(&self, world: &PongWorld) -> InputMessage {
+        InputMessage {
+            start_tick: self.pending.front().map_or(self.next_input, |(number, _)| *number),
+            inputs: self.pending.iter().map(|(_, input)| encode_input(input)).collect(),
+            tick: world.tick,
+            ..Default::default()
+        }
+    }
+
+    // Take over server `state` and replay own inputs it does not include yet. False for a stale state.
+    pub fn apply(&mut self, world: &mut PongWorld, state: &CmdWorld) -> bool {
+        if state.tick <= self.server_tick {
+            return false;
+        }
+        self.server_tick = state.tick;
+        self.stats.states = self.stats.states + 1;
+        let (acked, remote_last) = match self.local_side {
+            ScreenSide::Left => (state.left_input, state.right_last),
+            ScreenSide::Right => (state.right_input, state.left_last),
+        };
+        self.remote_input = decode_input(remote_last);
+        while self.pending.front().is_some_and(|(number, _)| *number <= acked) {
+            self.pending.pop_front();
+        }
+
+        let paddle_before = self.paddle_y(world);
+        let ball_before = (world.ball.pos_x, world.ball.pos_y);
+        apply_world_state(world, state);
+        let pending: Vec<PlayerInput> = self.pending.iter().map(|(_, input)| *input).collect();
+        for input in pending {
+            world.step(&self.inputs(input));
+        }
+        self.stats.replayed = self.stats.replayed + self.pending.len() as u64;
+
+        let correction = (self.paddle_y(world) - paddle_before).abs()
+            .max((world.ball.pos_x - ball_before.0).abs())
+            .max((world.ball.pos_y - ball_before.1).abs());
+        if correction > CORRECTION_DISTANCE {
+            self.stats.corrections = self.stats.corrections + 1;
+            self.stats.max_correction = self.stats.max_correction.max(correction);
+        }
+        true
+    }
+
+    fn paddle_y(&self, world: &PongWorld) -> f32 {
+        match self.local_side {
+            ScreenSide::Left => world.paddle_left.pos_y,
+            ScreenSide::Right => world.paddle_right.pos_y,
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pong::world::PaddleInput;
+
+    fn holding(up: bool) -> PlayerInput {
+        PlayerInput { paddle: PaddleInput { up, down: !up, axis: 0.0 }, serve: false }
+    }
+
+    #[test]
+    fn world_state_round_trips_every_field() {
+        let mut world = PongWorld::new(77);
+        world.tick = 1234;
+        world.ball.pos_x = 321.5;
+        world.ball.pos_y = 123.25;
+        world.ball.velocity_x = -640.0;
+        world.ball.velocity_y = 75.5;
+        world.ball.spin = 0.75;
+        world.paddle_left.pos_y = 200.0;
+        world.paddle_left.velocity_y = -480.0;
+        world.paddle_right.pos_y = 500.0;
+        world.paddle_right.velocity_y = 480.0;
+        world.phase = MatchPhase::Countdown { ticks_left: 42 };
+        world.score_left = 3;
+        world.score_right = 7;
+        world.sets_left = 1;
+        world.sets_right = 2;
+        world.points_played = 31;
+        world.set_ticks = 999;
+        world.set_scores = vec![(11, 4), (9, 11), (2, 11)];
+        world.rng.next_u64();
+        world.last_serve = ScreenSide::Left;
+        world.pending_serve = Some(ScreenSide::Right);
+
+        let state = world_state(&world);
+        let mut copy = PongWorld::new(0);
+        apply_world_state(&mut copy, &state);
+        assert_eq!(world_state(&copy), state);
+        assert_eq!(copy.set_scores, world.set_scores);
+        assert_eq!(copy.phase, world.phase);
+        assert_eq!((copy.last_serve, copy.pending_serve), (ScreenSide::Left, Some(ScreenSide::Right)));
+        // Random sequence goes on where it was
+        assert_eq!(copy.rng.next_u64(), world.rng.next_u64());
+
+        let phases = [MatchPhase::Playing, MatchPhase::Celebrating { ticks_left: 5 }, MatchPhase::WaitingServe, MatchPhase::Finished];
+        for phase in phases {
+            world.phase = phase;
+            world.pending_serve = None;
+            apply_world_state(&mut copy, &world_state(&world));
+            assert_eq!((copy.phase, copy.pending_serve), (phase, None));
+        }
+    }
+
+    #[test]
+    fn apply_replays_inputs_the_server_did_not_have() {
+        let mut server = PongWorld::new(5);
+        let mut client = server.clone();
+        let mut prediction = PredictionSession::new(ScreenSide::Left);
+        for _ in 0..3 {
+            prediction.advance(&mut client, holding(true));
+        }
+        let message = prediction.message(&client);
+        assert_eq!((message.start_tick, message.inputs.len(), message.tick), (1, 3, 3));
+
+        // Server got the first two, the opponent held down meanwhile
+        let server_inputs = WorldInputs { left: holding(true).paddle, right: holding(false).paddle, serve: false };
+        server.step(&server_inputs);
+        server.step(&server_inputs);
+        let mut state = world_state(&server);
+        state.left_input = 2;
+        state.right_last = encode_input(&holding(false));
+        assert!(prediction.apply(&mut client, &state));
+        server.step(&server_inputs);
+        assert_eq!(world_state(&client), world_state(&server));
+        let message = prediction.message(&client);
+        assert_eq!((message.start_tick, message.inputs.len()), (3, 1));
+        assert_eq!((prediction.stats.states, prediction.stats.replayed), (1, 1));
+        // Only the opponent was guessed wrong, own paddle and the ball stayed where they were predicted
+        assert_eq!(prediction.stats.corrections, 0);
+
+        // Same or older state changes nothing
+        let before = world_state(&client);
+        assert!(!prediction.apply(&mut client, &state));
+        state.tick = 1;
+        assert!(!prediction.apply(&mut client, &state));
+        assert_eq!(world_state(&client), before);
+        assert_eq!(prediction.stats.states, 1);
+
+        // Everything acknowledged, the next input gets the following number
+        let mut state = world_state(&server);
+        state.left_input = 3;
+        state.right_last = encode_input(&holding(false));
+        assert!(prediction.apply(&mut client, &state));
+        let message = prediction.message(&client);
+        assert_eq!((message.start_tick, message.inputs.len()), (4, 0));
+        prediction.advance(&mut client, holding(false));
+        assert_eq!(prediction.message(&client).start_tick, 4);
+    }
+
+    #[test]
+    fn pending_inputs_are_limited() {
+        let mut world = PongWorld::new(1);
+        let mut prediction = PredictionSession::new(ScreenSide::Right);
+        for _ in 0..MAX_PENDING_INPUTS {
+            assert!(prediction.can_advance());
+            prediction.advance(&mut world, holding(true));
+        }
+        assert!(!prediction.can_advance());
+    }
+}
@@ -0,0 +1,279 @@
+// Match recording. The simulation is deterministic, so seed, match settings and the inputs
+// of every tick are enough to rebuild the whole match.
+
+use std::fs::{self, File};
+use std::io::{self, Read, Write};
+use std::path::Path;
+
+use super::world::{quantize_axis, BallPhysics, MatchRules, PaddleInput, PongWorld, ServeDirection, ServeRules, ServeStart, WorldInputs};
+
+const REPLAY_MAGIC: &[u8; 4] = b"RPLY";
+const REPLAY_VERSION: u8 = 4;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct Replay {
+    pub seed: u64,
+    pub physics: BallPhysics,
+    pub serve_rules: ServeRules,
+    pub rules: MatchRules,
+    pub inputs: Vec<WorldInputs>,
+}
+
+// Every tick takes 3 bytes: button bits, left and right stick.
+const INPUT_SIZE: usize = 3;
+
+fn encode_inputs(inputs: &WorldInputs) -> [u8; INPUT_SIZE] {
+    let buttons = (inputs.left.up as u8) | (inputs.left.down as u8) << 1 | (inputs.right.up as u8) << 2 | (inputs.right.down as u8) << 3 |
+        (inputs.serve as u8) << 4;
+    [buttons, encode_axis(inputs.left.axis), encode_axis(inputs.right.axis)]
+}
+
+fn decode_inputs(bytes: &[u8]) -> WorldInputs {
+    WorldInputs {
+        left: PaddleInput { up: bytes[0] & 0x1 != 0, down: bytes[0] & 0x2 != 0, axis: decode_axis(bytes[1]) },
+        right: PaddleInput { up: bytes[0] & 0x4 != 0, down: bytes[0] & 0x8 != 0, axis: decode_axis(bytes[2]) },
+        serve: bytes[0] & 0x10 != 0,
+    }
+}
+
+fn encode_axis(axis: f32) -> u8 {
+    ((quantize_axis(axis) * 127.0).round() as i8) as u8
+}
+
+fn decode_axis(byte: u8) -> f32 {
+    (byte as i8) as f32 / 127.0
+}
+
+fn read_f32(reader: &mut impl Read) -> io::Result<f32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(f32::from_le_bytes(buf))
+}
+
+fn read_u32(reader: &mut impl Read) -> io::Result<u32> {
+    let mut buf = [0u8; 4];
+    reader.read_exact(&mut buf)?;
+    Ok(u32::from_le_bytes(buf))
+}
+
+impl Replay {
+    // Start recording a match played in `world`, which was seeded with `seed`.
+    pub fn new(seed: u64, world: &PongWorld) -> Replay {
+        Replay {
+            seed,
+            physics: world.ball.physics,
+            serve_rules: world.serve_rules,
+            rules: world.rules,
+            inputs: Vec::new(),
+        }
+    }
+
+    pub fn record(&mut self, inputs: &WorldInputs) {
+        self.inputs.push(*inputs);
+    }
+
+    // World in the state it was after the last recorded tick.
+    pub fn simulate(&self) -> PongWorld {
+        let mut world = PongWorld::new(self.seed);
+        world.ball.physics = self.physics;
+        world.serve_rules = self.serve_rules;
+        world.rules = self.rules;
+        world.reset();
+        for inputs in self.inputs.iter() {
+            world.step(inputs);
+        }
+        world
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut file = File::create(path)?;
+        file.write_all(REPLAY_MAGIC)?;
+        file.write_all(&[REPLAY_VERSION])?;
+        file.write_all(&self.seed.to_le_bytes())?;
+        for value in [self.physics.max_speed, self.physics.max_bounce_angle, self.physics.spin_transfer, self.physics.spin_curve, self.physics.spin_decay] {
+            file.write_all(&value.to_le_bytes())?;
+        }
+        let direction = (self.serve_rules.direction == ServeDirection::Alternating) as u8;
+        let start = (self.serve_rules.start == ServeStart::Countdown) as u8;
+        file.write_all(&[direction, start])?;
+        file.write_all(&self.serve_rules.celebration_ticks.to_le_bytes())?;
+        file.write_all(&self.serve_rules.countdown_ticks.to_le_bytes())?;
+        file.write_all(&self.rules.points_to_win.to_le_bytes())?;
+        file.write_all(&[self.rules.win_by_two as u8])?;
+        file.write_all(&self.rules.best_of.to_le_bytes())?;
+        file.write_all(&self.rules.time_limit_secs.to_le_bytes())?;
+        file.write_all(&(self.inputs.len() as u32).to_le_bytes())?;
+        let inputs: Vec<u8> = self.inputs.iter().flat_map(encode_inputs).collect();
+        file.write_all(&inputs)
+    }
+
+    pub fn load(path: &Path) -> io::Result<Replay> {
+        let mut file = File::open(path)?;
+        let mut header = [0u8; 5];
+        file.read_exact(&mut header)?;
+        if &header[..4] != REPLAY_MAGIC || header[4] != REPLAY_VERSION {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Not a replay file or unsupported version"));
+        }
+        let mut seed = [0u8; 8];
+        file.read_exact(&mut seed)?;
+        let physics = BallPhysics {
+            max_speed: read_f32(&mut file)?,
+            max_bounce_angle: read_f32(&mut file)?,
+            spin_transfer: read_f32(&mut file)?,
+            spin_curve: read_f32(&mut file)?,
+            spin_decay: read_f32(&mut file)?,
+        };
+        if !physics.is_valid() {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "Replay has invalid ball physics"));
+        }
+        let mut serve = [0u8; 2];
+        file.read_exact(&mut serve)?;
+        let serve_rules = ServeRules {
+            direction: if serve[0] != 0 { ServeDirection::Alternating } else { ServeDirection::TowardConceder },
+            start: if serve[1] != 0 { ServeStart::Countdown } else { ServeStart::PressToServe },
+            celebration_ticks: read_u32(&mut file)?,
+            countdown_ticks: read_u32(&mut file)?,
+        };
+        let points_to_win = read_u32(&mut file)? as i32;
+        let mut win_by_two = [0u8; 1];
+        file.read_exact(&mut win_by_two)?;
+        let rules = MatchRules {
+            points_to_win,
+            win_by_two: win_by_two[0] != 0,
+            best_of: read_u32(&mut file)?,
+            time_limit_secs: read_u32(&mut file)?,
+        }.sanitized();
+        // Count comes from the file, read what is there and check it instead of allocating for it up front
+        let size = read_u32(&mut file)? as u64 * INPUT_SIZE as u64;
+        let mut inputs = Vec::new();
+        file.take(size).read_to_end(&mut inputs)?;
+        if inputs.len() as u64 != size {
+            return Err(io::Error::new(io::ErrorKind::UnexpectedEof, "Replay file is truncated"));
+        }
+        Ok(Replay {
+            seed: u64::from_le_bytes(seed),
+            physics,
+            serve_rules,
+            rules,
+            inputs: inputs.chunks(INPUT_SIZE).map(decode_inputs).collect(),
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pong::rng::GameRng;
+    use crate::pong::world::MATCH_PRESETS;
+
+    // Match played with non default settings and the replay recorded along the way.
+    fn recorded_match(ticks: u32) -> (PongWorld, Replay) {
+        let mut world = PongWorld::new(1234);
+        world.ball.physics = BallPhysics::classic();
+        world.serve_rules.direction = ServeDirection::Alternating;
+        world.serve_rules.start = ServeStart::Countdown;
+        world.rules = MATCH_PRESETS[2].1;
+        world.reset();
+        let mut replay = Replay::new(1234, &world);
+        let mut rng = GameRng::new(5);
+        for _ in 0..ticks {
+            let mut inputs = WorldInputs::default();
+            inputs.left.up = rng.range(0, 1) == 0;
+            inputs.left.down = !inputs.left.up;
+            inputs.right.axis = quantize_axis(rng.range(-127, 127) as f32 / 127.0);
+            replay.record(&inputs);
+            world.step(&inputs);
+        }
+        (world, replay)
+    }
+
+    #[test]
+    fn simulate_rebuilds_recorded_match() {
+        let (world, replay) = recorded_match(30000);
+        assert!(world.points_played > 0);
+        let replayed = replay.simulate();
+        assert_eq!(replayed.tick, 30000);
+        assert_eq!(format!("{:?}", replayed), format!("{:?}", world));
+    }
+
+    #[test]
+    fn simulate_finishes_like_the_match() {
+        let mut world = PongWorld::new(8);
+        world.rules.points_to_win = 2;
+        world.reset();
+        let mut replay = Replay::new(8, &world);
+        let inputs = WorldInputs { serve: true, ..WorldInputs::default() };
+        while world.winner().is_none() {
+            replay.record(&inputs);
+            world.step(&inputs);
+        }
+        let replayed = replay.simulate();
+        assert_eq!(replayed.winner(), world.winner());
+        assert_ne!(replayed.winner(), None);
+        assert_eq!(replayed.set_scores, world.set_scores);
+    }
+
+    fn temp_path(name: &str) -> std::path::PathBuf {
+        std::env::temp_dir().join(format!("rengine_replay_{}_{}.rply", std::process::id(), name))
+    }
+
+    #[test]
+    fn save_and_load_round_trip() {
+        let (_, replay) = recorded_match(1000);
+        let path = temp_path("round_trip");
+        replay.save(&path).unwrap();
+        let loaded = Replay::load(&path);
+        let _ = fs::remove_file(&path);
+        assert_eq!(loaded.unwrap(), replay);
+    }
+
+    #[test]
+    fn load_rejects_truncated_and_oversized_inputs() {
+        let (_, replay) = recorded_match(100);
+        let path = temp_path("truncated");
+        replay.save(&path).unwrap();
+        let bytes = fs::read(&path).unwrap();
+
+        fs::write(&path, &bytes[..bytes.len() - 1]).unwrap();
+        let truncated = Replay::load(&path);
+
+        // Header claims 4G ticks with only 100 behind it
+        let mut oversized = bytes.clone();
+        let count_at = bytes.len() - 100 * INPUT_SIZE - 4;
+        oversized[count_at..count_at + 4].copy_from_slice(&u32::MAX.to_le_bytes());
+        fs::write(&path, &oversized).unwrap();
+        let oversized = Replay::load(&path);
+        let _ = fs::remove_file(&path);
+
+        assert_eq!(truncated.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+        assert_eq!(oversized.unwrap_err().kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn load_sanitizes_rules() {
+        let (_, mut replay) = recorded_match(10);
+        replay.rules.points_to_win = -3;
+        replay.rules.best_of = 0;
+        let path = temp_path("rules");
+        replay.save(&path).unwrap();
+        let loaded = Replay::load(&path);
+        let _ = fs::remove_file(&path);
+        let rules = loaded.unwrap().rules;
+        assert_eq!((rules.points_to_win, rules.best_of), (1, 1));
+
+        // Broken ball physics cannot be made playable, the file is refused
+        for (name, max_speed, spin_decay) in [("nan", f32::NAN, 0.8), ("infinite", f32::INFINITY, 0.8), ("zero", 0.0, 0.8), ("negative", 3000.0, -1.0)] {
+            let (_, mut replay) = recorded_match(10);
+            replay.physics.max_speed = max_speed;
+            replay.physics.spin_decay = spin_decay;
+            let path = temp_path(name);
+            replay.save(&path).unwrap();
+            let loaded = Replay::load(&path);
+            let _ = fs::remove_file(&path);
+            assert_eq!(loaded.unwrap_err().kind(), io::ErrorKind::InvalidData, "{}", name);
+        }
+    }
+}
@@ -0,0 +1,43 @@
+// Game owned pseudo random generator (SplitMix64). Same seed gives the same sequence on every
+// machine, which keeps multiplayer peers and replays in lockstep.
+
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct GameRng {
+    state: u64,
+}
+
+impl GameRng {
+    pub fn new(seed: u64) -> GameRng {
+        GameRng {
+            state: seed,
+        }
+    }
+
+    // Position in the sequence, new() with it continues from here.
+    pub fn state(&self) -> u64 {
+        self.state
+    }
+
+    pub fn next_u64(&mut self) -> u64 {
+        self.state = self.state.wrapping_add(0x9E37_79B9_7F4A_7C15);
+        let mut z = self.state;
+        z = (z ^ (z >> 30)).wrapping_mul(0xBF58_476D_1CE4_E5B9);
+        z = (z ^ (z >> 27)).wrapping_mul(0x94D0_49BB_1331_11EB);
+        z ^ (z >> 31)
+    }
+
+    // Random value in inclusive range [min, max], same contract as raylib's GetRandomValue.
+    pub fn range(&mut self, min: i32, max: i32) -> i32 {
+        let (min, max) = if min > max { (max, min) } else { (min, max) };
+        let span = (max as i64 - min as i64 + 1) as u64;
+        (min as i64 + (self.next_u64() % span) as i64) as i32
+    }
+}
+
+// Seed for matches which do not get one from elsewhere (server, replay file).
+pub fn new_seed() -> u64 {
+    let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default();
+    now.as_nanos() as u64
+}
@@ -0,0 +1,458 @@
+// Rollback netcode for two player online matches. Players exchange only their inputs and both
+// simulate the whole match. Opponent inputs which did not arrive yet are predicted to repeat the last
+// known one. When the real input differs, the world goes back to the tick before it and is simulated again.
+
+use std::collections::{BTreeMap, VecDeque};
+
+use serde::{Deserialize, Serialize};
+
+use super::world::{quantize_axis, PaddleInput, PongWorld, ScreenSide, WorldEvent, WorldInputs};
+
+// Ticks between pressing a key and the paddle moving, hides that much latency without any rollback
+pub const DEFAULT_INPUT_DELAY: u32 = 2;
+pub const MAX_INPUT_DELAY: u32 = 12;
+// Furthest the simulation runs past the last known opponent input, beyond it the match waits
+pub const MAX_PREDICTION_TICKS: u64 = 30;
+// Peer further ahead than this waits a tick now and then so the other one catches up
+const MAX_TICK_ADVANTAGE: i64 = 2;
+// Both peers fingerprint the world at every multiple of it to detect desyncs
+const CHECK_INTERVAL: u64 = 120;
+const MAX_CHECKS: usize = 16;
+
+// How online matches are kept in sync, picked by the player who opens the session.
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Netcode {
+    // Only inputs are exchanged, both players simulate everything
+    #[default]
+    Rollback,
+    // Players send positions, the ball belongs to the player it flies toward
+    BallMaster,
+    // Server simulates the match from inputs, players predict their side until its state arrives
+    Server,
+}
+
+impl Netcode {
+    pub fn name(&self) -> &'static str {
+        match self {
+            Netcode::Rollback => "Rollback",
+            Netcode::BallMaster => "Ball master",
+            Netcode::Server => "Server",
+        }
+    }
+
+    pub fn next(&self) -> Netcode {
+        match self {
+            Netcode::Rollback => Netcode::BallMaster,
+            Netcode::BallMaster => Netcode::Server,
+            Netcode::Server => Netcode::Rollback,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PlayerInput {
+    pub paddle: PaddleInput,
+    // Ready to serve, ball is launched once both players are
+    pub serve: bool,
+}
+
+// Bit 0 up, bit 1 down, bit 2 serve, bits 8-15 stick as signed byte.
+pub fn encode_input(input: &PlayerInput) -> u32 {
+    let buttons = (input.paddle.up as u32) | (input.paddle.down as u32) << 1 | (input.serve as u32) << 2;
+    let axis = ((quantize_axis(input.paddle.axis) * 127.0).round() as i8) as u8;
+    buttons | (axis as u32) << 8
+}
+
+pub fn decode_input(value: u32) -> PlayerInput {
+    PlayerInput {
+        paddle: PaddleInput {
+            up: value & 0x1 != 0,
+            down: value & 0x2 != 0,
+            axis: ((value >> 8) as u8 as i8) as f32 / 127.0,
+        },
+        serve: value & 0x4 != 0,
+    }
+}
+
+// Fingerprint of everything that decides how the match continues (FNV-1a).
+pub fn world_checksum(world: &PongWorld) -> u64 {
+    let values = [
+        world.tick,
+        world.ball.pos_x.to_bits() as u64,
+        world.ball.pos_y.to_bits() as u64,
+        world.ball.velocity_x.to_bits() as u64,
+        world.ball.velocity_y.to_bits() as u64,
+        world.ball.spin.to_bits() as u64,
+        world.paddle_left.pos_y.to_bits() as u64,
+        world.paddle_right.pos_y.to_bits() as u64,
+        world.score_left as u64,
+        world.score_right as u64,
+        world.sets_left as u64,
+        world.sets_right as u64,
+        world.rng.clone().next_u64(),
+    ];
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for value in values {
+        for byte in value.to_le_bytes() {
+            hash = (hash ^ byte as u64).wrapping_mul(0x0100_0000_01b3);
+        }
+    }
+    hash
+}
+
+// What one peer tells the other, sent every frame.
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct InputMessage {
+    // Tick of the first entry of inputs
+    pub start_tick: u64,
+    pub inputs: Vec<u32>,
+    // Last tick of the receiver's inputs the sender has without gaps
+    pub ack_tick: u64,
+    // Tick the sender's simulation is at
+    pub tick: u64,
+    // Sender's view of how far it is ahead of the receiver
+    pub advantage: i32,
+    // Newest world fingerprint of the sender, tick 0 when there is none yet
+    pub check_tick: u64,
+    pub checksum: u64,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+pub struct RollbackStats {
+    pub rollbacks: u64,
+    // Ticks simulated again after wrong predictions
+    pub resimulated: u64,
+    // Deepest rollback in ticks
+    pub max_depth: u64,
+    // Frames the match waited for opponent inputs
+    pub stalls: u64,
+    // Ticks skipped to let the opponent catch up
+    pub waits: u64,
+    pub desyncs: u64,
+}
+
+pub struct RollbackSession {
+    pub local_side: ScreenSide,
+    pub input_delay: u32,
+    // Own inputs by tick they apply to, kept until the opponent has them and they are final
+    local: BTreeMap<u64, PlayerInput>,
+    // Opponent inputs still needed to simulate again
+    remote: BTreeMap<u64, PlayerInput>,
+    // Guesses used for ticks simulated before the opponent's input arrived
+    predicted: BTreeMap<u64, PlayerInput>,
+    last_remote: PlayerInput,
+    // All opponent inputs up to this tick are known
+    remote_confirmed: u64,
+    // Opponent has all our inputs up to this tick
+    remote_acked: u64,
+    remote_tick: u64,
+    remote_advantage: i64,
+    // Earliest tick simulated with a wrong guess
+    rollback_to: Option<u64>,
+    // World as it was after the tick of the key, from the last confirmed tick on
+    snapshots: VecDeque<PongWorld>,
+    checks: BTreeMap<u64, u64>,
+    remote_checks: BTreeMap<u64, u64>,
+    // Newest fingerprint compared with the opponent's
+    compared_tick: u64,
+    pub stats: RollbackStats,
+}
+
+impl RollbackSession {
+    // Session starting from `world`, which must be the same on both sides.
+    pub fn new(local_side: ScreenSide, input_delay: u32, world: &PongWorld) -> RollbackSession {
+        let input_delay = input_delay.min(MAX_INPUT_DELAY);
+        let mut session = RollbackSession {
+            local_side,
+            input_delay,
+            local: BTreeMap::new(),
+            remote: BTreeMap::new(),
+            predicted: BTreeMap::new(),
+            last_remote: PlayerInput::default(),
+            remote_confirmed: world.tick,
+            remote_acked: world.tick,
+            remote_tick: world.tick,
+            remote_advantage: 0,
+            rollback_to: None,
+            snapshots: VecDeque::new(),
+            checks: BTreeMap::new(),
+            remote_checks: BTreeMap::new(),
+            compared_tick: 0,
+            stats: RollbackStats::default(),
+        };
+        // Nobody can act during the delay at the start, say so explicitly so the opponent does not wait
+        for tick in world.tick + 1..=world.tick + input_delay as u64 {
+            session.local.insert(tick, PlayerInput::default());
+        }
+        session.snapshots.push_back(world.clone());
+        session
+    }
+
+    // Input sampled now, applied `input_delay` ticks later.
+    pub fn add_local_input(&mut self, world: &PongWorld, input: PlayerInput) {
+        let tick = world.tick + 1 + self.input_delay as u64;
+        self.local.entry(tick).or_insert(input);
+    }
+
+    // Simulation may not run further ahead of the opponent's inputs.
+    pub fn can_advance(&self, world: &PongWorld) -> bool {
+        world.tick < self.remote_confirmed + MAX_PREDICTION_TICKS && self.local.contains_key(&(world.tick + 1))
+    }
+
+    // Ahead of the opponent by more than latency explains, waiting a tick evens it out.
+    pub fn should_wait(&self, world: &PongWorld) -> bool {
+        let advantage = world.tick as i64 - self.remote_tick as i64;
+        (advantage - self.remote_advantage) / 2 > MAX_TICK_ADVANTAGE
+    }
+
+    pub fn message(&self, world: &PongWorld) -> InputMessage {
+        let inputs: Vec<(u64, u32)> = self.local.range(self.remote_acked + 1..).map(|(tick, input)| (*tick, encode_input(input))).collect();
+        let (check_tick, checksum) = match self.checks.iter().next_back() {
+            Some((tick, checksum)) => (*tick, *checksum),
+            None => (0, 0),
+        };
+        InputMessage {
+            start_tick: inputs.first().map_or(self.remote_acked + 1, |(tick, _)| *tick),
+            inputs: inputs.iter().map(|(_, input)| *input).collect(),
+            ack_tick: self.remote_confirmed,
+            tick: world.tick,
+            advantage: (world.tick as i64 - self.remote_tick as i64) as i32,
+            check_tick,
+            checksum,
+        }
+    }
+
+    // Take in what the opponent sent. Inputs already known are skipped, so resent ones do no harm.
+    pub fn receive(&mut self, message: &InputMessage) {
+        for (index, value) in message.inputs.iter().enumerate() {
+            let tick = message.start_tick + index as u64;
+            if tick != self.remote_confirmed + 1 {
+                continue;
+            }
+            let input = decode_input(*value);
+            if let Some(guess) = self.predicted.remove(&tick) {
+                if guess != input {
+                    self.rollback_to = Some(self.rollback_to.map_or(tick, |earliest| earliest.min(tick)));
+                }
+            }
+            self.remote.insert(tick, input);
+            self.last_remote = input;
+            self.remote_confirmed = tick;
+        }
+        self.remote_acked = self.remote_acked.max(message.ack_tick);
+        if message.tick >= self.remote_tick {
+            self.remote_tick = message.tick;
+            self.remote_advantage = message.advantage as i64;
+        }
+        if message.check_tick > self.compared_tick {
+            self.remote_checks.insert(message.check_tick, message.checksum);
+            while self.remote_checks.len() > MAX_CHECKS {
+                self.remote_checks.pop_first();
+            }
+        }
+    }
+
+    fn inputs(&mut self, tick: u64) -> WorldInputs {
+        let local = self.local.get(&tick).copied().unwrap_or_default();
+        let remote = match self.remote.get(&tick) {
+            Some(input) => *input,
+            None => {
+                self.predicted.insert(tick, self.last_remote);
+                self.last_remote
+            },
+        };
+        let (left, right) = if self.local_side == ScreenSide::Left { (local, remote) } else { (remote, local) };
+        WorldInputs { left: left.paddle, right: right.paddle, serve: left.serve && right.serve }
+    }
+
+    fn step(&mut self, world: &mut PongWorld) -> Vec<WorldEvent> {
+        let inputs = self.inputs(world.tick + 1);
+        let events = world.step(&inputs);
+        self.snapshots.push_back(world.clone());
+        events
+    }
+
+    // Fix the world after wrong guesses and forget what no rollback can reach any more. Once per frame before advancing.
+    pub fn update(&mut self, world: &mut PongWorld) {
+        if let Some(tick) = self.rollback_to.take() {
+            let current = world.tick;
+            let first = self.snapshots.front().map_or(current, |snapshot| snapshot.tick);
+            if tick > first && tick <= current {
+                self.snapshots.truncate((tick - 1 - first) as usize + 1);
+                *world = self.snapshots.back().unwrap().clone();
+                self.predicted.retain(|predicted, _| *predicted < tick);
+                while world.tick < current {
+                    self.step(world);
+                }
+                let depth = current - tick + 1;
+                self.stats.rollbacks = self.stats.rollbacks + 1;
+                self.stats.resimulated = self.stats.resimulated + depth;
+                self.stats.max_depth = self.stats.max_depth.max(depth);
+            }
+        }
+
+        // World up to this tick will not change again
+        let final_tick = self.remote_confirmed.min(world.tick);
+        self.record_checks(final_tick);
+        while self.snapshots.len() > 1 && self.snapshots[0].tick < final_tick {
+            self.snapshots.pop_front();
+        }
+        self.remote.retain(|tick, _| *tick > final_tick);
+        let keep_from = final_tick.min(self.remote_acked);
+        self.local.retain(|tick, _| *tick > keep_from);
+    }
+
+    fn record_checks(&mut self, final_tick: u64) {
+        for snapshot in self.snapshots.iter() {
+            if snapshot.tick > final_tick {
+                break;
+            }
+            if snapshot.tick == 0 || snapshot.tick % CHECK_INTERVAL != 0 || self.checks.contains_key(&snapshot.tick) {
+                continue;
+            }
+            let checksum = world_checksum(snapshot);
+            self.checks.insert(snapshot.tick, checksum);
+        }
+        while self.checks.len() > MAX_CHECKS {
+            self.checks.pop_first();
+        }
+        let comparable: Vec<(u64, u64)> = self.remote_checks.iter()
+            .filter(|(tick, _)| self.checks.contains_key(tick))
+            .map(|(tick, checksum)| (*tick, *checksum))
+            .collect();
+        for (tick, checksum) in comparable {
+            if self.checks[&tick] != checksum {
+                println!("Rollback desync at tick {}, matches no longer agree", tick);
+                self.stats.desyncs = self.stats.desyncs + 1;
+            }
+            self.compared_tick = self.compared_tick.max(tick);
+            self.remote_checks.remove(&tick);
+        }
+    }
+
+    // Simulate the next tick, None while it has to wait for the opponent.
+    pub fn advance(&mut self, world: &mut PongWorld) -> Option<Vec<WorldEvent>> {
+        if !self.can_advance(world) {
+            return None;
+        }
+        Some(self.step(world))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pong::ai::{AiController, Difficulty};
+    use crate::pong::loopback::Loopback;
+
+    fn start_world() -> PongWorld {
+        let mut world = PongWorld::new(77);
+        // Long match, nobody wins during the test
+        world.rules.points_to_win = 1000;
+        world.reset();
+        world
+    }
+
+    fn player(up: bool, down: bool) -> PlayerInput {
+        PlayerInput { paddle: PaddleInput { up, down, axis: 0.0 }, serve: true }
+    }
+
+    // Local side played like the game does it, frame by frame, against the computer behind the loopback.
+    #[test]
+    fn loopback_match_stays_in_sync() {
+        let frame_ms = 1000.0 / 60.0;
+        let mut world = start_world();
+        let mut session = RollbackSession::new(ScreenSide::Left, DEFAULT_INPUT_DELAY, &world);
+        let mut loopback = Loopback::new(&world, DEFAULT_INPUT_DELAY, 60, 25, 9);
+        let mut ai = AiController::new(ScreenSide::Left, Difficulty::Expert, 3);
+        while world.tick < 6000 {
+            for message in loopback.update(frame_ms) {
+                session.receive(&message);
+            }
+            session.update(&mut world);
+            let mut waited = false;
+            for _ in 0..2 {
+                if !waited && session.should_wait(&world) {
+                    waited = true;
+                    continue;
+                }
+                let input = PlayerInput { paddle: ai.input(&world), serve: true };
+                session.add_local_input(&world, input);
+                if session.advance(&mut world).is_none() {
+                    break;
+                }
+            }
+            loopback.send(session.message(&world));
+        }
+
+        assert!(world.points_played > 0);
+        assert!(session.stats.rollbacks > 0 && loopback.session.stats.rollbacks > 0);
+        assert_eq!(session.stats.desyncs, 0);
+        assert_eq!(loopback.session.stats.desyncs, 0);
+        // Both sides fingerprinted the same final worlds
+        let common: Vec<u64> = session.checks.keys().filter(|tick| loopback.session.checks.contains_key(tick)).copied().collect();
+        assert!(common.len() > 5);
+        for tick in common {
+            assert_eq!(session.checks[&tick], loopback.session.checks[&tick], "tick {}", tick);
+        }
+        assert!(session.compared_tick > 5000);
+    }
+
+    // Session with every remote tick simulated on predictions and the world simulated with the real inputs.
+    fn predicted(remote: &[PlayerInput]) -> (RollbackSession, PongWorld, PongWorld) {
+        let mut world = start_world();
+        let mut expected = start_world();
+        let mut session = RollbackSession::new(ScreenSide::Left, 0, &world);
+        for (index, remote) in remote.iter().enumerate() {
+            let local = player(index % 3 == 0, false);
+            session.add_local_input(&world, local);
+            session.advance(&mut world).unwrap();
+            expected.step(&WorldInputs { left: local.paddle, right: remote.paddle, serve: local.serve && remote.serve });
+        }
+        (session, world, expected)
+    }
+
+    fn remote_message(remote: &[PlayerInput]) -> InputMessage {
+        InputMessage { start_tick: 1, inputs: remote.iter().map(encode_input).collect(), ..InputMessage::default() }
+    }
+
+    #[test]
+    fn rollback_from_first_tick_after_snapshot() {
+        // Opponent pressed down from the very first tick, nothing was guessed right
+        let remote = vec![player(false, true); 10];
+        let (mut session, mut world, expected) = predicted(&remote);
+        assert_eq!(session.snapshots.front().unwrap().tick, 0);
+        session.receive(&remote_message(&remote));
+        session.update(&mut world);
+        assert_eq!(world.tick, 10);
+        assert_eq!(world_checksum(&world), world_checksum(&expected));
+        assert_eq!((session.stats.rollbacks, session.stats.max_depth, session.stats.resimulated), (1, 10, 10));
+    }
+
+    #[test]
+    fn rollback_of_the_current_tick() {
+        // Guess of repeating the default input was wrong only for the newest tick
+        let mut remote = vec![PlayerInput::default(); 10];
+        remote[9] = player(true, false);
+        let (mut session, mut world, expected) = predicted(&remote);
+        session.receive(&remote_message(&remote));
+        session.update(&mut world);
+        assert_eq!(world.tick, 10);
+        assert_eq!(world_checksum(&world), world_checksum(&expected));
+        assert_eq!((session.stats.rollbacks, session.stats.max_depth), (1, 1));
+        assert_eq!(session.snapshots.back().unwrap().tick, 10);
+    }
+
+    #[test]
+    fn right_guesses_do_not_roll_back() {
+        let remote = vec![PlayerInput::default(); 10];
+        let (mut session, mut world, expected) = predicted(&remote);
+        session.receive(&remote_message(&remote));
+        session.update(&mut world);
+        assert_eq!(world_checksum(&world), world_checksum(&expected));
+        assert_eq!(session.stats.rollbacks, 0);
+        // Everything is final now, only the newest snapshot is kept
+        assert_eq!(session.snapshots.len(), 1);
+    }
+}
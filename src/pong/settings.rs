@@ -0,0 +1,234 @@
+// User settings kept between runs in a TOML file in the user's config directory.
+
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+use super::ai::Difficulty;
+use super::input::{GamepadSettings, Keymap};
+use super::interp::{DEFAULT_INTERP_DELAY_MS, MAX_INTERP_DELAY_MS};
+use super::rollback::{Netcode, DEFAULT_INPUT_DELAY, MAX_INPUT_DELAY};
+use super::tls::TlsSettings;
+use super::world::{MatchRules, RES_HEIGHT, RES_WIDTH};
+
+// Bump when the meaning of existing fields changes and add a step to migrate().
+// New fields do not need it, they get default values when missing.
+pub const SETTINGS_VERSION: u32 = 1;
+
+// Window sizes offered in options, all keep the 16:9 shape of the playfield
+pub const RESOLUTIONS: [(i32, i32); 4] = [(1280, 720), (1600, 900), (1920, 1080), (2560, 1440)];
+
+pub const DEFAULT_SERVER: &str = "wss://127.0.0.1:8443/ws";
+
+// Servers remembered for the multiplayer screen
+pub const MAX_RECENT_SERVERS: usize = 5;
+
+// Versions before 1 kept the file in the working directory
+const LEGACY_SETTINGS_PATH: &str = "settings.toml";
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Settings {
+    // Files written before versioning have no version, they read as 0
+    #[serde(default)]
+    pub version: u32,
+    // Master volume 0.0 - 1.0
+    pub volume: f32,
+    pub fullscreen: bool,
+    pub window_width: i32,
+    pub window_height: i32,
+    pub player_name: String,
+    // Made up on first start, servers keep the ranked rating under it
+    pub player_key: String,
+    pub server_address: String,
+    // Most recently used first
+    pub recent_servers: Vec<String>,
+    pub tls: TlsSettings,
+    // Remote paddle and ball are drawn this far in the past, larger hides more network jitter
+    pub interp_delay_ms: u32,
+    // Asked for when opening an online session, joining players use the session's
+    pub netcode: Netcode,
+    // Ticks between input and movement in rollback matches
+    pub input_delay: u32,
+    pub rules: MatchRules,
+    pub ai_difficulty: Difficulty,
+    pub controls: Keymap,
+    pub gamepads: GamepadSettings,
+}
+
+impl Default for Settings {
+    fn default() -> Settings {
+        Settings {
+            version: SETTINGS_VERSION,
+            volume: 1.0,
+            fullscreen: false,
+            window_width: RES_WIDTH,
+            window_height: RES_HEIGHT,
+            player_name: "Player".to_string(),
+            player_key: String::new(),
+            server_address: DEFAULT_SERVER.to_string(),
+            recent_servers: Vec::new(),
+            tls: TlsSettings::default(),
+            interp_delay_ms: DEFAULT_INTERP_DELAY_MS,
+            netcode: Netcode::default(),
+            input_delay: DEFAULT_INPUT_DELAY,
+            rules: MatchRules::default(),
+            ai_difficulty: Difficulty::default(),
+            controls: Keymap::default(),
+            gamepads: GamepadSettings::default(),
+        }
+    }
+}
+
+// <config dir>/rengine/settings.toml, working directory when the system has no config dir.
+pub fn settings_path() -> PathBuf {
+    match dirs::config_dir() {
+        Some(dir) => dir.join("rengine").join("settings.toml"),
+        None => PathBuf::from(LEGACY_SETTINGS_PATH),
+    }
+}
+
+fn migrate(mut settings: Settings) -> Settings {
+    if settings.version > SETTINGS_VERSION {
+        println!("Settings were written by newer version {}, unknown options are ignored", settings.version);
+    }
+    // 0 -> 1: file moved to the config directory, fields unchanged
+    settings.version = SETTINGS_VERSION;
+    settings
+}
+
+impl Settings {
+    // Missing file gives defaults, broken file is reported and replaced by defaults.
+    pub fn load(path: &Path) -> Settings {
+        Settings::load_from(path, Path::new(LEGACY_SETTINGS_PATH))
+    }
+
+    // File at `legacy` is read when there is none at `path` yet.
+    fn load_from(path: &Path, legacy: &Path) -> Settings {
+        let mut path = path.to_path_buf();
+        if !path.exists() && legacy.exists() {
+            println!("Using settings from {}, they will be saved to {}", legacy.display(), path.display());
+            path = legacy.to_path_buf();
+        }
+        let content = match fs::read_to_string(&path) {
+            Ok(content) => content,
+            Err(err) => {
+                if err.kind() != io::ErrorKind::NotFound {
+                    println!("Failed to read settings {}: {}", path.display(), err);
+                }
+                return Settings::default();
+            },
+        };
+        match toml::from_str::<Settings>(&content) {
+            Ok(settings) => migrate(settings).sanitized(),
+            Err(err) => {
+                println!("Invalid settings {}, using defaults: {}", path.display(), err);
+                Settings::default()
+            },
+        }
+    }
+
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let content = toml::to_string_pretty(self).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        fs::write(path, content)
+    }
+
+    // Make `address` the default server and move it to the top of the recent list.
+    pub fn remember_server(&mut self, address: &str) {
+        self.server_address = address.to_string();
+        self.recent_servers.retain(|recent| recent != address);
+        self.recent_servers.insert(0, address.to_string());
+        self.recent_servers.truncate(MAX_RECENT_SERVERS);
+    }
+
+    // Values edited by hand can be anything, keep them usable.
+    pub fn sanitized(&self) -> Settings {
+        let mut settings = self.clone();
+        settings.volume = settings.volume.max(0.0).min(1.0);
+        if settings.window_width <= 0 || settings.window_height <= 0 {
+            settings.window_width = RES_WIDTH;
+            settings.window_height = RES_HEIGHT;
+        }
+        if settings.server_address.trim().is_empty() {
+            settings.server_address = DEFAULT_SERVER.to_string();
+        }
+        settings.recent_servers.retain(|address| !address.trim().is_empty());
+        settings.recent_servers.truncate(MAX_RECENT_SERVERS);
+        settings.tls = settings.tls.sanitized();
+        settings.interp_delay_ms = settings.interp_delay_ms.min(MAX_INTERP_DELAY_MS);
+        settings.input_delay = settings.input_delay.min(MAX_INPUT_DELAY);
+        settings.rules = settings.rules.sanitized();
+        settings.controls = settings.controls.sanitized();
+        settings.gamepads = settings.gamepads.sanitized();
+        settings
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rengine_settings_{}_{}.toml", std::process::id(), name))
+    }
+
+    // Settings read from `content` like from a file the user edited.
+    fn load_content(name: &str, content: &str) -> Settings {
+        let path = temp_path(name);
+        fs::write(&path, content).unwrap();
+        let settings = Settings::load_from(&path, &temp_path("no_legacy"));
+        let _ = fs::remove_file(&path);
+        settings
+    }
+
+    #[test]
+    fn broken_file_falls_back_to_defaults() {
+        assert_eq!(Settings::load_from(&temp_path("missing"), &temp_path("no_legacy")), Settings::default());
+        assert_eq!(load_content("broken", "volume = [oops"), Settings::default());
+        assert_eq!(load_content("wrong_type", "volume = \"loud\""), Settings::default());
+
+        // Values out of range are kept usable instead
+        let settings = load_content("range", "version = 1\nvolume = 7.0\nwindow_width = -5\nserver_address = \" \"");
+        assert_eq!(settings.volume, 1.0);
+        assert_eq!((settings.window_width, settings.window_height), (RES_WIDTH, RES_HEIGHT));
+        assert_eq!(settings.server_address, DEFAULT_SERVER);
+    }
+
+    #[test]
+    fn old_and_newer_files_are_migrated() {
+        // Before versioning there was no version field
+        let settings = load_content("unversioned", "player_name = \"Old\"\nvolume = 0.5");
+        assert_eq!(settings.version, SETTINGS_VERSION);
+        assert_eq!((settings.player_name.as_str(), settings.volume), ("Old", 0.5));
+        assert_eq!(settings.controls, Keymap::default());
+
+        let settings = load_content("newer", "version = 99\nplayer_name = \"New\"\nfuture_option = true");
+        assert_eq!(settings.version, SETTINGS_VERSION);
+        assert_eq!(settings.player_name, "New");
+    }
+
+    #[test]
+    fn legacy_file_is_read_until_saved_to_the_new_path() {
+        let path = temp_path("new");
+        let legacy = temp_path("legacy");
+        let _ = fs::remove_file(&path);
+        let mut old = Settings::default();
+        old.player_name = "Legacy".to_string();
+        old.save(&legacy).unwrap();
+        assert_eq!(Settings::load_from(&path, &legacy).player_name, "Legacy");
+
+        // Once saved, the new file wins
+        let mut new = old.clone();
+        new.player_name = "Moved".to_string();
+        new.save(&path).unwrap();
+        let loaded = Settings::load_from(&path, &legacy);
+        let _ = fs::remove_file(&path);
+        let _ = fs::remove_file(&legacy);
+        assert_eq!(loaded, new);
+    }
+}
@@ -0,0 +1,232 @@
+// Connection to the game server. Certificates are verified against the system roots, optionally
+// a trusted CA file and a pinned fingerprint. Insecure mode skips all of it and is meant for development only.
+
+use std::fs;
+use std::net::{TcpStream, ToSocketAddrs};
+
+use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use websocket::native_tls::{Certificate, HandshakeError, TlsConnector};
+use websocket::stream::sync::NetworkStream;
+use websocket::url::Url;
+
+use super::net::{NetError, CONNECT_TIMEOUT, IO_TIMEOUT};
+
+#[derive(Debug, Clone, Default, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct TlsSettings {
+    // PEM file trusted in addition to the system roots, a CA or the server's self-signed certificate
+    pub ca_file: String,
+    // SHA-256 of the server certificate as printed by `openssl x509 -noout -fingerprint -sha256`, empty to not pin
+    pub pin_sha256: String,
+    // Accept any certificate, anybody on the way can pretend to be the server
+    pub insecure: bool,
+}
+
+impl TlsSettings {
+    pub fn sanitized(&self) -> TlsSettings {
+        let mut tls = self.clone();
+        tls.ca_file = tls.ca_file.trim().to_string();
+        if !tls.pin_sha256.is_empty() && parse_fingerprint(&tls.pin_sha256).is_none() {
+            println!("Ignoring invalid certificate pin {}", tls.pin_sha256);
+            tls.pin_sha256 = String::new();
+        }
+        tls
+    }
+}
+
+// "AB:CD:..." or "abcd..." to 32 bytes
+fn parse_fingerprint(text: &str) -> Option<[u8; 32]> {
+    let hex: Vec<char> = text.chars().filter(|c| *c != ':' && !c.is_whitespace()).collect();
+    if hex.len() != 64 {
+        return None;
+    }
+    let mut fingerprint = [0u8; 32];
+    for (index, pair) in hex.chunks(2).enumerate() {
+        let high = pair[0].to_digit(16)?;
+        let low = pair[1].to_digit(16)?;
+        fingerprint[index] = (high * 16 + low) as u8;
+    }
+    Some(fingerprint)
+}
+
+fn format_fingerprint(fingerprint: &[u8]) -> String {
+    fingerprint.iter().map(|byte| format!("{:02X}", byte)).collect::<Vec<String>>().join(":")
+}
+
+fn tls_connector(tls: &TlsSettings) -> Result<TlsConnector, NetError> {
+    let mut builder = TlsConnector::builder();
+    if tls.insecure {
+        println!("WARNING: server certificate is not verified");
+        builder.danger_accept_invalid_certs(true);
+        builder.danger_accept_invalid_hostnames(true);
+    } else if !tls.ca_file.is_empty() {
+        let pem = fs::read(&tls.ca_file).map_err(|err| NetError::Tls(format!("failed to read certificate {}: {}", tls.ca_file, err)))?;
+        let cert = Certificate::from_pem(&pem).map_err(|err| NetError::Tls(format!("invalid certificate {}: {}", tls.ca_file, err)))?;
+        builder.add_root_certificate(cert);
+    }
+    builder.build().map_err(|err| NetError::Tls(err.to_string()))
+}
+
+// Try every address the host resolves to, each gets CONNECT_TIMEOUT.
+fn tcp_connect(host: &str, port: u16) -> Result<TcpStream, NetError> {
+    let addrs = (host, port).to_socket_addrs().map_err(|err| NetError::InvalidAddress(format!("{}: {}", host, err)))?;
+    let mut last_err = NetError::InvalidAddress(format!("{} has no addresses", host));
+    for addr in addrs {
+        match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+            Ok(tcp) => {
+                tcp.set_read_timeout(Some(IO_TIMEOUT))?;
+                tcp.set_write_timeout(Some(IO_TIMEOUT))?;
+                return Ok(tcp);
+            },
+            Err(err) => {
+                last_err = match NetError::from(err) {
+                    NetError::Io(msg) => NetError::Connect(format!("{}:{}: {}", host, port, msg)),
+                    err => err,
+                };
+            },
+        }
+    }
+    Err(last_err)
+}
+
+// Open the stream the websocket handshake runs on, TLS for wss:// addresses.
+pub fn connect(url: &Url, tls: &TlsSettings) -> Result<Box<dyn NetworkStream + Send>, NetError> {
+    let host = url.host_str().ok_or(NetError::InvalidAddress(format!("{} has no host", url)))?;
+    let port = url.port_or_known_default().ok_or(NetError::InvalidAddress(format!("{} has no port", url)))?;
+    let tcp = tcp_connect(host, port)?;
+    if url.scheme() != "wss" {
+        return Ok(Box::new(tcp));
+    }
+
+    let connector = tls_connector(tls)?;
+    let stream = match connector.connect(host, tcp) {
+        Ok(stream) => stream,
+        // Read timeout of the socket ran out in the middle of the handshake
+        Err(HandshakeError::WouldBlock(_)) => return Err(NetError::Timeout),
+        Err(HandshakeError::Failure(err)) => return Err(NetError::Tls(err.to_string())),
+    };
+    if tls.insecure {
+        return Ok(Box::new(stream));
+    }
+    if let Some(pin) = parse_fingerprint(&tls.pin_sha256) {
+        let cert = stream.peer_certificate().ok().flatten().ok_or(NetError::Tls(format!("{} sent no certificate", host)))?;
+        let der = cert.to_der().map_err(|err| NetError::Tls(format!("invalid server certificate: {}", err)))?;
+        let fingerprint: [u8; 32] = Sha256::digest(&der).into();
+        if fingerprint != pin {
+            return Err(NetError::Tls(format!("certificate of {} does not match the pinned one, got {}", host, format_fingerprint(&fingerprint))));
+        }
+    }
+    Ok(Box::new(stream))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Read;
+    use std::net::TcpListener;
+    use std::path::PathBuf;
+    use std::thread;
+    use websocket::native_tls::{Identity, TlsAcceptor};
+
+    // Certificates from tests/certs, see the README there
+    const CA_CERT: &[u8] = include_bytes!("../../tests/certs/ca.crt");
+    const OTHER_CA_CERT: &[u8] = include_bytes!("../../tests/certs/other-ca.crt");
+    const SERVER_CERT: &[u8] = include_bytes!("../../tests/certs/server.crt");
+    const SERVER_KEY: &[u8] = include_bytes!("../../tests/certs/server.key");
+    const SERVER_PIN: &str = "CA:79:F1:54:56:8A:83:E6:ED:64:3A:EA:44:B4:7D:D2:CF:2D:49:E9:29:6D:72:46:F9:A9:AB:30:51:85:91:0B";
+
+    // TLS server with the test certificate, every connection is held open until the client drops it.
+    fn tls_server() -> Url {
+        let acceptor = TlsAcceptor::new(Identity::from_pkcs8(SERVER_CERT, SERVER_KEY).unwrap()).unwrap();
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        thread::spawn(move || {
+            for tcp in listener.incoming().flatten() {
+                let acceptor = acceptor.clone();
+                thread::spawn(move || {
+                    if let Ok(mut stream) = acceptor.accept(tcp) {
+                        let _ = stream.read(&mut [0u8; 1]);
+                    }
+                });
+            }
+        });
+        Url::parse(&format!("wss://localhost:{}/ws", port)).unwrap()
+    }
+
+    fn cert_file(name: &str, pem: &[u8]) -> PathBuf {
+        let path = std::env::temp_dir().join(format!("rengine_tls_{}_{}.crt", std::process::id(), name));
+        fs::write(&path, pem).unwrap();
+        path
+    }
+
+    fn trusting(name: &str, pem: &[u8], pin: &str) -> TlsSettings {
+        TlsSettings { ca_file: cert_file(name, pem).display().to_string(), pin_sha256: pin.to_string(), insecure: false }
+    }
+
+    fn tls_error(result: Result<Box<dyn NetworkStream + Send>, NetError>) -> String {
+        match result {
+            Ok(_) => panic!("connection should have failed"),
+            Err(NetError::Tls(msg)) => msg,
+            Err(err) => panic!("expected TLS error, got {:?}", err),
+        }
+    }
+
+    #[test]
+    fn ca_file_is_trusted() {
+        let url = tls_server();
+        assert!(connect(&url, &trusting("ca", CA_CERT, "")).is_ok());
+    }
+
+    #[test]
+    fn wrong_ca_is_rejected() {
+        let url = tls_server();
+        tls_error(connect(&url, &trusting("other_ca", OTHER_CA_CERT, "")));
+        // Not in the system roots either
+        tls_error(connect(&url, &TlsSettings::default()));
+    }
+
+    #[test]
+    fn pinned_certificate_matches() {
+        let url = tls_server();
+        assert!(connect(&url, &trusting("pin", CA_CERT, SERVER_PIN)).is_ok());
+        // Lower case without colons is the same pin
+        let pin = SERVER_PIN.replace(':', "").to_lowercase();
+        assert!(connect(&url, &trusting("pin_plain", CA_CERT, &pin)).is_ok());
+    }
+
+    #[test]
+    fn pin_mismatch_is_rejected() {
+        let url = tls_server();
+        let pin = "4B:BA:80:A0:80:4B:30:E5:49:E8:1C:51:C1:3D:CE:27:7C:54:F7:04:D1:05:27:AE:40:68:F4:4C:25:D8:D7:C0";
+        let msg = tls_error(connect(&url, &trusting("pin_other", CA_CERT, pin)));
+        assert!(msg.contains("does not match") && msg.contains(SERVER_PIN), "{}", msg);
+    }
+
+    #[test]
+    fn insecure_accepts_any_certificate() {
+        let url = tls_server();
+        let tls = TlsSettings { insecure: true, ..TlsSettings::default() };
+        assert!(connect(&url, &tls).is_ok());
+    }
+
+    #[test]
+    fn closed_port_is_a_connect_error() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let port = listener.local_addr().unwrap().port();
+        drop(listener);
+        let url = Url::parse(&format!("ws://127.0.0.1:{}/ws", port)).unwrap();
+        assert!(matches!(connect(&url, &TlsSettings::default()), Err(NetError::Connect(_))));
+    }
+
+    #[test]
+    fn fingerprints_parse_and_format() {
+        let pin = parse_fingerprint(SERVER_PIN).unwrap();
+        assert_eq!(format_fingerprint(&pin), SERVER_PIN);
+        assert_eq!(parse_fingerprint(&SERVER_PIN[3..]), None);
+        assert_eq!(parse_fingerprint(&SERVER_PIN.replace('C', "G")), None);
+
+        let tls = TlsSettings { pin_sha256: "not a pin".to_string(), ..TlsSettings::default() };
+        assert_eq!(tls.sanitized().pin_sha256, "");
+    }
+}
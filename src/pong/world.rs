@@ -0,0 +1,1127 @@
+// Renderer independent pong simulation. Nothing in here may touch raylib so the
+// rules can run in tests, servers and bots without a window.
+
+use serde::{Deserialize, Serialize};
+
+use super::physics::{sweep_aabb, sweep_wall, Hit};
+use super::rng::GameRng;
+
+pub const RES_WIDTH: i32 = 1280;
+pub const RES_HEIGHT: i32 = 720;
+
+// Simulation runs at fixed rate, tick number means the same on every machine.
+pub const TICK_RATE: u32 = 120;
+pub const TICK_DT: f32 = 1.0 / TICK_RATE as f32;
+
+const FIELD_WIDTH: f32 = RES_WIDTH as f32;
+const FIELD_HEIGHT: f32 = RES_HEIGHT as f32;
+
+// Speeds are in pixels per second.
+pub const PADDLE_WIDTH: f32 = 40.0;
+pub const PADDLE_HEIGHT: f32 = 200.0;
+pub const PADDLE_SPEED: f32 = 480.0;
+pub const PADDLE_OFFSET: f32 = 2.0;
+
+pub const BALL_WIDTH: f32 = 40.0;
+pub const BALL_HEIGHT: f32 = 40.0;
+pub const BALL_SPEED: i32 = 600;
+// Random horizontal speed gained on each paddle hit
+pub const BALL_SPEEDUP: i32 = 300;
+// Random vertical speed given to the ball on serve
+pub const BALL_SPREAD: i32 = 180;
+pub const BALL_MAX_SPEED: f32 = 3000.0;
+// Outgoing angle when the ball hits the very edge of a paddle, 60 degrees
+pub const BALL_MAX_BOUNCE_ANGLE: f32 = std::f32::consts::PI / 3.0;
+// Ball can hit a wall and a paddle in a corner during one tick, more than that is a bug
+const MAX_COLLISIONS_PER_TICK: usize = 4;
+
+pub const WINNING_SCORE: i32 = 10;
+
+// Points in a set needed to win, rules for the whole match and optional time limit of each set.
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(default)]
+pub struct MatchRules {
+    pub points_to_win: i32,
+    // Set continues past points_to_win until one player leads by two
+    pub win_by_two: bool,
+    // Number of sets, match is won by whoever takes the majority
+    pub best_of: u32,
+    // Time of ball in play per set, 0 means unlimited. When it runs out the leader
+    // takes the set, tied set is decided by the next point (sudden death).
+    pub time_limit_secs: u32,
+}
+
+impl Default for MatchRules {
+    fn default() -> MatchRules {
+        MatchRules {
+            points_to_win: WINNING_SCORE,
+            win_by_two: false,
+            best_of: 1,
+            time_limit_secs: 0,
+        }
+    }
+}
+
+// Rules selectable from the menu
+pub const MATCH_PRESETS: [(&str, MatchRules); 4] = [
+    ("Classic to 10", MatchRules { points_to_win: WINNING_SCORE, win_by_two: false, best_of: 1, time_limit_secs: 0 }),
+    ("Best of 3 to 11", MatchRules { points_to_win: 11, win_by_two: true, best_of: 3, time_limit_secs: 0 }),
+    ("Best of 5 to 21", MatchRules { points_to_win: 21, win_by_two: true, best_of: 5, time_limit_secs: 0 }),
+    ("Quick 3 minutes", MatchRules { points_to_win: 5, win_by_two: false, best_of: 1, time_limit_secs: 180 }),
+];
+
+impl MatchRules {
+    // Values loaded from a file can be anything, keep them playable.
+    pub fn sanitized(&self) -> MatchRules {
+        MatchRules {
+            points_to_win: self.points_to_win.max(1),
+            win_by_two: self.win_by_two,
+            best_of: self.best_of.max(1),
+            time_limit_secs: self.time_limit_secs,
+        }
+    }
+
+    pub fn sets_to_win(&self) -> u32 {
+        self.best_of / 2 + 1
+    }
+
+    pub fn time_limit_ticks(&self) -> Option<u64> {
+        if self.time_limit_secs == 0 {
+            None
+        } else {
+            Some(self.time_limit_secs as u64 * TICK_RATE as u64)
+        }
+    }
+
+    // Winner of a set with given points, ignoring the time limit.
+    pub fn set_winner(&self, left: i32, right: i32) -> Option<ScreenSide> {
+        let (leader, lead, trail) = if left > right { (ScreenSide::Left, left, right) } else { (ScreenSide::Right, right, left) };
+        if lead < self.points_to_win || lead == trail {
+            return None;
+        }
+        if self.win_by_two && lead - trail < 2 {
+            return None;
+        }
+        Some(leader)
+    }
+
+    pub fn name(&self) -> String {
+        for (name, rules) in MATCH_PRESETS.iter() {
+            if rules == self {
+                return name.to_string();
+            }
+        }
+        "Custom".to_string()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ScreenSide {
+    Left,
+    Right,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Rect {
+    pub x: f32,
+    pub y: f32,
+    pub width: f32,
+    pub height: f32,
+}
+
+impl ScreenSide {
+    pub fn opposite(&self) -> ScreenSide {
+        match self {
+            ScreenSide::Left => ScreenSide::Right,
+            ScreenSide::Right => ScreenSide::Left,
+        }
+    }
+}
+
+impl Rect {
+    pub fn overlaps(&self, other: &Rect) -> bool {
+        self.x < other.x + other.width && self.x + self.width > other.x &&
+            self.y < other.y + other.height && self.y + self.height > other.y
+    }
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct PaddleInput {
+    pub up: bool,
+    pub down: bool,
+    // Analog stick, -1.0 full speed up to 1.0 full speed down. Used when no button is held,
+    // values must come from quantize_axis so replays reproduce them exactly.
+    pub axis: f32,
+}
+
+// Round analog input to the precision stored in replays and sent over the network.
+pub fn quantize_axis(value: f32) -> f32 {
+    (value.max(-1.0).min(1.0) * 127.0).round() / 127.0
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct WorldInputs {
+    pub left: PaddleInput,
+    pub right: PaddleInput,
+    // Launch the ball when waiting for serve
+    pub serve: bool,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum WorldEvent {
+    PaddleBounce(ScreenSide), // Ball bounced off paddle on given side
+    WallBounce, // Ball bounced off top or bottom wall
+    Scored(ScreenSide), // Player on given side scored a point
+    Served(ScreenSide), // Ball was launched toward given side
+    SetWon(ScreenSide), // Player on given side won the current set
+    Won(ScreenSide), // Player on given side won the match
+}
+
+#[derive(Debug, Clone)]
+pub struct Paddle {
+    pub pos_x: f32,
+    pub pos_y: f32,
+    pub width: f32,
+    pub height: f32,
+    // Vertical speed during last tick, pixels per second
+    pub velocity_y: f32,
+    pub side: ScreenSide,
+}
+
+impl Paddle {
+    pub fn new(side: ScreenSide) -> Paddle {
+        Paddle {
+            pos_x: if side == ScreenSide::Left { 0.0 } else { FIELD_WIDTH },
+            pos_y: FIELD_HEIGHT/2.0,
+            width: PADDLE_WIDTH,
+            height: PADDLE_HEIGHT,
+            velocity_y: 0.0,
+            side,
+        }
+    }
+
+    pub fn rect(&self) -> Rect {
+        if self.side == ScreenSide::Left {
+            Rect { x: self.pos_x + PADDLE_OFFSET, y: self.pos_y - self.height/2.0, width: self.width, height: self.height }
+        } else {
+            Rect { x: self.pos_x - PADDLE_OFFSET - self.width, y: self.pos_y - self.height/2.0, width: self.width, height: self.height }
+        }
+    }
+
+    fn update(&mut self, input: PaddleInput) {
+        self.velocity_y = 0.0;
+        if input.up && input.down {
+            return;
+        }
+
+        let direction = if input.up {
+            -1.0
+        } else if input.down {
+            1.0
+        } else {
+            input.axis
+        };
+        if (self.pos_y > self.height/2.0 && direction < 0.0)
+            || (self.pos_y < (FIELD_HEIGHT - self.height/2.0) && direction > 0.0) {
+            self.velocity_y = direction * PADDLE_SPEED;
+        }
+        self.pos_y = self.pos_y + self.velocity_y * TICK_DT;
+    }
+}
+
+// Tunables describing how the ball reacts to paddles. Different game modes can use
+// different sets to change the feel of the game.
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct BallPhysics {
+    // Upper bound of ball speed (pixels per second), paddle hits never accelerate it past this value
+    pub max_speed: f32,
+    // Outgoing angle (radians) for a hit at the paddle edge, hit in the center goes straight
+    pub max_bounce_angle: f32,
+    // Portion of paddle vertical speed turned into spin on hit, 0.0 disables spin
+    pub spin_transfer: f32,
+    // Vertical acceleration caused by one unit of spin, relative to horizontal ball speed
+    pub spin_curve: f32,
+    // Fraction of spin lost every second
+    pub spin_decay: f32,
+}
+
+impl BallPhysics {
+    // Plain deflection by hit position, paddle movement does not affect the ball.
+    pub fn classic() -> BallPhysics {
+        BallPhysics {
+            max_speed: BALL_MAX_SPEED,
+            max_bounce_angle: BALL_MAX_BOUNCE_ANGLE,
+            spin_transfer: 0.0,
+            spin_curve: 0.0,
+            spin_decay: 0.0,
+        }
+    }
+
+    // Values loaded from a file can be anything, the ball must keep moving at a finite speed.
+    pub fn is_valid(&self) -> bool {
+        let values = [self.max_speed, self.max_bounce_angle, self.spin_transfer, self.spin_curve, self.spin_decay];
+        values.iter().all(|value| value.is_finite() && *value >= 0.0) && self.max_speed > 0.0
+    }
+}
+
+impl Default for BallPhysics {
+    fn default() -> BallPhysics {
+        BallPhysics {
+            max_speed: BALL_MAX_SPEED,
+            max_bounce_angle: BALL_MAX_BOUNCE_ANGLE,
+            spin_transfer: 0.5,
+            spin_curve: 1.0,
+            spin_decay: 0.8,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Collider {
+    Wall,
+    Paddle(ScreenSide),
+}
+
+#[derive(Debug, Clone)]
+pub struct Ball {
+    pub pos_x: f32,
+    pub pos_y: f32,
+    pub width: f32,
+    pub height: f32,
+    pub velocity_x: f32,
+    pub velocity_y: f32,
+    // Positive spin curves the ball downwards
+    pub spin: f32,
+    pub physics: BallPhysics,
+}
+
+impl Default for Ball {
+    fn default() -> Ball {
+        Ball::new()
+    }
+}
+
+impl Ball {
+    pub fn new() -> Ball {
+        Ball {
+            pos_x: FIELD_WIDTH/2.0,
+            pos_y: FIELD_HEIGHT/2.0,
+            width: BALL_WIDTH,
+            height: BALL_HEIGHT,
+            velocity_x: BALL_SPEED as f32,
+            velocity_y: BALL_SPEED as f32,
+            spin: 0.0,
+            physics: BallPhysics::default(),
+        }
+    }
+
+    pub fn rect(&self) -> Rect {
+        Rect { x: self.pos_x - self.width/2.0, y: self.pos_y - self.height/2.0, width: self.width, height: self.height }
+    }
+
+    fn clamp_speed(&mut self) {
+        let speed = (self.velocity_x * self.velocity_x + self.velocity_y * self.velocity_y).sqrt();
+        if speed > self.physics.max_speed {
+            let scale = self.physics.max_speed / speed;
+            self.velocity_x = self.velocity_x * scale;
+            self.velocity_y = self.velocity_y * scale;
+        }
+    }
+
+    // Paddle moved into the ball during this tick, push the ball out over the closer horizontal face.
+    fn push_out(&mut self, paddle: &Paddle) {
+        let paddle_rect = paddle.rect();
+        if self.pos_y < paddle.pos_y {
+            self.pos_y = paddle_rect.y - self.height/2.0;
+            self.velocity_y = -self.velocity_y.abs();
+        } else {
+            self.pos_y = paddle_rect.y + paddle_rect.height + self.height/2.0;
+            self.velocity_y = self.velocity_y.abs();
+        }
+        self.pos_y = self.pos_y.clamp(self.height/2.0, FIELD_HEIGHT - self.height/2.0);
+    }
+
+    // Classic pong deflection, distance of the hit from paddle center decides the outgoing angle.
+    fn bounce_off_paddle(&mut self, paddle: &Paddle, normal_x: f32, rng: &mut GameRng) {
+        let reach = paddle.height/2.0 + self.height/2.0;
+        let offset = ((self.pos_y - paddle.pos_y) / reach).clamp(-1.0, 1.0);
+        let angle = offset * self.physics.max_bounce_angle;
+        let speed = self.velocity_x.abs().max(BALL_SPEED as f32) + rng.range(0, BALL_SPEEDUP) as f32;
+        self.velocity_x = normal_x * speed * angle.cos();
+        self.velocity_y = speed * angle.sin();
+        self.spin = self.spin + paddle.velocity_y * self.physics.spin_transfer / PADDLE_SPEED;
+        self.clamp_speed();
+    }
+
+    fn apply_spin(&mut self) {
+        if self.spin == 0.0 {
+            return;
+        }
+        self.velocity_y = self.velocity_y + self.spin * self.physics.spin_curve * self.velocity_x.abs() * TICK_DT;
+        self.spin = self.spin * (1.0 - self.physics.spin_decay * TICK_DT).max(0.0);
+        self.clamp_speed();
+    }
+
+    // Earliest collision along the movement (dx, dy) among walls and paddles.
+    fn first_hit(&self, dx: f32, dy: f32, paddles: &[&Paddle; 2]) -> Option<(Hit, Collider)> {
+        let rect = self.rect();
+        let mut first: Option<(Hit, Collider)> = None;
+        let mut candidates: Vec<(Hit, Collider)> = Vec::new();
+
+        if let Some(time) = sweep_wall(rect.y, rect.y + rect.height, dy, 0.0) {
+            candidates.push((Hit { time, normal_x: 0.0, normal_y: 1.0 }, Collider::Wall));
+        }
+        if let Some(time) = sweep_wall(rect.y, rect.y + rect.height, dy, FIELD_HEIGHT) {
+            candidates.push((Hit { time, normal_x: 0.0, normal_y: -1.0 }, Collider::Wall));
+        }
+        for paddle in paddles.iter() {
+            if let Some(hit) = sweep_aabb(&rect, dx, dy, &paddle.rect()) {
+                candidates.push((hit, Collider::Paddle(paddle.side)));
+            }
+        }
+
+        for candidate in candidates {
+            if first.is_none() || candidate.0.time < first.unwrap().0.time {
+                first = Some(candidate);
+            }
+        }
+        first
+    }
+
+    fn update(&mut self, player_left: &Paddle, player_right: &Paddle, rng: &mut GameRng, events: &mut Vec<WorldEvent>) {
+        let paddles = [player_left, player_right];
+        self.apply_spin();
+        for paddle in paddles.iter() {
+            if self.rect().overlaps(&paddle.rect()) {
+                self.push_out(paddle);
+            }
+        }
+
+        // Move through the tick hit by hit, every collision consumes part of the remaining time.
+        let mut remaining = TICK_DT;
+        for _ in 0..MAX_COLLISIONS_PER_TICK {
+            let dx = self.velocity_x * remaining;
+            let dy = self.velocity_y * remaining;
+            let hit = self.first_hit(dx, dy, &paddles);
+            if hit.is_none() {
+                self.pos_x = self.pos_x + dx;
+                self.pos_y = self.pos_y + dy;
+                break;
+            }
+
+            let (hit, collider) = hit.unwrap();
+            self.pos_x = self.pos_x + dx * hit.time;
+            self.pos_y = self.pos_y + dy * hit.time;
+            remaining = remaining * (1.0 - hit.time);
+            if hit.normal_y != 0.0 {
+                self.velocity_y = hit.normal_y * self.velocity_y.abs();
+            }
+            match collider {
+                Collider::Wall => events.push(WorldEvent::WallBounce),
+                Collider::Paddle(side) => {
+                    if hit.normal_x != 0.0 {
+                        let paddle = if side == ScreenSide::Left { player_left } else { player_right };
+                        self.bounce_off_paddle(paddle, hit.normal_x, rng);
+                    }
+                    events.push(WorldEvent::PaddleBounce(side));
+                },
+            }
+        }
+        // Out of collision iterations the ball stays where the last hit left it, rest of the tick is dropped.
+
+        if self.pos_x < self.width/2.0 {
+            self.pos_x = self.width/2.0;
+            self.velocity_x = self.velocity_x.abs();
+            events.push(WorldEvent::Scored(ScreenSide::Right));
+        } else if self.pos_x > (FIELD_WIDTH-self.width/2.0) {
+            self.pos_x = FIELD_WIDTH - self.width/2.0;
+            self.velocity_x = -self.velocity_x.abs();
+            events.push(WorldEvent::Scored(ScreenSide::Left));
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ServeDirection {
+    TowardConceder, // Ball goes toward the player who lost the point
+    Alternating, // Every serve goes to the opposite side than the previous one
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ServeStart {
+    Countdown, // Ball is launched automatically when countdown ends
+    PressToServe, // Ball waits until serve input is given
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct ServeRules {
+    pub direction: ServeDirection,
+    pub start: ServeStart,
+    // Pause after a point before the ball is put back to the center
+    pub celebration_ticks: u32,
+    // Length of the countdown for ServeStart::Countdown
+    pub countdown_ticks: u32,
+}
+
+impl Default for ServeRules {
+    fn default() -> ServeRules {
+        ServeRules {
+            direction: ServeDirection::TowardConceder,
+            start: ServeStart::PressToServe,
+            celebration_ticks: TICK_RATE,
+            countdown_ticks: 3 * TICK_RATE,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum MatchPhase {
+    Playing, // Ball in play
+    Celebrating { ticks_left: u32 }, // Point was just scored, everything is frozen
+    WaitingServe, // Ball in the center, waiting for serve input
+    Countdown { ticks_left: u32 }, // Ball in the center, launched when countdown ends
+    Finished, // One of the players won
+}
+
+#[derive(Debug, Clone)]
+pub struct PongWorld {
+    pub paddle_left: Paddle,
+    pub paddle_right: Paddle,
+    pub ball: Ball,
+    // Points in the current set
+    pub score_left: i32,
+    pub score_right: i32,
+    pub rules: MatchRules,
+    pub sets_left: u32,
+    pub sets_right: u32,
+    // Final points of every finished set
+    pub set_scores: Vec<(i32, i32)>,
+    // Ticks of ball in play during the current set
+    pub set_ticks: u64,
+    // Points played in the whole match
+    pub points_played: u32,
+    // Number of simulation steps since the match started
+    pub tick: u64,
+    // Source of all gameplay randomness, seeding it the same way reproduces the match
+    pub rng: GameRng,
+    pub phase: MatchPhase,
+    pub serve_rules: ServeRules,
+    // Side the ball was sent toward on the last serve
+    pub last_serve: ScreenSide,
+    // Side the next serve goes toward, decided when the point is scored
+    pub pending_serve: Option<ScreenSide>,
+    // Points conceded by this side are decided on another machine (the opponent online),
+    // the ball stops at that goal until award_point says the point counts
+    pub remote_goal: Option<ScreenSide>,
+}
+
+impl PongWorld {
+    pub fn new(seed: u64) -> PongWorld {
+        PongWorld {
+            paddle_left: Paddle::new(ScreenSide::Left),
+            paddle_right: Paddle::new(ScreenSide::Right),
+            ball: Ball::new(),
+            score_left: 0,
+            score_right: 0,
+            rules: MatchRules::default(),
+            sets_left: 0,
+            sets_right: 0,
+            set_scores: Vec::new(),
+            set_ticks: 0,
+            points_played: 0,
+            tick: 0,
+            rng: GameRng::new(seed),
+            phase: MatchPhase::Playing,
+            serve_rules: ServeRules::default(),
+            last_serve: ScreenSide::Right,
+            pending_serve: None,
+            remote_goal: None,
+        }
+    }
+
+    // Restart the random sequence, peers using the same seed see identical ball trajectories.
+    pub fn reseed(&mut self, seed: u64) {
+        self.rng = GameRng::new(seed);
+    }
+
+    // Start a new match, scores are cleared and all entities are centered.
+    pub fn reset(&mut self) {
+        self.ball.pos_x = FIELD_WIDTH/2.0;
+        self.ball.pos_y = FIELD_HEIGHT/2.0;
+        self.ball.velocity_x = BALL_SPEED as f32;
+        self.ball.velocity_y = BALL_SPEED as f32;
+        self.ball.spin = 0.0;
+        self.paddle_left.pos_y = FIELD_HEIGHT/2.0;
+        self.paddle_left.velocity_y = 0.0;
+        self.paddle_right.pos_y = FIELD_HEIGHT/2.0;
+        self.paddle_right.velocity_y = 0.0;
+        self.score_left = 0;
+        self.score_right = 0;
+        self.sets_left = 0;
+        self.sets_right = 0;
+        self.set_scores.clear();
+        self.set_ticks = 0;
+        self.points_played = 0;
+        self.tick = 0;
+        self.phase = MatchPhase::Playing;
+        self.last_serve = ScreenSide::Right;
+        self.pending_serve = None;
+    }
+
+    // Put ball and paddles back to the center, ball stays still until it is launched.
+    fn place_for_serve(&mut self) {
+        self.ball.pos_x = FIELD_WIDTH/2.0;
+        self.ball.pos_y = FIELD_HEIGHT/2.0;
+        self.ball.velocity_x = 0.0;
+        self.ball.velocity_y = 0.0;
+        self.ball.spin = 0.0;
+        self.paddle_left.pos_y = FIELD_HEIGHT/2.0;
+        self.paddle_right.pos_y = FIELD_HEIGHT/2.0;
+    }
+
+    fn launch(&mut self, toward: ScreenSide, events: &mut Vec<WorldEvent>) {
+        self.ball.velocity_x = if toward == ScreenSide::Left { -BALL_SPEED as f32 } else { BALL_SPEED as f32 };
+        self.ball.velocity_y = self.rng.range(-BALL_SPREAD - BALL_SPEED, BALL_SPEED + BALL_SPREAD) as f32;
+        self.last_serve = toward;
+        self.pending_serve = None;
+        self.phase = MatchPhase::Playing;
+        events.push(WorldEvent::Served(toward));
+    }
+
+    fn next_serve(&self, scorer: ScreenSide) -> ScreenSide {
+        match self.serve_rules.direction {
+            ServeDirection::TowardConceder => scorer.opposite(),
+            ServeDirection::Alternating => self.last_serve.opposite(),
+        }
+    }
+
+    pub fn winner(&self) -> Option<ScreenSide> {
+        let sets_to_win = self.rules.sets_to_win();
+        if self.sets_left >= sets_to_win {
+            Some(ScreenSide::Left)
+        } else if self.sets_right >= sets_to_win {
+            Some(ScreenSide::Right)
+        } else {
+            None
+        }
+    }
+
+    // Time limit of the set ran out, next point decides a tied set.
+    pub fn is_overtime(&self) -> bool {
+        match self.rules.time_limit_ticks() {
+            Some(limit) => self.set_ticks >= limit,
+            None => false,
+        }
+    }
+
+    // Seconds of play left in the current set, None when sets are not timed.
+    pub fn set_time_left(&self) -> Option<u32> {
+        let limit = self.rules.time_limit_ticks()?;
+        let ticks_left = limit.saturating_sub(self.set_ticks);
+        Some(ticks_left.div_ceil(TICK_RATE as u64) as u32)
+    }
+
+    fn set_winner(&self) -> Option<ScreenSide> {
+        if self.is_overtime() && self.score_left != self.score_right {
+            return Some(if self.score_left > self.score_right { ScreenSide::Left } else { ScreenSide::Right });
+        }
+        self.rules.set_winner(self.score_left, self.score_right)
+    }
+
+    // Advance simulation by a single step and report everything that happened during it.
+    pub fn step(&mut self, inputs: &WorldInputs) -> Vec<WorldEvent> {
+        let mut events: Vec<WorldEvent> = Vec::new();
+        self.tick = self.tick + 1;
+        match self.phase {
+            MatchPhase::Playing => {
+                self.paddle_left.update(inputs.left);
+                self.paddle_right.update(inputs.right);
+                self.ball.update(&self.paddle_left, &self.paddle_right, &mut self.rng, &mut events);
+                self.hold_remote_goal(&mut events);
+                self.set_ticks = self.set_ticks + 1;
+                self.apply_scores(&mut events);
+            },
+            MatchPhase::Celebrating { ticks_left } => {
+                if ticks_left > 1 {
+                    self.phase = MatchPhase::Celebrating { ticks_left: ticks_left - 1 };
+                } else {
+                    self.place_for_serve();
+                    self.phase = match self.serve_rules.start {
+                        ServeStart::Countdown => MatchPhase::Countdown { ticks_left: self.serve_rules.countdown_ticks },
+                        ServeStart::PressToServe => MatchPhase::WaitingServe,
+                    };
+                }
+            },
+            MatchPhase::WaitingServe => {
+                self.paddle_left.update(inputs.left);
+                self.paddle_right.update(inputs.right);
+                if inputs.serve {
+                    let toward = self.next_serve_side();
+                    self.launch(toward, &mut events);
+                }
+            },
+            MatchPhase::Countdown { ticks_left } => {
+                self.paddle_left.update(inputs.left);
+                self.paddle_right.update(inputs.right);
+                if ticks_left > 1 {
+                    self.phase = MatchPhase::Countdown { ticks_left: ticks_left - 1 };
+                } else {
+                    let toward = self.next_serve_side();
+                    self.launch(toward, &mut events);
+                }
+            },
+            MatchPhase::Finished => (),
+        }
+        events
+    }
+
+    fn hold_remote_goal(&mut self, events: &mut Vec<WorldEvent>) {
+        let remote_goal = match self.remote_goal {
+            Some(side) => side,
+            None => return,
+        };
+        let count = events.len();
+        events.retain(|event| *event != WorldEvent::Scored(remote_goal.opposite()));
+        if events.len() != count {
+            self.ball.velocity_x = 0.0;
+            self.ball.velocity_y = 0.0;
+            self.ball.spin = 0.0;
+        }
+    }
+
+    // Point decided outside of the simulation, counts like one scored here.
+    pub fn award_point(&mut self, scorer: ScreenSide) -> Vec<WorldEvent> {
+        if self.phase == MatchPhase::Finished {
+            return Vec::new();
+        }
+        let mut events = vec![WorldEvent::Scored(scorer)];
+        self.apply_scores(&mut events);
+        events
+    }
+
+    // Take over the score decided elsewhere when ours differs, returns whether it did.
+    pub fn reconcile_score(&mut self, points_played: u32, score: (i32, i32), sets: (u32, u32)) -> bool {
+        if self.points_played == points_played && (self.score_left, self.score_right) == score && (self.sets_left, self.sets_right) == sets {
+            return false;
+        }
+        let own_score = (self.score_left, self.score_right);
+        self.points_played = points_played;
+        self.score_left = score.0;
+        self.score_right = score.1;
+        self.sets_left = sets.0;
+        self.sets_right = sets.1;
+        // One score per finished set. A set which ended elsewhere keeps our own count of it, except the
+        // last one of a match, its final score is the one we got.
+        let finished_sets = (sets.0 + sets.1) as usize;
+        self.set_scores.truncate(finished_sets);
+        while self.set_scores.len() < finished_sets {
+            self.set_scores.push(own_score);
+        }
+        if self.winner().is_some() {
+            self.set_scores[finished_sets - 1] = score;
+            self.phase = MatchPhase::Finished;
+        } else if self.phase == MatchPhase::Finished {
+            // We thought the match was over, it goes on with the next serve
+            self.phase = MatchPhase::Celebrating { ticks_left: self.serve_rules.celebration_ticks.max(1) };
+        }
+        true
+    }
+
+    fn apply_scores(&mut self, events: &mut Vec<WorldEvent>) {
+        let mut scorer: Option<ScreenSide> = None;
+        for event in events.iter() {
+            if let WorldEvent::Scored(side) = event {
+                if *side == ScreenSide::Left {
+                    self.score_left = self.score_left + 1;
+                } else {
+                    self.score_right = self.score_right + 1;
+                }
+                self.points_played = self.points_played + 1;
+                scorer = Some(*side);
+            }
+        }
+        let time_ran_out = self.rules.time_limit_ticks() == Some(self.set_ticks);
+        if scorer.is_none() && !time_ran_out {
+            return;
+        }
+
+        if let Some(side) = self.set_winner() {
+            self.finish_set(side, events);
+        } else if let Some(scorer) = scorer {
+            self.pending_serve = Some(self.next_serve(scorer));
+            self.phase = MatchPhase::Celebrating { ticks_left: self.serve_rules.celebration_ticks.max(1) };
+        }
+    }
+
+    fn finish_set(&mut self, side: ScreenSide, events: &mut Vec<WorldEvent>) {
+        self.set_scores.push((self.score_left, self.score_right));
+        if side == ScreenSide::Left {
+            self.sets_left = self.sets_left + 1;
+        } else {
+            self.sets_right = self.sets_right + 1;
+        }
+        events.push(WorldEvent::SetWon(side));
+
+        if let Some(winner) = self.winner() {
+            self.phase = MatchPhase::Finished;
+            events.push(WorldEvent::Won(winner));
+            return;
+        }
+        self.score_left = 0;
+        self.score_right = 0;
+        self.set_ticks = 0;
+        self.pending_serve = Some(self.next_serve(side));
+        self.phase = MatchPhase::Celebrating { ticks_left: self.serve_rules.celebration_ticks.max(1) };
+    }
+
+    // Side the upcoming serve goes toward.
+    pub fn next_serve_side(&self) -> ScreenSide {
+        self.pending_serve.unwrap_or(self.last_serve.opposite())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ball_at(pos_x: f32, pos_y: f32, velocity_x: f32, velocity_y: f32) -> Ball {
+        let mut ball = Ball::new();
+        ball.physics = BallPhysics::classic();
+        ball.pos_x = pos_x;
+        ball.pos_y = pos_y;
+        ball.velocity_x = velocity_x;
+        ball.velocity_y = velocity_y;
+        ball
+    }
+
+    fn update(ball: &mut Ball, left: &Paddle, right: &Paddle) -> Vec<WorldEvent> {
+        let mut rng = GameRng::new(7);
+        let mut events = Vec::new();
+        ball.update(left, right, &mut rng, &mut events);
+        events
+    }
+
+    fn speeds() -> [f32; 3] {
+        [BALL_MAX_SPEED, BALL_MAX_SPEED * 2.0, BALL_MAX_SPEED * 20.0]
+    }
+
+    #[test]
+    fn fast_ball_bounces_off_left_paddle() {
+        let (left, right) = (Paddle::new(ScreenSide::Left), Paddle::new(ScreenSide::Right));
+        let face = left.rect().x + left.rect().width;
+        for speed in speeds() {
+            let mut ball = ball_at(face + BALL_WIDTH/2.0 + 5.0, left.pos_y, -speed, 0.0);
+            let events = update(&mut ball, &left, &right);
+            assert_eq!(events, vec![WorldEvent::PaddleBounce(ScreenSide::Left)], "speed {}", speed);
+            assert!(ball.velocity_x > 0.0);
+            assert!(ball.rect().x >= face - 0.01);
+            assert!(ball.velocity_x.hypot(ball.velocity_y) <= BALL_MAX_SPEED + 0.01);
+        }
+    }
+
+    #[test]
+    fn fast_ball_bounces_off_right_paddle() {
+        let (left, right) = (Paddle::new(ScreenSide::Left), Paddle::new(ScreenSide::Right));
+        let face = right.rect().x;
+        for speed in speeds() {
+            let mut ball = ball_at(face - BALL_WIDTH/2.0 - 5.0, right.pos_y, speed, 0.0);
+            let events = update(&mut ball, &left, &right);
+            assert_eq!(events, vec![WorldEvent::PaddleBounce(ScreenSide::Right)], "speed {}", speed);
+            assert!(ball.velocity_x < 0.0);
+            assert!(ball.rect().x + ball.width <= face + 0.01);
+            assert!(ball.velocity_x.hypot(ball.velocity_y) <= BALL_MAX_SPEED + 0.01);
+        }
+    }
+
+    #[test]
+    fn fast_ball_bounces_off_walls() {
+        let (left, right) = (Paddle::new(ScreenSide::Left), Paddle::new(ScreenSide::Right));
+        for speed in speeds() {
+            let mut ball = ball_at(FIELD_WIDTH/2.0, BALL_HEIGHT/2.0 + 5.0, 0.0, -speed);
+            assert_eq!(update(&mut ball, &left, &right), vec![WorldEvent::WallBounce], "speed {}", speed);
+            assert!(ball.velocity_y > 0.0);
+            assert!(ball.rect().y >= -0.01);
+
+            let mut ball = ball_at(FIELD_WIDTH/2.0, FIELD_HEIGHT - BALL_HEIGHT/2.0 - 5.0, 0.0, speed);
+            assert_eq!(update(&mut ball, &left, &right), vec![WorldEvent::WallBounce], "speed {}", speed);
+            assert!(ball.velocity_y < 0.0);
+            assert!(ball.rect().y + ball.height <= FIELD_HEIGHT + 0.01);
+        }
+    }
+
+    #[test]
+    fn missed_ball_scores() {
+        let (left, right) = (Paddle::new(ScreenSide::Left), Paddle::new(ScreenSide::Right));
+        // Far above the left paddle
+        let mut ball = ball_at(BALL_WIDTH, BALL_HEIGHT, -BALL_MAX_SPEED, 0.0);
+        assert_eq!(update(&mut ball, &left, &right), vec![WorldEvent::Scored(ScreenSide::Right)]);
+        assert_eq!(ball.pos_x, BALL_WIDTH/2.0);
+    }
+
+    #[test]
+    fn paddle_edge_hit_deflects_at_max_angle() {
+        let (left, right) = (Paddle::new(ScreenSide::Left), Paddle::new(ScreenSide::Right));
+        let rect = left.rect();
+        // Ball overlaps the top of the front face by one pixel
+        let mut ball = ball_at(rect.x + rect.width + BALL_WIDTH/2.0 + 5.0, rect.y - BALL_HEIGHT/2.0 + 1.0, -BALL_MAX_SPEED, 0.0);
+        assert_eq!(update(&mut ball, &left, &right), vec![WorldEvent::PaddleBounce(ScreenSide::Left)]);
+        let angle = (-ball.velocity_y).atan2(ball.velocity_x);
+        assert!(ball.velocity_x > 0.0 && ball.velocity_y < 0.0);
+        assert!((angle - BALL_MAX_BOUNCE_ANGLE).abs() < 0.02, "angle {}", angle);
+    }
+
+    #[test]
+    fn paddle_corner_hit_bounces_vertically() {
+        let (left, right) = (Paddle::new(ScreenSide::Left), Paddle::new(ScreenSide::Right));
+        let rect = left.rect();
+        // Ball reaches the top and front face at the same moment
+        let gap = 10.0;
+        let mut ball = ball_at(rect.x + rect.width + BALL_WIDTH/2.0 + gap, rect.y - BALL_HEIGHT/2.0 - gap, -BALL_MAX_SPEED, BALL_MAX_SPEED);
+        assert_eq!(update(&mut ball, &left, &right), vec![WorldEvent::PaddleBounce(ScreenSide::Left)]);
+        assert!(ball.velocity_y < 0.0);
+        assert!(ball.velocity_x < 0.0);
+        assert!(!ball.rect().overlaps(&rect));
+    }
+
+    #[test]
+    fn wedged_ball_stops_at_collision_limit() {
+        // Ball fits exactly between the top wall and a paddle pushed against it, every hit happens at time 0
+        let mut left = Paddle::new(ScreenSide::Left);
+        left.pos_y = BALL_HEIGHT + left.height/2.0;
+        let right = Paddle::new(ScreenSide::Right);
+        let rect = left.rect();
+        let mut ball = ball_at(rect.x + rect.width/2.0, BALL_HEIGHT/2.0, 0.0, -BALL_MAX_SPEED);
+        let events = update(&mut ball, &left, &right);
+        assert_eq!(events.len(), MAX_COLLISIONS_PER_TICK);
+        assert_eq!(events[0], WorldEvent::WallBounce);
+        assert_eq!(events[1], WorldEvent::PaddleBounce(ScreenSide::Left));
+        assert_eq!(ball.pos_y, BALL_HEIGHT/2.0);
+        assert!(!ball.rect().overlaps(&rect));
+    }
+
+    fn world_with(rules: MatchRules) -> PongWorld {
+        let mut world = PongWorld::new(1);
+        world.rules = rules;
+        world.reset();
+        world
+    }
+
+    fn award(world: &mut PongWorld, scorer: ScreenSide, points: i32) -> Vec<WorldEvent> {
+        let mut events = Vec::new();
+        for _ in 0..points {
+            events = world.award_point(scorer);
+        }
+        events
+    }
+
+    // Inputs drawn from their own generator, always serving so points keep being played.
+    fn scripted_inputs(rng: &mut GameRng) -> WorldInputs {
+        let mut inputs = WorldInputs::default();
+        inputs.left = PaddleInput { up: rng.range(0, 3) == 0, down: rng.range(0, 3) == 0, axis: 0.0 };
+        inputs.right = PaddleInput { up: false, down: false, axis: quantize_axis(rng.range(-127, 127) as f32 / 127.0) };
+        inputs.serve = true;
+        inputs
+    }
+
+    fn play(seed: u64, ticks: u32) -> PongWorld {
+        let mut world = world_with(MATCH_PRESETS[1].1);
+        world.reseed(seed);
+        let mut rng = GameRng::new(99);
+        for _ in 0..ticks {
+            world.step(&scripted_inputs(&mut rng));
+        }
+        world
+    }
+
+    #[test]
+    fn same_seed_and_inputs_give_same_world() {
+        let world = play(42, 20000);
+        assert!(world.points_played > 0);
+        assert_eq!(format!("{:?}", world), format!("{:?}", play(42, 20000)));
+        assert_ne!(format!("{:?}", world), format!("{:?}", play(43, 20000)));
+    }
+
+    #[test]
+    fn point_is_followed_by_celebration_and_serve() {
+        let mut world = PongWorld::new(1);
+        world.serve_rules.celebration_ticks = 2;
+        assert_eq!(world.award_point(ScreenSide::Left), vec![WorldEvent::Scored(ScreenSide::Left)]);
+        assert_eq!(world.phase, MatchPhase::Celebrating { ticks_left: 2 });
+        assert_eq!(world.next_serve_side(), ScreenSide::Right);
+
+        world.step(&WorldInputs::default());
+        world.step(&WorldInputs::default());
+        assert_eq!(world.phase, MatchPhase::WaitingServe);
+        assert_eq!((world.ball.pos_x, world.ball.pos_y), (FIELD_WIDTH/2.0, FIELD_HEIGHT/2.0));
+        assert!(world.step(&WorldInputs::default()).is_empty());
+
+        let inputs = WorldInputs { serve: true, ..WorldInputs::default() };
+        assert_eq!(world.step(&inputs), vec![WorldEvent::Served(ScreenSide::Right)]);
+        assert_eq!(world.phase, MatchPhase::Playing);
+        assert!(world.ball.velocity_x > 0.0);
+    }
+
+    #[test]
+    fn award_point_wins_classic_match() {
+        let mut world = world_with(MatchRules::default());
+        let events = award(&mut world, ScreenSide::Left, WINNING_SCORE - 1);
+        assert_eq!(events, vec![WorldEvent::Scored(ScreenSide::Left)]);
+        assert_eq!((world.score_left, world.score_right), (WINNING_SCORE - 1, 0));
+        assert_eq!(world.next_serve_side(), ScreenSide::Right);
+        assert!(matches!(world.phase, MatchPhase::Celebrating { .. }));
+
+        let events = world.award_point(ScreenSide::Left);
+        assert_eq!(events, vec![WorldEvent::Scored(ScreenSide::Left), WorldEvent::SetWon(ScreenSide::Left), WorldEvent::Won(ScreenSide::Left)]);
+        assert_eq!(world.phase, MatchPhase::Finished);
+        assert_eq!(world.winner(), Some(ScreenSide::Left));
+        assert_eq!(world.set_scores, vec![(WINNING_SCORE, 0)]);
+        assert_eq!(world.points_played, WINNING_SCORE as u32);
+
+        // Nothing counts once the match is over
+        assert!(world.award_point(ScreenSide::Right).is_empty());
+        assert_eq!(world.score_right, 0);
+    }
+
+    #[test]
+    fn presets_are_won_after_enough_sets() {
+        for (name, rules) in MATCH_PRESETS.iter() {
+            assert_eq!(rules.name(), *name);
+            let mut world = world_with(*rules);
+            for set in 0..rules.sets_to_win() {
+                assert_eq!(world.winner(), None, "{} set {}", name, set);
+                let events = award(&mut world, ScreenSide::Right, rules.points_to_win);
+                assert!(events.contains(&WorldEvent::SetWon(ScreenSide::Right)), "{}", name);
+                assert_eq!(world.sets_right, set + 1);
+            }
+            assert_eq!(world.winner(), Some(ScreenSide::Right), "{}", name);
+            assert_eq!(world.phase, MatchPhase::Finished);
+            assert_eq!(world.set_scores.len() as u32, rules.sets_to_win());
+        }
+    }
+
+    #[test]
+    fn win_by_two_extends_the_set() {
+        let rules = MATCH_PRESETS[1].1;
+        assert!(rules.win_by_two);
+        let mut world = world_with(rules);
+        for _ in 0..rules.points_to_win - 1 {
+            world.award_point(ScreenSide::Left);
+            world.award_point(ScreenSide::Right);
+        }
+        let events = world.award_point(ScreenSide::Left);
+        assert!(!events.contains(&WorldEvent::SetWon(ScreenSide::Left)));
+        world.award_point(ScreenSide::Right);
+        world.award_point(ScreenSide::Left);
+        assert_eq!((world.score_left, world.score_right), (rules.points_to_win + 1, rules.points_to_win));
+        assert_eq!(world.sets_left, 0);
+
+        let events = world.award_point(ScreenSide::Left);
+        assert_eq!(events, vec![WorldEvent::Scored(ScreenSide::Left), WorldEvent::SetWon(ScreenSide::Left)]);
+        assert_eq!(world.set_scores, vec![(rules.points_to_win + 2, rules.points_to_win)]);
+        assert_eq!((world.score_left, world.score_right, world.sets_left), (0, 0, 1));
+        // Next set starts with a serve toward the player who lost this one
+        assert_eq!(world.next_serve_side(), ScreenSide::Right);
+        assert_eq!(world.winner(), None);
+
+        assert_eq!(rules.set_winner(rules.points_to_win, rules.points_to_win - 1), None);
+        assert_eq!(rules.set_winner(rules.points_to_win, rules.points_to_win - 2), Some(ScreenSide::Left));
+        assert_eq!(MatchRules::default().set_winner(WINNING_SCORE, WINNING_SCORE - 1), Some(ScreenSide::Left));
+    }
+
+    #[test]
+    fn time_limit_gives_set_to_leader() {
+        let rules = MATCH_PRESETS[3].1;
+        let limit = rules.time_limit_ticks().unwrap();
+        let mut world = world_with(rules);
+        assert_eq!(world.set_time_left(), Some(rules.time_limit_secs));
+        world.award_point(ScreenSide::Left);
+        world.phase = MatchPhase::Playing;
+        world.set_ticks = limit - 1;
+        assert!(!world.is_overtime());
+
+        let events = world.step(&WorldInputs::default());
+        assert_eq!(events, vec![WorldEvent::SetWon(ScreenSide::Left), WorldEvent::Won(ScreenSide::Left)]);
+        assert_eq!(world.set_scores, vec![(1, 0)]);
+        assert_eq!(world.set_time_left(), Some(0));
+    }
+
+    #[test]
+    fn tied_set_goes_to_sudden_death() {
+        let rules = MATCH_PRESETS[3].1;
+        let mut world = world_with(rules);
+        world.award_point(ScreenSide::Left);
+        world.award_point(ScreenSide::Right);
+        world.phase = MatchPhase::Playing;
+        world.set_ticks = rules.time_limit_ticks().unwrap() - 1;
+
+        assert!(world.step(&WorldInputs::default()).is_empty());
+        assert!(world.is_overtime());
+        assert_eq!(world.winner(), None);
+
+        let events = world.award_point(ScreenSide::Right);
+        assert_eq!(events, vec![WorldEvent::Scored(ScreenSide::Right), WorldEvent::SetWon(ScreenSide::Right), WorldEvent::Won(ScreenSide::Right)]);
+        assert_eq!(world.set_scores, vec![(1, 2)]);
+    }
+
+    #[test]
+    fn reconcile_score_takes_over_remote_score() {
+        let mut world = world_with(MatchRules::default());
+        world.award_point(ScreenSide::Left);
+        assert!(!world.reconcile_score(1, (1, 0), (0, 0)));
+
+        assert!(world.reconcile_score(3, (1, 2), (0, 0)));
+        assert_eq!((world.points_played, world.score_left, world.score_right), (3, 1, 2));
+        assert_eq!(world.phase, MatchPhase::Celebrating { ticks_left: world.serve_rules.celebration_ticks });
+
+        // Remote side says the match is over
+        assert!(world.reconcile_score(14, (4, 10), (0, 1)));
+        assert_eq!(world.phase, MatchPhase::Finished);
+        assert_eq!(world.winner(), Some(ScreenSide::Right));
+        assert_eq!(world.set_scores, vec![(4, 10)]);
+
+        // Final point corrected, only the last set score changes
+        assert!(world.reconcile_score(14, (3, 10), (0, 1)));
+        assert_eq!(world.set_scores, vec![(3, 10)]);
+
+        // And taken back, the match goes on
+        assert!(world.reconcile_score(12, (3, 9), (0, 0)));
+        assert_eq!(world.winner(), None);
+        assert!(world.set_scores.is_empty());
+        assert!(matches!(world.phase, MatchPhase::Celebrating { .. }));
+    }
+
+    #[test]
+    fn reconcile_score_keeps_one_score_per_set() {
+        let rules = MATCH_PRESETS[1].1;
+        let mut world = world_with(rules);
+        award(&mut world, ScreenSide::Left, rules.points_to_win);
+        assert_eq!(world.set_scores, vec![(rules.points_to_win, 0)]);
+        award(&mut world, ScreenSide::Right, 5);
+        award(&mut world, ScreenSide::Left, 3);
+
+        // Second set ended elsewhere, our count of it is all we have
+        assert!(world.reconcile_score(20, (0, 0), (1, 1)));
+        assert_eq!(world.set_scores, vec![(rules.points_to_win, 0), (3, 5)]);
+        assert!(matches!(world.phase, MatchPhase::Celebrating { .. }));
+
+        // Match ends with the score of the deciding set
+        assert!(world.reconcile_score(33, (11, 2), (2, 1)));
+        assert_eq!(world.phase, MatchPhase::Finished);
+        assert_eq!(world.winner(), Some(ScreenSide::Left));
+        assert_eq!(world.set_scores, vec![(rules.points_to_win, 0), (3, 5), (11, 2)]);
+
+        // Final point corrected, only the last set score changes
+        assert!(world.reconcile_score(33, (11, 3), (2, 1)));
+        assert_eq!(world.set_scores, vec![(rules.points_to_win, 0), (3, 5), (11, 3)]);
+
+        // Taken back, the deciding set goes on and the earlier sets stay
+        assert!(world.reconcile_score(31, (10, 3), (1, 1)));
+        assert_eq!(world.winner(), None);
+        assert_eq!(world.set_scores, vec![(rules.points_to_win, 0), (3, 5)]);
+        assert!(matches!(world.phase, MatchPhase::Celebrating { .. }));
+
+        // And the second set was not over after all
+        assert!(world.reconcile_score(19, (3, 5), (1, 0)));
+        assert_eq!(world.set_scores, vec![(rules.points_to_win, 0)]);
+    }
+}
@@ -0,0 +1,970 @@
+// Game server speaking the same protocol as the Go backend in backend/, for any number of sessions.
+// Every connection has a reader and a writer thread, one more thread runs the matches of Server
+// netcode sessions, pairs queued ranked players and pushes session state to players and spectators. All shared state is behind a single mutex, nothing touches it without the lock.
+
+use std::collections::{BTreeMap, HashMap};
+use std::io;
+use std::net::{Shutdown, SocketAddr, TcpListener, TcpStream};
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
+use std::thread::{self, sleep, JoinHandle};
+use std::time::{Duration, Instant};
+
+use protobuf::Message;
+use websocket::sync::server::IntoWs;
+use websocket::sync::Writer;
+use websocket::OwnedMessage;
+
+use crate::pong::ai::Difficulty;
+use crate::pong::net::now_ms;
+use crate::pong::prediction::decode_rules;
+use crate::pong::protos::pong::{CmdCpuAdd, CmdHello, CmdIdGet, CmdIdSet, CmdQueueJoin, CmdQueueStatus, CmdRated, CmdRoomCreate, CmdRoomJoin, CmdRooms, CmdSpectate, CmdSpectating, DataType, Difficulty as ProtoDifficulty, Netcode, PongData};
+use crate::pong::rng::{new_seed, GameRng};
+use crate::pong::world::{ScreenSide, TICK_RATE};
+
+mod matchmaking;
+mod ratings;
+mod session;
+mod simulation;
+
+use self::matchmaking::{MatchQueue, QueueEntry};
+use self::ratings::RatingStore;
+use self::session::{Session, NO_PLAYER};
+
+pub const DEFAULT_ADDRESS: &str = "0.0.0.0:8080";
+// How long a disconnected player keeps the seat for resuming
+pub const RESUME_GRACE: Duration = Duration::from_secs(30);
+// Period of the tick thread, the same rate as matches are simulated on clients
+pub const TICK_INTERVAL: Duration = Duration::from_micros(1_000_000 / TICK_RATE as u64);
+// Session state is pushed to subscribed clients every this many ticks
+pub const STREAM_TICKS: u64 = 2;
+// Queued players are paired every this many ticks and told how their search goes
+const MATCHMAKING_TICKS: u64 = 30;
+const QUEUE_STATUS_TICKS: u64 = 120;
+// How often the accept loop looks whether the server was stopped
+const ACCEPT_POLL: Duration = Duration::from_millis(20);
+// Longer names and codes are cut, in characters
+const MAX_ROOM_NAME: usize = 24;
+const MAX_PLAYER_NAME: usize = 16;
+const MAX_JOIN_CODE: usize = 8;
+const MAX_PLAYER_KEY: usize = 64;
+
+type ConnId = u64;
+
+struct Connection {
+    tx: Sender<OwnedMessage>,
+    // Clone of the socket to close it from outside of the reader thread
+    stream: TcpStream,
+    // Player and session given out on this connection
+    player: u32,
+    session: u32,
+}
+
+struct Subscription {
+    conn: ConnId,
+    session: u32,
+    seq: u64,
+    // Watching without a seat
+    spectator: bool,
+}
+
+struct State {
+    rng: GameRng,
+    next_conn: ConnId,
+    connections: HashMap<ConnId, Connection>,
+    sessions: BTreeMap<u32, Session>,
+    // Connection currently used by player, old connection of resumed player must not free the seat
+    player_conns: HashMap<u32, ConnId>,
+    // Secret for resuming to (session, player)
+    resume_tokens: HashMap<u64, (u32, u32)>,
+    subscriptions: Vec<Subscription>,
+    queue: MatchQueue,
+    ratings: RatingStore,
+}
+
+type Shared = Arc<Mutex<State>>;
+
+pub struct ServerHandle {
+    addr: SocketAddr,
+    stop: Arc<AtomicBool>,
+    state: Shared,
+    threads: Vec<JoinHandle<()>>,
+}
+
+impl ServerHandle {
+    pub fn local_addr(&self) -> SocketAddr {
+        self.addr
+    }
+
+    // Address clients connect to, port 0 from start() is replaced by the real one.
+    pub fn url(&self) -> String {
+        format!("ws://{}/ws", self.addr)
+    }
+
+    // Block until the server stops.
+    pub fn wait(mut self) {
+        for thread in self.threads.drain(..) {
+            let _ = thread.join();
+        }
+    }
+
+    // Close every connection and wait for the server threads to finish.
+    pub fn stop(self) {
+        self.stop.store(true, Ordering::SeqCst);
+        {
+            let state = self.state.lock().unwrap();
+            for connection in state.connections.values() {
+                let _ = connection.stream.shutdown(Shutdown::Both);
+            }
+        }
+        self.wait();
+    }
+}
+
+// Listen on `address` and serve in background threads, "127.0.0.1:0" picks a free port.
+// Ratings of ranked players are forgotten when it stops.
+pub fn start(address: &str) -> io::Result<ServerHandle> {
+    start_server(address, RatingStore::in_memory())
+}
+
+// Same as start() with ratings kept in the file at `ratings`, it is created when missing.
+pub fn start_with_ratings(address: &str, ratings: &Path) -> io::Result<ServerHandle> {
+    start_server(address, RatingStore::open(ratings)?)
+}
+
+fn start_server(address: &str, ratings: RatingStore) -> io::Result<ServerHandle> {
+    let listener = TcpListener::bind(address)?;
+    listener.set_nonblocking(true)?;
+    let addr = listener.local_addr()?;
+    let stop = Arc::new(AtomicBool::new(false));
+    let state: Shared = Arc::new(Mutex::new(State {
+        rng: GameRng::new(new_seed()),
+        next_conn: 1,
+        connections: HashMap::new(),
+        sessions: BTreeMap::new(),
+        player_conns: HashMap::new(),
+        resume_tokens: HashMap::new(),
+        subscriptions: Vec::new(),
+        queue: MatchQueue::default(),
+        ratings,
+    }));
+    println!("rengine server listening on {}", addr);
+
+    let accept_state = state.clone();
+    let accept_stop = stop.clone();
+    let tick_state = state.clone();
+    let tick_stop = stop.clone();
+    let threads = vec![
+        thread::spawn(move || accept_loop(listener, accept_state, accept_stop)),
+        thread::spawn(move || tick_loop(tick_state, tick_stop)),
+    ];
+    Ok(ServerHandle { addr, stop, state, threads })
+}
+
+fn accept_loop(listener: TcpListener, state: Shared, stop: Arc<AtomicBool>) {
+    while !stop.load(Ordering::SeqCst) {
+        match listener.accept() {
+            Ok((stream, peer)) => {
+                let state = state.clone();
+                thread::spawn(move || connection_thread(stream, peer, state));
+            },
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => sleep(ACCEPT_POLL),
+            Err(err) => {
+                println!("Accept failed: {}", err);
+                sleep(ACCEPT_POLL);
+            },
+        }
+    }
+}
+
+fn encode(data: &PongData) -> Option<OwnedMessage> {
+    match data.write_to_bytes() {
+        Ok(bytes) => Some(OwnedMessage::Binary(bytes)),
+        Err(err) => {
+            println!("Failed to serialize {:?} message: {}", data.type_, err);
+            None
+        },
+    }
+}
+
+fn send(state: &State, conn: ConnId, data: &PongData) {
+    if let (Some(connection), Some(message)) = (state.connections.get(&conn), encode(data)) {
+        let _ = connection.tx.send(message);
+    }
+}
+
+fn hello_msg() -> PongData {
+    let mut hello = CmdHello::default();
+    hello.msg = "Hello mighty Client!".to_string();
+    let mut data = PongData::new();
+    data.type_ = DataType::Hello.into();
+    data.set_hello(hello);
+    data
+}
+
+fn id_rsp_msg(rsp: CmdIdSet) -> PongData {
+    let mut data = PongData::new();
+    data.type_ = DataType::SetId.into();
+    data.set_id_rsp(rsp);
+    data
+}
+
+fn rooms_msg(rooms: CmdRooms) -> PongData {
+    let mut data = PongData::new();
+    data.type_ = DataType::Rooms.into();
+    data.set_rooms(rooms);
+    data
+}
+
+fn queue_status_msg(status: CmdQueueStatus) -> PongData {
+    let mut data = PongData::new();
+    data.type_ = DataType::QueueStatus.into();
+    data.set_queue_status(status);
+    data
+}
+
+fn rated_msg(rated: CmdRated) -> PongData {
+    let mut data = PongData::new();
+    data.type_ = DataType::Rated.into();
+    data.set_rated(rated);
+    data
+}
+
+fn spectating_msg(spectating: CmdSpectating) -> PongData {
+    let mut data = PongData::new();
+    data.type_ = DataType::Spectating.into();
+    data.set_spectating(spectating);
+    data
+}
+
+fn ctx_msg(session: &Session, seq: u64, spectators: u32) -> PongData {
+    let mut ctx = session.ctx(seq, now_ms());
+    ctx.spectators = spectators;
+    let mut data = PongData::new();
+    data.type_ = DataType::SetCtx.into();
+    data.set_ctx_rsp(ctx);
+    data
+}
+
+fn world_msg(session: &Session, seq: u64) -> Option<PongData> {
+    let mut state = session.simulation.as_ref()?.state();
+    state.session = session.id;
+    state.seq = seq;
+    state.sent_ms = now_ms();
+    let mut data = PongData::new();
+    data.type_ = DataType::World.into();
+    data.set_world(state);
+    Some(data)
+}
+
+fn writer_thread(mut writer: Writer<TcpStream>, rx: Receiver<OwnedMessage>) {
+    for message in rx {
+        if writer.send_message(&message).is_err() {
+            break;
+        }
+    }
+    let _ = writer.shutdown_all();
+}
+
+fn connection_thread(stream: TcpStream, peer: SocketAddr, state: Shared) {
+    let _ = stream.set_nonblocking(false);
+    let shutdown_stream = match stream.try_clone() {
+        Ok(stream) => stream,
+        Err(err) => {
+            println!("Connection from {} failed: {}", peer, err);
+            return;
+        },
+    };
+    let client = match stream.into_ws() {
+        Ok(upgrade) => upgrade.accept().map_err(|(_, err)| err.to_string()),
+        Err((_, _, _, err)) => Err(format!("{:?}", err)),
+    };
+    let (mut reader, writer) = match client.and_then(|client| client.split().map_err(|err| err.to_string())) {
+        Ok(parts) => parts,
+        Err(err) => {
+            println!("Websocket handshake with {} failed: {}", peer, err);
+            return;
+        },
+    };
+    println!("Client connected from {}", peer);
+
+    let (tx, rx) = channel::<OwnedMessage>();
+    thread::spawn(move || writer_thread(writer, rx));
+    let conn = {
+        let mut state = state.lock().unwrap();
+        let conn = state.next_conn;
+        state.next_conn = state.next_conn + 1;
+        state.connections.insert(conn, Connection { tx, stream: shutdown_stream, player: NO_PLAYER, session: u32::MAX });
+        send(&state, conn, &hello_msg());
+        conn
+    };
+
+    loop {
+        let payload = match reader.recv_message() {
+            Ok(OwnedMessage::Binary(payload)) => payload,
+            Ok(OwnedMessage::Text(text)) => text.into_bytes(),
+            Ok(OwnedMessage::Ping(data)) => {
+                let state = state.lock().unwrap();
+                if let Some(connection) = state.connections.get(&conn) {
+                    let _ = connection.tx.send(OwnedMessage::Pong(data));
+                }
+                continue;
+            },
+            Ok(OwnedMessage::Pong(_)) => continue,
+            Ok(OwnedMessage::Close(_)) => break,
+            Err(err) => {
+                println!("Connection {} from {} lost: {}", conn, peer, err);
+                break;
+            },
+        };
+        let data = match PongData::parse_from_bytes(&payload) {
+            Ok(data) => data,
+            Err(err) => {
+                println!("Failed to parse message from {}: {}", peer, err);
+                break;
+            },
+        };
+        handle_message(&mut state.lock().unwrap(), conn, &payload, data);
+    }
+    disconnected(&mut state.lock().unwrap(), conn);
+}
+
+fn handle_message(state: &mut State, conn: ConnId, payload: &[u8], data: PongData) {
+    match data.type_.enum_value() {
+        Ok(DataType::Hello) => println!("Hello msg: {}", data.hello().msg),
+        Ok(DataType::GetId) if data.id_req().resume_token != 0 => resume(state, conn, data.id_req().resume_token),
+        Ok(DataType::GetId) => join(state, conn, data.id_req()),
+        Ok(DataType::GetCtx) => {
+            if seated_player(state, conn, data.ctx_req().session).is_none() {
+                return;
+            }
+            if let Some(session) = state.sessions.get(&data.ctx_req().session) {
+                let reply = ctx_msg(session, 0, spectators(state, session.id));
+                send(state, conn, &reply);
+            }
+        },
+        Ok(DataType::SetCtx) => {
+            let player = match seated_player(state, conn, data.ctx_rsp().session) {
+                Some(player) => player,
+                None => return,
+            };
+            if let Some(session) = state.sessions.get_mut(&data.ctx_rsp().session) {
+                session.set_ctx(player, data.ctx_rsp());
+            }
+        },
+        Ok(DataType::Ready) => {
+            let player = match seated_player(state, conn, data.ready().session) {
+                Some(player) => player,
+                None => return,
+            };
+            if let Some(session) = state.sessions.get_mut(&data.ready().session) {
+                session.ready(player, data.ready());
+            }
+        },
+        Ok(DataType::LostPoint) => {
+            let player = match seated_player(state, conn, data.lost_point().session) {
+                Some(player) => player,
+                None => return,
+            };
+            if let Some(session) = state.sessions.get_mut(&data.lost_point().session) {
+                session.lost_point(player, data.lost_point());
+            }
+        },
+        Ok(DataType::Subscribe) => {
+            let session = data.subscribe().session;
+            if seated_player(state, conn, session).is_some() {
+                println!("Streaming session {} to connection {}", session, conn);
+                state.subscriptions.push(Subscription { conn, session, seq: 0, spectator: false });
+            } else {
+                // Watching without a seat is up to the room, like for any other spectator
+                let mut req = CmdSpectate::default();
+                req.session = session;
+                spectate(state, conn, &req);
+            }
+        },
+        Ok(DataType::Input) => forward_input(state, conn, payload, data),
+        Ok(DataType::ListRooms) => list_rooms(state, conn),
+        Ok(DataType::CreateRoom) => create_room(state, conn, data.room_create()),
+        Ok(DataType::JoinRoom) => join_room(state, conn, data.room_join()),
+        Ok(DataType::LeaveRoom) => leave_room(state, conn),
+        Ok(DataType::Queue) => queue_join(state, conn, data.queue_join()),
+        Ok(DataType::LeaveQueue) => {
+            if state.queue.leave(conn) {
+                println!("Connection {} left the ranked queue", conn);
+            }
+        },
+        Ok(DataType::Spectate) => spectate(state, conn, data.spectate()),
+        Ok(DataType::StopSpectating) => stop_spectating(state, conn),
+        Ok(DataType::AddCpu) => add_cpu(state, conn, data.cpu_add()),
+        _ => println!("Unsupported message received: {:?}", data.type_),
+    }
+}
+
+fn clean_name(name: &str, max: usize) -> String {
+    name.trim().chars().filter(|c| !c.is_control()).take(max).collect()
+}
+
+// Join codes are letters and digits, compared upper case.
+fn clean_join_code(code: &str) -> Result<String, String> {
+    let code = code.trim().to_uppercase();
+    if code.chars().count() > MAX_JOIN_CODE || !code.chars().all(|c| c.is_ascii_alphanumeric()) {
+        return Err(format!("Join code must be up to {} letters and digits", MAX_JOIN_CODE));
+    }
+    Ok(code)
+}
+
+fn new_session(state: &mut State, netcode: Netcode) -> u32 {
+    let mut id = (state.rng.next_u64() >> 34) as u32;
+    while state.sessions.contains_key(&id) {
+        id = (state.rng.next_u64() >> 34) as u32;
+    }
+    println!("New session {}", id);
+    state.sessions.insert(id, Session::new(id, new_seed(), netcode));
+    id
+}
+
+fn room_error(state: &State, conn: ConnId, error: &str) {
+    println!("Connection {}: {}", conn, error);
+    let mut rsp = CmdIdSet::default();
+    rsp.id = u32::MAX;
+    rsp.session = u32::MAX;
+    rsp.error = error.to_string();
+    send(state, conn, &id_rsp_msg(rsp));
+}
+
+// Take the free seat of session `id` for the player on `conn`, the seat held before is given up.
+fn seat(state: &mut State, conn: ConnId, id: u32, player_name: &str) -> Option<u32> {
+    // Leaving first would close the session of a player alone in it
+    if state.connections.get(&conn).is_some_and(|connection| connection.player != NO_PLAYER && connection.session == id) {
+        room_error(state, conn, "Already in this room");
+        return None;
+    }
+    leave_room(state, conn);
+    state.queue.leave(conn);
+    let session = match state.sessions.get_mut(&id) {
+        Some(session) => session,
+        None => {
+            room_error(state, conn, "Room is gone");
+            return None;
+        },
+    };
+    let player = match session.join(&clean_name(player_name, MAX_PLAYER_NAME)) {
+        Some(player) => player,
+        None => {
+            room_error(state, conn, "Room is full");
+            return None;
+        },
+    };
+    let (seed, netcode, room) = (session.seed, session.netcode, session.room(true));
+
+    let mut token = state.rng.next_u64();
+    while token == 0 || state.resume_tokens.contains_key(&token) {
+        token = state.rng.next_u64();
+    }
+    state.resume_tokens.insert(token, (id, player));
+    state.player_conns.insert(player, conn);
+    if let Some(connection) = state.connections.get_mut(&conn) {
+        connection.player = player;
+        connection.session = id;
+    }
+    println!("Player {} joined session {}", player, id);
+
+    let mut rsp = CmdIdSet::default();
+    rsp.id = player;
+    rsp.session = id;
+    rsp.seed = seed;
+    rsp.resume_token = token;
+    rsp.netcode = netcode.into();
+    rsp.room = Some(room).into();
+    send(state, conn, &id_rsp_msg(rsp));
+    Some(player)
+}
+
+// Seat in the oldest public session waiting for a second player, or a new session.
+fn join(state: &mut State, conn: ConnId, req: &CmdIdGet) {
+    let current = state.connections.get(&conn).map_or(u32::MAX, |connection| connection.session);
+    let open = state.sessions.values()
+        .find(|session| session.has_free_seat() && !session.is_private() && session.id != current)
+        .map(|session| session.id);
+    let id = match open {
+        Some(id) => id,
+        None => {
+            let id = new_session(state, req.netcode.enum_value_or(Netcode::BallMaster));
+            state.sessions.get_mut(&id).unwrap().rules = decode_rules(req.rules.as_ref());
+            id
+        },
+    };
+    seat(state, conn, id, &req.player_name);
+}
+
+fn spectators(state: &State, session: u32) -> u32 {
+    state.subscriptions.iter().filter(|subscription| subscription.spectator && subscription.session == session).count() as u32
+}
+
+// Open rooms to join and matches to watch. Matches of private rooms are watched with the join code.
+fn list_rooms(state: &State, conn: ConnId) {
+    let mut rooms = CmdRooms::default();
+    rooms.rooms = state.sessions.values()
+        .filter(|session| session.has_free_seat() && !session.is_private() && !session.is_empty())
+        .map(|session| session.room(false))
+        .collect();
+    rooms.live = state.sessions.values()
+        .filter(|session| !session.has_free_seat() && session.join_code.is_empty())
+        .map(|session| {
+            let mut room = session.room(false);
+            room.spectators = spectators(state, session.id);
+            room
+        })
+        .collect();
+    send(state, conn, &rooms_msg(rooms));
+}
+
+fn create_room(state: &mut State, conn: ConnId, req: &CmdRoomCreate) {
+    let join_code = match clean_join_code(&req.join_code) {
+        Ok(code) => code,
+        Err(err) => return room_error(state, conn, &err),
+    };
+    if !join_code.is_empty() && state.sessions.values().any(|session| session.join_code == join_code) {
+        return room_error(state, conn, "Join code is already used by another room");
+    }
+    let mut name = clean_name(&req.name, MAX_ROOM_NAME);
+    if name.is_empty() {
+        name = clean_name(&format!("{}'s room", clean_name(&req.player_name, MAX_PLAYER_NAME)), MAX_ROOM_NAME);
+    }
+    let id = new_session(state, req.netcode.enum_value_or(Netcode::BallMaster));
+    let session = state.sessions.get_mut(&id).unwrap();
+    println!("Session {} is room {} {}", id, name, if join_code.is_empty() { "(public)" } else { "(private)" });
+    session.name = name;
+    session.join_code = join_code;
+    session.rules = decode_rules(req.rules.as_ref());
+    seat(state, conn, id, &req.player_name);
+}
+
+// By join code when one is given, otherwise the listed room of the session.
+fn join_room(state: &mut State, conn: ConnId, req: &CmdRoomJoin) {
+    let join_code = match clean_join_code(&req.join_code) {
+        Ok(code) => code,
+        Err(err) => return room_error(state, conn, &err),
+    };
+    let session = if join_code.is_empty() {
+        state.sessions.get(&req.session).filter(|session| !session.is_private())
+    } else {
+        state.sessions.values().find(|session| session.join_code == join_code)
+    };
+    let id = match session {
+        Some(session) if session.has_free_seat() => session.id,
+        Some(_) => return room_error(state, conn, "Room is full"),
+        None if join_code.is_empty() => return room_error(state, conn, "Room is gone"),
+        None => return room_error(state, conn, "No room with this join code"),
+    };
+    seat(state, conn, id, &req.player_name);
+}
+
+// Free the seat of the player on `conn` right away, the connection stays in the lobby.
+fn leave_room(state: &mut State, conn: ConnId) {
+    let (session, player) = match state.connections.get_mut(&conn) {
+        Some(connection) if connection.player != NO_PLAYER => {
+            let seat = (connection.session, connection.player);
+            connection.player = NO_PLAYER;
+            connection.session = u32::MAX;
+            seat
+        },
+        _ => return,
+    };
+    println!("Player {} left session {}", player, session);
+    state.subscriptions.retain(|subscription| subscription.conn != conn);
+    remove_player(state, session, player);
+}
+
+// Watch a session without a seat, the match watched before is left. A player watching gives up the seat.
+fn spectate(state: &mut State, conn: ConnId, req: &CmdSpectate) {
+    let join_code = match clean_join_code(&req.join_code) {
+        Ok(code) => code,
+        Err(err) => return spectate_error(state, conn, &err),
+    };
+    let session = if join_code.is_empty() {
+        state.sessions.get(&req.session).filter(|session| session.join_code.is_empty())
+    } else {
+        state.sessions.values().find(|session| session.join_code == join_code)
+    };
+    let (id, netcode, room) = match session {
+        Some(session) => (session.id, session.netcode, session.room(false)),
+        None if join_code.is_empty() => return spectate_error(state, conn, "Match is over"),
+        None => return spectate_error(state, conn, "No room with this join code"),
+    };
+    leave_room(state, conn);
+    state.queue.leave(conn);
+    state.subscriptions.retain(|subscription| subscription.conn != conn);
+    state.subscriptions.push(Subscription { conn, session: id, seq: 0, spectator: true });
+    println!("Connection {} watching session {}", conn, id);
+    let mut rsp = CmdSpectating::default();
+    rsp.session = id;
+    rsp.netcode = netcode.into();
+    rsp.room = Some(room).into();
+    send(state, conn, &spectating_msg(rsp));
+}
+
+fn spectate_error(state: &State, conn: ConnId, error: &str) {
+    println!("Connection {}: {}", conn, error);
+    let mut rsp = CmdSpectating::default();
+    rsp.session = u32::MAX;
+    rsp.error = error.to_string();
+    send(state, conn, &spectating_msg(rsp));
+}
+
+fn stop_spectating(state: &mut State, conn: ConnId) {
+    let watched = state.subscriptions.len();
+    state.subscriptions.retain(|subscription| !(subscription.conn == conn && subscription.spectator));
+    if state.subscriptions.len() != watched {
+        println!("Connection {} stopped watching", conn);
+    }
+}
+
+fn cpu_difficulty(difficulty: ProtoDifficulty) -> Difficulty {
+    match difficulty {
+        ProtoDifficulty::Easy => Difficulty::Easy,
+        ProtoDifficulty::Normal => Difficulty::Normal,
+        ProtoDifficulty::Hard => Difficulty::Hard,
+        ProtoDifficulty::Expert => Difficulty::Expert,
+    }
+}
+
+// Computer opponent for a player waiting alone. Only the server runs it, so a room of another netcode
+// becomes a Server one first and the player gets the seat again with the new netcode.
+fn add_cpu(state: &mut State, conn: ConnId, req: &CmdCpuAdd) {
+    let player = match seated_player(state, conn, req.session) {
+        Some(player) => player,
+        None => return,
+    };
+    let session = match state.sessions.get_mut(&req.session) {
+        Some(session) if !session.ranked => session,
+        _ => {
+            println!("Connection {} asked for a computer player in a ranked session", conn);
+            return;
+        },
+    };
+    let difficulty = cpu_difficulty(req.difficulty.enum_value_or(ProtoDifficulty::Normal));
+    if !session.has_free_seat() {
+        println!("No free seat for a computer player in session {}", req.session);
+        return;
+    }
+    let reopened = session.netcode != Netcode::Server;
+    session.netcode = Netcode::Server;
+    match session.seat_cpu(difficulty) {
+        Some(cpu) => println!("Computer player {} ({}) joined session {}", cpu, difficulty.name(), req.session),
+        None => return,
+    }
+    if !reopened {
+        return;
+    }
+    println!("Session {} is a Server session now", req.session);
+    let mut rsp = CmdIdSet::default();
+    rsp.id = player;
+    rsp.session = req.session;
+    rsp.seed = session.seed;
+    rsp.resume_token = state.resume_tokens.iter()
+        .find(|(_, seat)| **seat == (req.session, player))
+        .map_or(0, |(token, _)| *token);
+    rsp.netcode = Netcode::Server.into();
+    rsp.room = Some(session.room(true)).into();
+    send(state, conn, &id_rsp_msg(rsp));
+}
+
+// Give the player the seat back, session max u32 tells the client it is gone.
+fn resume(state: &mut State, conn: ConnId, token: u64) {
+    let mut rsp = CmdIdSet::default();
+    rsp.id = u32::MAX;
+    rsp.session = u32::MAX;
+    if let Some((id, player)) = state.resume_tokens.get(&token).copied() {
+        if let Some(session) = state.sessions.get_mut(&id) {
+            if let Some(seat) = session.seat_mut(player) {
+                seat.away_since = None;
+            }
+            rsp.id = player;
+            rsp.session = id;
+            rsp.seed = session.seed;
+            rsp.resume_token = token;
+            rsp.netcode = session.netcode.into();
+            state.player_conns.insert(player, conn);
+            if let Some(connection) = state.connections.get_mut(&conn) {
+                connection.player = player;
+                connection.session = id;
+            }
+            println!("Player {} resumed session {}", player, id);
+        }
+    }
+    if rsp.session == u32::MAX {
+        println!("Unknown resume token");
+    }
+    send(state, conn, &id_rsp_msg(rsp));
+}
+
+// Player seated on this connection, messages for any other session are dropped. The player in
+// the message itself is never trusted, session ids are public.
+fn seated_player(state: &State, conn: ConnId, session: u32) -> Option<u32> {
+    match state.connections.get(&conn) {
+        Some(connection) if connection.player != NO_PLAYER && connection.session == session => Some(connection.player),
+        _ => {
+            println!("Dropping message for session {} from connection {}", session, conn);
+            None
+        },
+    }
+}
+
+// Rollback sessions only exchange inputs, pass them to the opponent as they are and run the copy
+// of the match spectators watch. Server sessions feed them to the match the server runs.
+fn forward_input(state: &mut State, conn: ConnId, payload: &[u8], data: PongData) {
+    let input = data.input();
+    let player = match seated_player(state, conn, input.session) {
+        Some(player) => player,
+        None => return,
+    };
+    let opponent = match state.sessions.get_mut(&input.session) {
+        Some(session) if session.netcode == Netcode::Server => {
+            session.input(player, input);
+            return;
+        },
+        Some(session) => {
+            if session.netcode == Netcode::Rollback {
+                session.input(player, input);
+            }
+            session.report_tick(input.tick);
+            session.opponent(player)
+        },
+        None => {
+            println!("Invalid session id for input");
+            return;
+        },
+    };
+    // Opponent not there yet or reconnecting, inputs are sent again until acknowledged
+    let conn = match opponent.and_then(|player| state.player_conns.get(&player)) {
+        Some(conn) => *conn,
+        None => return,
+    };
+    if let Some(connection) = state.connections.get(&conn) {
+        let _ = connection.tx.send(OwnedMessage::Binary(payload.to_vec()));
+    }
+}
+
+// Mark the player away, the seat is freed by the tick thread unless the player resumes in time.
+fn disconnected(state: &mut State, conn: ConnId) {
+    state.subscriptions.retain(|subscription| subscription.conn != conn);
+    state.queue.leave(conn);
+    let connection = match state.connections.remove(&conn) {
+        Some(connection) => connection,
+        None => return,
+    };
+    println!("Connection {} closed, session {} player {}", conn, connection.session, connection.player);
+    if state.player_conns.get(&connection.player) != Some(&conn) {
+        // Never got a seat or already resumed on another connection
+        return;
+    }
+    if let Some(seat) = state.sessions.get_mut(&connection.session).and_then(|session| session.seat_mut(connection.player)) {
+        seat.away_since = Some(Instant::now());
+    }
+}
+
+fn remove_player(state: &mut State, session_id: u32, player: u32) {
+    state.resume_tokens.retain(|_, (_, token_player)| *token_player != player);
+    state.player_conns.remove(&player);
+    let empty = match state.sessions.get_mut(&session_id) {
+        Some(session) => {
+            // Giving up a ranked match loses it
+            let opponent = session.opponent(player).filter(|opponent| *opponent != NO_PLAYER);
+            if let (true, false, true, Some(opponent)) = (session.ranked, session.rated, session.simulation.is_some(), opponent) {
+                let side = session.side(opponent).unwrap();
+                println!("Player {} gave up ranked session {}", player, session_id);
+                rate_match(state, session_id, side);
+            }
+            let session = state.sessions.get_mut(&session_id).unwrap();
+            session.leave(player);
+            session.is_empty()
+        },
+        None => false,
+    };
+    if empty {
+        println!("Session {} closed", session_id);
+        state.sessions.remove(&session_id);
+        let watching: Vec<ConnId> = state.subscriptions.iter()
+            .filter(|subscription| subscription.spectator && subscription.session == session_id)
+            .map(|subscription| subscription.conn)
+            .collect();
+        for conn in watching {
+            spectate_error(state, conn, "Match is over");
+        }
+        state.subscriptions.retain(|subscription| subscription.session != session_id);
+    }
+}
+
+// Free seats of players away for too long.
+fn expire_away(state: &mut State) {
+    let mut expired = Vec::new();
+    for session in state.sessions.values() {
+        for seat in [&session.left, &session.right] {
+            if seat.away_since.is_some_and(|since| since.elapsed() > RESUME_GRACE) {
+                expired.push((session.id, seat.player));
+            }
+        }
+    }
+    for (session, player) in expired {
+        println!("Player {} did not come back, leaving session {}", player, session);
+        remove_player(state, session, player);
+    }
+}
+
+fn step_sessions(state: &mut State) {
+    let mut finished = Vec::new();
+    for session in state.sessions.values_mut() {
+        session.step();
+        if let (true, false, Some(winner)) = (session.ranked, session.rated, session.winner()) {
+            finished.push((session.id, winner));
+        }
+    }
+    for (session, winner) in finished {
+        rate_match(state, session, winner);
+    }
+}
+
+// Wait in the ranked queue, the rating comes from the store under the key of the client.
+fn queue_join(state: &mut State, conn: ConnId, req: &CmdQueueJoin) {
+    let key = clean_name(&req.player_key, MAX_PLAYER_KEY);
+    if key.is_empty() {
+        return room_error(state, conn, "Ranked matches need a player key, update the game");
+    }
+    leave_room(state, conn);
+    let rating = state.ratings.get(&key).rating;
+    println!("Connection {} queued for ranked match with rating {}", conn, rating);
+    state.queue.join(QueueEntry { conn, key, name: clean_name(&req.player_name, MAX_PLAYER_NAME), rating, since: Instant::now() });
+    queue_status(state, conn);
+}
+
+fn queue_status(state: &State, conn: ConnId) {
+    let now = Instant::now();
+    let entry = match state.queue.entries().iter().find(|entry| entry.conn == conn) {
+        Some(entry) => entry,
+        None => return,
+    };
+    let rating = state.ratings.get(&entry.key);
+    let mut status = CmdQueueStatus::default();
+    status.rating = rating.rating;
+    status.matches = rating.matches;
+    status.queued_ms = now.saturating_duration_since(entry.since).as_millis() as u64;
+    status.estimated_wait_ms = state.queue.estimated_wait().map_or(0, |wait| wait.as_millis() as u64);
+    status.players_queued = state.queue.entries().len() as u32;
+    status.window = entry.window(now);
+    send(state, conn, &queue_status_msg(status));
+}
+
+// Ranked matches are simulated by the server, so the result is known without trusting either player.
+fn matchmake(state: &mut State) {
+    for (first, second) in state.queue.pairs(Instant::now()) {
+        let id = new_session(state, Netcode::Server);
+        let session = state.sessions.get_mut(&id).unwrap();
+        // Players see both ratings in the room name, left one first
+        session.name = format!("Ranked {} vs {}", first.rating, second.rating);
+        session.ranked = true;
+        println!("Ranked session {} for {} ({}) and {} ({})", id, first.name, first.rating, second.name, second.rating);
+        for entry in [first, second] {
+            let player = match seat(state, entry.conn, id, &entry.name) {
+                Some(player) => player,
+                None => continue,
+            };
+            if let Some(seat) = state.sessions.get_mut(&id).and_then(|session| session.seat_mut(player)) {
+                seat.key = entry.key;
+            }
+        }
+    }
+}
+
+fn queue_update(state: &mut State, tick: u64) {
+    if tick.is_multiple_of(MATCHMAKING_TICKS) {
+        matchmake(state);
+    }
+    if tick.is_multiple_of(QUEUE_STATUS_TICKS) {
+        let queued: Vec<ConnId> = state.queue.entries().iter().map(|entry| entry.conn).collect();
+        for conn in queued {
+            queue_status(state, conn);
+        }
+    }
+}
+
+// Update ratings once `winner` won ranked session `id` and tell both players.
+fn rate_match(state: &mut State, id: u32, winner: ScreenSide) {
+    let session = match state.sessions.get_mut(&id) {
+        Some(session) => session,
+        None => return,
+    };
+    session.rated = true;
+    let (won, lost) = match winner {
+        ScreenSide::Left => (session.left.clone(), session.right.clone()),
+        ScreenSide::Right => (session.right.clone(), session.left.clone()),
+    };
+    if won.key.is_empty() || lost.key.is_empty() {
+        return;
+    }
+    let before = (state.ratings.get(&won.key).rating, state.ratings.get(&lost.key).rating);
+    let (won_rating, lost_rating) = state.ratings.record((&won.key, &won.name), (&lost.key, &lost.name));
+    println!("Ranked session {} won by {}: {} -> {}, {} -> {}", id, won.name, before.0, won_rating.rating, before.1, lost_rating.rating);
+    for (seat, rating, old) in [(&won, won_rating, before.0), (&lost, lost_rating, before.1)] {
+        let mut rated = CmdRated::default();
+        rated.rating = rating.rating;
+        rated.change = rating.rating - old;
+        rated.matches = rating.matches;
+        rated.wins = rating.wins;
+        if let Some(conn) = state.player_conns.get(&seat.player) {
+            send(state, *conn, &rated_msg(rated));
+        }
+    }
+}
+
+// Players of Rollback sessions simulate the match themselves, only their spectators get world states.
+fn stream_sessions(state: &mut State) {
+    let mut watching: HashMap<u32, u32> = HashMap::new();
+    for subscription in state.subscriptions.iter().filter(|subscription| subscription.spectator) {
+        let count = watching.entry(subscription.session).or_insert(0);
+        *count = *count + 1;
+    }
+    let mut messages = Vec::new();
+    for subscription in state.subscriptions.iter_mut() {
+        if let Some(session) = state.sessions.get(&subscription.session) {
+            subscription.seq = subscription.seq + 1;
+            let spectators = watching.get(&session.id).copied().unwrap_or(0);
+            messages.push((subscription.conn, ctx_msg(session, subscription.seq, spectators)));
+            if session.netcode != Netcode::Server && !subscription.spectator {
+                continue;
+            }
+            if let Some(world) = world_msg(session, subscription.seq) {
+                messages.push((subscription.conn, world));
+            }
+        }
+    }
+    for (conn, message) in messages {
+        send(state, conn, &message);
+    }
+}
+
+fn tick_loop(state: Shared, stop: Arc<AtomicBool>) {
+    let mut next = Instant::now();
+    let mut tick: u64 = 0;
+    while !stop.load(Ordering::SeqCst) {
+        {
+            let mut state = state.lock().unwrap();
+            expire_away(&mut state);
+            step_sessions(&mut state);
+            queue_update(&mut state, tick);
+            if tick.is_multiple_of(STREAM_TICKS) {
+                stream_sessions(&mut state);
+            }
+        }
+        tick = tick + 1;
+        next = next + TICK_INTERVAL;
+        let now = Instant::now();
+        if next > now {
+            sleep(next - now);
+        } else {
+            next = now;
+        }
+    }
+}
@@ -0,0 +1,181 @@
+// Players waiting for a ranked match. Everybody first looks for opponents close to their own rating,
+// the accepted difference grows the longer they wait, so nobody waits forever on a quiet server.
+
+use std::collections::VecDeque;
+use std::time::{Duration, Instant};
+
+use super::ConnId;
+
+// Rating difference accepted right after joining the queue
+pub const BASE_WINDOW: i32 = 50;
+pub const WINDOW_GROWTH_PER_SEC: i32 = 25;
+pub const MAX_WINDOW: i32 = 1000;
+// Recent waits the estimate is the average of
+const WAIT_HISTORY: usize = 20;
+
+#[derive(Debug, Clone)]
+pub struct QueueEntry {
+    pub conn: ConnId,
+    pub key: String,
+    pub name: String,
+    pub rating: i32,
+    pub since: Instant,
+}
+
+impl QueueEntry {
+    pub fn window(&self, now: Instant) -> i32 {
+        let waited = now.saturating_duration_since(self.since).as_secs() as i32;
+        (BASE_WINDOW + waited * WINDOW_GROWTH_PER_SEC).min(MAX_WINDOW)
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct MatchQueue {
+    // Oldest first
+    entries: Vec<QueueEntry>,
+    waits: VecDeque<Duration>,
+}
+
+impl MatchQueue {
+    // The same connection or player queued again keeps only the new entry.
+    pub fn join(&mut self, entry: QueueEntry) {
+        self.entries.retain(|queued| queued.conn != entry.conn && queued.key != entry.key);
+        self.entries.push(entry);
+    }
+
+    pub fn leave(&mut self, conn: ConnId) -> bool {
+        let len = self.entries.len();
+        self.entries.retain(|queued| queued.conn != conn);
+        self.entries.len() != len
+    }
+
+    pub fn entries(&self) -> &[QueueEntry] {
+        &self.entries
+    }
+
+    pub fn estimated_wait(&self) -> Option<Duration> {
+        if self.waits.is_empty() {
+            return None;
+        }
+        Some(self.waits.iter().sum::<Duration>() / self.waits.len() as u32)
+    }
+
+    // Take out pairs both of whose windows accept the rating difference, the longest waiting
+    // player first and each with the closest opponent.
+    pub fn pairs(&mut self, now: Instant) -> Vec<(QueueEntry, QueueEntry)> {
+        let mut pairs = Vec::new();
+        let mut index = 0;
+        while index < self.entries.len() {
+            let player = &self.entries[index];
+            let opponent = self.entries.iter().enumerate()
+                .skip(index + 1)
+                .map(|(other, entry)| (other, (entry.rating - player.rating).abs(), entry))
+                .filter(|(_, diff, entry)| *diff <= player.window(now) && *diff <= entry.window(now))
+                .min_by_key(|(_, diff, _)| *diff)
+                .map(|(other, _, _)| other);
+            match opponent {
+                Some(other) => {
+                    let second = self.entries.remove(other);
+                    let first = self.entries.remove(index);
+                    for entry in [&first, &second] {
+                        self.waits.push_back(now.saturating_duration_since(entry.since));
+                    }
+                    while self.waits.len() > WAIT_HISTORY {
+                        self.waits.pop_front();
+                    }
+                    pairs.push((first, second));
+                },
+                None => index = index + 1,
+            }
+        }
+        pairs
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn entry(conn: ConnId, key: &str, rating: i32, since: Instant) -> QueueEntry {
+        QueueEntry { conn, key: key.to_string(), name: key.to_string(), rating, since }
+    }
+
+    #[test]
+    fn window_grows_with_waiting() {
+        let start = Instant::now();
+        let queued = entry(1, "a", 1200, start);
+        assert_eq!(queued.window(start), BASE_WINDOW);
+        assert_eq!(queued.window(start + Duration::from_secs(4)), BASE_WINDOW + 4 * WINDOW_GROWTH_PER_SEC);
+        assert_eq!(queued.window(start + Duration::from_secs(3600)), MAX_WINDOW);
+    }
+
+    #[test]
+    fn far_ratings_pair_after_waiting() {
+        let start = Instant::now();
+        let mut queue = MatchQueue::default();
+        queue.join(entry(1, "a", 1200, start));
+        queue.join(entry(2, "b", 1400, start));
+        assert!(queue.pairs(start).is_empty());
+        assert!(queue.pairs(start + Duration::from_secs(5)).is_empty());
+        // 50 + 6 * 25 accepts the 200 difference
+        let pairs = queue.pairs(start + Duration::from_secs(6));
+        assert_eq!(pairs.len(), 1);
+        assert_eq!((pairs[0].0.conn, pairs[0].1.conn), (1, 2));
+        assert!(queue.entries().is_empty());
+        assert_eq!(queue.estimated_wait(), Some(Duration::from_secs(6)));
+    }
+
+    #[test]
+    fn both_windows_must_accept() {
+        let start = Instant::now();
+        let mut queue = MatchQueue::default();
+        queue.join(entry(1, "a", 1200, start));
+        queue.join(entry(2, "b", 1400, start + Duration::from_secs(10)));
+        // The first waited long enough, the second just came
+        assert!(queue.pairs(start + Duration::from_secs(10)).is_empty());
+        assert_eq!(queue.pairs(start + Duration::from_secs(16)).len(), 1);
+    }
+
+    #[test]
+    fn longest_waiting_gets_the_closest_opponent() {
+        let start = Instant::now();
+        let mut queue = MatchQueue::default();
+        queue.join(entry(1, "a", 1200, start));
+        queue.join(entry(2, "b", 1240, start));
+        queue.join(entry(3, "c", 1210, start));
+        queue.join(entry(4, "d", 1230, start));
+        let pairs = queue.pairs(start);
+        let conns: Vec<(ConnId, ConnId)> = pairs.iter().map(|(first, second)| (first.conn, second.conn)).collect();
+        assert_eq!(conns, vec![(1, 3), (2, 4)]);
+    }
+
+    #[test]
+    fn queued_again_replaces_the_entry() {
+        let start = Instant::now();
+        let mut queue = MatchQueue::default();
+        queue.join(entry(1, "a", 1200, start));
+        queue.join(entry(2, "b", 1800, start));
+        // Same player from a new connection, and the same connection again
+        queue.join(entry(3, "a", 1210, start));
+        queue.join(entry(2, "b", 1190, start));
+        assert_eq!(queue.entries().len(), 2);
+        assert_eq!(queue.entries()[0].conn, 3);
+        assert_eq!(queue.entries()[1].rating, 1190);
+
+        let pairs = queue.pairs(start);
+        assert_eq!(pairs.len(), 1);
+        assert_eq!((pairs[0].0.conn, pairs[0].1.conn), (3, 2));
+        assert!(!queue.leave(3));
+    }
+
+    #[test]
+    fn leave_removes_the_connection() {
+        let start = Instant::now();
+        let mut queue = MatchQueue::default();
+        queue.join(entry(1, "a", 1200, start));
+        assert!(queue.leave(1));
+        assert!(!queue.leave(1));
+        assert!(queue.pairs(start).is_empty());
+        assert_eq!(queue.estimated_wait(), None);
+    }
+}
@@ -0,0 +1,174 @@
+// Ratings of ranked players under the key their client made up. Kept in a TOML file when the
+// server was given one, only in memory otherwise. The file is rewritten after every rated match.
+
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+use serde::{Deserialize, Serialize};
+
+pub const INITIAL_RATING: i32 = 1200;
+// Most a single match can move a rating
+const K_FACTOR: f64 = 32.0;
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+pub struct PlayerRating {
+    // Name the player had in the last match
+    pub name: String,
+    pub rating: i32,
+    pub matches: u32,
+    pub wins: u32,
+}
+
+impl Default for PlayerRating {
+    fn default() -> PlayerRating {
+        PlayerRating { name: String::new(), rating: INITIAL_RATING, matches: 0, wins: 0 }
+    }
+}
+
+#[derive(Debug, Default, Serialize, Deserialize)]
+#[serde(default)]
+struct RatingsFile {
+    players: BTreeMap<String, PlayerRating>,
+}
+
+// Points `rating` gains (or loses when negative) with `score` 1 for a win and 0 for a loss.
+pub fn elo_change(rating: i32, opponent: i32, score: f64) -> i32 {
+    let expected = 1.0 / (1.0 + 10f64.powf((opponent - rating) as f64 / 400.0));
+    (K_FACTOR * (score - expected)).round() as i32
+}
+
+pub struct RatingStore {
+    path: Option<PathBuf>,
+    players: BTreeMap<String, PlayerRating>,
+}
+
+impl RatingStore {
+    pub fn in_memory() -> RatingStore {
+        RatingStore { path: None, players: BTreeMap::new() }
+    }
+
+    // Missing file starts empty, a broken one is an error so it does not get overwritten.
+    pub fn open(path: &Path) -> io::Result<RatingStore> {
+        let players = match fs::read_to_string(path) {
+            Ok(content) => {
+                let file: RatingsFile = toml::from_str(&content).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+                file.players
+            },
+            Err(err) if err.kind() == io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(err) => return Err(err),
+        };
+        println!("Loaded {} player ratings from {}", players.len(), path.display());
+        Ok(RatingStore { path: Some(path.to_path_buf()), players })
+    }
+
+    pub fn get(&self, key: &str) -> PlayerRating {
+        self.players.get(key).cloned().unwrap_or_default()
+    }
+
+    // Update both players after `winner` beat `loser`, gives their new ratings.
+    pub fn record(&mut self, winner: (&str, &str), loser: (&str, &str)) -> (PlayerRating, PlayerRating) {
+        let mut won = self.get(winner.0);
+        let mut lost = self.get(loser.0);
+        let gain = elo_change(won.rating, lost.rating, 1.0);
+        let loss = elo_change(lost.rating, won.rating, 0.0);
+        won.name = winner.1.to_string();
+        won.rating = won.rating + gain;
+        won.matches = won.matches + 1;
+        won.wins = won.wins + 1;
+        lost.name = loser.1.to_string();
+        lost.rating = lost.rating + loss;
+        lost.matches = lost.matches + 1;
+        self.players.insert(winner.0.to_string(), won.clone());
+        self.players.insert(loser.0.to_string(), lost.clone());
+        if let Err(err) = self.save() {
+            println!("Failed to save ratings: {}", err);
+        }
+        (won, lost)
+    }
+
+    // Written next to the file and renamed over it, a crash never leaves half a file.
+    fn save(&self) -> io::Result<()> {
+        let path = match self.path.as_ref() {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        let file = RatingsFile { players: self.players.clone() };
+        let content = toml::to_string_pretty(&file).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let temp = path.with_extension("tmp");
+        fs::write(&temp, content)?;
+        fs::rename(&temp, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn temp_path(name: &str) -> PathBuf {
+        std::env::temp_dir().join(format!("rengine_ratings_{}_{}", std::process::id(), name))
+    }
+
+    #[test]
+    fn elo_change_is_symmetric_and_bounded() {
+        for (rating, opponent) in [(1200, 1200), (1500, 1100), (900, 1700), (1200, 4000), (3000, 100)] {
+            let gain = elo_change(rating, opponent, 1.0);
+            let loss = elo_change(opponent, rating, 0.0);
+            assert_eq!(gain, -loss, "{} against {}", rating, opponent);
+            assert!(gain >= 0 && gain <= K_FACTOR as i32);
+        }
+        assert_eq!(elo_change(1200, 1200, 1.0), 16);
+        // Beating a much weaker player is worth less than beating a stronger one
+        assert!(elo_change(1600, 1200, 1.0) < elo_change(1200, 1600, 1.0));
+    }
+
+    #[test]
+    fn record_moves_both_ratings() {
+        let mut store = RatingStore::in_memory();
+        let (won, lost) = store.record(("a", "Alice"), ("b", "Bob"));
+        assert_eq!((won.rating, won.matches, won.wins), (INITIAL_RATING + 16, 1, 1));
+        assert_eq!((lost.rating, lost.matches, lost.wins), (INITIAL_RATING - 16, 1, 0));
+        assert_eq!(store.get("a").name, "Alice");
+        assert_eq!(store.get("nobody"), PlayerRating::default());
+    }
+
+    #[test]
+    fn open_missing_file_starts_empty() {
+        let path = temp_path("missing.toml");
+        let _ = fs::remove_file(&path);
+        let store = RatingStore::open(&path).unwrap();
+        assert!(store.players.is_empty());
+        assert!(!path.exists());
+    }
+
+    #[test]
+    fn open_broken_file_fails() {
+        let path = temp_path("broken.toml");
+        fs::write(&path, "players = [not toml").unwrap();
+        let err = RatingStore::open(&path).err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        // Left alone for whoever broke it
+        assert_eq!(fs::read_to_string(&path).unwrap(), "players = [not toml");
+        fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn save_round_trip() {
+        let dir = temp_path("dir");
+        let path = dir.join("ratings.toml");
+        let _ = fs::remove_dir_all(&dir);
+        let mut store = RatingStore::open(&path).unwrap();
+        store.record(("a", "Alice"), ("b", "Bob"));
+        store.record(("b", "Bob"), ("a", "Alice"));
+        assert!(!path.with_extension("tmp").exists());
+
+        let loaded = RatingStore::open(&path).unwrap();
+        assert_eq!(loaded.players, store.players);
+        assert_eq!(loaded.get("a").matches, 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
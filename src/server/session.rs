@@ -0,0 +1,398 @@
+// State of one match on the server, the same fields the Go backend keeps in GameContext.
+// Positions and the ball are whatever the players report, the server only passes them on,
+// except in Server netcode sessions where the server simulates the match itself.
+
+use std::time::Instant;
+
+use crate::pong::protos::pong::{CmdCtxSet, CmdInput, CmdLostPoint, CmdReady, Netcode, Room};
+use crate::pong::ai::Difficulty;
+use crate::pong::prediction::encode_rules;
+use crate::pong::world::{MatchRules, ScreenSide};
+
+use super::simulation::ServerMatch;
+
+pub const NO_PLAYER: u32 = u32::MAX;
+
+#[derive(Debug, Clone)]
+pub struct Seat {
+    pub player: u32,
+    // Name the player gave, shown to the other one
+    pub name: String,
+    // Rating key of the player in ranked matches, empty otherwise
+    pub key: String,
+    // Paddle position, -1 until the player reports one
+    pub pos: i32,
+    // Ready to start the match
+    pub ready: bool,
+    // Last point after which the player is ready to serve
+    pub serve: u32,
+    // Sequence number of the last message accepted from the player
+    pub seq: u64,
+    // Connection dropped at, the seat is kept for a while for the player to resume
+    pub away_since: Option<Instant>,
+}
+
+impl Seat {
+    fn empty() -> Seat {
+        Seat { player: NO_PLAYER, name: String::new(), key: String::new(), pos: -1, ready: false, serve: 0, seq: 0, away_since: None }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.player == NO_PLAYER
+    }
+
+    fn take(&mut self, player: u32, name: &str) {
+        *self = Seat::empty();
+        self.player = player;
+        self.name = name.to_string();
+    }
+}
+
+#[derive(Debug)]
+pub struct Session {
+    pub id: u32,
+    // Room name shown in the lobby
+    pub name: String,
+    // Private rooms can only be joined with the code, empty for public ones
+    pub join_code: String,
+    // Paired by the matchmaking queue, the result changes ratings
+    pub ranked: bool,
+    // Ratings were updated for this match already
+    pub rated: bool,
+    // Seed of gameplay random generator shared by both players
+    pub seed: u64,
+    // How players keep in sync, picked by the player who opened the session
+    pub netcode: Netcode,
+    // Rules of the match, also picked by the player who opened the session
+    pub rules: MatchRules,
+    pub left: Seat,
+    pub right: Seat,
+    pub ball_vx: i32,
+    pub ball_vy: i32,
+    // Ball position is i32::MAX until the ball master reports one
+    pub ball_posx: i32,
+    pub ball_posy: i32,
+    // Player the ball flies toward, only that one moves it
+    pub ball_master: u32,
+    // Newest simulation tick reported by players
+    pub tick: u64,
+    // Score agreed on, only the player who conceded a point reports it
+    pub points_played: u32,
+    pub score_left: i32,
+    pub score_right: i32,
+    pub sets_left: u32,
+    pub sets_right: u32,
+    pub point_winner: u32,
+    // Match the server runs, Server netcode only, from when both players are ready
+    pub simulation: Option<ServerMatch>,
+    // Seat taken by the server's computer player, Server netcode only
+    pub cpu: Option<(ScreenSide, Difficulty)>,
+}
+
+impl Session {
+    pub fn new(id: u32, seed: u64, netcode: Netcode) -> Session {
+        Session {
+            id,
+            name: String::new(),
+            join_code: String::new(),
+            ranked: false,
+            rated: false,
+            seed,
+            netcode,
+            rules: MatchRules::default(),
+            left: Seat::empty(),
+            right: Seat::empty(),
+            ball_vx: i32::MAX,
+            ball_vy: i32::MAX,
+            ball_posx: i32::MAX,
+            ball_posy: i32::MAX,
+            ball_master: NO_PLAYER,
+            tick: 0,
+            points_played: 0,
+            score_left: 0,
+            score_right: 0,
+            sets_left: 0,
+            sets_right: 0,
+            point_winner: 0,
+            simulation: None,
+            cpu: None,
+        }
+    }
+
+    // Player ids are unique as long as session ids stay below 2^30.
+    pub fn join(&mut self, name: &str) -> Option<u32> {
+        if self.left.is_empty() {
+            let player = (self.id << 2) | 0x1;
+            self.left.take(player, name);
+            return Some(player);
+        }
+        if self.right.is_empty() {
+            let player = (self.id << 2) | 0x2;
+            self.right.take(player, name);
+            return Some(player);
+        }
+        None
+    }
+
+    // Not listed and nobody can join it from the lobby. Ranked seats are only given by the queue.
+    pub fn is_private(&self) -> bool {
+        !self.join_code.is_empty() || self.ranked
+    }
+
+    // Side which won the match the server simulates.
+    pub fn winner(&self) -> Option<ScreenSide> {
+        self.simulation.as_ref().and_then(|simulation| simulation.world.winner())
+    }
+
+    // Room as listed in the lobby, the join code only for players of the room.
+    pub fn room(&self, with_code: bool) -> Room {
+        let mut room = Room::default();
+        room.session = self.id;
+        room.name = self.name.clone();
+        room.players = [&self.left, &self.right].iter().filter(|seat| !seat.is_empty()).map(|seat| seat.name.clone()).collect();
+        room.netcode = self.netcode.into();
+        if with_code {
+            room.join_code = self.join_code.clone();
+        }
+        room.score_left = self.score_left;
+        room.score_right = self.score_right;
+        room.rules = Some(encode_rules(&self.rules)).into();
+        room
+    }
+
+    pub fn has_free_seat(&self) -> bool {
+        self.left.is_empty() || self.right.is_empty()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.left.is_empty() && self.right.is_empty()
+    }
+
+    pub fn side(&self, player: u32) -> Option<ScreenSide> {
+        if player == NO_PLAYER {
+            None
+        } else if player == self.left.player {
+            Some(ScreenSide::Left)
+        } else if player == self.right.player {
+            Some(ScreenSide::Right)
+        } else {
+            None
+        }
+    }
+
+    pub fn seat_mut(&mut self, player: u32) -> Option<&mut Seat> {
+        match self.side(player)? {
+            ScreenSide::Left => Some(&mut self.left),
+            ScreenSide::Right => Some(&mut self.right),
+        }
+    }
+
+    pub fn opponent(&self, player: u32) -> Option<u32> {
+        match self.side(player)? {
+            ScreenSide::Left => Some(self.right.player),
+            ScreenSide::Right => Some(self.left.player),
+        }
+    }
+
+    pub fn leave(&mut self, player: u32) {
+        let side = self.side(player);
+        if let Some(seat) = self.seat_mut(player) {
+            *seat = Seat::empty();
+        }
+        // Computer does not play on alone, the session closes
+        if let (Some(side), Some((cpu_side, _))) = (side, self.cpu) {
+            if side != cpu_side {
+                self.cpu = None;
+                match cpu_side {
+                    ScreenSide::Left => self.left = Seat::empty(),
+                    ScreenSide::Right => self.right = Seat::empty(),
+                }
+            }
+        }
+    }
+
+    // Computer takes the free seat and is ready right away, None when the session is full.
+    pub fn seat_cpu(&mut self, difficulty: Difficulty) -> Option<u32> {
+        let player = self.join(&format!("CPU ({})", difficulty.name()))?;
+        let side = self.side(player)?;
+        self.seat_mut(player)?.ready = true;
+        self.cpu = Some((side, difficulty));
+        Some(player)
+    }
+
+    // Whether message `seq` from player is newer than the ones already applied, 0 is from clients that do not count.
+    pub fn accept_seq(&mut self, player: u32, seq: u64) -> bool {
+        if seq == 0 {
+            return true;
+        }
+        match self.seat_mut(player) {
+            Some(seat) if seq <= seat.seq => false,
+            Some(seat) => {
+                seat.seq = seq;
+                true
+            },
+            None => true,
+        }
+    }
+
+    pub fn report_tick(&mut self, tick: u64) {
+        if tick > self.tick {
+            self.tick = tick;
+        }
+    }
+
+    pub fn ctx(&self, seq: u64, sent_ms: u64) -> CmdCtxSet {
+        let mut ctx = CmdCtxSet::default();
+        ctx.session = self.id;
+        ctx.left_id = self.left.player;
+        ctx.right_id = self.right.player;
+        ctx.left_pos = self.left.pos;
+        ctx.right_pos = self.right.pos;
+        ctx.ball_vx = self.ball_vx;
+        ctx.ball_vy = self.ball_vy;
+        ctx.ball_posx = self.ball_posx;
+        ctx.ball_posy = self.ball_posy;
+        ctx.ball_master = self.ball_master;
+        ctx.left_serve = self.left.serve;
+        ctx.right_serve = self.right.serve;
+        ctx.left_away = self.left.away_since.is_some();
+        ctx.right_away = self.right.away_since.is_some();
+        ctx.seq = seq;
+        ctx.tick = self.tick;
+        ctx.sent_ms = sent_ms;
+        ctx.points_played = self.points_played;
+        ctx.score_left = self.score_left;
+        ctx.score_right = self.score_right;
+        ctx.sets_left = self.sets_left;
+        ctx.sets_right = self.sets_right;
+        ctx.point_winner = self.point_winner;
+        ctx.left_name = self.left.name.clone();
+        ctx.right_name = self.right.name.clone();
+        ctx
+    }
+
+    // Positions reported by `player`, ball only from the one it flies toward once both are ready.
+    pub fn set_ctx(&mut self, player: u32, ctx: &CmdCtxSet) {
+        if !self.accept_seq(player, ctx.seq) {
+            println!("Dropping out of order context {}", ctx.seq);
+            return;
+        }
+        self.report_tick(ctx.tick);
+        if ctx.left_pos != -1 {
+            self.left.pos = ctx.left_pos;
+        }
+        if ctx.right_pos != -1 {
+            self.right.pos = ctx.right_pos;
+        }
+        if !(self.left.ready && self.right.ready) {
+            return;
+        }
+        if ctx.ball_vx != i32::MAX && ctx.ball_vy != i32::MAX {
+            self.ball_vx = ctx.ball_vx;
+            self.ball_vy = ctx.ball_vy;
+            self.ball_posx = ctx.ball_posx;
+            self.ball_posy = ctx.ball_posy;
+            self.ball_master = if ctx.ball_vx > 0 { self.right.player } else { self.left.player };
+        }
+    }
+
+    pub fn ready(&mut self, player: u32, ready: &CmdReady) {
+        if !self.accept_seq(player, ready.seq) {
+            println!("Dropping out of order ready {}", ready.seq);
+            return;
+        }
+        let seat = match self.seat_mut(player) {
+            Some(seat) => seat,
+            None => {
+                println!("Invalid player ID for ready cmd");
+                return;
+            },
+        };
+        // Ready to serve after a point, players launch the ball themselves once both agreed
+        if ready.serve > 0 {
+            seat.serve = ready.serve;
+            return;
+        }
+        seat.ready = true;
+        if self.left.ready && self.right.ready && self.netcode == Netcode::Server {
+            if self.simulation.is_none() {
+                println!("Session {} simulation started", self.id);
+                let mut simulation = ServerMatch::new(self.seed, self.rules);
+                if let Some((side, difficulty)) = self.cpu {
+                    simulation.seat_cpu(side, difficulty, self.seed);
+                }
+                self.simulation = Some(simulation);
+            }
+        } else if self.left.ready && self.right.ready {
+            self.ball_vx = 300;
+            self.ball_vy = 300;
+            self.ball_posx = i32::MAX;
+            self.ball_posy = i32::MAX;
+            self.ball_master = self.right.player;
+        }
+    }
+
+    // Inputs for the server simulation, received before the match starts they are thrown away.
+    // Rollback matches start their lockstep copy with the first input once both players are in.
+    pub fn input(&mut self, player: u32, input: &CmdInput) {
+        if self.netcode == Netcode::Rollback && self.simulation.is_none() && !self.has_free_seat() {
+            println!("Session {} spectator simulation started", self.id);
+            self.simulation = Some(ServerMatch::lockstep(self.seed, self.rules));
+        }
+        let side = self.side(player);
+        if let (Some(simulation), Some(side)) = (self.simulation.as_mut(), side) {
+            simulation.receive(side, input.start_tick, &input.inputs);
+        }
+    }
+
+    // One tick of the server simulation, it waits while a player is away.
+    pub fn step(&mut self) {
+        let away = self.left.away_since.is_some() || self.right.away_since.is_some();
+        let simulation = match self.simulation.as_mut() {
+            Some(simulation) if !away => simulation,
+            _ => return,
+        };
+        simulation.step();
+        let world = &simulation.world;
+        self.tick = world.tick;
+        if world.points_played != self.points_played {
+            self.point_winner = if world.score_left > self.score_left || world.sets_left > self.sets_left { self.left.player } else { self.right.player };
+        }
+        self.points_played = world.points_played;
+        self.score_left = world.score_left;
+        self.score_right = world.score_right;
+        self.sets_left = world.sets_left;
+        self.sets_right = world.sets_right;
+    }
+
+    // Point reported by the player who conceded it, each point number is counted once.
+    pub fn lost_point(&mut self, player: u32, lost: &CmdLostPoint) {
+        if self.simulation.is_some() {
+            // Server counts the points of its own match
+            return;
+        }
+        let winner = match self.opponent(player) {
+            Some(winner) => winner,
+            None => {
+                println!("Invalid player ID for lost point");
+                return;
+            },
+        };
+        if lost.point != self.points_played + 1 {
+            println!("Ignoring lost point {} with {} points played", lost.point, self.points_played);
+            return;
+        }
+        if !self.accept_seq(player, lost.seq) {
+            println!("Dropping out of order lost point {}", lost.seq);
+            return;
+        }
+        self.report_tick(lost.tick);
+        self.points_played = lost.point;
+        self.score_left = lost.score_left;
+        self.score_right = lost.score_right;
+        self.sets_left = lost.sets_left;
+        self.sets_right = lost.sets_right;
+        self.point_winner = winner;
+        println!("Session {} point {} to {} score {}:{}", self.id, self.points_played, winner, self.score_left, self.score_right);
+    }
+}
@@ -0,0 +1,206 @@
+// Match of a Server netcode session. Each tick takes the next queued input of both players,
+// a player whose input did not arrive in time keeps doing what it did last.
+// Rollback sessions get a lockstep copy for their spectators instead: a tick runs only once the
+// inputs of both players for it are in, so it is the very match the players see.
+// A player waiting alone can have the server's computer take the other seat of a Server session.
+
+use std::collections::VecDeque;
+
+use crate::pong::ai::{AiController, Difficulty};
+use crate::pong::prediction::world_state;
+use crate::pong::protos::pong::CmdWorld;
+use crate::pong::rollback::{decode_input, encode_input, PlayerInput};
+use crate::pong::world::{MatchRules, PongWorld, ScreenSide, WorldInputs};
+
+// Inputs waiting beyond this are from a client running too far ahead, the oldest are dropped
+const MAX_QUEUED_INPUTS: usize = 16;
+
+#[derive(Debug, Default)]
+struct InputQueue {
+    queued: VecDeque<PlayerInput>,
+    // Number of the newest input received and of the last one taken from the queue
+    received: u64,
+    applied: u64,
+    last: PlayerInput,
+}
+
+impl InputQueue {
+    fn receive(&mut self, start: u64, inputs: &[u32]) {
+        for (index, input) in inputs.iter().enumerate() {
+            let number = start + index as u64;
+            // Inputs are sent again until the state includes them, older copies are skipped
+            if number != self.received + 1 {
+                continue;
+            }
+            self.queued.push_back(decode_input(*input));
+            self.received = number;
+        }
+    }
+
+    fn trim(&mut self) {
+        while self.queued.len() > MAX_QUEUED_INPUTS {
+            self.last = self.queued.pop_front().unwrap();
+            self.applied = self.applied + 1;
+        }
+    }
+
+    fn next(&mut self) -> PlayerInput {
+        if let Some(input) = self.queued.pop_front() {
+            self.last = input;
+            self.applied = self.applied + 1;
+        }
+        self.last
+    }
+}
+
+#[derive(Debug)]
+pub struct ServerMatch {
+    pub world: PongWorld,
+    left: InputQueue,
+    right: InputQueue,
+    lockstep: bool,
+    cpu: Option<AiController>,
+}
+
+impl ServerMatch {
+    pub fn new(seed: u64, rules: MatchRules) -> ServerMatch {
+        let mut world = PongWorld::new(seed);
+        world.rules = rules;
+        world.reset();
+        ServerMatch { world, left: InputQueue::default(), right: InputQueue::default(), lockstep: false, cpu: None }
+    }
+
+    pub fn lockstep(seed: u64, rules: MatchRules) -> ServerMatch {
+        ServerMatch { lockstep: true, ..ServerMatch::new(seed, rules) }
+    }
+
+    // Computer plays `side`, its inputs are made up here every tick.
+    pub fn seat_cpu(&mut self, side: ScreenSide, difficulty: Difficulty, seed: u64) {
+        self.cpu = Some(AiController::new(side, difficulty, seed));
+    }
+
+    pub fn receive(&mut self, side: ScreenSide, start: u64, inputs: &[u32]) {
+        match side {
+            ScreenSide::Left => self.left.receive(start, inputs),
+            ScreenSide::Right => self.right.receive(start, inputs),
+        }
+        if !self.lockstep {
+            self.left.trim();
+            self.right.trim();
+        }
+    }
+
+    // One tick, a lockstep match runs all ticks both players sent inputs for.
+    pub fn step(&mut self) {
+        if !self.lockstep {
+            self.step_tick();
+            return;
+        }
+        while !self.left.queued.is_empty() && !self.right.queued.is_empty() {
+            self.step_tick();
+        }
+    }
+
+    fn step_tick(&mut self) {
+        if let Some(cpu) = self.cpu.as_mut() {
+            // Rounded like inputs sent over the network, players predict the computer from the last one
+            let input = decode_input(encode_input(&PlayerInput { paddle: cpu.input(&self.world), serve: true }));
+            match cpu.side {
+                ScreenSide::Left => self.left.last = input,
+                ScreenSide::Right => self.right.last = input,
+            }
+        }
+        let left = self.left.next();
+        let right = self.right.next();
+        self.world.step(&WorldInputs { left: left.paddle, right: right.paddle, serve: left.serve && right.serve });
+    }
+
+    pub fn state(&self) -> CmdWorld {
+        let mut state = world_state(&self.world);
+        state.left_input = self.left.applied;
+        state.right_input = self.right.applied;
+        state.left_last = encode_input(&self.left.last);
+        state.right_last = encode_input(&self.right.last);
+        state
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::pong::world::PaddleInput;
+
+    fn encoded(count: usize) -> Vec<u32> {
+        (0..count).map(|index| encode_input(&PlayerInput { paddle: PaddleInput { up: index % 2 == 0, down: false, axis: 0.0 }, serve: index % 3 == 0 })).collect()
+    }
+
+    #[test]
+    fn queue_takes_each_input_once_in_order() {
+        let inputs = encoded(5);
+        let mut queue = InputQueue::default();
+        queue.receive(1, &inputs[..3]);
+        // Sent again with one more, and one after a gap that never came
+        queue.receive(2, &inputs[1..4]);
+        queue.receive(6, &inputs[4..]);
+        assert_eq!((queue.queued.len(), queue.received), (4, 4));
+        for input in &inputs[..4] {
+            assert_eq!(queue.next(), decode_input(*input));
+        }
+        // Dry queue repeats the last input
+        assert_eq!(queue.next(), decode_input(inputs[3]));
+        assert_eq!(queue.applied, 4);
+    }
+
+    #[test]
+    fn queue_trim_drops_the_oldest_inputs() {
+        let inputs = encoded(MAX_QUEUED_INPUTS + 4);
+        let mut queue = InputQueue::default();
+        queue.receive(1, &inputs);
+        queue.trim();
+        assert_eq!(queue.queued.len(), MAX_QUEUED_INPUTS);
+        // Dropped ones count as applied, the state tells the client they are done
+        assert_eq!(queue.applied, 4);
+        assert_eq!(queue.last, decode_input(inputs[3]));
+        assert_eq!(queue.next(), decode_input(inputs[4]));
+    }
+
+    #[test]
+    fn server_match_steps_without_waiting() {
+        let mut simulation = ServerMatch::new(3, MatchRules::default());
+        simulation.receive(ScreenSide::Left, 1, &encoded(MAX_QUEUED_INPUTS + 10));
+        assert_eq!(simulation.left.queued.len(), MAX_QUEUED_INPUTS);
+        simulation.step();
+        let state = simulation.state();
+        assert_eq!(state.tick, 1);
+        assert_eq!((state.left_input, state.right_input), (11, 0));
+    }
+
+    #[test]
+    fn lockstep_runs_ticks_both_players_sent() {
+        let mut simulation = ServerMatch::lockstep(3, MatchRules::default());
+        let inputs = encoded(MAX_QUEUED_INPUTS + 10);
+        simulation.receive(ScreenSide::Left, 1, &inputs);
+        // Nothing is dropped, the ticks have to be played as they were
+        assert_eq!(simulation.left.queued.len(), inputs.len());
+        simulation.step();
+        assert_eq!(simulation.world.tick, 0);
+
+        simulation.receive(ScreenSide::Right, 1, &inputs[..3]);
+        simulation.step();
+        assert_eq!(simulation.world.tick, 3);
+        simulation.receive(ScreenSide::Right, 4, &inputs[3..5]);
+        simulation.step();
+        let state = simulation.state();
+        assert_eq!((state.tick, state.left_input, state.right_input), (5, 5, 5));
+
+        // Same match as the players ran with these inputs
+        let mut world = PongWorld::new(3);
+        world.rules = MatchRules::default();
+        world.reset();
+        for input in &inputs[..5] {
+            let input = decode_input(*input);
+            world.step(&WorldInputs { left: input.paddle, right: input.paddle, serve: input.serve });
+        }
+        assert_eq!(world_state(&world), world_state(&simulation.world));
+    }
+}
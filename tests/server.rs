@@ -0,0 +1,331 @@
+// Game server driven through the client connection thread, like the game does it.
+#![allow(clippy::field_reassign_with_default)]
+
+use std::sync::mpsc::{channel, Receiver, Sender};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use rengine::pong::prediction::{apply_world_state, decode_rules};
+use rengine::pong::protos::pong::*;
+use rengine::pong::rollback::{encode_input, PlayerInput};
+use rengine::pong::tls::TlsSettings;
+use rengine::pong::world::{PaddleInput, PongWorld, ScreenSide};
+use rengine::pong::{srv_thread, SrvMessage};
+use rengine::server::{self, ServerHandle};
+use websocket::url::Url;
+
+const NO_PLAYER: u32 = u32::MAX;
+
+struct Client {
+    tx: Sender<PongData>,
+    rx: Receiver<SrvMessage>,
+    id: u32,
+    session: u32,
+    resume_token: u64,
+    netcode: Netcode,
+    room: Option<Room>,
+    // Why the last room request was refused
+    error: String,
+    ctx: Option<CmdCtxSet>,
+    worlds: Vec<CmdWorld>,
+    spectating: Option<CmdSpectating>,
+}
+
+impl Client {
+    fn connect(handle: &ServerHandle) -> Client {
+        let (thread_tx, rx) = channel();
+        let (tx, thread_rx) = channel();
+        let url = Url::parse(&handle.url()).unwrap();
+        thread::spawn(move || srv_thread(url, TlsSettings::default(), thread_tx, thread_rx));
+        Client { tx, rx, id: 0, session: 0, resume_token: 0, netcode: Netcode::BallMaster, room: None, error: String::new(), ctx: None, worlds: Vec::new(), spectating: None }
+    }
+
+    fn send(&self, data_type: DataType, fill: impl FnOnce(&mut PongData)) {
+        let mut msg = PongData::new();
+        msg.type_ = data_type.into();
+        fill(&mut msg);
+        self.tx.send(msg).unwrap();
+    }
+
+    fn get_id(&self, netcode: Netcode, name: &str, resume_token: u64) {
+        let mut cmd_id = CmdIdGet::default();
+        cmd_id.netcode = netcode.into();
+        cmd_id.player_name = name.to_string();
+        cmd_id.resume_token = resume_token;
+        self.send(DataType::GetId, |msg| msg.set_id_req(cmd_id));
+    }
+
+    fn ready(&self) {
+        let mut cmd_ready = CmdReady::default();
+        cmd_ready.session = self.session;
+        cmd_ready.player = self.id;
+        cmd_ready.seq = 1;
+        self.send(DataType::Ready, |msg| msg.set_ready(cmd_ready));
+    }
+
+    // Server keeps applying the last input once the queue runs dry. Inputs sent before the match
+    // starts are thrown away, so wait for its first state.
+    fn hold(&self, input: PlayerInput) {
+        let mut cmd_input = CmdInput::default();
+        cmd_input.session = self.session;
+        cmd_input.player = self.id;
+        cmd_input.start_tick = 1;
+        cmd_input.tick = 1;
+        cmd_input.inputs = vec![encode_input(&input)];
+        self.send(DataType::Input, |msg| msg.set_input(cmd_input));
+    }
+
+    fn receive(&mut self) {
+        while let Ok(msg) = self.rx.try_recv() {
+            let data = match msg {
+                SrvMessage::Data(data) => data,
+                SrvMessage::Error(err) => panic!("Connection failed: {}", err),
+                _ => continue,
+            };
+            match data.type_.enum_value() {
+                Ok(DataType::SetId) if !data.id_rsp().error.is_empty() => self.error = data.id_rsp().error.clone(),
+                Ok(DataType::SetId) => {
+                    let rsp = data.id_rsp();
+                    self.id = rsp.id;
+                    self.session = rsp.session;
+                    self.resume_token = rsp.resume_token;
+                    self.netcode = rsp.netcode.enum_value_or(Netcode::BallMaster);
+                    self.room = rsp.room.clone().into_option();
+                },
+                Ok(DataType::SetCtx) => self.ctx = Some(data.ctx_rsp().clone()),
+                Ok(DataType::World) => self.worlds.push(data.world().clone()),
+                Ok(DataType::Spectating) => self.spectating = Some(data.spectating().clone()),
+                _ => {},
+            }
+        }
+    }
+
+    // Keep receiving until `done` holds, fails after a while.
+    fn wait_for(&mut self, what: &str, done: impl Fn(&Client) -> bool) {
+        let start = Instant::now();
+        loop {
+            self.receive();
+            if done(self) {
+                return;
+            }
+            assert!(start.elapsed() < Duration::from_secs(20), "Timed out waiting for {}", what);
+            thread::sleep(Duration::from_millis(5));
+        }
+    }
+}
+
+// Two quick match players of a Server session, both seated and ready.
+fn server_match(handle: &ServerHandle) -> (Client, Client) {
+    let mut left = Client::connect(handle);
+    left.get_id(Netcode::Server, "Left", 0);
+    left.wait_for("left seat", |client| client.id != 0);
+    let mut right = Client::connect(handle);
+    right.get_id(Netcode::Server, "Right", 0);
+    right.wait_for("right seat", |client| client.id != 0);
+    (left, right)
+}
+
+fn create_room(client: &mut Client, join_code: &str) {
+    let mut cmd_create = CmdRoomCreate::default();
+    cmd_create.join_code = join_code.to_string();
+    cmd_create.player_name = "Host".to_string();
+    cmd_create.netcode = Netcode::Server.into();
+    client.send(DataType::CreateRoom, |msg| msg.set_room_create(cmd_create));
+    client.wait_for("room", |client| client.id != 0);
+}
+
+fn subscribe(client: &Client, session: u32) {
+    let mut cmd_subscribe = CmdSubscribe::default();
+    cmd_subscribe.session = session;
+    client.send(DataType::Subscribe, |msg| msg.set_subscribe(cmd_subscribe));
+}
+
+fn hold_up(up: bool) -> PlayerInput {
+    PlayerInput { paddle: PaddleInput { up, down: false, axis: 0.0 }, serve: true }
+}
+
+#[test]
+fn quick_match_seats_both_players() {
+    let handle = server::start("127.0.0.1:0").unwrap();
+    let (mut left, right) = server_match(&handle);
+    assert_eq!(left.session, right.session);
+    assert_ne!(left.id, right.id);
+    left.wait_for("both names", |client| client.ctx.as_ref().is_some_and(|ctx| ctx.right_name == "Right"));
+    let ctx = left.ctx.clone().unwrap();
+    assert_eq!((ctx.session, ctx.left_id, ctx.right_id), (left.session, left.id, right.id));
+    assert_eq!(ctx.left_name, "Left");
+
+    // Next player gets a new session
+    let mut third = Client::connect(&handle);
+    third.get_id(Netcode::Server, "Third", 0);
+    third.wait_for("third seat", |client| client.id != 0);
+    assert_ne!(third.session, left.session);
+    handle.stop();
+}
+
+#[test]
+fn ready_players_get_world_states() {
+    let handle = server::start("127.0.0.1:0").unwrap();
+    let (mut left, mut right) = server_match(&handle);
+    left.ready();
+    right.ready();
+    left.wait_for("match start", |client| !client.worlds.is_empty());
+    left.hold(hold_up(true));
+    right.hold(hold_up(false));
+    left.wait_for("left input applied", |client| client.worlds.last().is_some_and(|world| world.left_input == 1 && world.tick > 60));
+    right.wait_for("world states", |client| client.worlds.len() > 5);
+    let world = right.worlds.last().unwrap();
+    assert_eq!(world.left_last, encode_input(&hold_up(true)));
+    assert!(world.left_y < world.right_y);
+    // States come in tick order
+    let ticks: Vec<u64> = left.worlds.iter().map(|world| world.tick).collect();
+    assert!(ticks.windows(2).all(|pair| pair[0] < pair[1]));
+    handle.stop();
+}
+
+#[test]
+fn dropped_player_resumes_the_seat() {
+    let handle = server::start("127.0.0.1:0").unwrap();
+    let (mut left, right) = server_match(&handle);
+    let (id, session, token) = (right.id, right.session, right.resume_token);
+    assert_ne!(token, 0);
+    drop(right);
+    left.wait_for("right away", |client| client.ctx.as_ref().is_some_and(|ctx| ctx.right_away));
+
+    // Wrong token gets nothing
+    let mut stranger = Client::connect(&handle);
+    stranger.get_id(Netcode::Server, "Stranger", token + 1);
+    stranger.wait_for("resume refused", |client| client.session != 0);
+    assert_eq!((stranger.id, stranger.session), (u32::MAX, u32::MAX));
+
+    let mut right = Client::connect(&handle);
+    right.get_id(Netcode::Server, "Right", token);
+    right.wait_for("resumed seat", |client| client.id != 0);
+    assert_eq!((right.id, right.session, right.resume_token), (id, session, token));
+    left.ready();
+    right.ready();
+    left.wait_for("right back", |client| client.ctx.as_ref().is_some_and(|ctx| !ctx.right_away && ctx.right_id == id));
+    handle.stop();
+}
+
+#[test]
+fn server_match_is_played_to_the_end() {
+    let handle = server::start("127.0.0.1:0").unwrap();
+    let mut left = Client::connect(&handle);
+    let mut rules = Rules::default();
+    rules.points_to_win = 1;
+    rules.best_of = 1;
+    let mut cmd_create = CmdRoomCreate::default();
+    cmd_create.player_name = "Left".to_string();
+    cmd_create.netcode = Netcode::Server.into();
+    cmd_create.rules = Some(rules).into();
+    left.send(DataType::CreateRoom, |msg| msg.set_room_create(cmd_create));
+    left.wait_for("room", |client| client.id != 0);
+
+    let mut right = Client::connect(&handle);
+    let mut cmd_join = CmdRoomJoin::default();
+    cmd_join.session = left.session;
+    cmd_join.player_name = "Right".to_string();
+    right.send(DataType::JoinRoom, |msg| msg.set_room_join(cmd_join));
+    right.wait_for("joined room", |client| client.id != 0);
+    assert_eq!(right.session, left.session);
+    // Joining player plays by the rules of the room
+    let rules = decode_rules(right.room.as_ref().unwrap().rules.as_ref());
+    assert_eq!(rules.points_to_win, 1);
+
+    // Left paddle hides at the top, the right one waits in the middle
+    left.ready();
+    right.ready();
+    left.wait_for("match start", |client| !client.worlds.is_empty());
+    left.hold(hold_up(true));
+    right.hold(hold_up(false));
+    let mut world = PongWorld::new(0);
+    world.rules = rules;
+    world.reset();
+    right.wait_for("match winner", |client| {
+        let mut world = world.clone();
+        client.worlds.last().is_some_and(|state| {
+            apply_world_state(&mut world, state);
+            world.winner().is_some()
+        })
+    });
+    apply_world_state(&mut world, right.worlds.last().unwrap());
+    let winner = world.winner().unwrap();
+    assert_eq!(world.points_played, 1);
+    assert_eq!((world.sets_left, world.sets_right), if winner == ScreenSide::Left { (1, 0) } else { (0, 1) });
+    left.receive();
+    assert_ne!(left.ctx.as_ref().unwrap().right_id, NO_PLAYER);
+    handle.stop();
+}
+
+#[test]
+fn joining_own_room_is_refused() {
+    let handle = server::start("127.0.0.1:0").unwrap();
+    let mut host = Client::connect(&handle);
+    create_room(&mut host, "");
+    let (id, session) = (host.id, host.session);
+    let mut cmd_join = CmdRoomJoin::default();
+    cmd_join.session = session;
+    host.send(DataType::JoinRoom, |msg| msg.set_room_join(cmd_join));
+    host.wait_for("refusal", |client| !client.error.is_empty());
+    assert_eq!(host.error, "Already in this room");
+    assert_eq!((host.id, host.session), (id, session));
+
+    // Quick match never picks the room the player is alone in
+    host.get_id(Netcode::Server, "Host", 0);
+    host.wait_for("quick match seat", |client| client.session != session);
+
+    // Server is still there for everybody else
+    let mut other = Client::connect(&handle);
+    other.get_id(Netcode::Server, "Other", 0);
+    other.wait_for("seat", |client| client.id != 0);
+    assert_eq!(other.session, host.session);
+    handle.stop();
+}
+
+#[test]
+fn subscribing_without_a_seat_is_watching() {
+    let handle = server::start("127.0.0.1:0").unwrap();
+    let mut private = Client::connect(&handle);
+    create_room(&mut private, "SECRET");
+    let mut public = Client::connect(&handle);
+    create_room(&mut public, "");
+
+    // Session ids are no way around the join code
+    let mut stranger = Client::connect(&handle);
+    subscribe(&stranger, private.session);
+    stranger.wait_for("refusal", |client| client.spectating.is_some());
+    assert_eq!(stranger.spectating.as_ref().unwrap().session, u32::MAX);
+    assert!(stranger.ctx.is_none());
+
+    subscribe(&stranger, public.session);
+    stranger.wait_for("public room state", |client| client.ctx.is_some());
+    assert_eq!(stranger.spectating.as_ref().unwrap().session, public.session);
+    public.wait_for("spectator counted", |client| client.ctx.as_ref().is_some_and(|ctx| ctx.spectators == 1));
+    assert_eq!(stranger.id, 0);
+    handle.stop();
+}
+
+#[test]
+fn cpu_takes_the_free_seat_of_any_room() {
+    let handle = server::start("127.0.0.1:0").unwrap();
+    let mut player = Client::connect(&handle);
+    player.get_id(Netcode::Rollback, "Player", 0);
+    player.wait_for("seat", |client| client.id != 0);
+    assert_eq!(player.netcode, Netcode::Rollback);
+    let (id, session, token) = (player.id, player.session, player.resume_token);
+
+    // Only the server runs the computer, the room becomes a Server one and the seat stays
+    let mut cmd_cpu = CmdCpuAdd::default();
+    cmd_cpu.session = session;
+    cmd_cpu.difficulty = Difficulty::Easy.into();
+    player.send(DataType::AddCpu, |msg| msg.set_cpu_add(cmd_cpu));
+    player.wait_for("Server netcode", |client| client.netcode == Netcode::Server);
+    assert_eq!((player.id, player.session, player.resume_token), (id, session, token));
+    player.wait_for("computer seated", |client| client.ctx.as_ref().is_some_and(|ctx| ctx.right_id != NO_PLAYER));
+    assert_eq!(player.ctx.as_ref().unwrap().right_name, "CPU (Easy)");
+
+    player.ready();
+    player.wait_for("match start", |client| !client.worlds.is_empty());
+    handle.stop();
+}